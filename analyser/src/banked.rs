@@ -0,0 +1,419 @@
+use crate::{Analysis, Disassembly, FunctionStep, FunctionTrace, MemoryMap, Observations};
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::machine::MemoryReadOnly;
+use mos6502_model::Address;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+pub type Bank = usize;
+
+// How many instructions before a JSR are searched for code which switches banks
+const BANK_SWITCH_WINDOW: usize = 24;
+// How many instructions of a function called while switching banks are followed
+const BANK_SWITCH_CALLEE_WINDOW: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BankedAddress {
+    pub bank: Bank,
+    pub address: Address,
+}
+
+impl fmt::Display for BankedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02X}:{:04X}", self.bank, self.address)
+    }
+}
+
+/// A memory map for cartridges whose PRG ROM is split into banks, some of which can be switched
+/// in and out of a window of the address space at runtime
+pub trait BankedMemoryMap: MemoryMap {
+    /// The banks which can be mapped into the switchable window
+    fn switchable_banks(&self) -> Vec<Bank>;
+
+    /// Returns the bank containing `address` when `selected` is mapped into the switchable window,
+    /// or None if `address` isn't in PRG ROM
+    fn bank_at(&self, selected: Bank, address: Address) -> Option<Bank>;
+
+    /// Reads the byte at `address` when `bank` is mapped at that address
+    fn read_banked_u8(&self, bank: Bank, address: Address) -> u8;
+
+    /// Returns the bank which would be mapped into the switchable window after writing each
+    /// value to its address in order, or None if the writes don't switch banks
+    fn bank_selected_by_writes(&self, writes: &[(Address, u8)]) -> Option<Bank>;
+}
+
+/// The memory seen by the CPU when a particular bank is mapped into the switchable window
+pub struct BankView<'a, MRO: MemoryReadOnly, BMM: BankedMemoryMap> {
+    memory: &'a MRO,
+    memory_map: &'a BMM,
+    selected: Bank,
+}
+
+impl<'a, MRO: MemoryReadOnly, BMM: BankedMemoryMap> BankView<'a, MRO, BMM> {
+    /// Addresses outside of PRG ROM are read from `memory`
+    pub fn new(memory: &'a MRO, memory_map: &'a BMM, selected: Bank) -> Self {
+        Self {
+            memory,
+            memory_map,
+            selected,
+        }
+    }
+}
+
+impl<MRO: MemoryReadOnly, BMM: BankedMemoryMap> MemoryReadOnly for BankView<'_, MRO, BMM> {
+    fn read_u8_read_only(&self, address: Address) -> u8 {
+        match self.memory_map.bank_at(self.selected, address) {
+            Some(bank) => self.memory_map.read_banked_u8(bank, address),
+            None => self.memory.read_u8_read_only(address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Registers {
+    pub a: Option<u8>,
+    pub x: Option<u8>,
+    pub y: Option<u8>,
+}
+
+impl Registers {
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+    // Updates the known register values after running an instruction. Returns the address and
+    // value written by the instruction if it's a store.
+    fn step(
+        &mut self,
+        instruction_with_operand: &InstructionWithOperand,
+    ) -> Option<(Address, Option<u8>)> {
+        use InstructionType::*;
+        let instruction = instruction_with_operand.instruction();
+        let immediate = match instruction.addressing_mode() {
+            AddressingMode::Immediate => instruction_with_operand.operand_u8(),
+            _ => None,
+        };
+        let store_address = match instruction.addressing_mode() {
+            AddressingMode::ZeroPage => instruction_with_operand.operand_u8().map(Address::from),
+            AddressingMode::Absolute => instruction_with_operand.operand_u16_le(),
+            _ => None,
+        };
+        match instruction.instruction_type() {
+            Lda => self.a = immediate,
+            Ldx => self.x = immediate,
+            Ldy => self.y = immediate,
+            Tax => self.x = self.a,
+            Tay => self.y = self.a,
+            Txa => self.a = self.x,
+            Tya => self.a = self.y,
+            Inx => self.x = self.x.map(|x| x.wrapping_add(1)),
+            Dex => self.x = self.x.map(|x| x.wrapping_sub(1)),
+            Iny => self.y = self.y.map(|y| y.wrapping_add(1)),
+            Dey => self.y = self.y.map(|y| y.wrapping_sub(1)),
+            And => self.a = self.a.zip(immediate).map(|(a, i)| a & i),
+            Ora => self.a = self.a.zip(immediate).map(|(a, i)| a | i),
+            Eor => self.a = self.a.zip(immediate).map(|(a, i)| a ^ i),
+            Lsr if instruction.addressing_mode() == AddressingMode::Accumulator => {
+                self.a = self.a.map(|a| a >> 1)
+            }
+            Asl if instruction.addressing_mode() == AddressingMode::Accumulator => {
+                self.a = self.a.map(|a| a << 1)
+            }
+            Rol | Ror if instruction.addressing_mode() == AddressingMode::Accumulator => {
+                self.a = None
+            }
+            Adc | Sbc | Pla => self.a = None,
+            Tsx => self.x = None,
+            Lax => self.clear(),
+            Sta => return store_address.map(|address| (address, self.a)),
+            Stx => return store_address.map(|address| (address, self.x)),
+            Sty => return store_address.map(|address| (address, self.y)),
+            _ => (),
+        }
+        None
+    }
+}
+
+// Returns the instructions of the function at `address`, excluding the RTS, if it contains no
+// branches or jumps
+fn straight_line_function<M: MemoryReadOnly>(
+    memory: &M,
+    address: Address,
+) -> Option<Vec<InstructionWithOperand>> {
+    let mut address = address;
+    let mut body = Vec::new();
+    for _ in 0..BANK_SWITCH_CALLEE_WINDOW {
+        let instruction_with_operand = InstructionWithOperand::decode(address, memory).ok()?;
+        let instruction = instruction_with_operand.instruction();
+        match instruction.instruction_type() {
+            InstructionType::Rts => return Some(body),
+            InstructionType::Jmp
+            | InstructionType::Jsr
+            | InstructionType::Rti
+            | InstructionType::Brk => return None,
+            _ if instruction.addressing_mode() == AddressingMode::Relative => return None,
+            _ => (),
+        }
+        address = address.wrapping_add(instruction.size() as Address);
+        body.push(instruction_with_operand);
+    }
+    None
+}
+
+// Runs straight-line code, following calls to functions which contain no branches, and records
+// the values written by stores whose value is known
+pub(crate) fn simulate_writes<M: MemoryReadOnly>(
+    instructions: &[&InstructionWithOperand],
+    memory: &M,
+    registers: &mut Registers,
+    writes: &mut Vec<(Address, u8)>,
+) {
+    for instruction_with_operand in instructions {
+        let instruction = instruction_with_operand.instruction();
+        if instruction.instruction_type() == InstructionType::Jsr {
+            let callee = instruction_with_operand.operand_u16_le().unwrap();
+            match straight_line_function(memory, callee) {
+                Some(body) => {
+                    simulate_writes(&body.iter().collect::<Vec<_>>(), memory, registers, writes)
+                }
+                None => registers.clear(),
+            }
+            continue;
+        }
+        if let Some((address, value)) = registers.step(instruction_with_operand) {
+            match value {
+                Some(value) => writes.push((address, value)),
+                // an unknown write to a mapper register makes earlier writes meaningless
+                None if address >= 0x8000 => writes.clear(),
+                None => (),
+            }
+        }
+    }
+}
+
+// Returns the bank switched to by the code immediately before a JSR, if it can be determined
+fn bank_selected_before_call<M: MemoryReadOnly, BMM: BankedMemoryMap>(
+    disassembly: &Disassembly,
+    memory: &M,
+    memory_map: &BMM,
+    call_site: Address,
+) -> Option<Bank> {
+    let preceding = disassembly.preceding_instructions(call_site, BANK_SWITCH_WINDOW);
+    let mut writes = Vec::new();
+    simulate_writes(&preceding, memory, &mut Registers::default(), &mut writes);
+    memory_map.bank_selected_by_writes(&writes)
+}
+
+// Removes observed code and data in banks which aren't mapped while `selected` is selected
+fn observations_with_bank_selected<BMM: BankedMemoryMap>(
+    observations: &Observations,
+    memory_map: &BMM,
+    selected: Bank,
+) -> Observations {
+    let is_mapped = |location: &&BankedAddress| {
+        memory_map.bank_at(selected, location.address) == Some(location.bank)
+    };
+    Observations {
+        opcodes: observations
+            .opcodes
+            .iter()
+            .filter(is_mapped)
+            .cloned()
+            .collect(),
+        data: observations
+            .data
+            .iter()
+            .filter(is_mapped)
+            .cloned()
+            .collect(),
+        ..observations.clone()
+    }
+}
+
+/// The result of analysing a program once for each bank which can be mapped into the switchable
+/// window, with functions and calls identified by (bank, address) pairs
+pub struct BankedAnalysis {
+    analyses_by_selected_bank: BTreeMap<Bank, Analysis>,
+    /// Maps each JSR instruction to the banks which may be mapped where its callee is
+    call_site_banks: BTreeMap<BankedAddress, BTreeSet<Bank>>,
+    function_traces: BTreeMap<BankedAddress, (Bank, Address)>,
+    by_caller: BTreeMap<BankedAddress, BTreeSet<BankedAddress>>,
+    by_callee: BTreeMap<BankedAddress, BTreeSet<BankedAddress>>,
+}
+
+impl BankedAnalysis {
+    pub fn analyse<MRO: MemoryReadOnly, BMM: BankedMemoryMap, I: IntoIterator<Item = Address>>(
+        memory: &MRO,
+        memory_map: &BMM,
+        extra_function_definition_addresses: I,
+        observations: &Observations,
+    ) -> Self {
+        let extra_function_definition_addresses = extra_function_definition_addresses
+            .into_iter()
+            .collect::<Vec<_>>();
+        let switchable_banks = memory_map.switchable_banks();
+        let mut observed_banks: BTreeMap<BankedAddress, BTreeSet<Bank>> = BTreeMap::new();
+        for &(call_site, bank) in observations.call_site_banks.iter() {
+            observed_banks.entry(call_site).or_default().insert(bank);
+        }
+        let mut analyses_by_selected_bank = BTreeMap::new();
+        let mut call_site_banks: BTreeMap<BankedAddress, BTreeSet<Bank>> = BTreeMap::new();
+        for &selected in switchable_banks.iter() {
+            let view = BankView::new(memory, memory_map, selected);
+            let analysis = Analysis::analyse_with_observations(
+                &view,
+                memory_map,
+                extra_function_definition_addresses.iter().cloned(),
+                &observations_with_bank_selected(observations, memory_map, selected),
+            );
+            let disassembly = analysis.disassembly();
+            for callee in disassembly.called_addresses() {
+                let callee_bank = match memory_map.bank_at(selected, callee) {
+                    Some(callee_bank) => callee_bank,
+                    None => continue,
+                };
+                for call_site in disassembly.call_sites(callee) {
+                    let site_bank = match memory_map.bank_at(selected, call_site) {
+                        Some(site_bank) => site_bank,
+                        None => continue,
+                    };
+                    let banked_call_site = BankedAddress {
+                        bank: site_bank,
+                        address: call_site,
+                    };
+                    // an address is switchable if which bank it's in depends on the selection
+                    let is_switchable = |address| {
+                        switchable_banks.iter().any(|&other| {
+                            memory_map.bank_at(other, address)
+                                != memory_map.bank_at(selected, address)
+                        })
+                    };
+                    let banks = call_site_banks.entry(banked_call_site).or_default();
+                    if !is_switchable(callee) || is_switchable(call_site) {
+                        // the callee is in the same bank regardless of which bank is selected,
+                        // or the caller is in the switchable window and is assumed not to switch
+                        // itself out
+                        banks.insert(callee_bank);
+                        continue;
+                    }
+                    let mut known = false;
+                    if let Some(bank) =
+                        bank_selected_before_call(disassembly, &view, memory_map, call_site)
+                    {
+                        banks.insert(bank);
+                        known = true;
+                    }
+                    if let Some(observed) = observed_banks.get(&banked_call_site) {
+                        banks.extend(observed.iter().cloned());
+                        known = true;
+                    }
+                    if !known {
+                        // with nothing known about the call, any bank could be selected
+                        banks.insert(selected);
+                    }
+                }
+            }
+            analyses_by_selected_bank.insert(selected, analysis);
+        }
+        let mut banked_analysis = Self {
+            analyses_by_selected_bank,
+            call_site_banks,
+            function_traces: BTreeMap::new(),
+            by_caller: BTreeMap::new(),
+            by_callee: BTreeMap::new(),
+        };
+        banked_analysis.build_call_graph(memory_map);
+        banked_analysis
+    }
+
+    fn build_call_graph<BMM: BankedMemoryMap>(&mut self, memory_map: &BMM) {
+        for (&selected, analysis) in self.analyses_by_selected_bank.iter() {
+            for (address, trace) in analysis.function_traces() {
+                let bank = match memory_map.bank_at(selected, address) {
+                    Some(bank) => bank,
+                    None => continue,
+                };
+                let caller = BankedAddress { bank, address };
+                self.function_traces
+                    .entry(caller)
+                    .or_insert((selected, address));
+                self.by_caller.entry(caller).or_default();
+                self.by_callee.entry(caller).or_default();
+                for step in trace.steps() {
+                    let callee = match step {
+                        FunctionStep::FunctionCall { callee, .. } => *callee,
+                        _ => continue,
+                    };
+                    let (site_bank, callee_bank) = match (
+                        memory_map.bank_at(selected, step.address()),
+                        memory_map.bank_at(selected, callee),
+                    ) {
+                        (Some(site_bank), Some(callee_bank)) => (site_bank, callee_bank),
+                        _ => continue,
+                    };
+                    let call_site = BankedAddress {
+                        bank: site_bank,
+                        address: step.address(),
+                    };
+                    let mappable = self
+                        .call_site_banks
+                        .get(&call_site)
+                        .map(|banks| banks.contains(&callee_bank))
+                        .unwrap_or(false);
+                    if mappable {
+                        let callee = BankedAddress {
+                            bank: callee_bank,
+                            address: callee,
+                        };
+                        self.by_caller.entry(caller).or_default().insert(callee);
+                        self.by_callee.entry(callee).or_default().insert(caller);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the analysis of the program as seen with `bank` mapped into the switchable window
+    pub fn analysis_with_bank_selected(&self, bank: Bank) -> Option<&Analysis> {
+        self.analyses_by_selected_bank.get(&bank)
+    }
+
+    /// Returns the banks which may be mapped at the callee of the JSR at `call_site`
+    pub fn mappable_banks(&self, call_site: BankedAddress) -> Option<&BTreeSet<Bank>> {
+        self.call_site_banks.get(&call_site)
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = BankedAddress> + '_ {
+        self.function_traces.keys().cloned()
+    }
+
+    pub fn function_trace(&self, function: BankedAddress) -> Option<&FunctionTrace> {
+        let &(selected, address) = self.function_traces.get(&function)?;
+        self.analyses_by_selected_bank[&selected].function_trace(address)
+    }
+
+    pub fn callees_of_function(&self, function: BankedAddress) -> Option<&BTreeSet<BankedAddress>> {
+        self.by_caller.get(&function)
+    }
+
+    pub fn callers_of_function(&self, function: BankedAddress) -> Option<&BTreeSet<BankedAddress>> {
+        self.by_callee.get(&function)
+    }
+
+    pub fn call_graph_dot_string(&self) -> String {
+        use std::fmt::Write;
+        let mut s = String::new();
+        writeln!(&mut s, "digraph {{").unwrap();
+        for (caller, callees) in self.by_caller.iter() {
+            for callee in callees {
+                writeln!(
+                    &mut s,
+                    "  f{:X}_0x{:X} -> f{:X}_0x{:X};",
+                    caller.bank, caller.address, callee.bank, callee.address
+                )
+                .unwrap();
+            }
+        }
+        writeln!(&mut s, "}}").unwrap();
+        s
+    }
+}
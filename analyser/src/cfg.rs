@@ -0,0 +1,398 @@
+use crate::{AddressGraph, FunctionStep, FunctionTrace};
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::Address;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    start: Address,
+    instructions: Vec<InstructionWithOperand>,
+}
+
+impl BasicBlock {
+    pub fn start(&self) -> Address {
+        self.start
+    }
+    /// Empty if the block consists of an invalid opcode
+    pub fn instructions(&self) -> &[InstructionWithOperand] {
+        &self.instructions
+    }
+}
+
+/// A loop whose header dominates every block in its body
+#[derive(Debug, Clone)]
+pub struct Loop {
+    header: Address,
+    latches: BTreeSet<Address>,
+    body: BTreeSet<Address>,
+}
+
+impl Loop {
+    pub fn header(&self) -> Address {
+        self.header
+    }
+    /// Blocks with an edge back to the header
+    pub fn latches(&self) -> &BTreeSet<Address> {
+        &self.latches
+    }
+    /// All blocks in the loop, including the header
+    pub fn body(&self) -> &BTreeSet<Address> {
+        &self.body
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ControlFlowGraph {
+    entry: Address,
+    blocks: BTreeMap<Address, BasicBlock>,
+    successors: AddressGraph,
+    predecessors: AddressGraph,
+    /// Edges which can never be followed, such as the fall-through of a branch whose condition
+    /// was set by the previous instruction
+    infeasible_edges: BTreeSet<(Address, Address)>,
+    /// Maps each block reachable from the entry to the set of blocks which dominate it
+    dominators: BTreeMap<Address, BTreeSet<Address>>,
+}
+
+fn step_successors(step: &FunctionStep) -> Vec<Address> {
+    match step {
+        FunctionStep::InvalidOpcode { .. } | FunctionStep::JumpIndirect(_) => Vec::new(),
+        FunctionStep::Branch {
+            instruction_with_operand,
+            absolute_target,
+            ..
+        } => vec![*absolute_target, next_address(instruction_with_operand)],
+        FunctionStep::FunctionCall {
+            instruction_with_operand,
+            ..
+        } => vec![next_address(instruction_with_operand)],
+        FunctionStep::TracedInstruction(instruction_with_operand) => {
+            let instruction = instruction_with_operand.instruction();
+            match instruction.instruction_type() {
+                InstructionType::Rts | InstructionType::Rti | InstructionType::Brk => Vec::new(),
+                InstructionType::Jmp => match instruction.addressing_mode() {
+                    AddressingMode::Absolute => {
+                        vec![instruction_with_operand.operand_u16_le().unwrap()]
+                    }
+                    _ => Vec::new(),
+                },
+                _ => vec![next_address(instruction_with_operand)],
+            }
+        }
+    }
+}
+
+fn next_address(instruction_with_operand: &InstructionWithOperand) -> Address {
+    instruction_with_operand
+        .address()
+        .wrapping_add(instruction_with_operand.instruction().size() as Address)
+}
+
+// Returns whether a branch is always taken given the instruction executed before it, or None if
+// this can't be determined
+fn constant_branch_condition(
+    previous: &InstructionWithOperand,
+    branch: &InstructionWithOperand,
+) -> Option<bool> {
+    use InstructionType::*;
+    let previous_instruction = previous.instruction();
+    match (
+        previous_instruction.instruction_type(),
+        branch.instruction().instruction_type(),
+    ) {
+        (Clc, Bcc) | (Sec, Bcs) | (Clv, Bvc) => Some(true),
+        (Clc, Bcs) | (Sec, Bcc) | (Clv, Bvs) => Some(false),
+        (Lda | Ldx | Ldy, branch_type)
+            if previous_instruction.addressing_mode() == AddressingMode::Immediate =>
+        {
+            let value = previous.operand_u8().unwrap();
+            match branch_type {
+                Beq => Some(value == 0),
+                Bne => Some(value != 0),
+                Bmi => Some(value & 0x80 != 0),
+                Bpl => Some(value & 0x80 == 0),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+impl ControlFlowGraph {
+    pub fn new(entry: Address, trace: &FunctionTrace) -> Self {
+        let steps = trace.steps();
+        let successors_by_step = steps.iter().map(step_successors).collect::<Vec<_>>();
+        let mut num_predecessors = BTreeMap::new();
+        for &successor in successors_by_step.iter().flatten() {
+            *num_predecessors.entry(successor).or_insert(0) += 1;
+        }
+        let mut blocks = BTreeMap::new();
+        let mut successors = AddressGraph(BTreeMap::new());
+        let mut predecessors = AddressGraph(BTreeMap::new());
+        let mut infeasible_edges = BTreeSet::new();
+        let mut current: Option<BasicBlock> = None;
+        for (i, step) in steps.iter().enumerate() {
+            let block = current.get_or_insert_with(|| BasicBlock {
+                start: step.address(),
+                instructions: Vec::new(),
+            });
+            if let Some(instruction_with_operand) = step.instruction_with_operand() {
+                block.instructions.push(instruction_with_operand.clone());
+            }
+            let step_successors = &successors_by_step[i];
+            let continues = match (step_successors.as_slice(), steps.get(i + 1)) {
+                ([successor], Some(next)) => {
+                    *successor == next.address()
+                        && next.address() != entry
+                        && num_predecessors.get(successor) == Some(&1)
+                }
+                _ => false,
+            };
+            if continues {
+                continue;
+            }
+            let block = current.take().unwrap();
+            let constant_condition = match (step, block.instructions.as_slice()) {
+                (FunctionStep::Branch { .. }, [.., previous, branch])
+                    if step_successors[0] != step_successors[1] =>
+                {
+                    constant_branch_condition(previous, branch)
+                }
+                _ => None,
+            };
+            for (j, &successor) in step_successors.iter().enumerate() {
+                successors
+                    .0
+                    .entry(block.start)
+                    .or_default()
+                    .insert(successor);
+                predecessors
+                    .0
+                    .entry(successor)
+                    .or_default()
+                    .insert(block.start);
+                // the branch target is first, followed by the fall-through
+                if let Some(taken) = constant_condition {
+                    if taken != (j == 0) {
+                        infeasible_edges.insert((block.start, successor));
+                    }
+                }
+            }
+            successors.0.entry(block.start).or_default();
+            predecessors.0.entry(block.start).or_default();
+            blocks.insert(block.start, block);
+        }
+        let mut cfg = Self {
+            entry,
+            blocks,
+            successors,
+            predecessors,
+            infeasible_edges,
+            dominators: BTreeMap::new(),
+        };
+        cfg.compute_dominators();
+        cfg
+    }
+
+    fn feasible_successors(&self, block: Address) -> impl Iterator<Item = Address> + '_ {
+        self.successors(block)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .filter(move |&successor| !self.infeasible_edges.contains(&(block, successor)))
+    }
+
+    fn feasible_predecessors(&self, block: Address) -> impl Iterator<Item = Address> + '_ {
+        self.predecessors(block)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .filter(move |&predecessor| !self.infeasible_edges.contains(&(predecessor, block)))
+    }
+
+    fn reachable_blocks(&self) -> BTreeSet<Address> {
+        let mut seen = BTreeSet::new();
+        let mut to_visit = vec![self.entry];
+        while let Some(block) = to_visit.pop() {
+            if self.blocks.contains_key(&block) && seen.insert(block) {
+                to_visit.extend(self.feasible_successors(block));
+            }
+        }
+        seen
+    }
+
+    fn compute_dominators(&mut self) {
+        let reachable = self.reachable_blocks();
+        let mut dominators = reachable
+            .iter()
+            .map(|&block| {
+                if block == self.entry {
+                    (block, [block].into_iter().collect::<BTreeSet<_>>())
+                } else {
+                    (block, reachable.clone())
+                }
+            })
+            .collect::<BTreeMap<_, _>>();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in reachable.iter() {
+                if block == self.entry {
+                    continue;
+                }
+                let mut new = self
+                    .feasible_predecessors(block)
+                    .filter_map(|predecessor| dominators.get(&predecessor))
+                    .fold(None, |acc: Option<BTreeSet<Address>>, set| match acc {
+                        None => Some(set.clone()),
+                        Some(acc) => Some(acc.intersection(set).cloned().collect()),
+                    })
+                    .unwrap_or_default();
+                new.insert(block);
+                if new != dominators[&block] {
+                    dominators.insert(block, new);
+                    changed = true;
+                }
+            }
+        }
+        self.dominators = dominators;
+    }
+
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.values()
+    }
+
+    pub fn block(&self, start: Address) -> Option<&BasicBlock> {
+        self.blocks.get(&start)
+    }
+
+    pub fn successors(&self, block: Address) -> Option<&BTreeSet<Address>> {
+        self.successors.get(block)
+    }
+
+    pub fn predecessors(&self, block: Address) -> Option<&BTreeSet<Address>> {
+        self.predecessors.get(block)
+    }
+
+    pub fn is_feasible_edge(&self, from: Address, to: Address) -> bool {
+        !self.infeasible_edges.contains(&(from, to))
+    }
+
+    /// Returns the blocks dominating the given block, including itself. Returns None if the block
+    /// is unreachable.
+    pub fn dominators(&self, block: Address) -> Option<&BTreeSet<Address>> {
+        self.dominators.get(&block)
+    }
+
+    pub fn dominates(&self, dominator: Address, block: Address) -> bool {
+        self.dominators(block)
+            .map(|dominators| dominators.contains(&dominator))
+            .unwrap_or(false)
+    }
+
+    /// Returns the strict dominator of the given block which is dominated by every other strict
+    /// dominator. Returns None for the entry and unreachable blocks.
+    pub fn immediate_dominator(&self, block: Address) -> Option<Address> {
+        let dominators = self.dominators(block)?;
+        let strict_dominators = || {
+            dominators
+                .iter()
+                .cloned()
+                .filter(move |&dominator| dominator != block)
+        };
+        strict_dominators().find(|&candidate| {
+            strict_dominators().all(|dominator| self.dominates(dominator, candidate))
+        })
+    }
+
+    /// Blocks which can't be reached from the entry of the function
+    pub fn unreachable_blocks(&self) -> impl Iterator<Item = Address> + '_ {
+        self.blocks
+            .keys()
+            .cloned()
+            .filter(move |block| !self.dominators.contains_key(block))
+    }
+
+    fn is_back_edge(&self, from: Address, to: Address) -> bool {
+        self.is_feasible_edge(from, to) && self.dominates(to, from)
+    }
+
+    /// Natural loops, one per loop header. Only blocks reachable from the entry are included in
+    /// loop bodies.
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: BTreeMap<Address, Loop> = BTreeMap::new();
+        for (&from, tos) in self.successors.0.iter() {
+            for &to in tos {
+                if !self.is_back_edge(from, to) {
+                    continue;
+                }
+                let loop_ = loops.entry(to).or_insert_with(|| Loop {
+                    header: to,
+                    latches: BTreeSet::new(),
+                    body: [to].into_iter().collect(),
+                });
+                loop_.latches.insert(from);
+                let mut to_visit = vec![from];
+                while let Some(block) = to_visit.pop() {
+                    if loop_.body.insert(block) {
+                        // every block in the body of a natural loop is dominated by its header,
+                        // which excludes predecessors that can't be reached from the entry
+                        to_visit.extend(
+                            self.feasible_predecessors(block)
+                                .filter(|&predecessor| self.dominates(to, predecessor)),
+                        );
+                    }
+                }
+            }
+        }
+        loops.into_values().collect()
+    }
+
+    pub fn dot_string(&self) -> String {
+        use std::fmt::Write;
+        let unreachable = self.unreachable_blocks().collect::<BTreeSet<_>>();
+        let mut s = String::new();
+        writeln!(&mut s, "digraph {{").unwrap();
+        writeln!(&mut s, "  node [shape=box, fontname=monospace];").unwrap();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if block.instructions.is_empty() {
+                write!(&mut label, "{:04X}  ????????\\l", block.start).unwrap();
+            }
+            for instruction_with_operand in block.instructions.iter() {
+                write!(&mut label, "{}\\l", instruction_with_operand).unwrap();
+            }
+            let style = if unreachable.contains(&block.start) {
+                ", style=dashed"
+            } else if block.start == self.entry {
+                ", style=bold"
+            } else {
+                ""
+            };
+            writeln!(
+                &mut s,
+                "  b0x{:X} [label=\"{}\"{}];",
+                block.start, label, style
+            )
+            .unwrap();
+        }
+        for (from, tos) in self.successors.0.iter() {
+            for &to in tos {
+                let style = if !self.is_feasible_edge(*from, to) {
+                    " [style=dashed]"
+                } else if self.is_back_edge(*from, to) {
+                    " [color=red]"
+                } else {
+                    ""
+                };
+                writeln!(&mut s, "  b0x{:X} -> b0x{:X}{};", from, to, style).unwrap();
+            }
+        }
+        writeln!(&mut s, "}}").unwrap();
+        s
+    }
+}
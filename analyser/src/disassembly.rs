@@ -0,0 +1,611 @@
+use crate::jump_table::{self, Dispatch, JumpTable};
+use crate::MemoryMap;
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::format::{Dialect, Formatter, SymbolTable};
+use mos6502_model::machine::MemoryReadOnly;
+use mos6502_model::{interrupt_vector, Address};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::RangeInclusive;
+
+const LISTING_BYTES_PER_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteKind {
+    Unknown,
+    Opcode,
+    Operand,
+    Data,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EntryPoint {
+    Nmi,
+    Reset,
+    Irq,
+    Extra,
+}
+
+/// The result of recursively following control flow from a set of entry points.
+/// Every byte is classified as code (opcode or operand), data, or unknown.
+#[derive(Debug, Clone)]
+pub struct Disassembly {
+    instructions: BTreeMap<Address, InstructionWithOperand>,
+    operand_bytes: BTreeSet<Address>,
+    /// Bytes known to be data, which control flow will not be followed into
+    data: BTreeSet<Address>,
+    /// Bytes in ROM referred to by the operands of instructions which access memory.
+    /// These are only considered data if they aren't found to be code.
+    data_references: BTreeSet<Address>,
+    entry_points: BTreeMap<Address, BTreeSet<EntryPoint>>,
+    /// Maps each called address to the addresses of the JSR instructions calling it
+    call_sites: BTreeMap<Address, BTreeSet<Address>>,
+    /// Maps each branch or JMP target to the addresses of the instructions jumping there
+    jump_sites: BTreeMap<Address, BTreeSet<Address>>,
+    /// Calls to these functions are not assumed to return to the following instruction
+    non_returning_functions: BTreeSet<Address>,
+    /// JSR instructions followed by a table of addresses used by the called function
+    inline_table_sites: BTreeSet<Address>,
+    /// Maps each instruction which jumps to an address computed at runtime (JMP (ind), or an
+    /// RTS used for dispatch) to the addresses it's known to jump to
+    indirect_jumps: BTreeMap<Address, BTreeSet<Address>>,
+    /// Maps each target of an indirect jump to the instructions jumping there
+    indirect_jump_sites: BTreeMap<Address, BTreeSet<Address>>,
+    /// Jump tables found by `resolve_jump_tables`, keyed by the address of the instruction which
+    /// dispatches through them
+    jump_tables: BTreeMap<Address, JumpTable>,
+    /// Addresses where control flow led to an invalid opcode or into the middle of
+    /// another instruction or data
+    conflicts: BTreeSet<Address>,
+}
+
+impl Disassembly {
+    fn empty() -> Self {
+        Self {
+            instructions: BTreeMap::new(),
+            operand_bytes: BTreeSet::new(),
+            data: BTreeSet::new(),
+            data_references: BTreeSet::new(),
+            entry_points: BTreeMap::new(),
+            call_sites: BTreeMap::new(),
+            jump_sites: BTreeMap::new(),
+            non_returning_functions: BTreeSet::new(),
+            inline_table_sites: BTreeSet::new(),
+            indirect_jumps: BTreeMap::new(),
+            indirect_jump_sites: BTreeMap::new(),
+            jump_tables: BTreeMap::new(),
+            conflicts: BTreeSet::new(),
+        }
+    }
+
+    /// Disassembles all code reachable from the interrupt vectors and the given entry points,
+    /// including through any jump tables which can be recognised
+    pub fn new<MRO: MemoryReadOnly, MM: MemoryMap, I: IntoIterator<Item = Address>>(
+        memory: &MRO,
+        memory_map: &MM,
+        extra_entry_points: I,
+    ) -> Self {
+        Self::with_known_data(memory, memory_map, extra_entry_points, None)
+    }
+
+    /// Like `new`, but the bytes in `known_data` (e.g. from a log of the bytes a program read
+    /// while running) are marked as data before disassembling, so they are never treated as code
+    pub fn with_known_data<
+        MRO: MemoryReadOnly,
+        MM: MemoryMap,
+        I: IntoIterator<Item = Address>,
+        D: IntoIterator<Item = Address>,
+    >(
+        memory: &MRO,
+        memory_map: &MM,
+        extra_entry_points: I,
+        known_data: D,
+    ) -> Self {
+        let mut disassembly = Self::empty();
+        disassembly.add_data(known_data);
+        for (vector_lo, entry_point) in [
+            (interrupt_vector::NMI_LO, EntryPoint::Nmi),
+            (interrupt_vector::START_LO, EntryPoint::Reset),
+            (interrupt_vector::IRQ_LO, EntryPoint::Irq),
+        ] {
+            for i in 0..2 {
+                if memory_map.is_rom(vector_lo + i) {
+                    disassembly.data.insert(vector_lo + i);
+                }
+            }
+            let address = memory.read_u16_le_read_only(vector_lo);
+            if memory_map.is_rom(address) {
+                disassembly.add_entry_point(memory, memory_map, address, entry_point);
+            }
+        }
+        for address in extra_entry_points {
+            disassembly.add_entry_point(memory, memory_map, address, EntryPoint::Extra);
+        }
+        disassembly.resolve_jump_tables(memory, memory_map);
+        disassembly
+    }
+
+    pub fn add_entry_point<MRO: MemoryReadOnly, MM: MemoryMap>(
+        &mut self,
+        memory: &MRO,
+        memory_map: &MM,
+        address: Address,
+        entry_point: EntryPoint,
+    ) {
+        self.entry_points
+            .entry(address)
+            .or_default()
+            .insert(entry_point);
+        self.trace(memory, memory_map, address);
+    }
+
+    /// Marks bytes as data, preventing them from being disassembled as code
+    pub fn add_data<I: IntoIterator<Item = Address>>(&mut self, addresses: I) {
+        self.data.extend(addresses);
+    }
+
+    pub fn add_non_returning_function(&mut self, address: Address) {
+        self.non_returning_functions.insert(address);
+    }
+
+    pub fn is_non_returning_function(&self, address: Address) -> bool {
+        self.non_returning_functions.contains(&address)
+    }
+
+    /// Records that the indirect jump (or dispatching RTS) at `site` can jump to `target`, and
+    /// disassembles the code there
+    pub fn add_indirect_jump<MRO: MemoryReadOnly, MM: MemoryMap>(
+        &mut self,
+        memory: &MRO,
+        memory_map: &MM,
+        site: Address,
+        target: Address,
+    ) {
+        self.indirect_jumps.entry(site).or_default().insert(target);
+        self.indirect_jump_sites
+            .entry(target)
+            .or_default()
+            .insert(site);
+        self.trace(memory, memory_map, target);
+    }
+
+    fn is_control_flow_target(&self, address: Address) -> bool {
+        self.entry_points.contains_key(&address)
+            || self.call_sites.contains_key(&address)
+            || self.jump_sites.contains_key(&address)
+            || self.indirect_jump_sites.contains_key(&address)
+    }
+
+    // Returns up to `max` instructions immediately preceding the one at `address` in memory, in
+    // order. This stops at instructions which control can reach from elsewhere, so all the
+    // instructions returned certainly run immediately before the one at `address`.
+    pub(crate) fn preceding_instructions(
+        &self,
+        address: Address,
+        max: usize,
+    ) -> Vec<&InstructionWithOperand> {
+        let mut preceding = Vec::new();
+        let mut next = address;
+        for instruction_with_operand in self.instructions.range(..address).rev().map(|(_, i)| i) {
+            if preceding.len() == max
+                || next_address(instruction_with_operand) != next
+                || self.is_control_flow_target(next)
+            {
+                break;
+            }
+            preceding.push(instruction_with_operand);
+            next = instruction_with_operand.address();
+        }
+        preceding.reverse();
+        preceding
+    }
+
+    fn is_jump_table_entry<MRO: MemoryReadOnly, MM: MemoryMap>(
+        &self,
+        memory: &MRO,
+        memory_map: &MM,
+        lo: Address,
+        hi: Address,
+        target: Address,
+    ) -> bool {
+        let is_code = |address| {
+            self.instructions.contains_key(&address) || self.operand_bytes.contains(&address)
+        };
+        memory_map.is_rom(lo)
+            && memory_map.is_rom(hi)
+            && !is_code(lo)
+            && !is_code(hi)
+            && !self.is_control_flow_target(lo)
+            && !self.is_control_flow_target(hi)
+            && memory_map.is_rom(target)
+            && !self.operand_bytes.contains(&target)
+            && !self.data.contains(&target)
+            && InstructionWithOperand::decode(target, memory).is_ok()
+    }
+
+    /// Looks for tables of addresses used by indirect jumps, RTS dispatch and functions which
+    /// read a table following their call site. Table entries are marked as data and their
+    /// targets are disassembled. This repeats until no new tables are found, as the newly
+    /// disassembled code may contain jump tables of its own.
+    pub fn resolve_jump_tables<MRO: MemoryReadOnly, MM: MemoryMap>(
+        &mut self,
+        memory: &MRO,
+        memory_map: &MM,
+    ) {
+        loop {
+            let mut jump_tables = Vec::new();
+            for instruction_with_operand in self.instructions.values() {
+                let site = instruction_with_operand.address();
+                if self.jump_tables.contains_key(&site) {
+                    continue;
+                }
+                let preceding = self.preceding_instructions(site, jump_table::DISPATCH_WINDOW);
+                let preceding = preceding.as_slice();
+                let instruction = instruction_with_operand.instruction();
+                let found = match (
+                    instruction.instruction_type(),
+                    instruction.addressing_mode(),
+                ) {
+                    (InstructionType::Jmp, AddressingMode::Indirect) => {
+                        jump_table::jump_indirect_table(preceding, instruction_with_operand)
+                            .map(|location| (Dispatch::JumpIndirect, location))
+                    }
+                    (InstructionType::Rts, _) => jump_table::return_address_table(preceding)
+                        .map(|location| (Dispatch::ReturnAddress, location)),
+                    (InstructionType::Jsr, _) if self.inline_table_sites.contains(&site) => Some((
+                        Dispatch::InlineAfterCall,
+                        jump_table::inline_table_location(site),
+                    )),
+                    _ => None,
+                };
+                if let Some((dispatch, location)) = found {
+                    jump_tables.push(jump_table::read_table(
+                        memory,
+                        site,
+                        dispatch,
+                        location,
+                        |lo, hi, target| {
+                            self.is_jump_table_entry(memory, memory_map, lo, hi, target)
+                        },
+                    ));
+                }
+            }
+            if jump_tables.is_empty() {
+                break;
+            }
+            for jump_table in jump_tables {
+                self.data.extend(jump_table.bytes());
+                for &target in jump_table.targets.iter() {
+                    self.add_indirect_jump(memory, memory_map, jump_table.site, target);
+                }
+                self.jump_tables.insert(jump_table.site, jump_table);
+            }
+        }
+    }
+
+    /// Follows control flow from `address`, disassembling each instruction reached
+    pub fn trace<MRO: MemoryReadOnly, MM: MemoryMap>(
+        &mut self,
+        memory: &MRO,
+        memory_map: &MM,
+        address: Address,
+    ) {
+        let mut to_visit = vec![address];
+        while let Some(address) = to_visit.pop() {
+            if !memory_map.is_rom(address) || self.instructions.contains_key(&address) {
+                continue;
+            }
+            if self.operand_bytes.contains(&address) || self.data.contains(&address) {
+                self.conflicts.insert(address);
+                continue;
+            }
+            let instruction_with_operand = match InstructionWithOperand::decode(address, memory) {
+                Ok(instruction_with_operand) => instruction_with_operand,
+                Err(_) => {
+                    self.conflicts.insert(address);
+                    continue;
+                }
+            };
+            let instruction = instruction_with_operand.instruction();
+            let size = instruction.size() as Address;
+            let operand_addresses = (1..size).map(|i| address.wrapping_add(i));
+            if operand_addresses.clone().any(|operand_address| {
+                self.instructions.contains_key(&operand_address)
+                    || self.operand_bytes.contains(&operand_address)
+                    || self.data.contains(&operand_address)
+            }) {
+                self.conflicts.insert(address);
+                continue;
+            }
+            self.operand_bytes.extend(operand_addresses);
+            let next_address = address.wrapping_add(size);
+            match instruction.instruction_type() {
+                InstructionType::Jmp => {
+                    let operand = instruction_with_operand.operand_u16_le().unwrap();
+                    match instruction.addressing_mode() {
+                        AddressingMode::Absolute => {
+                            self.jump_sites.entry(operand).or_default().insert(address);
+                            to_visit.push(operand);
+                        }
+                        _ => {
+                            for i in 0..2 {
+                                if memory_map.is_rom(operand.wrapping_add(i)) {
+                                    self.data_references.insert(operand.wrapping_add(i));
+                                }
+                            }
+                        }
+                    }
+                }
+                InstructionType::Jsr => {
+                    let callee = instruction_with_operand.operand_u16_le().unwrap();
+                    self.call_sites.entry(callee).or_default().insert(address);
+                    to_visit.push(callee);
+                    if jump_table::pulls_return_address(memory, callee) {
+                        self.non_returning_functions.insert(callee);
+                        self.inline_table_sites.insert(address);
+                    }
+                    if !self.non_returning_functions.contains(&callee) {
+                        to_visit.push(next_address);
+                    }
+                }
+                InstructionType::Rts | InstructionType::Rti | InstructionType::Brk => (),
+                InstructionType::Bcc
+                | InstructionType::Bcs
+                | InstructionType::Beq
+                | InstructionType::Bmi
+                | InstructionType::Bne
+                | InstructionType::Bpl
+                | InstructionType::Bvc
+                | InstructionType::Bvs => {
+                    let target = branch_target(&instruction_with_operand);
+                    self.jump_sites.entry(target).or_default().insert(address);
+                    to_visit.push(target);
+                    to_visit.push(next_address);
+                }
+                _ => {
+                    if let Some(operand) = data_reference(&instruction_with_operand) {
+                        if memory_map.is_rom(operand) {
+                            self.data_references.insert(operand);
+                        }
+                    }
+                    to_visit.push(next_address);
+                }
+            }
+            self.instructions.insert(address, instruction_with_operand);
+        }
+    }
+
+    pub fn kind(&self, address: Address) -> ByteKind {
+        if self.instructions.contains_key(&address) {
+            ByteKind::Opcode
+        } else if self.operand_bytes.contains(&address) {
+            ByteKind::Operand
+        } else if self.data.contains(&address) || self.data_references.contains(&address) {
+            ByteKind::Data
+        } else {
+            ByteKind::Unknown
+        }
+    }
+
+    pub fn instruction(&self, address: Address) -> Option<&InstructionWithOperand> {
+        self.instructions.get(&address)
+    }
+
+    pub fn instructions(&self) -> impl Iterator<Item = &InstructionWithOperand> {
+        self.instructions.values()
+    }
+
+    pub fn entry_points(&self) -> impl Iterator<Item = (Address, &BTreeSet<EntryPoint>)> {
+        self.entry_points
+            .iter()
+            .map(|(&address, entry_points)| (address, entry_points))
+    }
+
+    /// Returns the addresses of all JSR instructions calling the given address
+    pub fn call_sites(&self, callee: Address) -> impl Iterator<Item = Address> + '_ {
+        self.call_sites.get(&callee).into_iter().flatten().cloned()
+    }
+
+    pub fn called_addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.call_sites.keys().cloned()
+    }
+
+    /// Returns the known targets of the indirect jump (or dispatching RTS) at `site`
+    pub fn indirect_jump_targets(&self, site: Address) -> impl Iterator<Item = Address> + '_ {
+        self.indirect_jumps
+            .get(&site)
+            .into_iter()
+            .flatten()
+            .cloned()
+    }
+
+    pub fn indirectly_jumped_addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.indirect_jump_sites.keys().cloned()
+    }
+
+    pub fn jump_tables(&self) -> impl Iterator<Item = &JumpTable> {
+        self.jump_tables.values()
+    }
+
+    pub fn conflicts(&self) -> impl Iterator<Item = Address> + '_ {
+        self.conflicts.iter().cloned()
+    }
+
+    /// Returns the name used for `address` in listings, if it's an entry point, the target of a
+    /// call or jump, or the start of a jump table
+    pub fn label(&self, address: Address) -> Option<String> {
+        if let Some(entry_points) = self.entry_points.get(&address) {
+            let names = entry_points
+                .iter()
+                .map(|entry_point| match entry_point {
+                    EntryPoint::Nmi => "nmi".to_string(),
+                    EntryPoint::Reset => "reset".to_string(),
+                    EntryPoint::Irq => "irq".to_string(),
+                    EntryPoint::Extra => format!("entry_{:04X}", address),
+                })
+                .collect::<Vec<_>>();
+            Some(names.join("_"))
+        } else if self.call_sites.contains_key(&address)
+            || self.indirect_jump_sites.contains_key(&address)
+        {
+            Some(format!("sub_{:04X}", address))
+        } else if self.jump_sites.contains_key(&address) {
+            Some(format!("loc_{:04X}", address))
+        } else if self.jump_tables.values().any(|jump_table| {
+            jump_table.lo == address || (jump_table.stride == 1 && jump_table.hi == address)
+        }) {
+            Some(format!("tbl_{:04X}", address))
+        } else {
+            None
+        }
+    }
+
+    fn jump_table_comment(
+        &self,
+        instruction_with_operand: &InstructionWithOperand,
+    ) -> Option<String> {
+        let jump_table = self.jump_tables.get(&instruction_with_operand.address())?;
+        Some(format!(
+            "jump table at {:04X} with {} targets",
+            jump_table.lo,
+            jump_table.targets.len()
+        ))
+    }
+
+    /// Returns an annotated listing of the given range of addresses in ca65 syntax, with
+    /// operands replaced by labels and register names
+    pub fn listing<M: MemoryReadOnly>(&self, memory: &M, range: RangeInclusive<Address>) -> String {
+        self.listing_with(
+            memory,
+            range,
+            Formatter::new(Dialect::Ca65).with_register_names(true),
+        )
+    }
+
+    /// Returns an annotated listing of the given range of addresses, with instructions formatted
+    /// by `formatter`. Operands are replaced by the labels used in the listing.
+    pub fn listing_with<M: MemoryReadOnly>(
+        &self,
+        memory: &M,
+        range: RangeInclusive<Address>,
+        formatter: Formatter,
+    ) -> String {
+        let formatter = formatter.with_symbols(self);
+        let mut s = String::new();
+        let end = *range.end() as u32;
+        let mut address = *range.start() as u32;
+        while address <= end {
+            let current = address as Address;
+            if let Some(label) = self.label(current) {
+                writeln!(&mut s).unwrap();
+                let mut comments = Vec::new();
+                for (description, sites) in [
+                    ("called from", self.call_sites.get(&current)),
+                    ("dispatched from", self.indirect_jump_sites.get(&current)),
+                ] {
+                    if let Some(sites) = sites {
+                        let sites = sites
+                            .iter()
+                            .map(|site| format!("{:04X}", site))
+                            .collect::<Vec<_>>();
+                        comments.push(format!("{} {}", description, sites.join(", ")));
+                    }
+                }
+                if comments.is_empty() {
+                    writeln!(&mut s, "{}:", label).unwrap();
+                } else {
+                    writeln!(&mut s, "{}: ; {}", label, comments.join("; ")).unwrap();
+                }
+            }
+            match self.kind(current) {
+                ByteKind::Opcode => {
+                    let instruction_with_operand = &self.instructions[&current];
+                    let text = format!(
+                        "{:04X}  {}",
+                        current,
+                        formatter.instruction(instruction_with_operand)
+                    );
+                    match self.jump_table_comment(instruction_with_operand) {
+                        Some(comment) => writeln!(&mut s, "{:<32}; {}", text, comment).unwrap(),
+                        None => writeln!(&mut s, "{}", text).unwrap(),
+                    }
+                    address += instruction_with_operand.instruction().size() as u32;
+                }
+                ByteKind::Operand => {
+                    // only reachable when the range starts in the middle of an instruction
+                    writeln!(
+                        &mut s,
+                        "{:04X}  .byte ${:02X}{:<18}; operand",
+                        current,
+                        memory.read_u8_read_only(current),
+                        ""
+                    )
+                    .unwrap();
+                    address += 1;
+                }
+                kind @ (ByteKind::Data | ByteKind::Unknown) => {
+                    let mut bytes = vec![memory.read_u8_read_only(current)];
+                    address += 1;
+                    while address <= end
+                        && bytes.len() < LISTING_BYTES_PER_LINE
+                        && self.kind(address as Address) == kind
+                        && self.label(address as Address).is_none()
+                    {
+                        bytes.push(memory.read_u8_read_only(address as Address));
+                        address += 1;
+                    }
+                    let text = bytes
+                        .iter()
+                        .map(|byte| format!("${:02X}", byte))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let comment = if kind == ByteKind::Data {
+                        "data"
+                    } else {
+                        "unknown"
+                    };
+                    writeln!(
+                        &mut s,
+                        "{:<32}; {}",
+                        format!("{:04X}  .byte {}", current, text),
+                        comment
+                    )
+                    .unwrap();
+                }
+            }
+        }
+        s
+    }
+}
+
+impl SymbolTable for Disassembly {
+    fn symbol(&self, address: Address) -> Option<String> {
+        self.label(address)
+    }
+}
+
+fn next_address(instruction_with_operand: &InstructionWithOperand) -> Address {
+    instruction_with_operand
+        .address()
+        .wrapping_add(instruction_with_operand.instruction().size() as Address)
+}
+
+pub(crate) fn branch_target(instruction_with_operand: &InstructionWithOperand) -> Address {
+    let address = instruction_with_operand.address();
+    let size = instruction_with_operand.instruction().size() as Address;
+    let offset = instruction_with_operand.operand_u8().unwrap() as i8;
+    address
+        .wrapping_add(size)
+        .wrapping_add(offset as i16 as Address)
+}
+
+/// Returns the address accessed by an instruction which reads or writes memory at an
+/// absolute address, ignoring any index
+fn data_reference(instruction_with_operand: &InstructionWithOperand) -> Option<Address> {
+    match instruction_with_operand.instruction().addressing_mode() {
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteXIndexed
+        | AddressingMode::AbsoluteYIndexed => instruction_with_operand.operand_u16_le(),
+        _ => None,
+    }
+}
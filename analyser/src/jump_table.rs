@@ -0,0 +1,257 @@
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::machine::MemoryReadOnly;
+use mos6502_model::Address;
+
+// How many instructions before an indirect jump are searched for the code which loads its target
+pub(crate) const DISPATCH_WINDOW: usize = 12;
+// How many instructions at the start of a function are searched for pulls of the return address
+const RETURN_ADDRESS_PULL_WINDOW: usize = 8;
+// Upper bound on the number of entries read from a table whose length can't be inferred
+const MAX_TABLE_ENTRIES: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    // JMP (ptr) after storing table entries in ptr
+    JumpIndirect,
+    // RTS after pushing a table entry, which is one less than the target address
+    ReturnAddress,
+    // JSR to a function which pulls its return address to find a table of addresses which
+    // directly follows the JSR
+    InlineAfterCall,
+}
+
+#[derive(Debug, Clone)]
+pub struct JumpTable {
+    pub site: Address,
+    pub dispatch: Dispatch,
+    // Address of the low byte of the first entry
+    pub lo: Address,
+    // Address of the high byte of the first entry
+    pub hi: Address,
+    // Distance between consecutive entries. 2 for tables of words, 1 for split tables.
+    pub stride: Address,
+    pub targets: Vec<Address>,
+}
+
+impl JumpTable {
+    pub fn bytes(&self) -> impl Iterator<Item = Address> + '_ {
+        (0..self.targets.len() as Address).flat_map(move |i| {
+            let offset = i.wrapping_mul(self.stride);
+            [self.lo.wrapping_add(offset), self.hi.wrapping_add(offset)]
+        })
+    }
+}
+
+// The location of the low and high bytes of a table's first entry
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TableLocation {
+    pub lo: Address,
+    pub hi: Address,
+}
+
+impl TableLocation {
+    fn stride(&self) -> Address {
+        if self.hi == self.lo.wrapping_add(1) {
+            2
+        } else {
+            1
+        }
+    }
+    // Adjacent split tables are assumed to be the same length
+    fn known_length(&self) -> Option<usize> {
+        if self.stride() == 1 {
+            let distance = self
+                .hi
+                .wrapping_sub(self.lo)
+                .min(self.lo.wrapping_sub(self.hi));
+            if distance > 0 && distance <= 256 {
+                return Some(distance as usize);
+            }
+        }
+        None
+    }
+}
+
+fn indexed_load(
+    instruction_with_operand: &InstructionWithOperand,
+) -> Option<(InstructionType, Address)> {
+    let instruction = instruction_with_operand.instruction();
+    match (
+        instruction.instruction_type(),
+        instruction.addressing_mode(),
+    ) {
+        (
+            instruction_type @ (InstructionType::Lda | InstructionType::Ldx | InstructionType::Ldy),
+            AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed,
+        ) => Some((instruction_type, instruction_with_operand.operand_u16_le()?)),
+        _ => None,
+    }
+}
+
+fn register_written_by(instruction_type: InstructionType) -> Option<char> {
+    use InstructionType::*;
+    match instruction_type {
+        Lda | Txa | Tya | Pla | Adc | Sbc | And | Ora | Eor | Lax => Some('a'),
+        Ldx | Tax | Tsx | Inx | Dex => Some('x'),
+        Ldy | Tay | Iny | Dey => Some('y'),
+        _ => None,
+    }
+}
+
+// Finds the base address of the table which the value stored by `preceding[store_index]` was
+// loaded from
+fn stored_table_address(
+    preceding: &[&InstructionWithOperand],
+    store_index: usize,
+) -> Option<Address> {
+    let register = match preceding[store_index].instruction().instruction_type() {
+        InstructionType::Sta => 'a',
+        InstructionType::Stx => 'x',
+        InstructionType::Sty => 'y',
+        _ => return None,
+    };
+    let source = preceding[..store_index]
+        .iter()
+        .rev()
+        .find(|instruction_with_operand| {
+            register_written_by(instruction_with_operand.instruction().instruction_type())
+                == Some(register)
+        })?;
+    let (_, address) = indexed_load(source)?;
+    Some(address)
+}
+
+fn stored_address(instruction_with_operand: &InstructionWithOperand) -> Option<Address> {
+    let instruction = instruction_with_operand.instruction();
+    match (
+        instruction.instruction_type(),
+        instruction.addressing_mode(),
+    ) {
+        (
+            InstructionType::Sta | InstructionType::Stx | InstructionType::Sty,
+            AddressingMode::ZeroPage,
+        ) => instruction_with_operand.operand_u8().map(Address::from),
+        (
+            InstructionType::Sta | InstructionType::Stx | InstructionType::Sty,
+            AddressingMode::Absolute,
+        ) => instruction_with_operand.operand_u16_le(),
+        _ => None,
+    }
+}
+
+// Recognises a table lookup whose entry is stored in the pointer used by JMP (ptr). `preceding`
+// are the instructions before the jump, in order.
+pub(crate) fn jump_indirect_table(
+    preceding: &[&InstructionWithOperand],
+    jump: &InstructionWithOperand,
+) -> Option<TableLocation> {
+    let pointer = jump.operand_u16_le()?;
+    let last_store_to = |address: Address| {
+        preceding.iter().rposition(|instruction_with_operand| {
+            stored_address(instruction_with_operand) == Some(address)
+        })
+    };
+    let lo = stored_table_address(preceding, last_store_to(pointer)?)?;
+    let hi = stored_table_address(preceding, last_store_to(pointer.wrapping_add(1))?)?;
+    Some(TableLocation { lo, hi })
+}
+
+// Recognises the high and then low byte of a table entry being pushed before an RTS
+pub(crate) fn return_address_table(preceding: &[&InstructionWithOperand]) -> Option<TableLocation> {
+    match preceding {
+        [.., load_hi, push_hi, load_lo, push_lo] => {
+            let is_push = |instruction_with_operand: &InstructionWithOperand| {
+                instruction_with_operand.instruction().instruction_type() == InstructionType::Pha
+            };
+            if !is_push(push_hi) || !is_push(push_lo) {
+                return None;
+            }
+            match (indexed_load(load_hi)?, indexed_load(load_lo)?) {
+                ((InstructionType::Lda, hi), (InstructionType::Lda, lo)) => {
+                    Some(TableLocation { lo, hi })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+// Returns true if the function starting at `address` pulls its return address off the stack
+// before doing anything else with the stack
+pub(crate) fn pulls_return_address<M: MemoryReadOnly>(memory: &M, address: Address) -> bool {
+    let mut address = address;
+    let mut num_pulls = 0;
+    for _ in 0..RETURN_ADDRESS_PULL_WINDOW {
+        let instruction_with_operand = match InstructionWithOperand::decode(address, memory) {
+            Ok(instruction_with_operand) => instruction_with_operand,
+            Err(_) => return false,
+        };
+        let instruction = instruction_with_operand.instruction();
+        match instruction.instruction_type() {
+            InstructionType::Pla => {
+                num_pulls += 1;
+                if num_pulls == 2 {
+                    return true;
+                }
+            }
+            InstructionType::Pha
+            | InstructionType::Php
+            | InstructionType::Plp
+            | InstructionType::Jsr
+            | InstructionType::Jmp
+            | InstructionType::Rts
+            | InstructionType::Rti
+            | InstructionType::Brk
+            | InstructionType::Txs => return false,
+            _ if instruction.addressing_mode() == AddressingMode::Relative => return false,
+            _ => (),
+        }
+        address = address.wrapping_add(instruction.size() as Address);
+    }
+    false
+}
+
+// Reads the entries of a table. `is_entry_valid` takes the addresses of the bytes of an entry and
+// the address it refers to, and reading stops at the first invalid entry.
+pub(crate) fn read_table<M: MemoryReadOnly, F: FnMut(Address, Address, Address) -> bool>(
+    memory: &M,
+    site: Address,
+    dispatch: Dispatch,
+    location: TableLocation,
+    mut is_entry_valid: F,
+) -> JumpTable {
+    let stride = location.stride();
+    let num_entries = location.known_length().unwrap_or(MAX_TABLE_ENTRIES);
+    let mut targets = Vec::new();
+    for i in 0..num_entries as Address {
+        let lo = location.lo.wrapping_add(i.wrapping_mul(stride));
+        let hi = location.hi.wrapping_add(i.wrapping_mul(stride));
+        let entry = ((memory.read_u8_read_only(hi) as Address) << 8)
+            | memory.read_u8_read_only(lo) as Address;
+        let target = match dispatch {
+            Dispatch::ReturnAddress => entry.wrapping_add(1),
+            Dispatch::JumpIndirect | Dispatch::InlineAfterCall => entry,
+        };
+        if !is_entry_valid(lo, hi, target) {
+            break;
+        }
+        targets.push(target);
+    }
+    JumpTable {
+        site,
+        dispatch,
+        lo: location.lo,
+        hi: location.hi,
+        stride,
+        targets,
+    }
+}
+
+pub(crate) fn inline_table_location(call_site: Address) -> TableLocation {
+    let lo = call_site.wrapping_add(3);
+    TableLocation {
+        lo,
+        hi: lo.wrapping_add(1),
+    }
+}
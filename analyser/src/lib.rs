@@ -1,10 +1,24 @@
 use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
 use mos6502_model::machine::MemoryReadOnly;
-use mos6502_model::opcode;
 use mos6502_model::Address;
 use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
 
+mod banked;
+mod cfg;
+mod disassembly;
+mod jump_table;
+mod xref;
+
+#[cfg(test)]
+mod test;
+
+pub use banked::{Bank, BankView, BankedAddress, BankedAnalysis, BankedMemoryMap};
+pub use cfg::{BasicBlock, ControlFlowGraph, Loop};
+pub use disassembly::{ByteKind, Disassembly, EntryPoint};
+pub use jump_table::{Dispatch, JumpTable};
+pub use xref::{Access, Reference, Region, XrefIndex};
+
 pub trait MemoryMap {
     /// Takes the address of a JSR opcode and returns the address of the beginning of the function
     /// being called.
@@ -13,26 +27,48 @@ pub trait MemoryMap {
         jsr_opcode_address: Address,
         memory: &M,
     ) -> Option<Address>;
+
+    /// Returns true if the given address is mapped to ROM. Only ROM is disassembled, as the
+    /// contents of RAM at the time of analysis needn't be what's there when it's executed.
+    fn is_rom(&self, address: Address) -> bool {
+        address >= 0x8000
+    }
+}
+
+/// Information gathered while running a program which static analysis can't discover by itself
+#[derive(Debug, Clone, Default)]
+pub struct Observations {
+    /// Pairs of the address of an indirect jump (or an RTS used to jump to an address pushed
+    /// onto the stack) and the address it jumped to
+    pub indirect_jumps: BTreeSet<(Address, Address)>,
+    /// Pairs of the location of a JSR instruction and the bank mapped where its callee is, at
+    /// the time the call was made
+    pub call_site_banks: BTreeSet<(BankedAddress, Bank)>,
+    /// Locations of bytes which were executed as the first byte of an instruction
+    pub opcodes: BTreeSet<BankedAddress>,
+    /// Locations of bytes which were read as data and never executed
+    pub data: BTreeSet<BankedAddress>,
 }
 
+// Functions are the entry points, the callees of every JSR found while disassembling, and the
+// targets of indirect jumps
 fn enumerate_function_definition_addresses<MRO: MemoryReadOnly, MM: MemoryMap>(
+    disassembly: &Disassembly,
     memory: &MRO,
     memory_map: &MM,
-) -> Vec<Address> {
-    let mut function_definition_addresses = BTreeSet::new();
-    for address in 0..=0xFFFF {
-        let byte = memory.read_u8_read_only(address);
-        if byte == opcode::jsr::ABSOLUTE {
-            if let Some(function_address) = memory_map.normalise_function_call(address, memory) {
+) -> BTreeSet<Address> {
+    let mut function_definition_addresses = disassembly
+        .entry_points()
+        .map(|(address, _)| address)
+        .collect::<BTreeSet<_>>();
+    function_definition_addresses.extend(disassembly.indirectly_jumped_addresses());
+    for callee in disassembly.called_addresses() {
+        for call_site in disassembly.call_sites(callee) {
+            if let Some(function_address) = memory_map.normalise_function_call(call_site, memory) {
                 function_definition_addresses.insert(function_address);
             }
         }
     }
-    let mut function_definition_addresses = function_definition_addresses
-        .iter()
-        .cloned()
-        .collect::<Vec<_>>();
-    function_definition_addresses.sort();
     function_definition_addresses
 }
 
@@ -83,6 +119,21 @@ impl fmt::Display for FunctionStep {
 }
 
 impl FunctionStep {
+    fn instruction_with_operand(&self) -> Option<&InstructionWithOperand> {
+        match self {
+            FunctionStep::InvalidOpcode { .. } => None,
+            FunctionStep::JumpIndirect(instruction_with_operand)
+            | FunctionStep::TracedInstruction(instruction_with_operand)
+            | FunctionStep::FunctionCall {
+                instruction_with_operand,
+                ..
+            }
+            | FunctionStep::Branch {
+                instruction_with_operand,
+                ..
+            } => Some(instruction_with_operand),
+        }
+    }
     fn address(&self) -> Address {
         match self {
             FunctionStep::InvalidOpcode { address, .. } => *address,
@@ -130,6 +181,7 @@ impl FunctionTrace {
 fn trace_function_definition<M: MemoryReadOnly>(
     function_definition_address: Address,
     memory: &M,
+    disassembly: &Disassembly,
 ) -> FunctionTrace {
     let mut steps = Vec::new();
     let mut seen = BTreeSet::new();
@@ -182,14 +234,18 @@ fn trace_function_definition<M: MemoryReadOnly>(
                     other => {
                         let next_address =
                             visited_address.wrapping_add(instruction.size() as Address);
-                        to_visit.push(next_address);
                         if let InstructionType::Jsr = other {
+                            let callee = instruction_with_operand.operand_u16_le().unwrap();
+                            if !disassembly.is_non_returning_function(callee) {
+                                to_visit.push(next_address);
+                            }
                             steps.push(FunctionStep::FunctionCall {
-                                callee: instruction_with_operand.operand_u16_le().unwrap(),
+                                callee,
                                 instruction_with_operand,
                             });
                             continue;
                         }
+                        to_visit.push(next_address);
                     }
                 }
                 steps.push(FunctionStep::TracedInstruction(instruction_with_operand));
@@ -259,26 +315,12 @@ impl CallGraph {
         }
     }
     fn insert_empty(&mut self, address: Address) {
-        self.by_caller
-            .0
-            .entry(address)
-            .or_insert_with(BTreeSet::new);
-        self.by_callee
-            .0
-            .entry(address)
-            .or_insert_with(BTreeSet::new);
+        self.by_caller.0.entry(address).or_default();
+        self.by_callee.0.entry(address).or_default();
     }
     fn insert(&mut self, caller: Address, callee: Address) {
-        self.by_caller
-            .0
-            .entry(caller)
-            .or_insert_with(BTreeSet::new)
-            .insert(callee);
-        self.by_callee
-            .0
-            .entry(callee)
-            .or_insert_with(BTreeSet::new)
-            .insert(caller);
+        self.by_caller.0.entry(caller).or_default().insert(callee);
+        self.by_callee.0.entry(callee).or_default().insert(caller);
     }
 
     pub fn by_caller(&self) -> &AddressGraph {
@@ -291,6 +333,7 @@ impl CallGraph {
 }
 
 pub struct Analysis {
+    disassembly: Disassembly,
     call_graph: CallGraph,
     function_traces_by_definition_address: BTreeMap<Address, FunctionTrace>,
 }
@@ -301,31 +344,75 @@ impl Analysis {
         memory_map: &MM,
         extra_function_definition_addresses: I,
     ) -> Self {
-        let mut function_definition_addresses =
-            enumerate_function_definition_addresses(memory, memory_map);
-        for address in extra_function_definition_addresses {
-            function_definition_addresses.push(address);
+        Self::analyse_with_observations(
+            memory,
+            memory_map,
+            extra_function_definition_addresses,
+            &Observations::default(),
+        )
+    }
+    pub fn analyse_with_observations<
+        MRO: MemoryReadOnly,
+        MM: MemoryMap,
+        I: IntoIterator<Item = Address>,
+    >(
+        memory: &MRO,
+        memory_map: &MM,
+        extra_function_definition_addresses: I,
+        observations: &Observations,
+    ) -> Self {
+        // the banks of observed code and data are ignored, as only one bank is assumed to be
+        // mapped at each address
+        let mut disassembly = Disassembly::with_known_data(
+            memory,
+            memory_map,
+            extra_function_definition_addresses,
+            observations.data.iter().map(|location| location.address),
+        );
+        for location in observations.opcodes.iter() {
+            disassembly.trace(memory, memory_map, location.address);
         }
+        for &(site, target) in observations.indirect_jumps.iter() {
+            disassembly.add_indirect_jump(memory, memory_map, site, target);
+        }
+        disassembly.resolve_jump_tables(memory, memory_map);
+        let function_definition_addresses =
+            enumerate_function_definition_addresses(&disassembly, memory, memory_map);
         let mut function_traces_by_definition_address = BTreeMap::new();
         let mut call_graph = CallGraph::new();
         for &function_definition_address in function_definition_addresses.iter() {
-            let trace = trace_function_definition(function_definition_address, memory);
+            let trace =
+                trace_function_definition(function_definition_address, memory, &disassembly);
             call_graph.insert_empty(function_definition_address);
             for step in trace.steps() {
                 if let FunctionStep::FunctionCall { callee, .. } = step {
                     call_graph.insert(function_definition_address, *callee);
                 }
+                for target in disassembly.indirect_jump_targets(step.address()) {
+                    call_graph.insert(function_definition_address, target);
+                }
             }
             function_traces_by_definition_address.insert(function_definition_address, trace);
         }
         Self {
+            disassembly,
             call_graph,
             function_traces_by_definition_address,
         }
     }
+    pub fn disassembly(&self) -> &Disassembly {
+        &self.disassembly
+    }
     pub fn function_trace(&self, address: Address) -> Option<&FunctionTrace> {
         self.function_traces_by_definition_address.get(&address)
     }
+    pub fn xref_index<MRO: MemoryReadOnly>(&self, memory: &MRO) -> XrefIndex {
+        XrefIndex::new(&self.disassembly, memory)
+    }
+    pub fn control_flow_graph(&self, address: Address) -> Option<ControlFlowGraph> {
+        self.function_trace(address)
+            .map(|trace| ControlFlowGraph::new(address, trace))
+    }
     pub fn functions_containing_address<'a>(
         &'a self,
         address: Address,
@@ -0,0 +1,458 @@
+use crate::*;
+use mos6502_assembler::{Addr, AssembledBlock, Block, LabelRelativeOffset};
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::{addressing_mode::*, assembler_instruction::*, interrupt_vector};
+
+const BASE: Address = 0x8000;
+
+struct Rom;
+
+impl MemoryMap for Rom {
+    fn normalise_function_call<M: MemoryReadOnly>(
+        &self,
+        jsr_opcode_address: Address,
+        memory: &M,
+    ) -> Option<Address> {
+        Some(memory.read_u16_le_read_only(jsr_opcode_address.wrapping_add(1)))
+    }
+}
+
+// Assembles a program into ROM, with the reset vector pointing at the label "reset"
+fn assemble<F: FnOnce(&mut Block)>(program: F) -> (FlatMemory, AssembledBlock) {
+    let mut b = Block::new();
+    b.label("reset");
+    program(&mut b);
+    b.set_offset(interrupt_vector::START_LO - BASE);
+    b.label_offset_le("reset");
+    let mut rom = Vec::new();
+    let assembled_block = b.assemble(BASE, 0x8000, &mut rom).unwrap();
+    let mut memory = FlatMemory::new();
+    memory.load(BASE, &rom);
+    (memory, assembled_block)
+}
+
+fn analyse<F: FnOnce(&mut Block)>(program: F) -> (Analysis, AssembledBlock) {
+    let (memory, assembled_block) = assemble(program);
+    (Analysis::analyse(&memory, &Rom, None), assembled_block)
+}
+
+fn cfg<F: FnOnce(&mut Block)>(program: F) -> (ControlFlowGraph, AssembledBlock) {
+    let (analysis, assembled_block) = analyse(program);
+    (analysis.control_flow_graph(BASE).unwrap(), assembled_block)
+}
+
+#[test]
+fn code_and_data_are_classified() {
+    let (analysis, a) = analyse(|b| {
+        b.inst(Ldx(ZeroPage), 0);
+        b.inst(Lda(AbsoluteXIndexed), "table");
+        b.inst(Sta(ZeroPage), 0x10);
+        b.label("call");
+        b.inst(Jsr(Absolute), "f");
+        b.inst(Jmp(Absolute), "reset");
+        b.label("f");
+        b.inst(Rts, ());
+        b.label("table");
+        b.literal_byte(1);
+        b.literal_byte(2);
+    });
+    let disassembly = analysis.disassembly();
+    let f = a.address_of_label("f").unwrap();
+    let table = a.address_of_label("table").unwrap();
+    assert_eq!(disassembly.kind(BASE), ByteKind::Opcode);
+    assert_eq!(disassembly.kind(BASE + 1), ByteKind::Operand);
+    assert_eq!(disassembly.kind(f), ByteKind::Opcode);
+    assert_eq!(disassembly.kind(table), ByteKind::Data);
+    // nothing refers to the second byte of the table
+    assert_eq!(disassembly.kind(table + 1), ByteKind::Unknown);
+    assert_eq!(disassembly.label(BASE).as_deref(), Some("reset"));
+    assert_eq!(disassembly.label(f), Some(format!("sub_{:04X}", f)));
+    assert_eq!(
+        disassembly.call_sites(f).collect::<Vec<_>>(),
+        vec![a.address_of_label("call").unwrap()]
+    );
+    assert_eq!(disassembly.conflicts().count(), 0);
+}
+
+#[test]
+fn branch_into_an_operand_is_a_conflict() {
+    let (analysis, _) = analyse(|b| {
+        b.inst(Lda(Immediate), 0);
+        // branches to the operand of the LDA
+        b.inst(Beq, -3i8);
+        b.inst(Rts, ());
+    });
+    let disassembly = analysis.disassembly();
+    assert_eq!(disassembly.conflicts().collect::<Vec<_>>(), vec![BASE + 1]);
+    assert_eq!(disassembly.kind(BASE + 1), ByteKind::Operand);
+}
+
+#[test]
+fn diamond() {
+    let (cfg, a) = cfg(|b| {
+        b.inst(Lda(ZeroPage), 0);
+        b.inst(Beq, LabelRelativeOffset("else"));
+        b.label("then");
+        b.inst(Ldx(Immediate), 1);
+        b.inst(Jmp(Absolute), "join");
+        b.label("else");
+        b.inst(Ldx(Immediate), 2);
+        b.label("join");
+        b.inst(Rts, ());
+    });
+    let [then, else_, join] = ["then", "else", "join"].map(|l| a.address_of_label(l).unwrap());
+    assert_eq!(cfg.blocks().count(), 4);
+    assert_eq!(cfg.immediate_dominator(BASE), None);
+    assert_eq!(cfg.immediate_dominator(then), Some(BASE));
+    assert_eq!(cfg.immediate_dominator(else_), Some(BASE));
+    assert_eq!(cfg.immediate_dominator(join), Some(BASE));
+    assert_eq!(
+        cfg.dominators(join)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>(),
+        vec![BASE, join]
+    );
+    assert!(cfg.loops().is_empty());
+}
+
+#[test]
+fn nested_loops() {
+    let (cfg, a) = cfg(|b| {
+        b.inst(Ldx(Immediate), 10);
+        b.label("outer");
+        b.inst(Ldy(Immediate), 10);
+        b.label("inner");
+        b.inst(Dey, ());
+        b.inst(Bne, LabelRelativeOffset("inner"));
+        b.label("outer_latch");
+        b.inst(Dex, ());
+        b.inst(Bne, LabelRelativeOffset("outer"));
+        b.label("done");
+        b.inst(Rts, ());
+    });
+    let [outer, inner, outer_latch, done] =
+        ["outer", "inner", "outer_latch", "done"].map(|l| a.address_of_label(l).unwrap());
+    assert_eq!(cfg.immediate_dominator(inner), Some(outer));
+    assert_eq!(cfg.immediate_dominator(outer_latch), Some(inner));
+    assert_eq!(cfg.immediate_dominator(done), Some(outer_latch));
+    let loops = cfg.loops();
+    assert_eq!(loops.len(), 2);
+    let outer_loop = loops.iter().find(|l| l.header() == outer).unwrap();
+    let inner_loop = loops.iter().find(|l| l.header() == inner).unwrap();
+    assert_eq!(
+        outer_loop.body().iter().cloned().collect::<Vec<_>>(),
+        vec![outer, inner, outer_latch]
+    );
+    assert_eq!(
+        outer_loop.latches().iter().cloned().collect::<Vec<_>>(),
+        vec![outer_latch]
+    );
+    assert_eq!(
+        inner_loop.body().iter().cloned().collect::<Vec<_>>(),
+        vec![inner]
+    );
+}
+
+#[test]
+fn unreachable_predecessor_is_not_part_of_a_loop() {
+    let (cfg, a) = cfg(|b| {
+        b.inst(Ldx(Immediate), 3);
+        b.label("loop");
+        b.inst(Dex, ());
+        b.inst(Beq, LabelRelativeOffset("done"));
+        b.label("skip");
+        b.inst(Sec, ());
+        // always taken, so the code after it can't be reached
+        b.inst(Bcs, LabelRelativeOffset("latch"));
+        b.label("dead");
+        b.inst(Nop, ());
+        b.label("latch");
+        b.inst(Jmp(Absolute), "loop");
+        b.label("done");
+        b.inst(Rts, ());
+    });
+    let [loop_, skip, dead, latch] =
+        ["loop", "skip", "dead", "latch"].map(|l| a.address_of_label(l).unwrap());
+    assert_eq!(cfg.unreachable_blocks().collect::<Vec<_>>(), vec![dead]);
+    assert!(!cfg.is_feasible_edge(skip, dead));
+    assert_eq!(cfg.immediate_dominator(latch), Some(skip));
+    assert_eq!(cfg.immediate_dominator(dead), None);
+    let loops = cfg.loops();
+    assert_eq!(loops.len(), 1);
+    assert_eq!(loops[0].header(), loop_);
+    assert_eq!(
+        loops[0].body().iter().cloned().collect::<Vec<_>>(),
+        vec![loop_, skip, latch]
+    );
+}
+
+#[test]
+fn rts_jump_table() {
+    let (analysis, a) = analyse(|b| {
+        b.inst(Ldx(ZeroPage), 0);
+        b.inst(Lda(AbsoluteXIndexed), "hi");
+        b.inst(Pha, ());
+        b.inst(Lda(AbsoluteXIndexed), "lo");
+        b.inst(Pha, ());
+        b.label("dispatch");
+        b.inst(Rts, ());
+        // entries are one less than their targets, at 0x8100 and 0x8110
+        b.label("lo");
+        b.literal_byte(0xFF);
+        b.literal_byte(0x0F);
+        b.label("hi");
+        b.literal_byte(0x80);
+        b.literal_byte(0x81);
+        b.set_offset(0x100);
+        b.inst(Rts, ());
+        b.set_offset(0x110);
+        b.inst(Rts, ());
+    });
+    let disassembly = analysis.disassembly();
+    let [dispatch, lo, hi] = ["dispatch", "lo", "hi"].map(|l| a.address_of_label(l).unwrap());
+    let jump_tables = disassembly.jump_tables().collect::<Vec<_>>();
+    assert_eq!(jump_tables.len(), 1);
+    let jump_table = jump_tables[0];
+    assert_eq!(jump_table.site, dispatch);
+    assert_eq!(jump_table.dispatch, Dispatch::ReturnAddress);
+    assert_eq!(
+        (jump_table.lo, jump_table.hi, jump_table.stride),
+        (lo, hi, 1)
+    );
+    assert_eq!(jump_table.targets, vec![0x8100, 0x8110]);
+    assert!((lo..hi + 2).all(|address| disassembly.kind(address) == ByteKind::Data));
+    assert_eq!(disassembly.kind(0x8110), ByteKind::Opcode);
+    assert_eq!(
+        disassembly
+            .indirect_jump_targets(dispatch)
+            .collect::<Vec<_>>(),
+        vec![0x8100, 0x8110]
+    );
+    assert_eq!(disassembly.label(lo), Some(format!("tbl_{:04X}", lo)));
+}
+
+#[test]
+fn split_jump_indirect_table() {
+    let (analysis, a) = analyse(|b| {
+        b.inst(Ldx(ZeroPage), 0);
+        b.inst(Lda(AbsoluteXIndexed), "lo");
+        b.inst(Sta(ZeroPage), 0x10);
+        b.inst(Lda(AbsoluteXIndexed), "hi");
+        b.inst(Sta(ZeroPage), 0x11);
+        b.label("dispatch");
+        b.inst(Jmp(Indirect), Addr(0x0010));
+        b.label("lo");
+        b.label_offset_lo("a");
+        b.label_offset_lo("b");
+        b.label("hi");
+        b.label_offset_hi("a");
+        b.label_offset_hi("b");
+        b.label("a");
+        b.inst(Rts, ());
+        b.label("b");
+        b.inst(Nop, ());
+        b.inst(Rts, ());
+    });
+    let disassembly = analysis.disassembly();
+    let [dispatch, lo, hi, target_a, target_b] =
+        ["dispatch", "lo", "hi", "a", "b"].map(|l| a.address_of_label(l).unwrap());
+    let jump_table = disassembly.jump_tables().next().unwrap();
+    assert_eq!(jump_table.site, dispatch);
+    assert_eq!(jump_table.dispatch, Dispatch::JumpIndirect);
+    assert_eq!(
+        (jump_table.lo, jump_table.hi, jump_table.stride),
+        (lo, hi, 1)
+    );
+    assert_eq!(jump_table.targets, vec![target_a, target_b]);
+    assert_eq!(disassembly.kind(target_b + 1), ByteKind::Opcode);
+    // the targets are treated as functions
+    assert!(analysis.function_trace(target_b).is_some());
+}
+
+#[test]
+fn jump_table_entries_which_are_not_code_are_rejected() {
+    let (analysis, a) = analyse(|b| {
+        b.inst(Ldx(ZeroPage), 0);
+        b.inst(Lda(AbsoluteXIndexed), "lo");
+        b.inst(Sta(ZeroPage), 0x10);
+        b.inst(Lda(AbsoluteXIndexed), "hi");
+        b.inst(Sta(ZeroPage), 0x11);
+        b.inst(Jmp(Indirect), Addr(0x0010));
+        b.label("lo");
+        b.label_offset_lo("a");
+        // points into RAM, which isn't disassembled
+        b.literal_byte(0x00);
+        b.label("hi");
+        b.label_offset_hi("a");
+        b.literal_byte(0x02);
+        b.label("a");
+        b.inst(Rts, ());
+    });
+    let disassembly = analysis.disassembly();
+    let [lo, hi, target] = ["lo", "hi", "a"].map(|l| a.address_of_label(l).unwrap());
+    let jump_table = disassembly.jump_tables().next().unwrap();
+    assert_eq!(jump_table.targets, vec![target]);
+    assert_eq!(disassembly.kind(lo), ByteKind::Data);
+    assert_eq!(disassembly.kind(hi), ByteKind::Data);
+    assert_eq!(disassembly.kind(lo + 1), ByteKind::Unknown);
+    assert_eq!(disassembly.kind(hi + 1), ByteKind::Unknown);
+}
+
+#[test]
+fn xrefs() {
+    let (memory, a) = assemble(|b| {
+        b.inst(Ldx(Immediate), 3);
+        b.label("load");
+        b.inst(Lda(AbsoluteXIndexed), Addr(0x0200));
+        b.label("store");
+        b.inst(Sta(Absolute), Addr(0x2000));
+        b.label("call");
+        b.inst(Jsr(Absolute), "f");
+        b.label("modify");
+        b.inst(Inc(Absolute), Addr(0x0800));
+        b.inst(Ldy(ZeroPage), 0x20);
+        b.label("unknown_index");
+        b.inst(Lda(AbsoluteYIndexed), Addr(0x0300));
+        b.label("f");
+        b.inst(Rts, ());
+    });
+    let analysis = Analysis::analyse(&memory, &Rom, None);
+    let xref_index = analysis.xref_index(&memory);
+    let site = |label| a.address_of_label(label).unwrap();
+    let from = |label| xref_index.references_from(site(label)).collect::<Vec<_>>();
+    let load = from("load");
+    assert_eq!(load.len(), 1);
+    assert_eq!((load[0].access, load[0].start), (Access::Read, 0x0203));
+    assert!(load[0].is_exact());
+    let call = from("call");
+    assert_eq!((call[0].access, call[0].start), (Access::Call, site("f")));
+    let unknown_index = from("unknown_index");
+    assert_eq!(
+        (unknown_index[0].start, unknown_index[0].len),
+        (0x0300, 256)
+    );
+    assert!(!unknown_index[0].is_exact());
+    // mirrors of PPU registers and RAM are folded together
+    let to_ppu = xref_index.references_to(0x2008).collect::<Vec<_>>();
+    assert_eq!(to_ppu.len(), 1);
+    assert_eq!(
+        (to_ppu[0].site, to_ppu[0].access),
+        (site("store"), Access::Write)
+    );
+    let to_ram = xref_index.references_to(0x0000).collect::<Vec<_>>();
+    assert_eq!(to_ram.len(), 1);
+    assert_eq!(
+        (to_ram[0].site, to_ram[0].access),
+        (site("modify"), Access::ReadModifyWrite)
+    );
+    assert_eq!(Region::of(0x2000, &Rom), Region::PpuRegister);
+    assert!(xref_index
+        .report(&analysis, &Rom)
+        .contains("write   $2000 (PPU register)"));
+}
+
+// Two 16K banks which can be switched in at 0xC000, with a third fixed at 0x8000. Writing a
+// value to 0x8000 selects that bank.
+struct Banked {
+    banks: Vec<Vec<u8>>,
+}
+
+impl MemoryMap for Banked {
+    fn normalise_function_call<M: MemoryReadOnly>(
+        &self,
+        jsr_opcode_address: Address,
+        memory: &M,
+    ) -> Option<Address> {
+        Some(memory.read_u16_le_read_only(jsr_opcode_address.wrapping_add(1)))
+    }
+}
+
+impl BankedMemoryMap for Banked {
+    fn switchable_banks(&self) -> Vec<Bank> {
+        vec![1, 2]
+    }
+    fn bank_at(&self, selected: Bank, address: Address) -> Option<Bank> {
+        match address {
+            0x8000..=0xBFFF => Some(0),
+            0xC000..=0xFFFF => Some(selected),
+            _ => None,
+        }
+    }
+    fn read_banked_u8(&self, bank: Bank, address: Address) -> u8 {
+        self.banks[bank][address as usize % 0x4000]
+    }
+    fn bank_selected_by_writes(&self, writes: &[(Address, u8)]) -> Option<Bank> {
+        writes
+            .iter()
+            .rev()
+            .find(|&&(address, _)| address == 0x8000)
+            .map(|&(_, value)| value as Bank)
+    }
+}
+
+fn assemble_bank<F: FnOnce(&mut Block)>(base: Address, program: F) -> Vec<u8> {
+    let mut b = Block::new();
+    program(&mut b);
+    let mut bank = Vec::new();
+    b.assemble(base, 0x4000, &mut bank).unwrap();
+    bank
+}
+
+#[test]
+fn banked_calls_are_resolved_to_the_selected_bank() {
+    let fixed = assemble_bank(0x8000, |b| {
+        b.inst(Lda(Immediate), 2);
+        b.inst(Sta(Absolute), Addr(0x8000));
+        b.inst(Jsr(Absolute), Addr(0xC000));
+        b.inst(Rts, ());
+    });
+    let switchable = |b: &mut Block| {
+        b.inst(Rts, ());
+        b.set_offset(interrupt_vector::START_LO - 0xC000);
+        b.literal_offset_le(0);
+    };
+    let banked = Banked {
+        banks: vec![
+            fixed,
+            assemble_bank(0xC000, switchable),
+            assemble_bank(0xC000, switchable),
+        ],
+    };
+    let banked_analysis = BankedAnalysis::analyse(
+        &FlatMemory::new(),
+        &banked,
+        [0x8000],
+        &Observations::default(),
+    );
+    let caller = BankedAddress {
+        bank: 0,
+        address: 0x8000,
+    };
+    let call_site = BankedAddress {
+        bank: 0,
+        address: 0x8005,
+    };
+    assert_eq!(
+        banked_analysis
+            .mappable_banks(call_site)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>(),
+        vec![2]
+    );
+    assert_eq!(
+        banked_analysis
+            .callees_of_function(caller)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>(),
+        vec![BankedAddress {
+            bank: 2,
+            address: 0xC000
+        }]
+    );
+}
@@ -0,0 +1,396 @@
+use crate::banked::{simulate_writes, Registers};
+use crate::disassembly::branch_target;
+use crate::{Analysis, Disassembly, MemoryMap};
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::machine::MemoryReadOnly;
+use mos6502_model::Address;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+// How many instructions before an indexed or indirect access are searched for code which sets
+// the index register or pointer
+const REGISTER_WINDOW: usize = 16;
+
+/// How an instruction uses the address it refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+    /// Read as the address used by indirect addressing
+    Pointer,
+    Jump,
+    Call,
+    Branch,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::ReadModifyWrite => "modify",
+            Access::Pointer => "pointer",
+            Access::Jump => "jump",
+            Access::Call => "call",
+            Access::Branch => "branch",
+        };
+        f.pad(s)
+    }
+}
+
+/// The part of the address space an address belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Region {
+    Ram,
+    PpuRegister,
+    ApuIoRegister,
+    /// Cartridge RAM or registers below PRG ROM
+    Cartridge,
+    Rom,
+}
+
+impl Region {
+    pub fn of<MM: MemoryMap>(address: Address, memory_map: &MM) -> Self {
+        match address {
+            0x0000..=0x1FFF => Region::Ram,
+            0x2000..=0x3FFF => Region::PpuRegister,
+            0x4000..=0x401F => Region::ApuIoRegister,
+            _ if memory_map.is_rom(address) => Region::Rom,
+            _ => Region::Cartridge,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Region::Ram => "RAM",
+            Region::PpuRegister => "PPU register",
+            Region::ApuIoRegister => "APU/IO register",
+            Region::Cartridge => "cartridge",
+            Region::Rom => "ROM",
+        };
+        f.pad(s)
+    }
+}
+
+/// A reference from an instruction to the addresses it may access
+#[derive(Debug, Clone)]
+pub struct Reference {
+    /// Address of the referring instruction
+    pub site: Address,
+    pub instruction_type: InstructionType,
+    pub addressing_mode: AddressingMode,
+    pub access: Access,
+    /// The lowest address which may be accessed. For indexed addressing this is the base address.
+    pub start: Address,
+    /// How many consecutive addresses from `start` may be accessed. This is more than 1 when an
+    /// index register's value isn't known, in which case every index is assumed to be possible.
+    pub len: u16,
+}
+
+impl Reference {
+    /// Returns true if the reference is to a single address rather than a range approximating
+    /// the possible values of an index
+    pub fn is_exact(&self) -> bool {
+        // pointers are two bytes long
+        let exact_len = if self.access == Access::Pointer { 2 } else { 1 };
+        self.len == exact_len
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        let wraps_in_zero_page = match self.addressing_mode {
+            AddressingMode::ZeroPage
+            | AddressingMode::ZeroPageXIndexed
+            | AddressingMode::ZeroPageYIndexed => true,
+            // the pointer is in the zero page but the data it points to can be anywhere
+            AddressingMode::IndirectYIndexed | AddressingMode::XIndexedIndirect => {
+                self.access == Access::Pointer
+            }
+            _ => false,
+        };
+        (0..self.len).map(move |i| {
+            if wraps_in_zero_page {
+                // indexing wraps around within the zero page
+                (self.start as u8).wrapping_add(i as u8) as Address
+            } else {
+                self.start.wrapping_add(i)
+            }
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let last = self.addresses().last().unwrap_or(self.start);
+        if self.len == 1 {
+            write!(f, "{:<7} ${:04X}", self.access, self.start)
+        } else {
+            write!(f, "{:<7} ${:04X}-${:04X}", self.access, self.start, last)
+        }
+    }
+}
+
+// Mirrored RAM and PPU registers are indexed by the lowest address they're mirrored at
+fn canonical_address(address: Address) -> Address {
+    match address {
+        0x0000..=0x1FFF => address & 0x07FF,
+        0x2000..=0x3FFF => 0x2000 | (address & 0x0007),
+        _ => address,
+    }
+}
+
+fn data_access(instruction_type: InstructionType) -> Option<Access> {
+    use InstructionType::*;
+    match instruction_type {
+        Adc | And | Bit | Cmp | Cpx | Cpy | Eor | Ign | Lax | Lda | Ldx | Ldy | Ora | Sbc => {
+            Some(Access::Read)
+        }
+        Sta | Stx | Sty | Sax | Ahx | Sxa | Sya => Some(Access::Write),
+        Asl | Dcp | Dec | Inc | Isc | Lsr | Rla | Rol | Ror | Rra | Slo | Sre => {
+            Some(Access::ReadModifyWrite)
+        }
+        _ => None,
+    }
+}
+
+/// An index of the addresses referred to by every disassembled instruction
+pub struct XrefIndex {
+    references: Vec<Reference>,
+    by_address: BTreeMap<Address, Vec<usize>>,
+    by_site: BTreeMap<Address, Vec<usize>>,
+}
+
+impl XrefIndex {
+    pub fn new<MRO: MemoryReadOnly>(disassembly: &Disassembly, memory: &MRO) -> Self {
+        let mut xref_index = Self {
+            references: Vec::new(),
+            by_address: BTreeMap::new(),
+            by_site: BTreeMap::new(),
+        };
+        for instruction_with_operand in disassembly.instructions() {
+            for reference in
+                references_from_instruction(disassembly, memory, instruction_with_operand)
+            {
+                xref_index.insert(reference);
+            }
+        }
+        xref_index
+    }
+
+    fn insert(&mut self, reference: Reference) {
+        let index = self.references.len();
+        for address in reference.addresses() {
+            let references = self
+                .by_address
+                .entry(canonical_address(address))
+                .or_default();
+            if references.last() != Some(&index) {
+                references.push(index);
+            }
+        }
+        self.by_site.entry(reference.site).or_default().push(index);
+        self.references.push(reference);
+    }
+
+    pub fn references(&self) -> impl Iterator<Item = &Reference> {
+        self.references.iter()
+    }
+
+    /// Returns the references which may access `address`, including through mirrors of RAM and
+    /// PPU registers
+    pub fn references_to(&self, address: Address) -> impl Iterator<Item = &Reference> {
+        self.by_address
+            .get(&canonical_address(address))
+            .into_iter()
+            .flatten()
+            .map(move |&index| &self.references[index])
+    }
+
+    /// Returns the references made by the instruction at `site`
+    pub fn references_from(&self, site: Address) -> impl Iterator<Item = &Reference> {
+        self.by_site
+            .get(&site)
+            .into_iter()
+            .flatten()
+            .map(move |&index| &self.references[index])
+    }
+
+    /// Returns every address which is referenced, with mirrors folded together
+    pub fn referenced_addresses(&self) -> impl Iterator<Item = Address> + '_ {
+        self.by_address.keys().cloned()
+    }
+
+    /// A text report of the references made by each function of `analysis`
+    pub fn report<MM: MemoryMap>(&self, analysis: &Analysis, memory_map: &MM) -> String {
+        let mut s = String::new();
+        for (function_address, trace) in analysis.function_traces() {
+            let mut sites = trace
+                .steps()
+                .iter()
+                .map(|step| step.address())
+                .filter(|&site| self.by_site.contains_key(&site))
+                .collect::<Vec<_>>();
+            if sites.is_empty() {
+                continue;
+            }
+            sites.sort_unstable();
+            sites.dedup();
+            writeln!(&mut s, "function {:04X}:", function_address).unwrap();
+            for site in sites {
+                let instruction = match analysis.disassembly().instruction(site) {
+                    Some(instruction_with_operand) => instruction_with_operand.to_string(),
+                    None => format!("{:04X}", site),
+                };
+                for reference in self.references_from(site) {
+                    writeln!(
+                        &mut s,
+                        "    {:<34}{} ({})",
+                        instruction,
+                        reference,
+                        Region::of(reference.start, memory_map)
+                    )
+                    .unwrap();
+                }
+            }
+            writeln!(&mut s).unwrap();
+        }
+        s
+    }
+}
+
+fn references_from_instruction<MRO: MemoryReadOnly>(
+    disassembly: &Disassembly,
+    memory: &MRO,
+    instruction_with_operand: &InstructionWithOperand,
+) -> Vec<Reference> {
+    let instruction = instruction_with_operand.instruction();
+    let instruction_type = instruction.instruction_type();
+    let addressing_mode = instruction.addressing_mode();
+    let reference = |access, start, len| Reference {
+        site: instruction_with_operand.address(),
+        instruction_type,
+        addressing_mode,
+        access,
+        start,
+        len,
+    };
+    // the values of registers and zero page written by the straight-line code before the
+    // instruction, where they are constant
+    let known_state = || {
+        let preceding =
+            disassembly.preceding_instructions(instruction_with_operand.address(), REGISTER_WINDOW);
+        let mut registers = Registers::default();
+        let mut writes = Vec::new();
+        simulate_writes(&preceding, memory, &mut registers, &mut writes);
+        (registers, writes)
+    };
+    let indexed = |base: Address, index: Option<u8>| match index {
+        Some(index) => (base.wrapping_add(index as Address), 1),
+        None => (base, 256),
+    };
+    let operand_u8 = || instruction_with_operand.operand_u8().map(Address::from);
+    let operand_u16 = || instruction_with_operand.operand_u16_le();
+    match (instruction_type, addressing_mode) {
+        (InstructionType::Jsr, _) => operand_u16()
+            .map(|target| vec![reference(Access::Call, target, 1)])
+            .unwrap_or_default(),
+        (InstructionType::Jmp, AddressingMode::Absolute) => operand_u16()
+            .map(|target| vec![reference(Access::Jump, target, 1)])
+            .unwrap_or_default(),
+        (InstructionType::Jmp, AddressingMode::Indirect) => operand_u16()
+            .map(|pointer| vec![reference(Access::Pointer, pointer, 2)])
+            .unwrap_or_default(),
+        (_, AddressingMode::Relative) => vec![reference(
+            Access::Branch,
+            branch_target(instruction_with_operand),
+            1,
+        )],
+        (_, AddressingMode::IndirectYIndexed) => {
+            let pointer = match operand_u8() {
+                Some(pointer) => pointer,
+                None => return Vec::new(),
+            };
+            let mut references = vec![reference(Access::Pointer, pointer, 2)];
+            if let Some(access) = data_access(instruction_type) {
+                let (registers, writes) = known_state();
+                let last_write_to = |address: Address| {
+                    writes
+                        .iter()
+                        .rev()
+                        .find(|&&(write_address, _)| write_address == address)
+                        .map(|&(_, value)| value as Address)
+                };
+                let pointer_hi = (pointer as u8).wrapping_add(1) as Address;
+                if let (Some(lo), Some(hi)) = (last_write_to(pointer), last_write_to(pointer_hi)) {
+                    let (start, len) = indexed((hi << 8) | lo, registers.y);
+                    references.push(reference(access, start, len));
+                }
+            }
+            references
+        }
+        (_, AddressingMode::XIndexedIndirect) => {
+            let pointer = match operand_u8() {
+                Some(pointer) => pointer,
+                None => return Vec::new(),
+            };
+            let (registers, _) = known_state();
+            match registers.x {
+                Some(x) => vec![reference(
+                    Access::Pointer,
+                    (pointer as u8).wrapping_add(x) as Address,
+                    2,
+                )],
+                None => vec![reference(Access::Pointer, pointer, 256)],
+            }
+        }
+        (_, addressing_mode) => {
+            let access = match data_access(instruction_type) {
+                Some(access) => access,
+                None => return Vec::new(),
+            };
+            let (start, len) = match addressing_mode {
+                AddressingMode::ZeroPage => match operand_u8() {
+                    Some(address) => (address, 1),
+                    None => return Vec::new(),
+                },
+                AddressingMode::Absolute => match operand_u16() {
+                    Some(address) => (address, 1),
+                    None => return Vec::new(),
+                },
+                AddressingMode::ZeroPageXIndexed | AddressingMode::ZeroPageYIndexed => {
+                    let base = match operand_u8() {
+                        Some(base) => base,
+                        None => return Vec::new(),
+                    };
+                    let (registers, _) = known_state();
+                    let index = if addressing_mode == AddressingMode::ZeroPageXIndexed {
+                        registers.x
+                    } else {
+                        registers.y
+                    };
+                    match index {
+                        Some(index) => ((base as u8).wrapping_add(index) as Address, 1),
+                        None => (base, 256),
+                    }
+                }
+                AddressingMode::AbsoluteXIndexed | AddressingMode::AbsoluteYIndexed => {
+                    let base = match operand_u16() {
+                        Some(base) => base,
+                        None => return Vec::new(),
+                    };
+                    let (registers, _) = known_state();
+                    let index = if addressing_mode == AddressingMode::AbsoluteXIndexed {
+                        registers.x
+                    } else {
+                        registers.y
+                    };
+                    indexed(base, index)
+                }
+                _ => return Vec::new(),
+            };
+            vec![reference(access, start, len)]
+        }
+    }
+}
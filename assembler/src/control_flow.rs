@@ -0,0 +1,303 @@
+use crate::{Block, Data, DataAtOffset, LabelRef};
+use mos6502_model::*;
+use std::marker::PhantomData;
+use std::panic::Location;
+
+use addressing_mode::{Absolute, Immediate, Relative};
+use assembler_instruction::*;
+
+pub trait Branch: AssemblerInstruction<AddressingMode = Relative> {
+    // The branch which is taken exactly when this branch is not taken
+    type Inverse: Branch;
+}
+
+impl Branch for Bcc {
+    type Inverse = Bcs;
+}
+impl Branch for Bcs {
+    type Inverse = Bcc;
+}
+impl Branch for Beq {
+    type Inverse = Bne;
+}
+impl Branch for Bne {
+    type Inverse = Beq;
+}
+impl Branch for Bmi {
+    type Inverse = Bpl;
+}
+impl Branch for Bpl {
+    type Inverse = Bmi;
+}
+impl Branch for Bvc {
+    type Inverse = Bvs;
+}
+impl Branch for Bvs {
+    type Inverse = Bvc;
+}
+
+// Size in bytes of a JMP with an absolute operand
+const JMP_ABSOLUTE_BYTES: u8 = 3;
+
+// A relative branch added with `Block::branch`. It's replaced with the inverse
+// branch over a jump if its label turns out to be too far away, either when
+// the label is declared or when code between the branch and its label is moved
+// to relax another branch.
+pub(crate) struct PendingBranch {
+    // offset of the branch's opcode
+    offset: Address,
+    inverse_opcode: u8,
+    label: LabelRef,
+    location: &'static Location<'static>,
+}
+
+// A conditional returned by `Block::if_`. The code for the conditional is
+// added to the block by calling `else_` or `end_if`.
+#[must_use = "call `else_` or `end_if` to add the conditional to the block"]
+pub struct If<'a, B: Branch, F: FnOnce(&mut Block)> {
+    block: &'a mut Block,
+    then: Option<F>,
+    condition: PhantomData<B>,
+    // where `Block::if_` was called, which the generated instructions are
+    // attributed to
+    location: &'static Location<'static>,
+}
+
+impl<B: Branch, F: FnOnce(&mut Block)> If<'_, B, F> {
+    pub fn else_<G: FnOnce(&mut Block)>(mut self, else_: G) {
+        if let Some(then) = self.then.take() {
+            let else_label = self.block.generated_label("else");
+            let end_label = self.block.generated_label("end-if");
+            self.block
+                .branch_to::<B::Inverse>(&else_label, self.location);
+            then(self.block);
+            self.block
+                .inst_at(self.location, Jmp(Absolute), end_label.clone());
+            self.block.label(else_label);
+            else_(self.block);
+            self.block.label(end_label);
+        }
+    }
+    pub fn end_if(mut self) {
+        if let Some(then) = self.then.take() {
+            let end_label = self.block.generated_label("end-if");
+            self.block
+                .branch_to::<B::Inverse>(&end_label, self.location);
+            then(self.block);
+            self.block.label(end_label);
+        }
+    }
+}
+
+impl<B: Branch, F: FnOnce(&mut Block)> Drop for If<'_, B, F> {
+    fn drop(&mut self) {
+        if self.then.is_some() && !std::thread::panicking() {
+            panic!(
+                "conditional at {} was dropped without calling `else_` or `end_if`",
+                self.location
+            );
+        }
+    }
+}
+
+impl Block {
+    // Returns a label which is distinct from every other label in the block
+    pub fn generated_label(&mut self, name: &str) -> String {
+        let label = format!("{}~{}", name, self.num_generated_labels);
+        self.num_generated_labels += 1;
+        label
+    }
+    // Whether the branch can't reach its label. Branches to labels which
+    // haven't been declared yet are assumed to be in range.
+    fn is_out_of_range(&self, branch: &PendingBranch) -> bool {
+        branch
+            .label
+            .candidates
+            .iter()
+            .find_map(|candidate| self.labels.get(candidate))
+            .is_some_and(|&target| {
+                let delta = target as i32 - (branch.offset as i32 + 2);
+                !(-128..=127).contains(&delta)
+            })
+    }
+    fn branch_to<B: Branch>(&mut self, label: &str, location: &'static Location<'static>) {
+        let pending_branch = PendingBranch {
+            offset: self.cursor_offset,
+            inverse_opcode: B::Inverse::opcode(),
+            label: self.label_ref(label),
+            location,
+        };
+        if !self.is_out_of_range(&pending_branch) {
+            self.pending_branches.push(pending_branch);
+            self.opcode_at(B::opcode(), location);
+            self.label_relative_offset(label);
+        } else {
+            self.opcode_at(B::Inverse::opcode(), location);
+            self.literal_byte(JMP_ABSOLUTE_BYTES);
+            self.inst_at(location, Jmp(Absolute), label.to_string());
+        }
+    }
+    // Called when a label is declared, to relax any pending branches which
+    // can't reach their labels. Relaxing a branch moves the code after it, which
+    // can push other branches across the moved code out of range, so this
+    // repeats until every branch whose label is declared can reach it.
+    pub(crate) fn relax_branches(&mut self) {
+        while let Some(index) = self
+            .pending_branches
+            .iter()
+            .position(|pending_branch| self.is_out_of_range(pending_branch))
+        {
+            let pending_branch = self.pending_branches.remove(index);
+            self.relax_branch(pending_branch);
+        }
+    }
+    // Called by the optimiser after it moves code. `remap` returns the new
+    // offset of the instruction at an old offset, or `None` if it was removed.
+    pub(crate) fn remap_pending_branches<F: Fn(Address) -> Option<Address>>(&mut self, remap: F) {
+        self.pending_branches
+            .retain_mut(|pending_branch| match remap(pending_branch.offset) {
+                Some(offset) => {
+                    pending_branch.offset = offset;
+                    true
+                }
+                None => false,
+            });
+    }
+    // Called by the optimiser when it points the branch at `offset` at a
+    // different label
+    pub(crate) fn retarget_pending_branch(&mut self, offset: Address, label: &LabelRef) {
+        for pending_branch in self.pending_branches.iter_mut() {
+            if pending_branch.offset == offset {
+                pending_branch.label = label.clone();
+            }
+        }
+    }
+    // Makes room for a jump after the pending branch by moving everything
+    // from there up to the cursor forwards, then replaces the branch with the
+    // inverse branch over the jump. Literal offsets into the moved code are
+    // updated. If moving the code would overwrite data placed after the cursor
+    // with `set_offset`, the branch is left alone, and assembling the block
+    // will fail with `Error::BranchTargetOutOfRange`.
+    fn relax_branch(&mut self, pending_branch: PendingBranch) {
+        let start = pending_branch.offset.wrapping_add(2);
+        let end = self.cursor_offset;
+        let shift = JMP_ABSOLUTE_BYTES as Address;
+        let moved = |offset: Address| start <= offset && offset <= end;
+        if self
+            .program
+            .iter()
+            .any(|d| d.offset >= end && d.offset < end.wrapping_add(shift))
+        {
+            return;
+        }
+        let index =
+            match self.program.iter().position(|d| {
+                d.offset == pending_branch.offset && matches!(d.data, Data::Opcode(_))
+            }) {
+                Some(index) => index,
+                None => return,
+            };
+        for data_at_offset in self.program.iter_mut() {
+            if moved(data_at_offset.offset) {
+                data_at_offset.offset += shift;
+            }
+            if let Data::LiteralOffsetLe(offset) = data_at_offset.data {
+                if moved(offset) {
+                    data_at_offset.data = Data::LiteralOffsetLe(offset + shift);
+                }
+            }
+        }
+        for offset in self.labels.values_mut() {
+            if moved(*offset) {
+                *offset += shift;
+            }
+        }
+        for other in self.pending_branches.iter_mut() {
+            if moved(other.offset) {
+                other.offset += shift;
+            }
+        }
+        self.cursor_offset = end.wrapping_add(shift);
+        self.program[index].data = Data::Opcode(pending_branch.inverse_opcode);
+        self.program[index + 1].data = Data::LiteralByte(JMP_ABSOLUTE_BYTES);
+        self.program.splice(
+            index + 2..index + 2,
+            [
+                DataAtOffset {
+                    data: Data::Opcode(Jmp::<Absolute>::opcode()),
+                    offset: start,
+                    location: Some(pending_branch.location),
+                },
+                DataAtOffset {
+                    data: Data::LabelOffsetLe(pending_branch.label),
+                    offset: start.wrapping_add(1),
+                    location: None,
+                },
+            ],
+        );
+    }
+    // Adds a branch to `label`. If the label is too far away for a relative
+    // branch, the inverse branch is used to skip over a jump to the label
+    // instead. For labels declared later, this is decided when the label is
+    // declared, and the code in between is moved to make room for the jump.
+    #[track_caller]
+    pub fn branch<B: Branch, S: AsRef<str>>(&mut self, instruction: B, label: S) {
+        let _ = instruction;
+        self.branch_to::<B>(label.as_ref(), Location::caller());
+    }
+    // Runs `then` only when the branch `condition` would be taken. Finish the
+    // conditional with `else_` or `end_if`.
+    #[track_caller]
+    pub fn if_<B: Branch, F: FnOnce(&mut Self)>(&mut self, condition: B, then: F) -> If<'_, B, F> {
+        let _ = condition;
+        If {
+            block: self,
+            then: Some(then),
+            condition: PhantomData,
+            location: Location::caller(),
+        }
+    }
+    // Runs `body` and then repeats it for as long as the branch `condition`
+    // would be taken
+    #[track_caller]
+    pub fn loop_while<B: Branch, F: FnOnce(&mut Self)>(&mut self, condition: B, body: F) {
+        let start_label = self.generated_label("loop");
+        self.label(start_label.as_str());
+        body(self);
+        self.branch(condition, start_label);
+    }
+    // Runs `body` with the X register taking each value from `start` up to
+    // but not including `end`. The body must preserve X. If `start` and `end`
+    // are equal then the body runs 256 times.
+    #[track_caller]
+    pub fn for_x<F: FnOnce(&mut Self)>(&mut self, start: u8, end: u8, body: F) {
+        let location = Location::caller();
+        self.inst_at(location, Ldx(Immediate), start);
+        self.loop_while(Bne, |b| {
+            body(b);
+            b.inst_at(location, Inx, ());
+            b.inst_at(location, Cpx(Immediate), end);
+        });
+    }
+    // Runs `body` with the Y register taking each value from `start` up to
+    // but not including `end`. The body must preserve Y. If `start` and `end`
+    // are equal then the body runs 256 times.
+    #[track_caller]
+    pub fn for_y<F: FnOnce(&mut Self)>(&mut self, start: u8, end: u8, body: F) {
+        let location = Location::caller();
+        self.inst_at(location, Ldy(Immediate), start);
+        self.loop_while(Bne, |b| {
+            body(b);
+            b.inst_at(location, Iny, ());
+            b.inst_at(location, Cpy(Immediate), end);
+        });
+    }
+    #[track_caller]
+    pub fn call<S: AsRef<str>>(&mut self, label: S) {
+        self.inst(Jsr(Absolute), label.as_ref().to_string());
+    }
+    #[track_caller]
+    pub fn ret(&mut self) {
+        self.inst(Rts, ());
+    }
+}
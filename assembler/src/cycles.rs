@@ -0,0 +1,119 @@
+use crate::{Block, Error, LabelRef};
+use mos6502_model::debug::Instruction;
+use mos6502_model::timing::Penalty;
+use mos6502_model::*;
+
+// The range of cycles it could take to run a sequence of instructions once each
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cycles {
+    pub min: u32,
+    pub max: u32,
+}
+
+pub(crate) struct CycleBudget {
+    start: LabelRef,
+    end: LabelRef,
+    budget: u32,
+}
+
+// Counts the cycles taken by the instructions in `buffer` from `start` up to
+// but not including `end`, where offsets are relative to `base`. The target
+// of each branch is known, so the page crossing penalty is only included for
+// branches which cross a page when taken.
+fn count_cycles(
+    buffer: &[u8],
+    base: Address,
+    start: Address,
+    end: Address,
+) -> Result<Cycles, Error> {
+    if end as usize > buffer.len() {
+        return Err(Error::OffsetOutOfBounds);
+    }
+    let mut cycles = Cycles { min: 0, max: 0 };
+    let mut offset = start as usize;
+    while offset < end as usize {
+        let address = base.wrapping_add(offset as Address);
+        let opcode = buffer[offset];
+        let instruction =
+            Instruction::from_opcode(opcode).map_err(|_| Error::UnknownOpcode(address))?;
+        let timing = instruction.timing();
+        let max = if let Penalty::Branch = timing.penalty {
+            let next = address.wrapping_add(instruction.size() as Address);
+            let delta = *buffer.get(offset + 1).ok_or(Error::OffsetOutOfBounds)? as i8;
+            let target = next.wrapping_add(delta as i16 as Address);
+            if address::on_different_pages(next, target) {
+                timing.max_cycles()
+            } else {
+                timing.max_cycles() - 1
+            }
+        } else {
+            timing.max_cycles()
+        };
+        cycles.min += timing.min_cycles() as u32;
+        cycles.max += max as u32;
+        offset += instruction.size();
+    }
+    Ok(cycles)
+}
+
+impl Block {
+    fn region_offsets(
+        &self,
+        start: &LabelRef,
+        end: &LabelRef,
+    ) -> Result<(Address, Address), Error> {
+        let (start_label, start) = self.resolve(start)?;
+        let (end_label, end) = self.resolve(end)?;
+        if end < start {
+            return Err(Error::InvalidRegion(
+                start_label.to_string(),
+                end_label.to_string(),
+            ));
+        }
+        Ok((start, end))
+    }
+    // Returns the range of cycles it would take to run each instruction from
+    // the label `start` up to the label `end` once, when the block is
+    // assembled at `base`. The region must only contain instructions.
+    pub fn cycles<S: AsRef<str>, E: AsRef<str>>(
+        &self,
+        base: Address,
+        start: S,
+        end: E,
+    ) -> Result<Cycles, Error> {
+        let (start, end) = self.region_offsets(
+            &self.label_ref(start.as_ref()),
+            &self.label_ref(end.as_ref()),
+        )?;
+        let mut buffer = Vec::new();
+        self.write_program(base, 0x10000, &mut buffer)?;
+        count_cycles(&buffer, base, start, end)
+    }
+    // Causes assembly to fail if running each instruction from the label
+    // `start` up to the label `end` once could take more than `budget` cycles
+    pub fn assert_max_cycles<S: AsRef<str>, E: AsRef<str>>(
+        &mut self,
+        start: S,
+        end: E,
+        budget: u32,
+    ) {
+        let start = self.label_ref(start.as_ref());
+        let end = self.label_ref(end.as_ref());
+        self.cycle_budgets.push(CycleBudget { start, end, budget });
+    }
+    pub(crate) fn check_cycle_budgets(&self, base: Address, buffer: &[u8]) -> Result<(), Error> {
+        for cycle_budget in self.cycle_budgets.iter() {
+            let (start, end) = self.region_offsets(&cycle_budget.start, &cycle_budget.end)?;
+            let cycles = count_cycles(buffer, base, start, end)?;
+            if cycles.max > cycle_budget.budget {
+                return Err(Error::CycleBudgetExceeded {
+                    start: self.resolve(&cycle_budget.start)?.0.to_string(),
+                    end: self.resolve(&cycle_budget.end)?.0.to_string(),
+                    max_cycles: cycles.max,
+                    budget: cycle_budget.budget,
+                });
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,25 +1,140 @@
 use mos6502_model::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::panic::Location;
+use std::path::Path;
 
+mod control_flow;
+mod cycles;
+mod peephole;
+mod symbol_file;
+
+#[cfg(test)]
+mod test;
+
+pub use control_flow::{Branch, If};
+pub use cycles::Cycles;
+pub use peephole::{OptimisationReport, OptimiseOptions, Rule, RuleReport};
+pub use symbol_file::{ParseError, SourceLine, SymbolFile};
+
+// Labels beginning with this prefix are local to the innermost enclosing scope.
+pub const LOCAL_LABEL_PREFIX: char = '@';
+const SCOPE_SEPARATOR: &str = "::";
+
+// A reference to a label which is resolved when the block is assembled. Local
+// labels are looked up in each enclosing scope in turn, innermost first.
+#[derive(Clone, PartialEq, Eq)]
+struct LabelRef {
+    candidates: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
 enum Data {
+    Opcode(u8),
     LiteralByte(u8),
-    LabelOffsetLe(String),
+    LabelOffsetLe(LabelRef),
     LiteralOffsetLe(Address),
     LiteralAddressLe(Address),
-    LabelOffsetLo(String),
-    LabelOffsetHi(String),
-    LabelRelativeOffset(String),
+    LabelOffsetLo(LabelRef),
+    LabelOffsetHi(LabelRef),
+    LabelRelativeOffset(LabelRef),
 }
 
+impl Data {
+    fn size(&self) -> Address {
+        match self {
+            Data::Opcode(_)
+            | Data::LiteralByte(_)
+            | Data::LabelOffsetLo(_)
+            | Data::LabelOffsetHi(_)
+            | Data::LabelRelativeOffset(_) => 1,
+            Data::LabelOffsetLe(_) | Data::LiteralOffsetLe(_) | Data::LiteralAddressLe(_) => 2,
+        }
+    }
+}
+
+#[derive(Clone)]
 struct DataAtOffset {
     data: Data,
     offset: Address,
+    // the line of rust code which added an instruction, for opcodes
+    location: Option<&'static Location<'static>>,
 }
 
 pub struct Block {
     cursor_offset: Address,
     program: Vec<DataAtOffset>,
     labels: HashMap<String, Address>,
+    deferred_errors: Vec<Error>,
+    scopes: Vec<String>,
+    scope_uses: HashMap<String, usize>,
+    num_anonymous_labels: usize,
+    num_generated_labels: usize,
+    charmap: Charmap,
+    cycle_budgets: Vec<cycles::CycleBudget>,
+    pending_branches: Vec<control_flow::PendingBranch>,
+}
+
+// Maps characters in strings to the bytes that represent them in the program,
+// typically the indices of the tiles used to draw each character.
+#[derive(Debug, Clone)]
+pub struct Charmap {
+    map: HashMap<char, u8>,
+}
+
+impl Default for Charmap {
+    fn default() -> Self {
+        Self::ascii()
+    }
+}
+
+impl Charmap {
+    pub fn empty() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+    pub fn ascii() -> Self {
+        let mut charmap = Self::empty();
+        charmap.insert_range('\0'..='\x7F', 0);
+        charmap
+    }
+    pub fn insert(&mut self, c: char, byte: u8) {
+        self.map.insert(c, byte);
+    }
+    // Maps consecutive characters to consecutive bytes starting at `first_byte`
+    pub fn insert_range<I: IntoIterator<Item = char>>(&mut self, chars: I, first_byte: u8) {
+        for (i, c) in chars.into_iter().enumerate() {
+            self.insert(c, first_byte.wrapping_add(i as u8));
+        }
+    }
+    pub fn get(&self, c: char) -> Option<u8> {
+        self.map.get(&c).cloned()
+    }
+}
+
+// An entry in a table of little-endian words
+pub enum Word {
+    Label(String),
+    Address(Address),
+}
+
+impl From<&str> for Word {
+    fn from(label: &str) -> Self {
+        Self::Label(label.to_string())
+    }
+}
+
+impl From<String> for Word {
+    fn from(label: String) -> Self {
+        Self::Label(label)
+    }
+}
+
+impl From<Address> for Word {
+    fn from(address: Address) -> Self {
+        Self::Address(address)
+    }
 }
 
 pub trait ArgOperand {
@@ -124,6 +239,22 @@ pub enum Error {
     OffsetOutOfBounds,
     UndeclaredLabel(String),
     BranchTargetOutOfRange(String),
+    DuplicateLabel(String),
+    UnmappedCharacter(char),
+    UnknownOpcode(Address),
+    InvalidRegion(String, String),
+    CycleBudgetExceeded {
+        start: String,
+        end: String,
+        max_cycles: u32,
+        budget: u32,
+    },
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Block {
@@ -132,22 +263,103 @@ impl Block {
             cursor_offset: 0,
             program: Vec::new(),
             labels: HashMap::new(),
+            deferred_errors: Vec::new(),
+            scopes: Vec::new(),
+            scope_uses: HashMap::new(),
+            num_anonymous_labels: 0,
+            num_generated_labels: 0,
+            charmap: Charmap::default(),
+            cycle_budgets: Vec::new(),
+            pending_branches: Vec::new(),
+        }
+    }
+    fn anonymous_label_name(index: usize) -> String {
+        format!(":{}", index)
+    }
+    fn qualify_label(&self, label: &str) -> String {
+        match self.scopes.last() {
+            Some(scope) if label.starts_with(LOCAL_LABEL_PREFIX) => {
+                format!("{}{}{}", scope, SCOPE_SEPARATOR, label)
+            }
+            _ => label.to_string(),
+        }
+    }
+    fn label_ref(&self, label: &str) -> LabelRef {
+        let candidates = if !label.is_empty() && label.chars().all(|c| c == '-') {
+            // "-" is the closest anonymous label before this point, "--" the one before that, etc
+            match self.num_anonymous_labels.checked_sub(label.len()) {
+                Some(index) => vec![Self::anonymous_label_name(index)],
+                None => vec![label.to_string()],
+            }
+        } else if !label.is_empty() && label.chars().all(|c| c == '+') {
+            // "+" is the closest anonymous label after this point, "++" the one after that, etc
+            let index = self.num_anonymous_labels + label.len() - 1;
+            vec![Self::anonymous_label_name(index)]
+        } else if label.starts_with(LOCAL_LABEL_PREFIX) {
+            self.scopes
+                .iter()
+                .rev()
+                .map(|scope| format!("{}{}{}", scope, SCOPE_SEPARATOR, label))
+                .chain(std::iter::once(label.to_string()))
+                .collect()
+        } else {
+            vec![label.to_string()]
+        };
+        LabelRef { candidates }
+    }
+    // Runs `f` inside a new scope, so labels declared in `f` with a leading
+    // `LOCAL_LABEL_PREFIX` don't collide with labels elsewhere in the block.
+    // Entering a scope with the same name more than once produces a distinct
+    // scope each time, so a rust function which wraps its body in a scope can be
+    // used as a macro and expanded any number of times.
+    pub fn scope<S: AsRef<str>, T, F: FnOnce(&mut Self) -> T>(&mut self, name: S, f: F) -> T {
+        let mut scope = match self.scopes.last() {
+            Some(parent) => format!("{}{}{}", parent, SCOPE_SEPARATOR, name.as_ref()),
+            None => name.as_ref().to_string(),
+        };
+        let uses = self.scope_uses.entry(scope.clone()).or_insert(0);
+        if *uses > 0 {
+            scope = format!("{}#{}", scope, uses);
         }
+        *uses += 1;
+        self.scopes.push(scope);
+        let ret = f(self);
+        self.scopes.pop();
+        ret
     }
     pub fn set_offset(&mut self, offset: Address) {
         self.cursor_offset = offset;
     }
+    #[track_caller]
+    fn opcode(&mut self, opcode: u8) {
+        self.opcode_at(opcode, Location::caller());
+    }
+    fn opcode_at(&mut self, opcode: u8, location: &'static Location<'static>) {
+        self.program.push(DataAtOffset {
+            data: Data::Opcode(opcode),
+            offset: self.cursor_offset,
+            location: Some(location),
+        });
+        self.cursor_offset = self.cursor_offset.wrapping_add(1);
+    }
     pub fn literal_byte(&mut self, byte: u8) {
         self.program.push(DataAtOffset {
             data: Data::LiteralByte(byte),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(1);
     }
+    pub fn literal_bytes<B: AsRef<[u8]>>(&mut self, bytes: B) {
+        for &byte in bytes.as_ref() {
+            self.literal_byte(byte);
+        }
+    }
     pub fn literal_offset_le(&mut self, offset: Address) {
         self.program.push(DataAtOffset {
             data: Data::LiteralOffsetLe(offset),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(2);
     }
@@ -155,47 +367,134 @@ impl Block {
         self.program.push(DataAtOffset {
             data: Data::LiteralAddressLe(offset),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(2);
     }
     pub fn label_offset_le<S: AsRef<str>>(&mut self, label: S) {
-        let string = label.as_ref().to_string();
+        let label_ref = self.label_ref(label.as_ref());
         self.program.push(DataAtOffset {
-            data: Data::LabelOffsetLe(string),
+            data: Data::LabelOffsetLe(label_ref),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(2);
     }
     pub fn label_offset_lo<S: AsRef<str>>(&mut self, label: S) {
-        let string = label.as_ref().to_string();
+        let label_ref = self.label_ref(label.as_ref());
         self.program.push(DataAtOffset {
-            data: Data::LabelOffsetLo(string),
+            data: Data::LabelOffsetLo(label_ref),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(1);
     }
     pub fn label_offset_hi<S: AsRef<str>>(&mut self, label: S) {
-        let string = label.as_ref().to_string();
+        let label_ref = self.label_ref(label.as_ref());
         self.program.push(DataAtOffset {
-            data: Data::LabelOffsetHi(string),
+            data: Data::LabelOffsetHi(label_ref),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(1);
     }
     pub fn label_relative_offset<S: AsRef<str>>(&mut self, label: S) {
-        let string = label.as_ref().to_string();
+        let label_ref = self.label_ref(label.as_ref());
         self.program.push(DataAtOffset {
-            data: Data::LabelRelativeOffset(string),
+            data: Data::LabelRelativeOffset(label_ref),
             offset: self.cursor_offset,
+            location: None,
         });
         self.cursor_offset = self.cursor_offset.wrapping_add(1);
     }
+    pub fn word_table<W: Into<Word>, I: IntoIterator<Item = W>>(&mut self, words: I) {
+        for word in words {
+            match word.into() {
+                Word::Label(label) => self.label_offset_le(label),
+                Word::Address(address) => self.literal_address_le(address),
+            }
+        }
+    }
+    pub fn label_table_lo<S: AsRef<str>, I: IntoIterator<Item = S>>(&mut self, labels: I) {
+        for label in labels {
+            self.label_offset_lo(label);
+        }
+    }
+    pub fn label_table_hi<S: AsRef<str>, I: IntoIterator<Item = S>>(&mut self, labels: I) {
+        for label in labels {
+            self.label_offset_hi(label);
+        }
+    }
+    // Emits a table of the low bytes of the addresses of `labels` at `lo_label`,
+    // followed by a table of the high bytes at `hi_label`. Both tables can be
+    // indexed by the same register, as is common for jump tables.
+    pub fn split_label_table<L: AsRef<str>, H: AsRef<str>, S: AsRef<str>>(
+        &mut self,
+        lo_label: L,
+        hi_label: H,
+        labels: &[S],
+    ) {
+        self.label(lo_label);
+        self.label_table_lo(labels);
+        self.label(hi_label);
+        self.label_table_hi(labels);
+    }
+    pub fn set_charmap(&mut self, charmap: Charmap) {
+        self.charmap = charmap;
+    }
+    pub fn string<S: AsRef<str>>(&mut self, s: S) {
+        for c in s.as_ref().chars() {
+            if let Some(byte) = self.charmap.get(c) {
+                self.literal_byte(byte);
+            } else {
+                self.deferred_errors.push(Error::UnmappedCharacter(c));
+            }
+        }
+    }
+    // Includes `length` bytes (or the remainder of the file if `None`) from
+    // the file at `path`, starting `offset` bytes into the file
+    pub fn incbin<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        offset: usize,
+        length: Option<usize>,
+    ) -> io::Result<()> {
+        let bytes = std::fs::read(path)?;
+        let end = match length {
+            Some(length) => offset.checked_add(length),
+            None => Some(bytes.len()),
+        };
+        match end.and_then(|end| bytes.get(offset..end)) {
+            Some(bytes) => {
+                self.literal_bytes(bytes);
+                Ok(())
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "incbin range extends beyond the end of the file",
+            )),
+        }
+    }
     pub fn label<S: AsRef<str>>(&mut self, s: S) {
-        let string = s.as_ref().to_string();
-        if self.labels.insert(string, self.cursor_offset).is_some() {
-            panic!("Multiple definitions of label {}", s.as_ref());
+        let string = self.qualify_label(s.as_ref());
+        if self.labels.contains_key(&string) {
+            self.deferred_errors.push(Error::DuplicateLabel(string));
+        } else {
+            self.labels.insert(string.clone(), self.cursor_offset);
+            self.relax_branches();
         }
     }
+    // Declares a label which can be referred to as "-" by later code or "+" by
+    // earlier code. Use "--"/"++" etc to refer to labels further away.
+    pub fn anonymous_label(&mut self) {
+        let string = Self::anonymous_label_name(self.num_anonymous_labels);
+        self.num_anonymous_labels += 1;
+        self.labels.insert(string.clone(), self.cursor_offset);
+        self.relax_branches();
+    }
+    // Instructions remember the line of rust code which added them, for
+    // mapping addresses to source lines in symbol files
+    #[track_caller]
     pub fn inst<
         I: AssemblerInstruction,
         A: ArgOperand<Operand = <I::AddressingMode as addressing_mode::Trait>::Operand>,
@@ -203,46 +502,89 @@ impl Block {
         &mut self,
         instruction: I,
         arg: A,
+    ) {
+        self.inst_at(Location::caller(), instruction, arg);
+    }
+    fn inst_at<
+        I: AssemblerInstruction,
+        A: ArgOperand<Operand = <I::AddressingMode as addressing_mode::Trait>::Operand>,
+    >(
+        &mut self,
+        location: &'static Location<'static>,
+        instruction: I,
+        arg: A,
     ) {
         let _ = instruction;
-        self.literal_byte(I::opcode());
+        self.opcode_at(I::opcode(), location);
         arg.program(self);
     }
+    #[track_caller]
     pub fn infinite_loop(&mut self) {
         let offset = self.cursor_offset;
-        self.literal_byte(assembler_instruction::Jmp::<addressing_mode::Absolute>::opcode());
+        self.opcode(assembler_instruction::Jmp::<addressing_mode::Absolute>::opcode());
         self.literal_offset_le(offset);
     }
+    fn resolve<'a>(&self, label_ref: &'a LabelRef) -> Result<(&'a str, Address), Error> {
+        label_ref
+            .candidates
+            .iter()
+            .find_map(|candidate| {
+                self.labels
+                    .get(candidate)
+                    .map(|&offset| (candidate.as_str(), offset))
+            })
+            .ok_or_else(|| Error::UndeclaredLabel(label_ref.candidates[0].clone()))
+    }
     pub fn assemble(
         &self,
         base: Address,
         size: usize,
         buffer: &mut Vec<u8>,
     ) -> Result<AssembledBlock, Error> {
+        if let Some(error) = self.deferred_errors.first() {
+            return Err(error.clone());
+        }
         let mut labels = HashMap::new();
         for (label, address) in self.labels.iter() {
             labels.insert(label.clone(), address + base);
         }
+        let source_lines = self
+            .program
+            .iter()
+            .filter_map(|data_at_offset| {
+                data_at_offset
+                    .location
+                    .map(|location| (data_at_offset.offset.wrapping_add(base), location))
+            })
+            .collect();
+        self.write_program(base, size, buffer)?;
+        self.check_cycle_budgets(base, buffer)?;
+        Ok(AssembledBlock {
+            labels,
+            source_lines,
+        })
+    }
+    fn write_program(&self, base: Address, size: usize, buffer: &mut Vec<u8>) -> Result<(), Error> {
         buffer.resize(size, 0);
-        for &DataAtOffset { offset, ref data } in self.program.iter() {
+        for &DataAtOffset {
+            offset, ref data, ..
+        } in self.program.iter()
+        {
             match data {
-                &Data::LiteralByte(byte) => {
+                &Data::Opcode(byte) | &Data::LiteralByte(byte) => {
                     if offset as usize >= size {
                         return Err(Error::OffsetOutOfBounds);
                     }
                     buffer[offset as usize] = byte;
                 }
                 Data::LabelOffsetLe(label) => {
-                    if let Some(&label_offset) = self.labels.get(label) {
-                        if offset as usize + 1 >= size {
-                            return Err(Error::OffsetOutOfBounds);
-                        }
-                        let address = label_offset + base;
-                        buffer[offset as usize] = address::lo(address);
-                        buffer[offset as usize + 1] = address::hi(address);
-                    } else {
-                        return Err(Error::UndeclaredLabel(label.clone()));
+                    let (_, label_offset) = self.resolve(label)?;
+                    if offset as usize + 1 >= size {
+                        return Err(Error::OffsetOutOfBounds);
                     }
+                    let address = label_offset + base;
+                    buffer[offset as usize] = address::lo(address);
+                    buffer[offset as usize + 1] = address::hi(address);
                 }
                 Data::LiteralOffsetLe(literal_offset) => {
                     if offset as usize + 1 >= size {
@@ -257,50 +599,59 @@ impl Block {
                     buffer[offset as usize + 1] = address::hi(address);
                 }
                 Data::LabelOffsetLo(label) => {
-                    if let Some(&label_offset) = self.labels.get(label) {
-                        if offset as usize + 1 >= size {
-                            return Err(Error::OffsetOutOfBounds);
-                        }
-                        let address = label_offset + base;
-                        buffer[offset as usize] = address::lo(address);
-                    } else {
-                        return Err(Error::UndeclaredLabel(label.clone()));
+                    let (_, label_offset) = self.resolve(label)?;
+                    if offset as usize + 1 >= size {
+                        return Err(Error::OffsetOutOfBounds);
                     }
+                    let address = label_offset + base;
+                    buffer[offset as usize] = address::lo(address);
                 }
                 Data::LabelOffsetHi(label) => {
-                    if let Some(&label_offset) = self.labels.get(label) {
-                        if offset as usize + 1 >= size {
-                            return Err(Error::OffsetOutOfBounds);
-                        }
-                        let address = label_offset + base;
-                        buffer[offset as usize] = address::hi(address);
-                    } else {
-                        return Err(Error::UndeclaredLabel(label.clone()));
+                    let (_, label_offset) = self.resolve(label)?;
+                    if offset as usize + 1 >= size {
+                        return Err(Error::OffsetOutOfBounds);
                     }
+                    let address = label_offset + base;
+                    buffer[offset as usize] = address::hi(address);
                 }
                 Data::LabelRelativeOffset(label) => {
-                    if let Some(&label_offset) = self.labels.get(label) {
-                        let delta = label_offset as i16 - offset as i16 - 1;
-                        if delta < -128 || delta > 127 {
-                            return Err(Error::BranchTargetOutOfRange(label.clone()));
-                        }
-                        buffer[offset as usize] = (delta as i8) as u8;
-                    } else {
-                        return Err(Error::UndeclaredLabel(label.clone()));
+                    let (label, label_offset) = self.resolve(label)?;
+                    let delta = label_offset as i16 - offset as i16 - 1;
+                    if !(-128..=127).contains(&delta) {
+                        return Err(Error::BranchTargetOutOfRange(label.to_string()));
                     }
+                    buffer[offset as usize] = (delta as i8) as u8;
                 }
             }
         }
-        Ok(AssembledBlock { labels })
+        Ok(())
     }
 }
 
 pub struct AssembledBlock {
     labels: HashMap<String, Address>,
+    source_lines: BTreeMap<Address, &'static Location<'static>>,
 }
 
 impl AssembledBlock {
     pub fn address_of_label(&self, label: &str) -> Option<Address> {
         self.labels.get(label).cloned()
     }
+    // The labels and the source line of each instruction, for debuggers
+    pub fn symbol_file(&self) -> SymbolFile {
+        let mut symbol_file = SymbolFile::new();
+        for (label, &address) in self.labels.iter() {
+            symbol_file.insert_label(label.clone(), address);
+        }
+        for (&address, location) in self.source_lines.iter() {
+            symbol_file.insert_source_line(
+                address,
+                SourceLine {
+                    file: location.file().to_string(),
+                    line: location.line(),
+                },
+            );
+        }
+        symbol_file
+    }
 }
@@ -0,0 +1,796 @@
+use crate::{Block, Data, DataAtOffset};
+use mos6502_model::debug::{AddressingMode, Instruction, InstructionType};
+use mos6502_model::*;
+use std::collections::HashSet;
+use std::fmt;
+
+// Limit on the number of jumps followed when retargeting a branch, in case of
+// cycles of jumps
+const MAX_HOPS: usize = 16;
+
+mod flag {
+    pub const N: u8 = 1 << 0;
+    pub const V: u8 = 1 << 1;
+    pub const Z: u8 = 1 << 2;
+    pub const C: u8 = 1 << 3;
+    pub const ALL: u8 = N | V | Z | C;
+}
+
+#[derive(Debug, Clone)]
+pub struct OptimiseOptions {
+    // Addresses below this are assumed to be RAM, so reading them back has no
+    // side effects. The default covers the NES's internal RAM and its mirrors.
+    pub ram_end: u32,
+}
+
+impl Default for OptimiseOptions {
+    fn default() -> Self {
+        Self { ram_end: 0x2000 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    // LDA/LDX/LDY #x when the register already holds x, separated from the
+    // previous identical load only by stores
+    RedundantLoadImmediate,
+    // LDA/LDX/LDY from the RAM address just stored to from the same register
+    StoreThenLoad,
+    // CLC; ADC #0
+    ClcAdcZero,
+    // JSR x; RTS becomes JMP x
+    JsrRtsTail,
+    // Branches and jumps to a JMP, or to a branch on the same condition, are
+    // retargeted to its destination
+    BranchToBranch,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::RedundantLoadImmediate,
+        Rule::StoreThenLoad,
+        Rule::ClcAdcZero,
+        Rule::JsrRtsTail,
+        Rule::BranchToBranch,
+    ];
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::RedundantLoadImmediate => "redundant-load-immediate",
+            Rule::StoreThenLoad => "store-then-load",
+            Rule::ClcAdcZero => "clc-adc-zero",
+            Rule::JsrRtsTail => "jsr-rts-tail",
+            Rule::BranchToBranch => "branch-to-branch",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RuleReport {
+    pub applications: usize,
+    pub bytes_saved: usize,
+    // Cycles saved each time the optimised code runs
+    pub cycles_saved: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OptimisationReport {
+    rules: Vec<(Rule, RuleReport)>,
+}
+
+impl OptimisationReport {
+    fn record(&mut self, rule: Rule, bytes_saved: usize, cycles_saved: usize) {
+        let index = match self.rules.iter().position(|&(r, _)| r == rule) {
+            Some(index) => index,
+            None => {
+                self.rules.push((rule, RuleReport::default()));
+                self.rules.len() - 1
+            }
+        };
+        let report = &mut self.rules[index].1;
+        report.applications += 1;
+        report.bytes_saved += bytes_saved;
+        report.cycles_saved += cycles_saved;
+    }
+    pub fn rule(&self, rule: Rule) -> RuleReport {
+        self.rules
+            .iter()
+            .find(|&&(r, _)| r == rule)
+            .map(|&(_, report)| report)
+            .unwrap_or_default()
+    }
+    pub fn bytes_saved(&self) -> usize {
+        self.rules
+            .iter()
+            .map(|(_, report)| report.bytes_saved)
+            .sum()
+    }
+    pub fn cycles_saved(&self) -> usize {
+        self.rules
+            .iter()
+            .map(|(_, report)| report.cycles_saved)
+            .sum()
+    }
+}
+
+impl fmt::Display for OptimisationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &rule in Rule::ALL.iter() {
+            let report = self.rule(rule);
+            writeln!(
+                f,
+                "{:<26} {:>4} applications {:>5} bytes {:>6} cycles",
+                rule.name(),
+                report.applications,
+                report.bytes_saved,
+                report.cycles_saved
+            )?;
+        }
+        write!(
+            f,
+            "{:<26} {:>4}              {:>5} bytes {:>6} cycles",
+            "total",
+            "",
+            self.bytes_saved(),
+            self.cycles_saved()
+        )
+    }
+}
+
+// An instruction (or a piece of data which isn't part of an instruction)
+// along with the program data it's made of
+#[derive(Clone)]
+struct Item {
+    offset: Address,
+    data: Vec<DataAtOffset>,
+    instruction: Option<Instruction>,
+    // Index of the contiguous run of program data containing this item
+    segment: usize,
+    removed: bool,
+}
+
+impl Item {
+    fn size(&self) -> Address {
+        self.data.iter().map(|d| d.data.size()).sum()
+    }
+    fn is(&self, instruction_type: InstructionType, addressing_mode: AddressingMode) -> bool {
+        match self.instruction {
+            Some(instruction) => {
+                instruction.instruction_type() == instruction_type
+                    && instruction.addressing_mode() == addressing_mode
+            }
+            None => false,
+        }
+    }
+    fn operand(&self) -> &[DataAtOffset] {
+        &self.data[1..]
+    }
+    fn same_operand(&self, other: &Item) -> bool {
+        self.operand().len() == other.operand().len()
+            && self
+                .operand()
+                .iter()
+                .zip(other.operand().iter())
+                .all(|(a, b)| a.data == b.data)
+    }
+    fn min_cycles(&self) -> usize {
+        self.instruction
+            .map(|instruction| instruction.timing().min_cycles() as usize)
+            .unwrap_or(0)
+    }
+    fn is_relative(&self) -> bool {
+        self.instruction
+            .map(|instruction| instruction.addressing_mode() == AddressingMode::Relative)
+            .unwrap_or(false)
+    }
+    // The target of a branch whose offset is a literal byte rather than a label
+    fn literal_branch_target(&self) -> Option<Address> {
+        match self.operand() {
+            [DataAtOffset {
+                data: Data::LiteralByte(byte),
+                ..
+            }] if self.is_relative() => Some(branch_target(self.offset, *byte as i8)),
+            _ => None,
+        }
+    }
+}
+
+fn branch_target(branch_offset: Address, delta: i8) -> Address {
+    branch_offset.wrapping_add(2).wrapping_add(delta as Address)
+}
+
+fn branch_delta(branch_offset: Address, target: Address) -> i32 {
+    target as i32 - (branch_offset as i32 + 2)
+}
+
+fn in_branch_range(delta: i32) -> bool {
+    (-128..=127).contains(&delta)
+}
+
+fn is_control_transfer(instruction_type: InstructionType) -> bool {
+    use InstructionType::*;
+    matches!(
+        instruction_type,
+        Bcc | Bcs | Beq | Bmi | Bne | Bpl | Bvc | Bvs | Brk | Jmp | Jsr | Rti | Rts
+    )
+}
+
+// Returns the flags read and written by an instruction
+fn flag_effects(instruction_type: InstructionType) -> (u8, u8) {
+    use flag::*;
+    use InstructionType::*;
+    match instruction_type {
+        Adc | Sbc | Rra | Isc | Arr => (C, N | V | Z | C),
+        Rol | Ror | Rla => (C, N | Z | C),
+        Asl | Lsr | Cmp | Cpx | Cpy | Slo | Sre | Dcp | Alr | Anc | Axs => (0, N | Z | C),
+        And | Ora | Eor | Lda | Ldx | Ldy | Lax | Pla | Tax | Tay | Tsx | Txa | Tya | Inx | Iny
+        | Dex | Dey | Inc | Dec => (0, N | Z),
+        Bit => (0, N | V | Z),
+        Clc | Sec => (0, C),
+        Clv => (0, V),
+        Plp | Rti => (0, ALL),
+        Php | Brk => (ALL, 0),
+        Bcc | Bcs => (C, 0),
+        Beq | Bne => (Z, 0),
+        Bmi | Bpl => (N, 0),
+        Bvc | Bvs => (V, 0),
+        // Jsr and Rts leave flags alone but pass control to code which may
+        // read them, so they're treated as reading every flag
+        Jsr | Rts => (ALL, 0),
+        Ahx | Cld | Cli | Ign | Jmp | Nop | Pha | Sax | Sed | Sei | Skb | Sta | Stx | Sty | Sxa
+        | Sya | Txs => (0, 0),
+    }
+}
+
+fn load_for_store(instruction_type: InstructionType) -> Option<InstructionType> {
+    use InstructionType::*;
+    match instruction_type {
+        Sta => Some(Lda),
+        Stx => Some(Ldx),
+        Sty => Some(Ldy),
+        _ => None,
+    }
+}
+
+fn is_store(instruction_type: InstructionType) -> bool {
+    load_for_store(instruction_type).is_some()
+}
+
+struct Optimiser {
+    items: Vec<Item>,
+    // Offsets of all labels. An instruction with a label on it can be reached
+    // from elsewhere, so no assumptions are made about state on arrival.
+    label_offsets: HashSet<Address>,
+    // Indices of items which must be kept even if a rule would remove them
+    pinned: HashSet<usize>,
+    ram_end: u32,
+    report: OptimisationReport,
+}
+
+impl Optimiser {
+    fn run(&mut self) {
+        self.redundant_load_immediate();
+        self.store_then_load();
+        self.clc_adc_zero();
+        self.jsr_rts_tail();
+    }
+    fn next(&self, index: usize) -> Option<usize> {
+        let segment = self.items[index].segment;
+        self.items[index + 1..]
+            .iter()
+            .position(|item| !item.removed)
+            .map(|i| index + 1 + i)
+            .filter(|&i| self.items[i].segment == segment)
+    }
+    fn is_labelled(&self, index: usize) -> bool {
+        self.label_offsets.contains(&self.items[index].offset)
+    }
+    fn is_pinned(&self, index: usize) -> bool {
+        self.pinned.contains(&index)
+    }
+    // True if none of `flags` are read after the instruction at `index`
+    // before being overwritten. Conservatively false if this can't be
+    // determined without leaving the straight-line code following `index`.
+    fn flags_dead_after(&self, index: usize, mut flags: u8) -> bool {
+        let mut current = index;
+        while let Some(next) = self.next(current) {
+            if self.is_labelled(next) {
+                return false;
+            }
+            let instruction_type = match self.items[next].instruction {
+                Some(instruction) => instruction.instruction_type(),
+                None => return false,
+            };
+            let (reads, writes) = flag_effects(instruction_type);
+            if reads & flags != 0 {
+                return false;
+            }
+            flags &= !writes;
+            if flags == 0 {
+                return true;
+            }
+            if is_control_transfer(instruction_type) {
+                return false;
+            }
+            current = next;
+        }
+        false
+    }
+    // Removes an item, returning the number of bytes and cycles it took
+    fn remove(&mut self, index: usize) -> (usize, usize) {
+        let item = &mut self.items[index];
+        item.removed = true;
+        (item.size() as usize, item.min_cycles())
+    }
+    fn redundant_load_immediate(&mut self) {
+        use AddressingMode::Immediate;
+        use InstructionType::*;
+        for index in 0..self.items.len() {
+            if self.items[index].removed {
+                continue;
+            }
+            let load = match self.items[index].instruction {
+                Some(instruction)
+                    if matches!(instruction.instruction_type(), Lda | Ldx | Ldy)
+                        && instruction.addressing_mode() == Immediate =>
+                {
+                    instruction.instruction_type()
+                }
+                _ => continue,
+            };
+            let mut current = index;
+            while let Some(next) = self.next(current) {
+                if self.is_labelled(next) {
+                    break;
+                }
+                let next_item = &self.items[next];
+                if next_item.is(load, Immediate)
+                    && next_item.same_operand(&self.items[index])
+                    && !self.is_pinned(next)
+                {
+                    let (bytes_saved, cycles_saved) = self.remove(next);
+                    self.report
+                        .record(Rule::RedundantLoadImmediate, bytes_saved, cycles_saved);
+                } else if !next_item
+                    .instruction
+                    .map(|instruction| is_store(instruction.instruction_type()))
+                    .unwrap_or(false)
+                {
+                    break;
+                }
+                current = next;
+            }
+        }
+    }
+    fn is_ram_operand(&self, item: &Item) -> bool {
+        use AddressingMode::*;
+        let instruction = match item.instruction {
+            Some(instruction) => instruction,
+            None => return false,
+        };
+        match (instruction.addressing_mode(), &item.operand()[0].data) {
+            (ZeroPage | ZeroPageXIndexed | ZeroPageYIndexed, _) => 0x100 <= self.ram_end,
+            (Absolute, &Data::LiteralAddressLe(address)) => (address as u32) < self.ram_end,
+            (AbsoluteXIndexed | AbsoluteYIndexed, &Data::LiteralAddressLe(address)) => {
+                (address as u32 + 0xFF) < self.ram_end
+            }
+            _ => false,
+        }
+    }
+    fn store_then_load(&mut self) {
+        for index in 0..self.items.len() {
+            if self.items[index].removed {
+                continue;
+            }
+            let (load, addressing_mode) = match self.items[index].instruction {
+                Some(instruction) => match load_for_store(instruction.instruction_type()) {
+                    Some(load) => (load, instruction.addressing_mode()),
+                    None => continue,
+                },
+                None => continue,
+            };
+            if !self.is_ram_operand(&self.items[index]) {
+                continue;
+            }
+            if let Some(next) = self.next(index) {
+                let next_item = &self.items[next];
+                if !self.is_labelled(next)
+                    && !self.is_pinned(next)
+                    && next_item.is(load, addressing_mode)
+                    && next_item.same_operand(&self.items[index])
+                    && self.flags_dead_after(next, flag::N | flag::Z)
+                {
+                    let (bytes_saved, cycles_saved) = self.remove(next);
+                    self.report
+                        .record(Rule::StoreThenLoad, bytes_saved, cycles_saved);
+                }
+            }
+        }
+    }
+    fn clc_adc_zero(&mut self) {
+        use AddressingMode::{Immediate, Implied};
+        use InstructionType::{Adc, Clc};
+        for index in 0..self.items.len() {
+            if self.items[index].removed || !self.items[index].is(Clc, Implied) {
+                continue;
+            }
+            if let Some(next) = self.next(index) {
+                let next_item = &self.items[next];
+                if !self.is_labelled(next)
+                    && !self.is_pinned(index)
+                    && !self.is_pinned(next)
+                    && next_item.is(Adc, Immediate)
+                    && next_item.operand()[0].data == Data::LiteralByte(0)
+                    && self.flags_dead_after(next, flag::ALL)
+                {
+                    let (clc_bytes, clc_cycles) = self.remove(index);
+                    let (adc_bytes, adc_cycles) = self.remove(next);
+                    self.report.record(
+                        Rule::ClcAdcZero,
+                        clc_bytes + adc_bytes,
+                        clc_cycles + adc_cycles,
+                    );
+                }
+            }
+        }
+    }
+    fn jsr_rts_tail(&mut self) {
+        use AddressingMode::{Absolute, Implied};
+        use InstructionType::{Jsr, Rts};
+        for index in 0..self.items.len() {
+            if self.items[index].removed || !self.items[index].is(Jsr, Absolute) {
+                continue;
+            }
+            if let Some(next) = self.next(index) {
+                if !self.is_labelled(next)
+                    && !self.is_pinned(next)
+                    && self.items[next].is(Rts, Implied)
+                {
+                    let jsr_cycles = self.items[index].min_cycles();
+                    let jmp = assembler_instruction::Jmp::<addressing_mode::Absolute>::opcode();
+                    let item = &mut self.items[index];
+                    item.data[0].data = Data::Opcode(jmp);
+                    item.instruction = Instruction::from_opcode(jmp).ok();
+                    let jmp_cycles = item.min_cycles();
+                    let (rts_bytes, rts_cycles) = self.remove(next);
+                    self.report.record(
+                        Rule::JsrRtsTail,
+                        rts_bytes,
+                        jsr_cycles + rts_cycles - jmp_cycles,
+                    );
+                }
+            }
+        }
+    }
+}
+
+impl Block {
+    // Rewrites the instructions added so far to remove some common
+    // inefficiencies, and reports what was saved by each rule. Code is only
+    // changed where it's reachable solely by falling through from the
+    // previous instruction, so labels and the targets of branches and jumps
+    // with literal operands act as barriers to optimisation. Code is assumed
+    // not to inspect the stack to find its return address. Literal addresses
+    // which refer to code in the block aren't updated when code moves.
+    pub fn optimise(&mut self) -> OptimisationReport {
+        self.optimise_with_options(&OptimiseOptions::default())
+    }
+    pub fn optimise_with_options(&mut self, options: &OptimiseOptions) -> OptimisationReport {
+        let items = self.items();
+        let mut label_offsets: HashSet<Address> = self.labels.values().cloned().collect();
+        label_offsets.extend(items.iter().filter_map(Item::literal_branch_target));
+        label_offsets.extend(items.iter().filter_map(|item| match item.operand() {
+            [DataAtOffset {
+                data: Data::LiteralOffsetLe(offset),
+                ..
+            }] => Some(*offset),
+            _ => None,
+        }));
+        let mut pinned = HashSet::new();
+        loop {
+            let mut optimiser = Optimiser {
+                items: items.clone(),
+                label_offsets: label_offsets.clone(),
+                pinned: pinned.clone(),
+                ram_end: options.ram_end,
+                report: OptimisationReport::default(),
+            };
+            optimiser.run();
+            let Optimiser {
+                items, mut report, ..
+            } = optimiser;
+            let relocation = Relocation::new(&items);
+            // Removing code can move a branch further from its target if
+            // they're in different segments. Rather than relaxing such
+            // branches, the removals which moved them are undone.
+            let to_pin = self.removals_moving_branches_out_of_range(&items, &relocation);
+            if to_pin.is_empty() || to_pin.is_subset(&pinned) {
+                self.relocate(items, &relocation);
+                self.retarget_branches(&mut report);
+                return report;
+            }
+            pinned.extend(to_pin);
+        }
+    }
+    // Indices of removed items in the same segment as a branch or its target,
+    // for each branch which is in range before relocation but not after
+    fn removals_moving_branches_out_of_range(
+        &self,
+        items: &[Item],
+        relocation: &Relocation,
+    ) -> HashSet<usize> {
+        let mut segments = HashSet::new();
+        for item in items
+            .iter()
+            .filter(|item| item.is_relative() && !item.removed)
+        {
+            let target = match item.literal_branch_target() {
+                Some(target) => target,
+                None => match &item.operand()[0].data {
+                    Data::LabelRelativeOffset(label_ref) => match self.resolve(label_ref) {
+                        Ok((_, target)) => target,
+                        Err(_) => continue,
+                    },
+                    _ => continue,
+                },
+            };
+            let delta_before = branch_delta(item.offset, target);
+            let delta_after = branch_delta(relocation.remap(item.offset), relocation.remap(target));
+            if in_branch_range(delta_before) && !in_branch_range(delta_after) {
+                segments.insert(item.segment);
+                segments.extend(relocation.segment_of(target));
+            }
+        }
+        items
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| item.removed && segments.contains(&item.segment))
+            .map(|(index, _)| index)
+            .collect()
+    }
+    fn items(&mut self) -> Vec<Item> {
+        let mut items: Vec<Item> = Vec::new();
+        let mut segment = 0;
+        let mut operand_bytes_remaining = 0;
+        let mut end_offset = None;
+        for data_at_offset in std::mem::take(&mut self.program) {
+            if end_offset != Some(data_at_offset.offset) {
+                if end_offset.is_some() {
+                    segment += 1;
+                }
+                operand_bytes_remaining = 0;
+            }
+            end_offset = Some(
+                data_at_offset
+                    .offset
+                    .wrapping_add(data_at_offset.data.size()),
+            );
+            if operand_bytes_remaining > 0 && data_at_offset.data.size() <= operand_bytes_remaining
+            {
+                operand_bytes_remaining -= data_at_offset.data.size();
+                items.last_mut().unwrap().data.push(data_at_offset);
+                continue;
+            }
+            if operand_bytes_remaining > 0 {
+                // the operand of the previous instruction is incomplete
+                items.last_mut().unwrap().instruction = None;
+            }
+            let instruction = match data_at_offset.data {
+                Data::Opcode(opcode) => Instruction::from_opcode(opcode).ok(),
+                _ => None,
+            };
+            operand_bytes_remaining = instruction
+                .map(|instruction| instruction.size() as Address - 1)
+                .unwrap_or(0);
+            items.push(Item {
+                offset: data_at_offset.offset,
+                data: vec![data_at_offset],
+                instruction,
+                segment,
+                removed: false,
+            });
+        }
+        if operand_bytes_remaining > 0 {
+            if let Some(item) = items.last_mut() {
+                item.instruction = None;
+            }
+        }
+        items
+    }
+    // Puts the remaining items back into the program at their new offsets
+    fn relocate(&mut self, items: Vec<Item>, relocation: &Relocation) {
+        let removed_offsets: HashSet<Address> = items
+            .iter()
+            .filter(|item| item.removed)
+            .map(|item| item.offset)
+            .collect();
+        self.remap_pending_branches(|offset| {
+            (!removed_offsets.contains(&offset)).then(|| relocation.remap(offset))
+        });
+        for item in items.into_iter().filter(|item| !item.removed) {
+            let literal_branch_target = item.literal_branch_target();
+            let branch_offset = relocation.remap(item.offset);
+            for mut data_at_offset in item.data {
+                data_at_offset.offset = relocation.remap(data_at_offset.offset);
+                match data_at_offset.data {
+                    Data::LiteralOffsetLe(offset) => {
+                        data_at_offset.data = Data::LiteralOffsetLe(relocation.remap(offset));
+                    }
+                    Data::LiteralByte(_) => {
+                        if let Some(target) = literal_branch_target {
+                            let delta = branch_delta(branch_offset, relocation.remap(target));
+                            data_at_offset.data = Data::LiteralByte(delta as i8 as u8);
+                        }
+                    }
+                    _ => (),
+                }
+                self.program.push(data_at_offset);
+            }
+        }
+        for offset in self.labels.values_mut() {
+            *offset = relocation.remap(*offset);
+        }
+        self.cursor_offset = relocation.remap(self.cursor_offset);
+    }
+    // The label or literal offset targeted by the branch or jump starting at
+    // `index` in the program
+    fn jump_target(&self, index: usize) -> Option<(Address, Data)> {
+        let operand = self.program.get(index + 1)?;
+        match &operand.data {
+            Data::LabelRelativeOffset(label_ref) | Data::LabelOffsetLe(label_ref) => self
+                .resolve(label_ref)
+                .ok()
+                .map(|(_, offset)| (offset, operand.data.clone())),
+            &Data::LiteralOffsetLe(offset) => Some((offset, operand.data.clone())),
+            _ => None,
+        }
+    }
+    // Branch retargeting doesn't change the size of any code, so it happens
+    // after everything else when the final offset of each instruction is known
+    fn retarget_branches(&mut self, report: &mut OptimisationReport) {
+        let jmp = assembler_instruction::Jmp::<addressing_mode::Absolute>::opcode();
+        let opcode_index_by_offset: std::collections::HashMap<Address, usize> = self
+            .program
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| matches!(d.data, Data::Opcode(_)))
+            .map(|(i, d)| (d.offset, i))
+            .collect();
+        for index in 0..self.program.len() {
+            let opcode = match self.program[index].data {
+                Data::Opcode(opcode) => opcode,
+                _ => continue,
+            };
+            let is_branch = match Instruction::from_opcode(opcode) {
+                Ok(instruction) => instruction.addressing_mode() == AddressingMode::Relative,
+                Err(_) => false,
+            };
+            if !is_branch && opcode != jmp {
+                continue;
+            }
+            let mut new_target: Option<Data> = None;
+            let mut hops = 0;
+            let mut target_index = index;
+            while hops < MAX_HOPS {
+                let (target_offset, _) = match self.jump_target(target_index) {
+                    Some(target) => target,
+                    None => break,
+                };
+                let next_index = match opcode_index_by_offset.get(&target_offset) {
+                    Some(&next_index) => next_index,
+                    None => break,
+                };
+                let next_opcode = match self.program[next_index].data {
+                    Data::Opcode(next_opcode) => next_opcode,
+                    _ => break,
+                };
+                if next_opcode != jmp && next_opcode != opcode {
+                    break;
+                }
+                let (destination, destination_data) = match self.jump_target(next_index) {
+                    Some(destination) => destination,
+                    None => break,
+                };
+                if destination == target_offset {
+                    break;
+                }
+                let replacement = if is_branch {
+                    if !in_branch_range(branch_delta(self.program[index].offset, destination)) {
+                        break;
+                    }
+                    match destination_data {
+                        Data::LabelRelativeOffset(label_ref) | Data::LabelOffsetLe(label_ref) => {
+                            Data::LabelRelativeOffset(label_ref)
+                        }
+                        _ => break,
+                    }
+                } else {
+                    match destination_data {
+                        Data::LabelRelativeOffset(label_ref) | Data::LabelOffsetLe(label_ref) => {
+                            Data::LabelOffsetLe(label_ref)
+                        }
+                        Data::LiteralOffsetLe(offset) => Data::LiteralOffsetLe(offset),
+                        _ => break,
+                    }
+                };
+                if next_index == index {
+                    break;
+                }
+                new_target = Some(replacement);
+                target_index = next_index;
+                hops += 1;
+            }
+            if let Some(new_target) = new_target {
+                if let Data::LabelRelativeOffset(label_ref) = &new_target {
+                    self.retarget_pending_branch(self.program[index].offset, label_ref);
+                }
+                self.program[index + 1].data = new_target;
+                for _ in 0..hops {
+                    report.record(Rule::BranchToBranch, 0, 3);
+                }
+            }
+        }
+    }
+}
+
+// Maps offsets before removing items to offsets after, where everything after
+// a removed item within the same segment moves backwards to close the gap
+struct Relocation {
+    // (segment, offset, bytes removed from the segment up to the end of this
+    // item) for each removed item
+    shifts: Vec<(usize, Address, Address)>,
+    // (start, end) of each segment
+    segment_ranges: Vec<(Address, Address)>,
+}
+
+impl Relocation {
+    fn new(items: &[Item]) -> Self {
+        let mut shifts = Vec::new();
+        let mut removed_bytes = 0;
+        let mut current_segment = None;
+        for item in items.iter() {
+            if current_segment != Some(item.segment) {
+                current_segment = Some(item.segment);
+                removed_bytes = 0;
+            }
+            if item.removed {
+                removed_bytes += item.size();
+                shifts.push((item.segment, item.offset, removed_bytes));
+            }
+        }
+        let mut segment_ranges: Vec<(Address, Address)> = Vec::new();
+        for item in items.iter() {
+            let end = item.offset.wrapping_add(item.size());
+            if item.segment == segment_ranges.len() {
+                segment_ranges.push((item.offset, end));
+            } else {
+                segment_ranges[item.segment].1 = end;
+            }
+        }
+        Self {
+            shifts,
+            segment_ranges,
+        }
+    }
+    fn segment_of(&self, offset: Address) -> Option<usize> {
+        self.segment_ranges
+            .iter()
+            .position(|&(start, end)| start <= offset && offset <= end)
+    }
+    fn remap(&self, offset: Address) -> Address {
+        let segment = match self.segment_of(offset) {
+            Some(segment) => segment,
+            None => return offset,
+        };
+        let shift = self
+            .shifts
+            .iter()
+            .rev()
+            .find(|&&(s, removed_offset, _)| s == segment && removed_offset < offset)
+            .map(|&(_, _, removed_bytes)| removed_bytes)
+            .unwrap_or(0);
+        offset - shift
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,268 @@
+use crate::*;
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+
+const BASE: Address = 0xC000;
+
+fn assemble(block: &Block) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    block.assemble(BASE, 0x100, &mut bytes).unwrap();
+    bytes
+}
+
+// Returns the assembled program before and after optimisation
+fn optimise_with_options<F: Fn(&mut Block)>(
+    options: &OptimiseOptions,
+    program: F,
+) -> (Vec<u8>, Vec<u8>, OptimisationReport) {
+    let mut b = Block::new();
+    program(&mut b);
+    let original = assemble(&b);
+    let report = b.optimise_with_options(options);
+    (original, assemble(&b), report)
+}
+
+fn optimise<F: Fn(&mut Block)>(program: F) -> (Vec<u8>, Vec<u8>, OptimisationReport) {
+    optimise_with_options(&OptimiseOptions::default(), program)
+}
+
+#[test]
+fn redundant_load_immediate() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Lda(Immediate), 5);
+        b.inst(Sta(ZeroPage), 0x10);
+        b.inst(Lda(Immediate), 5);
+        b.inst(Sta(ZeroPage), 0x11);
+    });
+    assert_eq!(&bytes[0..6], &[0xA9, 0x05, 0x85, 0x10, 0x85, 0x11]);
+    assert_eq!(
+        report.rule(Rule::RedundantLoadImmediate),
+        RuleReport {
+            applications: 1,
+            bytes_saved: 2,
+            cycles_saved: 2,
+        }
+    );
+}
+
+#[test]
+fn store_then_load() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Sta(ZeroPage), 0x10);
+        b.inst(Lda(ZeroPage), 0x10);
+        // overwrites the flags set by the load
+        b.inst(Ldx(Immediate), 0);
+    });
+    assert_eq!(&bytes[0..4], &[0x85, 0x10, 0xA2, 0x00]);
+    assert_eq!(report.rule(Rule::StoreThenLoad).applications, 1);
+}
+
+#[test]
+fn clc_adc_zero() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Clc, ());
+        b.inst(Adc(Immediate), 0);
+        // overwrite every flag set by the addition
+        b.inst(Lda(Immediate), 1);
+        b.inst(Clv, ());
+        b.inst(Sec, ());
+    });
+    assert_eq!(&bytes[0..4], &[0xA9, 0x01, 0xB8, 0x38]);
+    assert_eq!(
+        report.rule(Rule::ClcAdcZero),
+        RuleReport {
+            applications: 1,
+            bytes_saved: 3,
+            cycles_saved: 4,
+        }
+    );
+}
+
+#[test]
+fn jsr_rts_tail() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Jsr(Absolute), "f");
+        b.inst(Rts, ());
+        b.label("f");
+        b.inst(Rts, ());
+    });
+    // the label moves back to fill the gap left by the removed RTS
+    assert_eq!(&bytes[0..4], &[0x4C, 0x03, 0xC0, 0x60]);
+    assert_eq!(
+        report.rule(Rule::JsrRtsTail),
+        RuleReport {
+            applications: 1,
+            bytes_saved: 1,
+            cycles_saved: 9,
+        }
+    );
+}
+
+#[test]
+fn branch_to_branch() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Beq, LabelRelativeOffset("a"));
+        b.inst(Nop, ());
+        b.label("a");
+        b.inst(Jmp(Absolute), "b");
+        b.inst(Nop, ());
+        b.label("b");
+        b.inst(Nop, ());
+    });
+    assert_eq!(
+        &bytes[0..8],
+        &[0xF0, 0x05, 0xEA, 0x4C, 0x07, 0xC0, 0xEA, 0xEA]
+    );
+    assert_eq!(report.rule(Rule::BranchToBranch).applications, 1);
+}
+
+#[test]
+fn labels_are_barriers() {
+    let (original, bytes, report) = optimise(|b| {
+        b.inst(Lda(Immediate), 5);
+        b.label("x");
+        b.inst(Lda(Immediate), 5);
+        b.inst(Sta(ZeroPage), 0x10);
+        b.label("y");
+        b.inst(Lda(ZeroPage), 0x10);
+        b.inst(Ldx(Immediate), 0);
+    });
+    assert_eq!(bytes, original);
+    assert_eq!(report.bytes_saved(), 0);
+}
+
+#[test]
+fn flags_read_by_the_successor_are_preserved() {
+    let (original, bytes, report) = optimise(|b| {
+        b.inst(Sta(ZeroPage), 0x10);
+        // the zero flag set by this load is read by the branch
+        b.inst(Lda(ZeroPage), 0x10);
+        b.inst(Beq, LabelRelativeOffset("end"));
+        b.inst(Clc, ());
+        // the carry flag set by this addition is read by the branch
+        b.inst(Adc(Immediate), 0);
+        b.inst(Bcc, LabelRelativeOffset("end"));
+        b.label("end");
+        b.inst(Nop, ());
+    });
+    assert_eq!(bytes, original);
+    assert_eq!(report.bytes_saved(), 0);
+}
+
+#[test]
+fn literal_branch_offsets_are_updated() {
+    let (_, bytes, report) = optimise(|b| {
+        b.inst(Bne, 4i8);
+        b.inst(Lda(Immediate), 5);
+        b.inst(Lda(Immediate), 5);
+        b.inst(Nop, ());
+    });
+    assert_eq!(&bytes[0..5], &[0xD0, 0x02, 0xA9, 0x05, 0xEA]);
+    assert_eq!(report.rule(Rule::RedundantLoadImmediate).applications, 1);
+}
+
+#[test]
+fn literal_branch_targets_are_barriers() {
+    let (original, bytes, report) = optimise(|b| {
+        b.inst(Lda(Immediate), 5);
+        b.inst(Lda(Immediate), 5);
+        b.inst(Bne, -4i8);
+    });
+    assert_eq!(bytes, original);
+    assert_eq!(report.bytes_saved(), 0);
+}
+
+#[test]
+fn removals_which_move_a_branch_out_of_range_are_undone() {
+    for literal in [false, true] {
+        let (original, bytes, report) = optimise(|b| {
+            b.inst(Lda(Immediate), 5);
+            b.inst(Lda(Immediate), 5);
+            // the target is in a separate segment, so removing code before
+            // the branch moves it further away
+            if literal {
+                b.inst(Bne, 127i8);
+            } else {
+                b.inst(Bne, LabelRelativeOffset("far"));
+            }
+            b.set_offset(0x85);
+            b.label("far");
+            b.inst(Nop, ());
+        });
+        assert_eq!(bytes, original, "literal: {}", literal);
+        assert_eq!(report.bytes_saved(), 0);
+    }
+}
+
+#[test]
+fn branches_to_later_labels_are_relaxed_at_their_new_offsets() {
+    let mut b = Block::new();
+    b.inst(Lda(Immediate), 0);
+    b.inst(Lda(Immediate), 0);
+    b.if_(Beq, |b| {
+        // moves the conditional's branch back before its label is declared
+        b.optimise();
+        for _ in 0..70 {
+            b.inst(Inc(ZeroPage), 0x10);
+        }
+    })
+    .end_if();
+    b.inst(Nop, ());
+    let bytes = assemble(&b);
+    // lda #0; beq +3; jmp end-if; inc $10
+    assert_eq!(
+        &bytes[0..9],
+        &[0xA9, 0x00, 0xF0, 0x03, 0x4C, 0x93, 0xC0, 0xE6, 0x10]
+    );
+    assert_eq!(bytes[0x93], 0xEA);
+}
+
+#[test]
+fn retargeted_branches_are_relaxed_towards_their_new_label() {
+    let mut b = Block::new();
+    b.branch(Bne, "hop");
+    b.if_(Beq, |b| {
+        b.label("hop");
+        b.inst(Jmp(Absolute), "far");
+        for _ in 0..120 {
+            b.inst(Nop, ());
+        }
+        b.label("far");
+        // points the first branch straight at "far", which relaxing the
+        // conditional then moves out of its range
+        b.optimise();
+        for _ in 0..10 {
+            b.inst(Nop, ());
+        }
+    })
+    .end_if();
+    let mut bytes = Vec::new();
+    let assembled_block = b.assemble(BASE, 0x100, &mut bytes).unwrap();
+    let [lo, hi] = assembled_block
+        .address_of_label("far")
+        .unwrap()
+        .to_le_bytes();
+    // beq +3; jmp far
+    assert_eq!(&bytes[0..5], &[0xF0, 0x03, 0x4C, lo, hi]);
+}
+
+#[test]
+fn ram_end_is_configurable() {
+    let program = |b: &mut Block| {
+        b.inst(Sta(Absolute), Addr(0x0300));
+        b.inst(Lda(Absolute), Addr(0x0300));
+        b.inst(Ldx(Immediate), 0);
+    };
+    let (_, _, report) = optimise(program);
+    assert_eq!(report.rule(Rule::StoreThenLoad).applications, 1);
+    let (original, bytes, report) =
+        optimise_with_options(&OptimiseOptions { ram_end: 0x0200 }, program);
+    assert_eq!(bytes, original);
+    assert_eq!(report.rule(Rule::StoreThenLoad).applications, 0);
+    // with no RAM at all, even zero page accesses are left alone
+    let (_, _, report) = optimise_with_options(&OptimiseOptions { ram_end: 0 }, |b| {
+        b.inst(Sta(ZeroPage), 0x10);
+        b.inst(Lda(ZeroPage), 0x10);
+        b.inst(Ldx(Immediate), 0);
+    });
+    assert_eq!(report.rule(Rule::StoreThenLoad).applications, 0);
+}
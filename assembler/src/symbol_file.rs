@@ -0,0 +1,153 @@
+use mos6502_model::format::SymbolTable;
+use mos6502_model::Address;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+
+// The line of rust code which added an instruction to a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    // the path of the file relative to the root of the workspace it was built in
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line_number: usize,
+    pub line: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid symbol file line {}: \"{}\"",
+            self.line_number, self.line
+        )
+    }
+}
+
+// The addresses of labels and the source line of each instruction of an
+// assembled block, for debuggers. In text form each line is either
+// "label ADDR NAME" or "line ADDR LINE FILE", with addresses in hex, e.g.
+//   label C010 factorial
+//   line C010 51 samples/src/factorial.rs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolFile {
+    labels: BTreeMap<String, Address>,
+    source_lines: BTreeMap<Address, SourceLine>,
+}
+
+// Generated and anonymous labels are only used for a name when there are no
+// others at an address
+fn is_generated_label(label: &str) -> bool {
+    label.starts_with(':') || label.contains('~')
+}
+
+impl SymbolFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn insert_label(&mut self, label: String, address: Address) {
+        self.labels.insert(label, address);
+    }
+    pub fn insert_source_line(&mut self, address: Address, source_line: SourceLine) {
+        self.source_lines.insert(address, source_line);
+    }
+    pub fn address_of_label(&self, label: &str) -> Option<Address> {
+        self.labels.get(label).cloned()
+    }
+    pub fn labels(&self) -> impl Iterator<Item = (&str, Address)> {
+        self.labels
+            .iter()
+            .map(|(label, &address)| (label.as_str(), address))
+    }
+    // The shortest label at an address, preferring labels written by hand
+    pub fn label_at(&self, address: Address) -> Option<&str> {
+        self.labels()
+            .filter(|&(_, label_address)| label_address == address)
+            .map(|(label, _)| label)
+            .min_by_key(|label| (is_generated_label(label), label.len()))
+    }
+    pub fn source_line(&self, address: Address) -> Option<&SourceLine> {
+        self.source_lines.get(&address)
+    }
+    pub fn source_lines(&self) -> impl Iterator<Item = (Address, &SourceLine)> {
+        self.source_lines
+            .iter()
+            .map(|(&address, source_line)| (address, source_line))
+    }
+    // The address of the first instruction of each run of instructions added
+    // by a line of a file. Debuggers tend to use absolute paths, so a path
+    // matches the file of a source line if either ends with the other, comparing
+    // whole path components so "src/lib.rs" doesn't match "mysrc/lib.rs".
+    pub fn addresses_of_line(&self, path: &str, line: u32) -> Vec<Address> {
+        let mut previous = None;
+        let mut addresses = Vec::new();
+        let path = Path::new(path);
+        for (address, source_line) in self.source_lines() {
+            let file = Path::new(&source_line.file);
+            let is_match =
+                source_line.line == line && (path.ends_with(file) || file.ends_with(path));
+            if is_match && previous != Some(source_line) {
+                addresses.push(address);
+            }
+            previous = Some(source_line);
+        }
+        addresses
+    }
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        let mut symbol_file = Self::new();
+        for (index, line) in s.lines().enumerate() {
+            let error = || ParseError {
+                line_number: index + 1,
+                line: line.to_string(),
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.splitn(3, ' ');
+            let kind = fields.next().ok_or_else(error)?;
+            let address = fields
+                .next()
+                .and_then(|address| Address::from_str_radix(address, 16).ok())
+                .ok_or_else(error)?;
+            let rest = fields.next().ok_or_else(error)?;
+            match kind {
+                "label" => symbol_file.insert_label(rest.to_string(), address),
+                "line" => {
+                    let (line_number, file) = rest.split_once(' ').ok_or_else(error)?;
+                    let source_line = SourceLine {
+                        file: file.to_string(),
+                        line: line_number.parse().map_err(|_| error())?,
+                    };
+                    symbol_file.insert_source_line(address, source_line);
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(symbol_file)
+    }
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (label, address) in self.labels() {
+            writeln!(writer, "label {:04X} {}", address, label)?;
+        }
+        for (address, source_line) in self.source_lines() {
+            writeln!(
+                writer,
+                "line {:04X} {} {}",
+                address, source_line.line, source_line.file
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SymbolTable for SymbolFile {
+    fn symbol(&self, address: Address) -> Option<String> {
+        self.label_at(address).map(|label| label.to_string())
+    }
+}
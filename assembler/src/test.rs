@@ -0,0 +1,445 @@
+use crate::*;
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::machine::{Cpu, MemoryReadOnly};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+
+const BASE: Address = 0xC000;
+
+fn assemble(block: &Block) -> Result<(Vec<u8>, AssembledBlock), Error> {
+    let mut bytes = Vec::new();
+    let assembled_block = block.assemble(BASE, 0x100, &mut bytes)?;
+    Ok((bytes, assembled_block))
+}
+
+#[test]
+fn local_labels_are_resolved_in_the_innermost_scope() {
+    let mut b = Block::new();
+    b.label("@x");
+    b.inst(Nop, ());
+    b.scope("outer", |b| {
+        b.label("@x");
+        b.inst(Nop, ());
+        b.scope("inner", |b| {
+            b.label("@x");
+            b.inst(Jmp(Absolute), "@x");
+            // declared only in the outer scope
+            b.inst(Jmp(Absolute), "@y");
+        });
+        b.inst(Jmp(Absolute), "@x");
+        b.label("@y");
+    });
+    b.inst(Jmp(Absolute), "@x");
+    let (bytes, assembled_block) = assemble(&b).unwrap();
+    assert_eq!(assembled_block.address_of_label("@x"), Some(0xC000));
+    assert_eq!(assembled_block.address_of_label("outer::@x"), Some(0xC001));
+    assert_eq!(
+        assembled_block.address_of_label("outer::inner::@x"),
+        Some(0xC002)
+    );
+    assert_eq!(assembled_block.address_of_label("outer::@y"), Some(0xC00B));
+    assert_eq!(
+        &bytes[2..14],
+        &[0x4C, 0x02, 0xC0, 0x4C, 0x0B, 0xC0, 0x4C, 0x01, 0xC0, 0x4C, 0x00, 0xC0]
+    );
+}
+
+#[test]
+fn reentering_a_scope_creates_a_fresh_scope() {
+    fn delay(b: &mut Block) {
+        b.scope("delay", |b| {
+            b.label("@loop");
+            b.inst(Dex, ());
+            b.inst(Bne, LabelRelativeOffset("@loop"));
+        });
+    }
+    let mut b = Block::new();
+    delay(&mut b);
+    delay(&mut b);
+    let (bytes, assembled_block) = assemble(&b).unwrap();
+    assert_eq!(
+        assembled_block.address_of_label("delay::@loop"),
+        Some(0xC000)
+    );
+    assert_eq!(
+        assembled_block.address_of_label("delay#1::@loop"),
+        Some(0xC003)
+    );
+    // each branch goes back to the start of its own expansion
+    assert_eq!(&bytes[0..6], &[0xCA, 0xD0, 0xFD, 0xCA, 0xD0, 0xFD]);
+}
+
+#[test]
+fn anonymous_labels() {
+    let mut b = Block::new();
+    b.anonymous_label();
+    b.inst(Nop, ());
+    b.anonymous_label();
+    b.inst(Jmp(Absolute), "-");
+    b.inst(Jmp(Absolute), "--");
+    b.inst(Jmp(Absolute), "+");
+    b.inst(Jmp(Absolute), "++");
+    b.anonymous_label();
+    b.inst(Nop, ());
+    b.anonymous_label();
+    let (bytes, _) = assemble(&b).unwrap();
+    assert_eq!(
+        &bytes[1..13],
+        &[0x4C, 0x01, 0xC0, 0x4C, 0x00, 0xC0, 0x4C, 0x0D, 0xC0, 0x4C, 0x0E, 0xC0]
+    );
+}
+
+#[test]
+fn duplicate_label() {
+    let mut b = Block::new();
+    b.label("start");
+    b.inst(Nop, ());
+    b.label("start");
+    assert!(matches!(assemble(&b), Err(Error::DuplicateLabel(label)) if label == "start"));
+}
+
+#[test]
+fn duplicate_local_label_in_one_scope() {
+    let mut b = Block::new();
+    b.scope("f", |b| {
+        b.label("@x");
+        b.inst(Nop, ());
+        b.label("@x");
+    });
+    assert!(matches!(assemble(&b), Err(Error::DuplicateLabel(label)) if label == "f::@x"));
+}
+
+#[test]
+fn same_local_label_in_different_scopes_is_not_a_duplicate() {
+    let mut b = Block::new();
+    b.scope("f", |b| b.label("@x"));
+    b.scope("g", |b| b.label("@x"));
+    b.label("@x");
+    assert!(assemble(&b).is_ok());
+}
+
+#[test]
+fn local_label_is_not_visible_outside_its_scope() {
+    let mut b = Block::new();
+    b.scope("f", |b| {
+        b.label("@x");
+        b.inst(Nop, ());
+    });
+    b.inst(Jmp(Absolute), "@x");
+    assert!(matches!(assemble(&b), Err(Error::UndeclaredLabel(label)) if label == "@x"));
+}
+
+// Runs a block assembled at `BASE` on a bare CPU, from the start of the block
+// until it reaches the label "end"
+fn run(block: &Block) -> FlatMemory {
+    let mut bytes = Vec::new();
+    let assembled_block = block.assemble(BASE, 0x1000, &mut bytes).unwrap();
+    let end = assembled_block.address_of_label("end").unwrap();
+    let mut memory = FlatMemory::new();
+    memory.load(BASE, &bytes);
+    let mut cpu = Cpu::new();
+    cpu.pc = BASE;
+    for _ in 0..100_000 {
+        if cpu.pc == end {
+            return memory;
+        }
+        cpu.step(&mut memory).unwrap();
+    }
+    panic!("didn't reach the end of the program");
+}
+
+// A body which is too long to branch over
+fn increment_many_times(b: &mut Block, address: u8) {
+    for _ in 0..70 {
+        b.inst(Inc(ZeroPage), address);
+    }
+}
+
+#[test]
+fn if_with_long_body() {
+    for value in [0, 1] {
+        let mut b = Block::new();
+        b.inst(Lda(Immediate), value);
+        b.if_(Beq, |b| increment_many_times(b, 0x10)).end_if();
+        b.inst(Inc(ZeroPage), 0x11);
+        b.label("end");
+        let memory = run(&b);
+        let expected = if value == 0 { 70 } else { 0 };
+        assert_eq!(memory.read_u8_read_only(0x10), expected);
+        assert_eq!(memory.read_u8_read_only(0x11), 1);
+    }
+}
+
+#[test]
+fn if_with_short_body_uses_a_relative_branch() {
+    let mut b = Block::new();
+    b.inst(Lda(Immediate), 0);
+    b.if_(Beq, |b| b.inst(Inc(ZeroPage), 0x10)).end_if();
+    let (bytes, _) = assemble(&b).unwrap();
+    // lda #0; bne +2; inc $10
+    assert_eq!(&bytes[0..6], &[0xA9, 0x00, 0xD0, 0x02, 0xE6, 0x10]);
+}
+
+#[test]
+fn if_else_with_long_bodies() {
+    for value in [0, 1] {
+        let mut b = Block::new();
+        b.inst(Lda(Immediate), value);
+        b.if_(Beq, |b| increment_many_times(b, 0x10))
+            .else_(|b| increment_many_times(b, 0x11));
+        b.label("end");
+        let memory = run(&b);
+        let (then_count, else_count) = if value == 0 { (70, 0) } else { (0, 70) };
+        assert_eq!(memory.read_u8_read_only(0x10), then_count);
+        assert_eq!(memory.read_u8_read_only(0x11), else_count);
+    }
+}
+
+#[test]
+fn nested_if_else_with_long_bodies() {
+    for (x, y) in [(0, 0), (0, 1), (1, 0), (1, 1)] {
+        let mut b = Block::new();
+        b.inst(Ldx(Immediate), x);
+        b.inst(Ldy(Immediate), y);
+        b.inst(Cpx(Immediate), 0);
+        b.if_(Beq, |b| {
+            b.inst(Cpy(Immediate), 0);
+            b.if_(Beq, |b| increment_many_times(b, 0x10))
+                .else_(|b| increment_many_times(b, 0x11));
+            increment_many_times(b, 0x12);
+        })
+        .else_(|b| {
+            b.inst(Cpy(Immediate), 0);
+            b.if_(Beq, |b| increment_many_times(b, 0x13)).end_if();
+            increment_many_times(b, 0x14);
+        });
+        b.label("end");
+        let memory = run(&b);
+        let counts: Vec<u8> = (0x10..=0x14)
+            .map(|address| memory.read_u8_read_only(address))
+            .collect();
+        let expected = match (x, y) {
+            (0, 0) => [70, 0, 70, 0, 0],
+            (0, _) => [0, 70, 70, 0, 0],
+            (_, 0) => [0, 0, 0, 70, 70],
+            _ => [0, 0, 0, 0, 70],
+        };
+        assert_eq!(counts, expected, "x = {}, y = {}", x, y);
+    }
+}
+
+#[test]
+fn loop_while_with_long_body() {
+    let mut b = Block::new();
+    b.inst(Ldy(Immediate), 3);
+    b.loop_while(Bne, |b| {
+        increment_many_times(b, 0x10);
+        b.inst(Dey, ());
+    });
+    b.label("end");
+    let memory = run(&b);
+    assert_eq!(memory.read_u8_read_only(0x10), 210);
+}
+
+#[test]
+fn relaxation_moves_labels_and_literal_offsets() {
+    let mut b = Block::new();
+    // points at the start of the conditional's body, before it's moved
+    b.literal_offset_le(6);
+    b.inst(Lda(Immediate), 1);
+    b.if_(Beq, |b| {
+        b.label("inside");
+        increment_many_times(b, 0x10);
+    })
+    .end_if();
+    b.label("after");
+    let (bytes, assembled_block) = assemble(&b).unwrap();
+    // lda #1; beq +3; jmp after
+    assert_eq!(&bytes[2..9], &[0xA9, 0x01, 0xF0, 0x03, 0x4C, 0x95, 0xC0]);
+    assert_eq!(assembled_block.address_of_label("inside"), Some(0xC009));
+    assert_eq!(assembled_block.address_of_label("after"), Some(0xC095));
+    assert_eq!(&bytes[0..2], &[0x09, 0xC0]);
+}
+
+#[test]
+fn backward_branch_pushed_out_of_range_by_relaxation_is_relaxed() {
+    let mut b = Block::new();
+    b.inst(Ldx(Immediate), 3);
+    b.label("top");
+    b.if_(Bne, |b| {
+        for _ in 0..61 {
+            b.inst(Inc(ZeroPage), 0x10);
+        }
+        b.inst(Dex, ());
+        // reaches "top" until the conditional around it is relaxed
+        b.branch(Bne, "top");
+        increment_many_times(b, 0x11);
+    })
+    .end_if();
+    b.label("end");
+    let memory = run(&b);
+    assert_eq!(memory.read_u8_read_only(0x10), 183);
+    assert_eq!(memory.read_u8_read_only(0x11), 70);
+}
+
+#[test]
+fn relaxation_repeats_until_every_branch_is_in_range() {
+    let mut b = Block::new();
+    b.label("inner");
+    b.if_(Bne, |b| {
+        b.label("outer");
+        for _ in 0..60 {
+            b.inst(Inc(ZeroPage), 0x10);
+        }
+        b.inst(Clc, ());
+        b.inst(Dex, ());
+        // pushed out of range by relaxing the conditional
+        b.branch(Bne, "inner");
+        // only pushed out of range by relaxing the branch to "inner"
+        b.branch(Bcs, "outer");
+        for _ in 0..40 {
+            b.inst(Inc(ZeroPage), 0x11);
+        }
+    })
+    .end_if();
+    let (bytes, assembled_block) = assemble(&b).unwrap();
+    for label in ["inner", "outer"] {
+        let [lo, hi] = assembled_block
+            .address_of_label(label)
+            .unwrap()
+            .to_le_bytes();
+        assert!(
+            bytes.windows(3).any(|window| window == [0x4C, lo, hi]),
+            "no jump to {}",
+            label
+        );
+    }
+}
+
+#[test]
+fn branch_is_not_relaxed_over_data_placed_after_the_cursor() {
+    let mut b = Block::new();
+    b.set_offset(0x90);
+    b.literal_byte(0xFF);
+    b.set_offset(0);
+    b.inst(Lda(Immediate), 1);
+    b.if_(Beq, |b| increment_many_times(b, 0x10)).end_if();
+    assert!(matches!(
+        assemble(&b),
+        Err(Error::BranchTargetOutOfRange(_))
+    ));
+}
+
+#[test]
+#[should_panic]
+fn dropping_an_unfinished_if_panics() {
+    let mut b = Block::new();
+    let _ = b.if_(Beq, |b| b.inst(Nop, ()));
+}
+
+#[test]
+fn source_lines_follow_relaxed_branches() {
+    let mut b = Block::new();
+    let lda_line = line!() + 1;
+    b.inst(Lda(Immediate), 1);
+    let if_line = line!() + 1;
+    b.if_(Beq, |b| {
+        for _ in 0..70 {
+            b.inst(Inc(ZeroPage), 0x10);
+        }
+    })
+    .end_if();
+    let if_body_line = if_line + 2;
+    let for_x_line = line!() + 1;
+    b.for_x(0, 4, |b| {
+        for _ in 0..70 {
+            b.inst(Inc(ZeroPage), 0x11);
+        }
+    });
+    let for_x_body_line = for_x_line + 2;
+    let branch_line = line!() + 1;
+    b.branch(Bne, "end");
+    let branch_body_line = line!() + 2;
+    for _ in 0..70 {
+        b.inst(Inc(ZeroPage), 0x12);
+    }
+    b.label("end");
+    let end_line = line!() + 1;
+    b.inst(Nop, ());
+    let mut bytes = Vec::new();
+    let symbol_file = b.assemble(BASE, 0x1000, &mut bytes).unwrap().symbol_file();
+    let lines_at = |addresses: &[Address]| {
+        addresses
+            .iter()
+            .map(|&address| symbol_file.source_line(address).unwrap().line)
+            .collect::<Vec<_>>()
+    };
+    // lda #1; bne +3; jmp; 70 x inc $10
+    assert_eq!(
+        lines_at(&[0xC000, 0xC002, 0xC004, 0xC007, 0xC091]),
+        [lda_line, if_line, if_line, if_body_line, if_body_line]
+    );
+    // ldx #0; 70 x inc $11; inx; cpx #4; beq +3; jmp
+    assert_eq!(
+        lines_at(&[0xC093, 0xC095, 0xC11F, 0xC121, 0xC122, 0xC124, 0xC126]),
+        [
+            for_x_line,
+            for_x_body_line,
+            for_x_body_line,
+            for_x_line,
+            for_x_line,
+            for_x_line,
+            for_x_line
+        ]
+    );
+    // beq +3; jmp end; 70 x inc $12; nop
+    assert_eq!(
+        lines_at(&[0xC129, 0xC12B, 0xC12E, 0xC1B8, 0xC1BA]),
+        [
+            branch_line,
+            branch_line,
+            branch_body_line,
+            branch_body_line,
+            end_line
+        ]
+    );
+    assert_eq!(symbol_file.address_of_label("end"), Some(0xC1BA));
+    assert_eq!(
+        symbol_file.addresses_of_line(file!(), for_x_line),
+        [0xC093, 0xC121]
+    );
+    assert_eq!(symbol_file.addresses_of_line(file!(), if_line), [0xC002]);
+}
+
+#[test]
+fn addresses_of_line_matches_whole_path_components() {
+    let mut symbol_file = SymbolFile::new();
+    let source_line = SourceLine {
+        file: "samples/src/lib.rs".to_string(),
+        line: 10,
+    };
+    symbol_file.insert_source_line(0xC000, source_line);
+    for path in [
+        "/home/user/project/samples/src/lib.rs",
+        "samples/src/lib.rs",
+        "src/lib.rs",
+    ] {
+        assert_eq!(
+            symbol_file.addresses_of_line(path, 10),
+            [0xC000],
+            "{}",
+            path
+        );
+    }
+    for path in [
+        "/home/user/project/mysamples/src/lib.rs",
+        "rc/lib.rs",
+        "b.rs",
+    ] {
+        assert!(
+            symbol_file.addresses_of_line(path, 10).is_empty(),
+            "{}",
+            path
+        );
+    }
+    assert!(symbol_file.addresses_of_line("src/lib.rs", 11).is_empty());
+}
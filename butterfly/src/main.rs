@@ -1,7 +1,10 @@
 use ines::Ines;
-use mos6502_assembler::{Addr, Block, LabelRelativeOffset, LabelRelativeOffsetOwned};
+use mos6502_assembler::{Addr, Block, LabelRelativeOffset};
 use mos6502_model::{interrupt_vector, Address};
 
+#[cfg(test)]
+mod test;
+
 pub const PRG_START: Address = 0xC000;
 pub const INTERRUPT_VECTOR_START_PC_OFFSET: Address = interrupt_vector::START_LO - PRG_START;
 pub const INTERRUPT_VECTOR_NMI_OFFSET: Address = interrupt_vector::NMI_LO - PRG_START;
@@ -84,7 +87,7 @@ fn test_image_bits(image: &[&str]) -> Vec<u8> {
                 bits.push(byte);
                 byte = 0;
             } else {
-                byte = byte >> 1;
+                byte >>= 1;
             }
         }
     }
@@ -115,13 +118,13 @@ fn program(b: &mut Block) {
 
     // wait for 2 vblanks to occur to make sure ppu has stabilized
 
-    b.label("vblankwait1");
-    b.inst(Bit(Absolute), Addr(0x2002));
-    b.inst(Bpl, LabelRelativeOffset("vblankwait1"));
+    b.loop_while(Bpl, |b| {
+        b.inst(Bit(Absolute), Addr(0x2002));
+    });
 
-    b.label("vblankwait2");
-    b.inst(Bit(Absolute), Addr(0x2002));
-    b.inst(Bpl, LabelRelativeOffset("vblankwait2"));
+    b.loop_while(Bpl, |b| {
+        b.inst(Bit(Absolute), Addr(0x2002));
+    });
 
     // set up palette
     let universal_background = 0x2C;
@@ -259,92 +262,82 @@ fn program(b: &mut Block) {
     b.inst(Sta(Absolute), Addr(0x2005));
     b.inst(Sta(Absolute), Addr(0x2005)); // fix scroll
 
-    fn enqueue_delta(b: &mut Block, from: u16, to: u16, prefix: &str) {
-        b.inst(Lda(Immediate), 0xFB);
-        b.inst(Sta(ZeroPage), 0); // clear update buffer
+    fn enqueue_delta(b: &mut Block, from: u16, to: u16) {
+        b.scope("enqueue-delta", |b| {
+            b.inst(Lda(Immediate), 0xFB);
+            b.inst(Sta(ZeroPage), 0); // clear update buffer
 
-        // write new draw queue by diffing previous and current images
-        b.inst(Ldx(Immediate), 0);
-        b.inst(Ldy(Immediate), 0);
+            // write new draw queue by diffing previous and current images
+            b.inst(Ldx(Immediate), 0);
+            b.inst(Ldy(Immediate), 0);
 
-        b.inst(Stx(ZeroPage), 255); // not currently in a run
-        b.inst(Stx(ZeroPage), 254); // MSB of count is always 0, but needed to form address
+            b.inst(Stx(ZeroPage), 255); // not currently in a run
+            b.inst(Stx(ZeroPage), 254); // MSB of count is always 0, but needed to form address
 
-        b.label(format!("{}-diff-start", prefix));
-        b.inst(Txa, ());
-        b.inst(Cmp(Immediate), 120);
-        b.inst(
-            Beq,
-            LabelRelativeOffsetOwned(format!("{}-diff-end", prefix)),
-        );
+            b.label("@diff-start");
+            b.inst(Txa, ());
+            b.inst(Cmp(Immediate), 120);
+            b.inst(Beq, LabelRelativeOffset("@diff-end"));
 
-        b.inst(Lda(AbsoluteXIndexed), Addr(from));
-        b.inst(Eor(AbsoluteXIndexed), Addr(to));
+            b.inst(Lda(AbsoluteXIndexed), Addr(from));
+            b.inst(Eor(AbsoluteXIndexed), Addr(to));
 
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-add-diff-to-draw-queue", prefix)),
-        );
+            b.inst(Bne, LabelRelativeOffset("@add-diff-to-draw-queue"));
 
-        b.inst(Sta(ZeroPage), 255); // we know A is zero - no longer in a run
-        b.inst(Inx, ());
-        b.inst(Jmp(Absolute), format!("{}-diff-start", prefix));
+            b.inst(Sta(ZeroPage), 255); // we know A is zero - no longer in a run
+            b.inst(Inx, ());
+            b.inst(Jmp(Absolute), "@diff-start");
 
-        b.label(format!("{}-add-diff-to-draw-queue", prefix));
+            b.label("@add-diff-to-draw-queue");
 
-        b.inst(Lda(ZeroPage), 255);
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-increment-counter-append-byte", prefix)),
-        );
+            b.inst(Lda(ZeroPage), 255);
+            b.inst(Bne, LabelRelativeOffset("@increment-counter-append-byte"));
 
-        b.inst(Tya, ());
-        b.inst(
-            Beq,
-            LabelRelativeOffsetOwned(format!("{}-append-offset", prefix)),
-        );
+            b.inst(Tya, ());
+            b.inst(Beq, LabelRelativeOffset("@append-offset"));
 
-        b.inst(Lda(ZeroPage), 253);
-        b.inst(Sta(AbsoluteYIndexed), Addr(0)); // store the previous counter value at Y
+            b.inst(Lda(ZeroPage), 253);
+            b.inst(Sta(AbsoluteYIndexed), Addr(0)); // store the previous counter value at Y
 
-        b.inst(Tya, ());
-        b.inst(Clc, ());
-        b.inst(Adc(ZeroPage), 253);
-        b.inst(Tay, ());
-        b.inst(Iny, ()); // Y now points where the offset will go
+            b.inst(Tya, ());
+            b.inst(Clc, ());
+            b.inst(Adc(ZeroPage), 253);
+            b.inst(Tay, ());
+            b.inst(Iny, ()); // Y now points where the offset will go
 
-        b.label(format!("{}-append-offset", prefix));
+            b.label("@append-offset");
 
-        b.inst(Stx(ZeroPageYIndexed), 0); // X contains the current offset
-        b.inst(Iny, ()); // Y now points where the length will go
+            b.inst(Stx(ZeroPageYIndexed), 0); // X contains the current offset
+            b.inst(Iny, ()); // Y now points where the length will go
 
-        b.inst(Lda(Immediate), 0);
-        b.inst(Sta(ZeroPage), 253); // clear current count LBS (MSB is always clear)
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), 253); // clear current count LBS (MSB is always clear)
 
-        b.label(format!("{}-increment-counter-append-byte", prefix));
+            b.label("@increment-counter-append-byte");
 
-        b.inst(Inc(ZeroPage), 253); // increment counter
-        b.inst(Lda(AbsoluteXIndexed), Addr(to)); // load byte from current image
-        b.inst(Sta(IndirectYIndexed), 253); // store at *(253) + Y
+            b.inst(Inc(ZeroPage), 253); // increment counter
+            b.inst(Lda(AbsoluteXIndexed), Addr(to)); // load byte from current image
+            b.inst(Sta(IndirectYIndexed), 253); // store at *(253) + Y
 
-        b.inst(Inx, ());
-        b.inst(Stx(ZeroPage), 255); // X can't be 0 at this point. Set flag to non-zero value.
+            b.inst(Inx, ());
+            b.inst(Stx(ZeroPage), 255); // X can't be 0 at this point. Set flag to non-zero value.
 
-        b.inst(Jmp(Absolute), format!("{}-diff-start", prefix));
-        b.label(format!("{}-diff-end", prefix));
+            b.inst(Jmp(Absolute), "@diff-start");
+            b.label("@diff-end");
 
-        b.inst(Tya, ());
-        b.inst(Tax, ());
-        b.inst(Lda(ZeroPage), 253);
-        b.inst(Sta(ZeroPageXIndexed), 0); // store the previous counter value at Y (X is copied from Y)
+            b.inst(Tya, ());
+            b.inst(Tax, ());
+            b.inst(Lda(ZeroPage), 253);
+            b.inst(Sta(ZeroPageXIndexed), 0); // store the previous counter value at Y (X is copied from Y)
 
-        b.inst(Txa, ());
-        b.inst(Clc, ());
-        b.inst(Adc(ZeroPage), 253);
-        b.inst(Tax, ());
-        b.inst(Inx, ()); // X now points where the terminator will go
-        b.inst(Lda(Immediate), 0xFC);
-        b.inst(Sta(ZeroPageXIndexed), 0);
+            b.inst(Txa, ());
+            b.inst(Clc, ());
+            b.inst(Adc(ZeroPage), 253);
+            b.inst(Tax, ());
+            b.inst(Inx, ()); // X now points where the terminator will go
+            b.inst(Lda(Immediate), 0xFC);
+            b.inst(Sta(ZeroPageXIndexed), 0);
+        });
     }
 
     b.inst(Lda(ZeroPage), 252);
@@ -352,11 +345,11 @@ fn program(b: &mut Block) {
     b.inst(Sta(ZeroPage), 252);
     b.inst(Beq, LabelRelativeOffset("enqueue-delta-b-to-a"));
 
-    enqueue_delta(b, 0x0200, 0x0280, "A");
+    enqueue_delta(b, 0x0200, 0x0280);
     b.inst(Jmp(Absolute), "post-enqueue-delta");
 
     b.label("enqueue-delta-b-to-a");
-    enqueue_delta(b, 0x0280, 0x0200, "B");
+    enqueue_delta(b, 0x0280, 0x0200);
 
     b.label("post-enqueue-delta");
 
@@ -397,9 +390,7 @@ fn chr_rom() -> Vec<u8> {
     chr_rom
 }
 
-fn prg_rom() -> Vec<u8> {
-    let mut block = Block::new();
-    program(&mut block);
+fn prg_rom(block: &Block) -> Vec<u8> {
     let mut prg_rom = Vec::new();
     block
         .assemble(PRG_START, ines::PRG_ROM_BLOCK_BYTES, &mut prg_rom)
@@ -407,9 +398,8 @@ fn prg_rom() -> Vec<u8> {
     prg_rom
 }
 
-fn main() {
-    use std::io::Write;
-    let ines = Ines {
+fn ines(block: &Block) -> Ines {
+    Ines {
         header: ines::Header {
             num_prg_rom_blocks: 1,
             num_chr_rom_blocks: 1,
@@ -417,9 +407,16 @@ fn main() {
             mirroring: ines::Mirroring::Vertical,
             four_screen_vram: false,
         },
-        prg_rom: prg_rom(),
+        prg_rom: prg_rom(block),
         chr_rom: chr_rom(),
-    };
+    }
+}
+
+fn main() {
+    use std::io::Write;
+    let mut block = Block::new();
+    program(&mut block);
+    let ines = ines(&block);
     let mut encoded = Vec::new();
     ines.encode(&mut encoded);
     std::io::stdout()
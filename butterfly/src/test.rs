@@ -0,0 +1,46 @@
+use crate::*;
+use nes_emulator_core::dynamic_nes::DynamicNes;
+use nes_headless_frame::Frame;
+
+// Renders the first `num_frames` frames of a rom
+fn run_frames(ines: &Ines, num_frames: usize) -> Vec<Frame> {
+    let mut nes = DynamicNes::from_ines(ines).unwrap();
+    (0..num_frames)
+        .map(|_| {
+            let mut frame = Frame::new();
+            nes.run_for_frame(&mut frame);
+            frame
+        })
+        .collect()
+}
+
+#[test]
+fn optimised_rom_renders_the_same_frames() {
+    const NUM_FRAMES: usize = 120;
+    let mut block = Block::new();
+    program(&mut block);
+    let frames = run_frames(&ines(&block), NUM_FRAMES);
+    let report = block.optimise();
+    assert!(report.bytes_saved() > 0, "{}", report);
+    let optimised_frames = run_frames(&ines(&block), NUM_FRAMES);
+    let first_difference = (0..NUM_FRAMES).find(|&i| frames[i] != optimised_frames[i]);
+    assert_eq!(first_difference, None);
+}
+
+// rom-to-dsl-round-trip's tests disassemble test-assets/butterfly.nes in place of this rom
+#[test]
+fn rom_matches_test_asset() {
+    let mut block = Block::new();
+    program(&mut block);
+    let mut encoded = Vec::new();
+    ines(&block).encode(&mut encoded);
+    let test_asset = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../test-assets/butterfly.nes"
+    ))
+    .unwrap();
+    assert!(
+        encoded == test_asset,
+        "test-assets/butterfly.nes is out of date; regenerate it with `cargo run -p butterfly`"
+    );
+}
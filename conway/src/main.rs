@@ -2,6 +2,9 @@ use ines::Ines;
 use mos6502_assembler::{Addr, Block, LabelRelativeOffset, LabelRelativeOffsetOwned};
 use mos6502_model::{address, interrupt_vector, Address};
 
+#[cfg(test)]
+mod test;
+
 const PRG_START: Address = 0xC000;
 const INTERRUPT_VECTOR_START_PC_OFFSET: Address = interrupt_vector::START_LO - PRG_START;
 const INTERRUPT_VECTOR_NMI_OFFSET: Address = interrupt_vector::NMI_LO - PRG_START;
@@ -32,13 +35,13 @@ fn program(b: &mut Block) {
 
     // wait for 2 vblanks to occur to make sure ppu has stabilized
 
-    b.label("vblankwait1");
-    b.inst(Bit(Absolute), Addr(0x2002));
-    b.inst(Bpl, LabelRelativeOffset("vblankwait1"));
+    b.loop_while(Bpl, |b| {
+        b.inst(Bit(Absolute), Addr(0x2002));
+    });
 
-    b.label("vblankwait2");
-    b.inst(Bit(Absolute), Addr(0x2002));
-    b.inst(Bpl, LabelRelativeOffset("vblankwait2"));
+    b.loop_while(Bpl, |b| {
+        b.inst(Bit(Absolute), Addr(0x2002));
+    });
 
     // set up palette
     let universal_background = 0x10;
@@ -168,303 +171,268 @@ fn program(b: &mut Block) {
     b.inst(Sta(Absolute), Addr(0x2005));
     b.inst(Sta(Absolute), Addr(0x2005)); // fix scroll
 
-    fn conway_update(b: &mut Block, from: u16, to: u16, prefix: &str) {
-        b.inst(Lda(Immediate), address::lo(OFFSET_TABLE_START));
-        b.inst(Sta(ZeroPage), 254);
-        b.inst(Lda(Immediate), address::hi(OFFSET_TABLE_START));
-        b.inst(Sta(ZeroPage), 255); // store offset table address at top of zero page
+    fn conway_update(b: &mut Block, from: u16, to: u16) {
+        b.scope("conway-update", |b| {
+            b.inst(Lda(Immediate), address::lo(OFFSET_TABLE_START));
+            b.inst(Sta(ZeroPage), 254);
+            b.inst(Lda(Immediate), address::hi(OFFSET_TABLE_START));
+            b.inst(Sta(ZeroPage), 255); // store offset table address at top of zero page
 
-        b.inst(Lda(Immediate), 0);
-        b.inst(Sta(ZeroPage), 253); // 253 will be offset into state
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), 253); // 253 will be offset into state
 
-        b.label(format!("{}-life-update-start", prefix));
-        b.inst(Lda(ZeroPage), 253);
-        b.inst(Cmp(Immediate), 120);
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-skip-life-update-end", prefix)),
-        );
+            b.label("@life-update-start");
+            b.inst(Lda(ZeroPage), 253);
+            b.inst(Cmp(Immediate), 120);
+            b.inst(Bne, LabelRelativeOffset("@skip-life-update-end"));
 
-        b.inst(Jmp(Absolute), format!("{}-life-update-end", prefix));
+            b.inst(Jmp(Absolute), "@life-update-end");
 
-        b.label(format!("{}-skip-life-update-end", prefix));
+            b.label("@skip-life-update-end");
 
-        // zero-out counters
-        b.inst(Lda(Immediate), 0);
-        for i in 0..8 {
-            b.inst(Sta(ZeroPage), i);
-        }
-
-        b.inst(Ldy(Immediate), 0); // Y will be offset into offset table
-
-        // top
-        b.inst(Lda(IndirectYIndexed), 254);
-        b.inst(Iny, ());
-        b.inst(Tax, ());
-        b.inst(Lda(AbsoluteXIndexed), from);
-        b.inst(Tax, ()); // backup in X
-
-        for i in 0..=7 {
-            if i != 0 {
-                // no point copying when i == 0 as A will already contain value
-                b.inst(Txa, ());
-            }
-            b.inst(And(Immediate), 1 << i);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-skip-top-{}", prefix, i)),
-            );
-            if i != 0 {
-                b.inst(Inc(ZeroPage), i - 1);
+            // zero-out counters
+            b.inst(Lda(Immediate), 0);
+            for i in 0..8 {
+                b.inst(Sta(ZeroPage), i);
             }
-            b.inst(Inc(ZeroPage), i);
-            if i != 7 {
-                b.inst(Inc(ZeroPage), i + 1);
-            }
-            b.label(format!("{}-skip-top-{}", prefix, i));
-        }
 
-        // bottom
-        b.inst(Lda(IndirectYIndexed), 254);
-        b.inst(Iny, ());
-        b.inst(Tax, ());
-        b.inst(Lda(AbsoluteXIndexed), from);
-        b.inst(Tax, ()); // backup in X
-
-        for i in 0..=7 {
-            if i != 0 {
-                // no point copying when i == 0 as A will already contain value
-                b.inst(Txa, ());
-            }
-            b.inst(And(Immediate), 1 << i);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-skip-bottom-{}", prefix, i)),
-            );
-            if i != 0 {
-                b.inst(Inc(ZeroPage), i - 1);
-            }
-            b.inst(Inc(ZeroPage), i);
-            if i != 7 {
-                b.inst(Inc(ZeroPage), i + 1);
-            }
-            b.label(format!("{}-skip-bottom-{}", prefix, i));
-        }
+            b.inst(Ldy(Immediate), 0); // Y will be offset into offset table
 
-        // left, top-left, bottom-left
-        for i in 0..=2 {
+            // top
             b.inst(Lda(IndirectYIndexed), 254);
             b.inst(Iny, ());
             b.inst(Tax, ());
             b.inst(Lda(AbsoluteXIndexed), from);
+            b.inst(Tax, ()); // backup in X
+
+            for i in 0..=7 {
+                if i != 0 {
+                    // no point copying when i == 0 as A will already contain value
+                    b.inst(Txa, ());
+                }
+                b.inst(And(Immediate), 1 << i);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@skip-top-{}", i)));
+                if i != 0 {
+                    b.inst(Inc(ZeroPage), i - 1);
+                }
+                b.inst(Inc(ZeroPage), i);
+                if i != 7 {
+                    b.inst(Inc(ZeroPage), i + 1);
+                }
+                b.label(format!("@skip-top-{}", i));
+            }
 
-            b.inst(And(Immediate), 1 << 7);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-skip-left-{}", prefix, i)),
-            );
-            b.inst(Inc(ZeroPage), 0);
-            b.label(format!("{}-skip-left-{}", prefix, i));
-        }
-
-        // right, top-right, bottom-right
-        for i in 0..=2 {
+            // bottom
             b.inst(Lda(IndirectYIndexed), 254);
             b.inst(Iny, ());
             b.inst(Tax, ());
             b.inst(Lda(AbsoluteXIndexed), from);
+            b.inst(Tax, ()); // backup in X
+
+            for i in 0..=7 {
+                if i != 0 {
+                    // no point copying when i == 0 as A will already contain value
+                    b.inst(Txa, ());
+                }
+                b.inst(And(Immediate), 1 << i);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@skip-bottom-{}", i)));
+                if i != 0 {
+                    b.inst(Inc(ZeroPage), i - 1);
+                }
+                b.inst(Inc(ZeroPage), i);
+                if i != 7 {
+                    b.inst(Inc(ZeroPage), i + 1);
+                }
+                b.label(format!("@skip-bottom-{}", i));
+            }
 
-            b.inst(And(Immediate), 1 << 0);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-skip-right-{}", prefix, i)),
-            );
-            b.inst(Inc(ZeroPage), 7);
-            b.label(format!("{}-skip-right-{}", prefix, i));
-        }
-
-        // current
-        b.inst(Ldx(ZeroPage), 253);
-        b.inst(Lda(AbsoluteXIndexed), from);
-        b.inst(Tax, ()); // backup in X
+            // left, top-left, bottom-left
+            for i in 0..=2 {
+                b.inst(Lda(IndirectYIndexed), 254);
+                b.inst(Iny, ());
+                b.inst(Tax, ());
+                b.inst(Lda(AbsoluteXIndexed), from);
+
+                b.inst(And(Immediate), 1 << 7);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@skip-left-{}", i)));
+                b.inst(Inc(ZeroPage), 0);
+                b.label(format!("@skip-left-{}", i));
+            }
 
-        for i in 0..=7 {
-            if i != 0 {
-                // no point copying when i == 0 as A will already contain value
-                b.inst(Txa, ());
+            // right, top-right, bottom-right
+            for i in 0..=2 {
+                b.inst(Lda(IndirectYIndexed), 254);
+                b.inst(Iny, ());
+                b.inst(Tax, ());
+                b.inst(Lda(AbsoluteXIndexed), from);
+
+                b.inst(And(Immediate), 1 << 0);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@skip-right-{}", i)));
+                b.inst(Inc(ZeroPage), 7);
+                b.label(format!("@skip-right-{}", i));
             }
-            b.inst(And(Immediate), 1 << i);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-skip-current-{}", prefix, i)),
-            );
-            if i != 0 {
-                b.inst(Inc(ZeroPage), i - 1);
+
+            // current
+            b.inst(Ldx(ZeroPage), 253);
+            b.inst(Lda(AbsoluteXIndexed), from);
+            b.inst(Tax, ()); // backup in X
+
+            for i in 0..=7 {
+                if i != 0 {
+                    // no point copying when i == 0 as A will already contain value
+                    b.inst(Txa, ());
+                }
+                b.inst(And(Immediate), 1 << i);
+                b.inst(
+                    Beq,
+                    LabelRelativeOffsetOwned(format!("@skip-current-{}", i)),
+                );
+                if i != 0 {
+                    b.inst(Inc(ZeroPage), i - 1);
+                }
+                if i != 7 {
+                    b.inst(Inc(ZeroPage), i + 1);
+                }
+                b.label(format!("@skip-current-{}", i));
             }
-            if i != 7 {
-                b.inst(Inc(ZeroPage), i + 1);
+
+            // will build up result in zp8
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), 8);
+
+            for i in 0..=7 {
+                b.inst(Txa, ()); // X still contains current byte
+                b.inst(And(Immediate), 1 << i);
+                b.inst(
+                    Beq,
+                    LabelRelativeOffsetOwned(format!("@current-dead-{}", i)),
+                );
+                // currently alive
+                b.inst(Lda(ZeroPage), i);
+                b.inst(Cmp(Immediate), 2);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@next-alive-{}", i)));
+                b.inst(Cmp(Immediate), 3);
+                b.inst(Beq, LabelRelativeOffsetOwned(format!("@next-alive-{}", i)));
+                b.inst(Jmp(Absolute), format!("@next-dead-{}", i));
+
+                b.label(format!("@current-dead-{}", i));
+
+                b.inst(Lda(ZeroPage), i);
+                b.inst(Cmp(Immediate), 3);
+                b.inst(Bne, LabelRelativeOffsetOwned(format!("@next-dead-{}", i)));
+
+                b.label(format!("@next-alive-{}", i));
+
+                b.inst(Lda(ZeroPage), 8);
+                b.inst(Ora(Immediate), 1 << i);
+                b.inst(Sta(ZeroPage), 8);
+
+                b.label(format!("@next-dead-{}", i));
             }
-            b.label(format!("{}-skip-current-{}", prefix, i));
-        }
 
-        // will build up result in zp8
-        b.inst(Lda(Immediate), 0);
-        b.inst(Sta(ZeroPage), 8);
-
-        for i in 0..=7 {
-            b.inst(Txa, ()); // X still contains current byte
-            b.inst(And(Immediate), 1 << i);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-current-dead-{}", prefix, i)),
-            );
-            // currently alive
-            b.inst(Lda(ZeroPage), i);
-            b.inst(Cmp(Immediate), 2);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-next-alive-{}", prefix, i)),
-            );
-            b.inst(Cmp(Immediate), 3);
-            b.inst(
-                Beq,
-                LabelRelativeOffsetOwned(format!("{}-next-alive-{}", prefix, i)),
-            );
-            b.inst(Jmp(Absolute), format!("{}-next-dead-{}", prefix, i));
-
-            b.label(format!("{}-current-dead-{}", prefix, i));
+            // store the result in the output
+            b.inst(Lda(ZeroPage), 8);
+            b.inst(Ldx(ZeroPage), 253);
+            b.inst(Sta(AbsoluteXIndexed), to);
+
+            b.inst(Inc(ZeroPage), 253);
+            b.inst(Clc, ());
+            b.inst(Lda(ZeroPage), 254);
+            b.inst(Adc(Immediate), 8);
+            b.inst(Sta(ZeroPage), 254);
+            b.inst(Lda(ZeroPage), 255);
+            b.inst(Adc(Immediate), 0);
+            b.inst(Sta(ZeroPage), 255); // increment pointers
+
+            b.inst(Jmp(Absolute), "@life-update-start");
+            b.label("@life-update-end");
+        });
+    }
 
-            b.inst(Lda(ZeroPage), i);
-            b.inst(Cmp(Immediate), 3);
-            b.inst(
-                Bne,
-                LabelRelativeOffsetOwned(format!("{}-next-dead-{}", prefix, i)),
-            );
+    fn enqueue_delta(b: &mut Block, from: u16, to: u16) {
+        b.scope("enqueue-delta", |b| {
+            b.inst(Lda(Immediate), 0xFB);
+            b.inst(Sta(ZeroPage), 0); // clear update buffer
 
-            b.label(format!("{}-next-alive-{}", prefix, i));
+            // write new draw queue by diffing previous and current images
+            b.inst(Ldx(Immediate), 0);
+            b.inst(Ldy(Immediate), 0);
 
-            b.inst(Lda(ZeroPage), 8);
-            b.inst(Ora(Immediate), 1 << i);
-            b.inst(Sta(ZeroPage), 8);
+            b.inst(Stx(ZeroPage), 255); // not currently in a run
+            b.inst(Stx(ZeroPage), 254); // MSB of count is always 0, but needed to form address
+            b.inst(Stx(ZeroPage), 253); // LSB of count
 
-            b.label(format!("{}-next-dead-{}", prefix, i));
-        }
+            b.label("@diff-start");
+            b.inst(Txa, ());
+            b.inst(Cmp(Immediate), 120);
+            b.inst(Beq, LabelRelativeOffset("@diff-end"));
 
-        // store the result in the output
-        b.inst(Lda(ZeroPage), 8);
-        b.inst(Ldx(ZeroPage), 253);
-        b.inst(Sta(AbsoluteXIndexed), to);
-
-        b.inst(Inc(ZeroPage), 253);
-        b.inst(Clc, ());
-        b.inst(Lda(ZeroPage), 254);
-        b.inst(Adc(Immediate), 8);
-        b.inst(Sta(ZeroPage), 254);
-        b.inst(Lda(ZeroPage), 255);
-        b.inst(Adc(Immediate), 0);
-        b.inst(Sta(ZeroPage), 255); // increment pointers
-
-        b.inst(Jmp(Absolute), format!("{}-life-update-start", prefix));
-        b.label(format!("{}-life-update-end", prefix));
-    }
+            b.inst(Lda(AbsoluteXIndexed), Addr(from));
+            b.inst(Eor(AbsoluteXIndexed), Addr(to));
+
+            b.inst(Bne, LabelRelativeOffset("@add-diff-to-draw-queue"));
+
+            b.inst(Sta(ZeroPage), 255); // we know A is zero - no longer in a run
+            b.inst(Inx, ());
+            b.inst(Jmp(Absolute), "@diff-start");
+
+            b.label("@add-diff-to-draw-queue");
+
+            b.inst(Lda(ZeroPage), 255);
+            b.inst(Bne, LabelRelativeOffset("@increment-counter-append-byte"));
+
+            b.inst(Tya, ());
+            b.inst(Beq, LabelRelativeOffset("@append-offset"));
+
+            b.inst(Lda(ZeroPage), 253);
+            b.inst(Sta(AbsoluteYIndexed), Addr(0)); // store the previous counter value at Y
+
+            b.inst(Tya, ());
+            b.inst(Clc, ());
+            b.inst(Adc(ZeroPage), 253);
+            b.inst(Tay, ());
+            b.inst(Iny, ()); // Y now points where the offset will go
+
+            b.label("@append-offset");
+
+            b.inst(Stx(ZeroPageYIndexed), 0); // X contains the current offset
+            b.inst(Iny, ()); // Y now points where the length will go
+
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), 253); // clear current count LBS (MSB is always clear)
+
+            b.label("@increment-counter-append-byte");
+
+            b.inst(Inc(ZeroPage), 253); // increment counter
+            b.inst(Lda(AbsoluteXIndexed), Addr(to)); // load byte from current image
+            b.inst(Sta(IndirectYIndexed), 253); // store at *(253) + Y
+
+            b.inst(Inx, ());
+            b.inst(Stx(ZeroPage), 255); // X can't be 0 at this point. Set flag to non-zero value.
+
+            b.inst(Jmp(Absolute), "@diff-start");
+            b.label("@diff-end");
 
-    fn enqueue_delta(b: &mut Block, from: u16, to: u16, prefix: &str) {
-        b.inst(Lda(Immediate), 0xFB);
-        b.inst(Sta(ZeroPage), 0); // clear update buffer
-
-        // write new draw queue by diffing previous and current images
-        b.inst(Ldx(Immediate), 0);
-        b.inst(Ldy(Immediate), 0);
-
-        b.inst(Stx(ZeroPage), 255); // not currently in a run
-        b.inst(Stx(ZeroPage), 254); // MSB of count is always 0, but needed to form address
-        b.inst(Stx(ZeroPage), 253); // LSB of count
-
-        b.label(format!("{}-diff-start", prefix));
-        b.inst(Txa, ());
-        b.inst(Cmp(Immediate), 120);
-        b.inst(
-            Beq,
-            LabelRelativeOffsetOwned(format!("{}-diff-end", prefix)),
-        );
-
-        b.inst(Lda(AbsoluteXIndexed), Addr(from));
-        b.inst(Eor(AbsoluteXIndexed), Addr(to));
-
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-add-diff-to-draw-queue", prefix)),
-        );
-
-        b.inst(Sta(ZeroPage), 255); // we know A is zero - no longer in a run
-        b.inst(Inx, ());
-        b.inst(Jmp(Absolute), format!("{}-diff-start", prefix));
-
-        b.label(format!("{}-add-diff-to-draw-queue", prefix));
-
-        b.inst(Lda(ZeroPage), 255);
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-increment-counter-append-byte", prefix)),
-        );
-
-        b.inst(Tya, ());
-        b.inst(
-            Beq,
-            LabelRelativeOffsetOwned(format!("{}-append-offset", prefix)),
-        );
-
-        b.inst(Lda(ZeroPage), 253);
-        b.inst(Sta(AbsoluteYIndexed), Addr(0)); // store the previous counter value at Y
-
-        b.inst(Tya, ());
-        b.inst(Clc, ());
-        b.inst(Adc(ZeroPage), 253);
-        b.inst(Tay, ());
-        b.inst(Iny, ()); // Y now points where the offset will go
-
-        b.label(format!("{}-append-offset", prefix));
-
-        b.inst(Stx(ZeroPageYIndexed), 0); // X contains the current offset
-        b.inst(Iny, ()); // Y now points where the length will go
-
-        b.inst(Lda(Immediate), 0);
-        b.inst(Sta(ZeroPage), 253); // clear current count LBS (MSB is always clear)
-
-        b.label(format!("{}-increment-counter-append-byte", prefix));
-
-        b.inst(Inc(ZeroPage), 253); // increment counter
-        b.inst(Lda(AbsoluteXIndexed), Addr(to)); // load byte from current image
-        b.inst(Sta(IndirectYIndexed), 253); // store at *(253) + Y
-
-        b.inst(Inx, ());
-        b.inst(Stx(ZeroPage), 255); // X can't be 0 at this point. Set flag to non-zero value.
-
-        b.inst(Jmp(Absolute), format!("{}-diff-start", prefix));
-        b.label(format!("{}-diff-end", prefix));
-
-        b.inst(Tya, ());
-        b.inst(Tax, ());
-        b.inst(Lda(ZeroPage), 253);
-        b.inst(
-            Bne,
-            LabelRelativeOffsetOwned(format!("{}-non-empty", prefix)),
-        );
-        b.inst(Lda(Immediate), 0xFA);
-        b.inst(Sta(ZeroPage), 0); // empty queue - place terminator at start of zero page
-        b.inst(Jmp(Absolute), format!("{}-end", prefix));
-        b.label(format!("{}-non-empty", prefix));
-        b.inst(Sta(ZeroPageXIndexed), 0); // store the previous counter value at Y (X is copied from Y)
-
-        b.inst(Txa, ());
-        b.inst(Clc, ());
-        b.inst(Adc(ZeroPage), 253);
-        b.inst(Tax, ());
-        b.inst(Inx, ()); // X now points where the terminator will go
-        b.inst(Lda(Immediate), 0xFC);
-        b.inst(Sta(ZeroPageXIndexed), 0);
-
-        b.label(format!("{}-end", prefix));
+            b.inst(Tya, ());
+            b.inst(Tax, ());
+            b.inst(Lda(ZeroPage), 253);
+            b.inst(Bne, LabelRelativeOffset("@non-empty"));
+            b.inst(Lda(Immediate), 0xFA);
+            b.inst(Sta(ZeroPage), 0); // empty queue - place terminator at start of zero page
+            b.inst(Jmp(Absolute), "@end");
+            b.label("@non-empty");
+            b.inst(Sta(ZeroPageXIndexed), 0); // store the previous counter value at Y (X is copied from Y)
+
+            b.inst(Txa, ());
+            b.inst(Clc, ());
+            b.inst(Adc(ZeroPage), 253);
+            b.inst(Tax, ());
+            b.inst(Inx, ()); // X now points where the terminator will go
+            b.inst(Lda(Immediate), 0xFC);
+            b.inst(Sta(ZeroPageXIndexed), 0);
+
+            b.label("@end");
+        });
     }
 
     b.inst(Lda(ZeroPage), 252);
@@ -485,13 +453,13 @@ fn program(b: &mut Block) {
     b.infinite_loop();
 
     b.label("fn-a-to-b");
-    conway_update(b, 0x0200, 0x0280, "A");
-    enqueue_delta(b, 0x0200, 0x0280, "A");
+    conway_update(b, 0x0200, 0x0280);
+    enqueue_delta(b, 0x0200, 0x0280);
     b.inst(Rts, ());
 
     b.label("fn-b-to-a");
-    conway_update(b, 0x0280, 0x0200, "B");
-    enqueue_delta(b, 0x0280, 0x0200, "B");
+    conway_update(b, 0x0280, 0x0200);
+    enqueue_delta(b, 0x0280, 0x0200);
     b.inst(Rts, ());
 
     b.label("rng-init");
@@ -681,9 +649,7 @@ fn chr_rom() -> Vec<u8> {
     chr_rom
 }
 
-fn prg_rom() -> Vec<u8> {
-    let mut block = Block::new();
-    program(&mut block);
+fn prg_rom(block: &Block) -> Vec<u8> {
     let mut prg_rom = Vec::new();
     block
         .assemble(PRG_START, ines::PRG_ROM_BLOCK_BYTES, &mut prg_rom)
@@ -691,10 +657,8 @@ fn prg_rom() -> Vec<u8> {
     prg_rom
 }
 
-fn main() {
-    use std::io::Write;
-    env_logger::init();
-    let ines = Ines {
+fn ines(block: &Block) -> Ines {
+    Ines {
         header: ines::Header {
             num_prg_rom_blocks: 1,
             num_chr_rom_blocks: 1,
@@ -702,9 +666,17 @@ fn main() {
             mirroring: ines::Mirroring::Vertical,
             four_screen_vram: false,
         },
-        prg_rom: prg_rom(),
+        prg_rom: prg_rom(block),
         chr_rom: chr_rom(),
-    };
+    }
+}
+
+fn main() {
+    use std::io::Write;
+    env_logger::init();
+    let mut block = Block::new();
+    program(&mut block);
+    let ines = ines(&block);
     let mut encoded = Vec::new();
     ines.encode(&mut encoded);
     std::io::stdout()
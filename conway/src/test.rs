@@ -0,0 +1,120 @@
+use crate::*;
+use mos6502_model::machine::{Memory, MemoryReadOnly};
+use nes_emulator_core::dynamic_nes::DynamicNes;
+use nes_headless_frame::Frame;
+use proptest::prelude::*;
+use samples::subroutine_test::SubroutineTest;
+
+const NUM_ROWS: usize = 30;
+const NUM_COLS: usize = 32;
+const NUM_BYTES: usize = NUM_ROWS * NUM_COLS / 8;
+const STATE_A: Address = 0x0200;
+const STATE_B: Address = 0x0280;
+
+// Cells are packed 8 to a byte, in rows of 4 bytes, with the leftmost cell of each byte in bit 0
+fn is_alive(state: &[u8], row: usize, col: usize) -> bool {
+    state[row * NUM_COLS / 8 + col / 8] & (1 << (col % 8)) != 0
+}
+
+// Reference model of a generation, with all cells outside the grid dead
+fn next_state(state: &[u8]) -> Vec<u8> {
+    let mut next = vec![0; NUM_BYTES];
+    for row in 0..NUM_ROWS {
+        for col in 0..NUM_COLS {
+            let mut num_neighbours = 0;
+            for neighbour_row in row.saturating_sub(1)..=(row + 1).min(NUM_ROWS - 1) {
+                for neighbour_col in col.saturating_sub(1)..=(col + 1).min(NUM_COLS - 1) {
+                    if (neighbour_row, neighbour_col) != (row, col)
+                        && is_alive(state, neighbour_row, neighbour_col)
+                    {
+                        num_neighbours += 1;
+                    }
+                }
+            }
+            let alive = if is_alive(state, row, col) {
+                num_neighbours == 2 || num_neighbours == 3
+            } else {
+                num_neighbours == 3
+            };
+            if alive {
+                next[row * NUM_COLS / 8 + col / 8] |= 1 << (col % 8);
+            }
+        }
+    }
+    next
+}
+
+fn run_generation(state: &[u8]) -> Vec<u8> {
+    let mut block = Block::new();
+    program(&mut block);
+    let mut test = SubroutineTest::new(&block, PRG_START, ines::PRG_ROM_BLOCK_BYTES).unwrap();
+    for (i, &byte) in state.iter().enumerate() {
+        test.memory_mut().write_u8(STATE_A + i as Address, byte);
+    }
+    test.call("fn-a-to-b").unwrap();
+    (0..NUM_BYTES)
+        .map(|i| test.memory().read_u8_read_only(STATE_B + i as Address))
+        .collect()
+}
+
+#[test]
+fn blinker() {
+    let mut state = vec![0; NUM_BYTES];
+    // a horizontal line of 3 cells across the boundary between the first 2 bytes of row 1
+    state[4] = 0b1100_0000;
+    state[5] = 0b0000_0001;
+    let next = run_generation(&state);
+    assert_eq!(next, next_state(&state));
+    assert_eq!(&next[0..12], &[0x80, 0, 0, 0, 0x80, 0, 0, 0, 0x80, 0, 0, 0]);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+    #[test]
+    fn generation_matches_model(state in prop::collection::vec(any::<u8>(), NUM_BYTES)) {
+        prop_assert_eq!(run_generation(&state), next_state(&state));
+    }
+}
+
+// Renders the first `num_frames` frames of a rom
+fn run_frames(ines: &Ines, num_frames: usize) -> Vec<Frame> {
+    let mut nes = DynamicNes::from_ines(ines).unwrap();
+    (0..num_frames)
+        .map(|_| {
+            let mut frame = Frame::new();
+            nes.run_for_frame(&mut frame);
+            frame
+        })
+        .collect()
+}
+
+#[test]
+fn optimised_rom_renders_the_same_frames() {
+    const NUM_FRAMES: usize = 120;
+    let mut block = Block::new();
+    program(&mut block);
+    let frames = run_frames(&ines(&block), NUM_FRAMES);
+    let report = block.optimise();
+    assert!(report.bytes_saved() > 0, "{}", report);
+    let optimised_frames = run_frames(&ines(&block), NUM_FRAMES);
+    let first_difference = (0..NUM_FRAMES).find(|&i| frames[i] != optimised_frames[i]);
+    assert_eq!(first_difference, None);
+}
+
+// rom-to-dsl-round-trip's tests disassemble test-assets/conway.nes in place of this rom
+#[test]
+fn rom_matches_test_asset() {
+    let mut block = Block::new();
+    program(&mut block);
+    let mut encoded = Vec::new();
+    ines(&block).encode(&mut encoded);
+    let test_asset = std::fs::read(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../test-assets/conway.nes"
+    ))
+    .unwrap();
+    assert!(
+        encoded == test_asset,
+        "test-assets/conway.nes is out of date; regenerate it with `cargo run -p conway-nes`"
+    );
+}
@@ -17,7 +17,7 @@ pub struct Renderer<W: io::Write> {
 }
 
 #[cfg(not(feature = "background_pixel_ages"))]
-fn buffer_from_frame(frame: &Frame) -> Cow<[u8]> {
+fn buffer_from_frame(frame: &Frame) -> Cow<'_, [u8]> {
     Cow::Borrowed(frame.indices())
 }
 
@@ -47,8 +47,8 @@ impl<W: io::Write> Renderer<W> {
             palette
         };
         let mut palette = [0; nes_palette::NUM_COLOURS * 6];
-        (&mut palette[0..nes_palette::NUM_COLOURS * 3]).copy_from_slice(&palette_normal.to_bytes());
-        (&mut palette[nes_palette::NUM_COLOURS * 3..nes_palette::NUM_COLOURS * 6])
+        palette[0..nes_palette::NUM_COLOURS * 3].copy_from_slice(&palette_normal.to_bytes());
+        palette[nes_palette::NUM_COLOURS * 3..nes_palette::NUM_COLOURS * 6]
             .copy_from_slice(&palette_age.to_bytes());
         let mut encoder = gif::Encoder::new(
             output,
@@ -93,11 +93,13 @@ impl<W: io::Write> Renderer<W> {
         }
     }
     fn write_frame(&mut self, frame_delay: FrameDelay) {
-        let mut gif_frame = gif::Frame::default();
-        gif_frame.delay = frame_delay.delay;
-        gif_frame.width = nes_specs::SCREEN_WIDTH_PX;
-        gif_frame.height = nes_specs::SCREEN_HEIGHT_PX;
-        gif_frame.buffer = buffer_from_frame(&frame_delay.frame);
+        let gif_frame = gif::Frame {
+            delay: frame_delay.delay,
+            width: nes_specs::SCREEN_WIDTH_PX,
+            height: nes_specs::SCREEN_HEIGHT_PX,
+            buffer: buffer_from_frame(&frame_delay.frame),
+            ..Default::default()
+        };
         self.encoder.write_frame(&gif_frame).unwrap();
     }
 }
@@ -131,9 +133,9 @@ impl<W: io::Write> NameTableRenderer<W> {
             palette
         };
         let mut palette = [0; nes_palette::NUM_COLOURS * 6];
-        (&mut palette[0..nes_palette::NUM_COLOURS * 3])
+        palette[0..nes_palette::NUM_COLOURS * 3]
             .copy_from_slice(&palette_off_screen_pixels.to_bytes());
-        (&mut palette[nes_palette::NUM_COLOURS * 3..nes_palette::NUM_COLOURS * 6])
+        palette[nes_palette::NUM_COLOURS * 3..nes_palette::NUM_COLOURS * 6]
             .copy_from_slice(&palette_on_screen_pixels.to_bytes());
         let mut encoder = gif::Encoder::new(
             output,
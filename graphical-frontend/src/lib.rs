@@ -104,7 +104,6 @@ impl Frontend {
                 match app_control_flow {
                     ControlFlow::Quit => {
                         *control_flow = winit::event_loop::ControlFlow::Exit;
-                        return;
                     }
                 }
             }
@@ -144,7 +143,7 @@ impl<'a> Pixels<'a> {
             *current_depth = depth;
             let base_index = offset * 4;
             let [r, g, b] = self.colour_table.lookup(colour_index);
-            self.frame[base_index + 0] = r;
+            self.frame[base_index] = r;
             self.frame[base_index + 1] = g;
             self.frame[base_index + 2] = b;
             self.frame[base_index + 3] = 255;
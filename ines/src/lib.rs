@@ -49,7 +49,7 @@ pub struct Header {
 impl Header {
     fn parse(buffer: &[u8]) -> Result<Self, Error> {
         let checksum = &buffer[0..HEADER_CHECKSUM.len()];
-        if checksum != &HEADER_CHECKSUM {
+        if checksum != HEADER_CHECKSUM {
             panic!("Invalid checksum");
         }
         let four_screen_vram = buffer[6] & (1 << 3) != 0;
@@ -76,8 +76,8 @@ impl Header {
     fn chr_rom_bytes(&self) -> usize {
         self.num_chr_rom_blocks as usize * CHR_ROM_BLOCK_BYTES
     }
-    fn encode(&self, buffer: &mut Vec<u8>) {
-        (&mut buffer[0..HEADER_CHECKSUM.len()]).copy_from_slice(&HEADER_CHECKSUM);
+    fn encode(&self, buffer: &mut [u8]) {
+        buffer[0..HEADER_CHECKSUM.len()].copy_from_slice(&HEADER_CHECKSUM);
         buffer[4] = self.num_prg_rom_blocks;
         buffer[5] = self.num_chr_rom_blocks;
         let mapper_number = self.mapper.encode();
@@ -136,7 +136,7 @@ impl Ines {
         self.header.encode(buffer);
         let prg_start = HEADER_BYTES;
         let chr_start = prg_start + self.header.prg_rom_bytes();
-        (&mut buffer[prg_start..(prg_start + self.prg_rom.len())]).copy_from_slice(&self.prg_rom);
-        (&mut buffer[chr_start..(chr_start + self.chr_rom.len())]).copy_from_slice(&self.chr_rom);
+        buffer[prg_start..(prg_start + self.prg_rom.len())].copy_from_slice(&self.prg_rom);
+        buffer[chr_start..(chr_start + self.chr_rom.len())].copy_from_slice(&self.chr_rom);
     }
 }
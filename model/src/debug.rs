@@ -2,7 +2,7 @@ use crate::machine::{Cpu, MemoryReadOnly};
 use crate::{Address, UnknownOpcode};
 use std::fmt;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InstructionType {
     Adc,
     Ahx,
@@ -78,7 +78,7 @@ pub enum InstructionType {
     Txs,
     Tya,
 }
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressingMode {
     Absolute,
     AbsoluteXIndexed,
@@ -390,6 +390,7 @@ impl Instruction {
 #[derive(Debug, Clone)]
 pub struct InstructionWithOperand {
     address: Address,
+    opcode: u8,
     instruction: Instruction,
     operand: Vec<u8>,
 }
@@ -405,6 +406,7 @@ impl InstructionWithOperand {
         }
         Ok(Self {
             address,
+            opcode,
             instruction,
             operand,
         })
@@ -412,13 +414,25 @@ impl InstructionWithOperand {
     pub fn next<M: MemoryReadOnly>(cpu: &Cpu, memory: &M) -> Result<Self, UnknownOpcode> {
         Self::decode(cpu.pc, memory)
     }
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
     pub fn instruction(&self) -> Instruction {
         self.instruction
     }
+    pub fn operand(&self) -> &[u8] {
+        &self.operand
+    }
+    pub fn operand_u8(&self) -> Option<u8> {
+        match *self.operand.as_slice() {
+            [x] => Some(x),
+            _ => None,
+        }
+    }
     pub fn operand_u16_le(&self) -> Option<u16> {
-        match self.operand.as_slice() {
-            &[_x] => None,
-            &[x0, x1] => Some((x1 as u16) << 8 | x0 as u16),
+        match *self.operand.as_slice() {
+            [_x] => None,
+            [x0, x1] => Some((x1 as u16) << 8 | x0 as u16),
             _ => None,
         }
     }
@@ -433,9 +447,9 @@ impl fmt::Display for InstructionWithOperand {
             "{:04X}  {:?}({:?}) ",
             self.address, self.instruction.instruction_type, self.instruction.addressing_mode
         )?;
-        match self.operand.as_slice() {
-            &[x] => write!(f, "{:02X}", x)?,
-            &[x0, x1] => write!(f, "{:04X}", (x1 as u16) << 8 | x0 as u16)?,
+        match *self.operand.as_slice() {
+            [x] => write!(f, "{:02X}", x)?,
+            [x0, x1] => write!(f, "{:04X}", (x1 as u16) << 8 | x0 as u16)?,
             _ => (),
         }
         Ok(())
@@ -0,0 +1,52 @@
+use crate::machine::{Memory, MemoryReadOnly};
+use crate::Address;
+
+const NUM_BYTES: usize = 0x10000;
+
+// 64KB of RAM covering the entire address space, for running programs on a bare CPU
+#[derive(Clone)]
+pub struct FlatMemory {
+    bytes: Vec<u8>,
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        Self {
+            bytes: vec![0; NUM_BYTES],
+        }
+    }
+    // Copies `bytes` into memory starting at `address`, wrapping around at the end of the
+    // address space
+    pub fn load(&mut self, address: Address, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            self.bytes[(address as usize + i) % NUM_BYTES] = byte;
+        }
+    }
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+impl MemoryReadOnly for FlatMemory {
+    fn read_u8_read_only(&self, address: Address) -> u8 {
+        self.bytes[address as usize]
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read_u8(&mut self, address: Address) -> u8 {
+        self.bytes[address as usize]
+    }
+    fn write_u8(&mut self, address: Address, data: u8) {
+        self.bytes[address as usize] = data;
+    }
+}
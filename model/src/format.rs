@@ -0,0 +1,415 @@
+use crate::debug::{AddressingMode, Instruction, InstructionType, InstructionWithOperand};
+use crate::machine::{Cpu, MemoryReadOnly};
+use crate::{address, opcode, Address};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+// The assembler whose syntax instructions are formatted in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dialect {
+    // ca65 in 6502X mode, which includes unofficial instructions
+    Ca65,
+    Asm6,
+    Nesasm,
+    // The disassembly in Nintendulator's trace logs, as used by nestest.log. Unofficial
+    // instructions are marked with a leading "*". It isn't accepted by any assembler.
+    Nintendulator,
+}
+
+// The case of mnemonics and register names. Hex digits are always upper case.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Case {
+    Lower,
+    Upper,
+}
+
+pub trait SymbolTable {
+    fn symbol(&self, address: Address) -> Option<String>;
+}
+
+impl SymbolTable for BTreeMap<Address, String> {
+    fn symbol(&self, address: Address) -> Option<String> {
+        self.get(&address).cloned()
+    }
+}
+
+impl SymbolTable for HashMap<Address, String> {
+    fn symbol(&self, address: Address) -> Option<String> {
+        self.get(&address).cloned()
+    }
+}
+
+// The conventional names of the memory mapped PPU, APU and IO registers
+pub fn nes_register_name(address: Address) -> Option<&'static str> {
+    let name = match address {
+        0x2000 => "PPUCTRL",
+        0x2001 => "PPUMASK",
+        0x2002 => "PPUSTATUS",
+        0x2003 => "OAMADDR",
+        0x2004 => "OAMDATA",
+        0x2005 => "PPUSCROLL",
+        0x2006 => "PPUADDR",
+        0x2007 => "PPUDATA",
+        0x4000 => "SQ1_VOL",
+        0x4001 => "SQ1_SWEEP",
+        0x4002 => "SQ1_LO",
+        0x4003 => "SQ1_HI",
+        0x4004 => "SQ2_VOL",
+        0x4005 => "SQ2_SWEEP",
+        0x4006 => "SQ2_LO",
+        0x4007 => "SQ2_HI",
+        0x4008 => "TRI_LINEAR",
+        0x400A => "TRI_LO",
+        0x400B => "TRI_HI",
+        0x400C => "NOISE_VOL",
+        0x400E => "NOISE_LO",
+        0x400F => "NOISE_HI",
+        0x4010 => "DMC_FREQ",
+        0x4011 => "DMC_RAW",
+        0x4012 => "DMC_START",
+        0x4013 => "DMC_LEN",
+        0x4014 => "OAMDMA",
+        0x4015 => "SND_CHN",
+        0x4016 => "JOY1",
+        0x4017 => "JOY2",
+        _ => return None,
+    };
+    Some(name)
+}
+
+pub(crate) fn is_unofficial_opcode(opcode: u8, instruction: Instruction) -> bool {
+    use InstructionType::*;
+    match instruction.instruction_type() {
+        Ahx | Alr | Arr | Anc | Axs | Dcp | Ign | Isc | Lax | Rla | Rra | Sax | Skb | Slo | Sre
+        | Sxa | Sya => true,
+        Nop => opcode != opcode::nop::IMPLIED,
+        Sbc => opcode == opcode::sbc::unofficial0::IMMEDIATE,
+        _ => false,
+    }
+}
+
+fn is_unofficial(instruction_with_operand: &InstructionWithOperand) -> bool {
+    is_unofficial_opcode(
+        instruction_with_operand.opcode(),
+        instruction_with_operand.instruction(),
+    )
+}
+
+// Some unofficial instructions have several opcodes. Assemblers only produce the lowest of
+// them, so the others can't be written as mnemonics. The unofficial opcodes of NOP and SBC
+// duplicate official ones.
+fn is_lowest_opcode_of_unofficial_instruction(
+    instruction_with_operand: &InstructionWithOperand,
+) -> bool {
+    let instruction = instruction_with_operand.instruction();
+    if instruction.instruction_type() == InstructionType::Nop
+        || instruction.instruction_type() == InstructionType::Sbc
+    {
+        return false;
+    }
+    !(0..instruction_with_operand.opcode()).any(|opcode| {
+        Instruction::from_opcode(opcode)
+            .map(|other| {
+                other.instruction_type() == instruction.instruction_type()
+                    && other.addressing_mode() == instruction.addressing_mode()
+            })
+            .unwrap_or(false)
+    })
+}
+
+// Formats instructions as assembly source. By default instructions are formatted for ca65 in
+// lower case, with raw hex operands.
+#[derive(Clone, Copy)]
+pub struct Formatter<'a> {
+    dialect: Dialect,
+    case: Case,
+    symbols: Option<&'a dyn SymbolTable>,
+    register_names: bool,
+}
+
+impl Default for Formatter<'_> {
+    fn default() -> Self {
+        Self::new(Dialect::Ca65)
+    }
+}
+
+impl<'a> Formatter<'a> {
+    pub fn new(dialect: Dialect) -> Self {
+        Self {
+            dialect,
+            case: Case::Lower,
+            symbols: None,
+            register_names: false,
+        }
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        self.dialect
+    }
+
+    pub fn with_case(self, case: Case) -> Self {
+        Self { case, ..self }
+    }
+
+    // Operands which are the address of a symbol are replaced with the symbol's name
+    pub fn with_symbols<'b>(self, symbols: &'b dyn SymbolTable) -> Formatter<'b> {
+        Formatter {
+            dialect: self.dialect,
+            case: self.case,
+            symbols: Some(symbols),
+            register_names: self.register_names,
+        }
+    }
+
+    // Operands which are the address of a PPU, APU or IO register are replaced with its name,
+    // unless the symbol table has a name for it
+    pub fn with_register_names(self, register_names: bool) -> Self {
+        Self {
+            register_names,
+            ..self
+        }
+    }
+
+    fn cased(&self, s: &str) -> String {
+        match self.case {
+            Case::Lower => s.to_lowercase(),
+            Case::Upper => s.to_uppercase(),
+        }
+    }
+
+    fn byte_directive(&self) -> &'static str {
+        match self.dialect {
+            Dialect::Ca65 | Dialect::Nintendulator => ".byte",
+            Dialect::Asm6 | Dialect::Nesasm => ".db",
+        }
+    }
+
+    pub(crate) fn mnemonic(&self, instruction_type: InstructionType) -> String {
+        use InstructionType::*;
+        let name = match instruction_type {
+            Isc if self.dialect == Dialect::Nintendulator => "isb".to_string(),
+            Ahx => "sha".to_string(),
+            Sxa => "shx".to_string(),
+            Sya => "shy".to_string(),
+            Ign | Skb => "nop".to_string(),
+            other => format!("{:?}", other),
+        };
+        self.cased(&name)
+    }
+
+    fn symbol(&self, address: Address) -> Option<String> {
+        if let Some(symbol) = self.symbols.and_then(|symbols| symbols.symbol(address)) {
+            return Some(symbol);
+        }
+        if self.register_names {
+            return nes_register_name(address).map(|name| name.to_string());
+        }
+        None
+    }
+
+    fn zero_page(&self, address: u8) -> String {
+        let text = self
+            .symbol(address as Address)
+            .unwrap_or_else(|| format!("${:02X}", address));
+        match self.dialect {
+            Dialect::Nesasm => format!("<{}", text),
+            Dialect::Ca65 | Dialect::Asm6 | Dialect::Nintendulator => text,
+        }
+    }
+
+    fn absolute(&self, address: Address) -> String {
+        let text = self
+            .symbol(address)
+            .unwrap_or_else(|| format!("${:04X}", address));
+        // ca65 would otherwise assemble an address on the zero page with zero page addressing
+        if self.dialect == Dialect::Ca65 && address < 0x100 {
+            format!("a:{}", text)
+        } else {
+            text
+        }
+    }
+
+    fn indirect(&self, inner: &str) -> String {
+        match self.dialect {
+            Dialect::Nesasm => format!("[{}]", inner),
+            Dialect::Ca65 | Dialect::Asm6 | Dialect::Nintendulator => format!("({})", inner),
+        }
+    }
+
+    // Whether the instruction can only be written as raw bytes in this dialect, either because
+    // the assembler doesn't support it, or because it would assemble to different bytes
+    fn needs_raw_bytes(&self, instruction_with_operand: &InstructionWithOperand) -> bool {
+        if is_unofficial(instruction_with_operand)
+            && self.dialect != Dialect::Nintendulator
+            && (self.dialect != Dialect::Ca65
+                || !is_lowest_opcode_of_unofficial_instruction(instruction_with_operand))
+        {
+            return true;
+        }
+        // asm6 has no way to force absolute addressing of the zero page
+        self.dialect == Dialect::Asm6
+            && matches!(
+                instruction_with_operand.instruction().addressing_mode(),
+                AddressingMode::Absolute
+                    | AddressingMode::AbsoluteXIndexed
+                    | AddressingMode::AbsoluteYIndexed
+            )
+            && instruction_with_operand.operand_u16_le().unwrap_or(0) < 0x100
+    }
+
+    fn raw_bytes(&self, instruction_with_operand: &InstructionWithOperand) -> String {
+        let bytes = std::iter::once(instruction_with_operand.opcode())
+            .chain(instruction_with_operand.operand().iter().cloned())
+            .map(|byte| format!("${:02X}", byte))
+            .collect::<Vec<_>>();
+        format!("{} {}", self.byte_directive(), bytes.join(","))
+    }
+
+    fn operand(&self, instruction_with_operand: &InstructionWithOperand) -> String {
+        let x = self.cased("x");
+        let y = self.cased("y");
+        let operand_u8 = instruction_with_operand.operand_u8().unwrap_or(0);
+        let operand_u16 = instruction_with_operand.operand_u16_le().unwrap_or(0);
+        use AddressingMode::*;
+        match instruction_with_operand.instruction().addressing_mode() {
+            Implied => String::new(),
+            Accumulator => self.cased("a"),
+            Immediate => format!("#${:02X}", operand_u8),
+            ZeroPage => self.zero_page(operand_u8),
+            ZeroPageXIndexed => format!("{},{}", self.zero_page(operand_u8), x),
+            ZeroPageYIndexed => format!("{},{}", self.zero_page(operand_u8), y),
+            Absolute => self.absolute(operand_u16),
+            AbsoluteXIndexed => format!("{},{}", self.absolute(operand_u16), x),
+            AbsoluteYIndexed => format!("{},{}", self.absolute(operand_u16), y),
+            Indirect => self.indirect(&self.absolute(operand_u16)),
+            XIndexedIndirect => self.indirect(&format!("{},{}", self.zero_page(operand_u8), x)),
+            IndirectYIndexed => format!("{},{}", self.indirect(&self.zero_page(operand_u8)), y),
+            Relative => {
+                let target = branch_target(instruction_with_operand);
+                self.symbol(target)
+                    .unwrap_or_else(|| format!("${:04X}", target))
+            }
+        }
+    }
+
+    // Formats an instruction as a line of assembly source, without its address
+    pub fn instruction(&self, instruction_with_operand: &InstructionWithOperand) -> String {
+        if self.needs_raw_bytes(instruction_with_operand) {
+            return self.raw_bytes(instruction_with_operand);
+        }
+        let mut mnemonic = self.mnemonic(instruction_with_operand.instruction().instruction_type());
+        if self.dialect == Dialect::Nintendulator && is_unofficial(instruction_with_operand) {
+            mnemonic.insert(0, '*');
+        }
+        let operand = self.operand(instruction_with_operand);
+        if operand.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", mnemonic, operand)
+        }
+    }
+
+    // Formats an instruction which is about to be executed, followed by the address it will
+    // access and the value currently there, in the style of nestest logs, e.g.
+    // "lda ($80),y = 0200 @ 0205 = 5A"
+    pub fn instruction_with_state<M: MemoryReadOnly>(
+        &self,
+        instruction_with_operand: &InstructionWithOperand,
+        cpu: &Cpu,
+        memory: &M,
+    ) -> String {
+        let mut s = self.instruction(instruction_with_operand);
+        if !self.needs_raw_bytes(instruction_with_operand) {
+            write_state(&mut s, instruction_with_operand, cpu, memory);
+        }
+        s
+    }
+}
+
+pub(crate) fn branch_target(instruction_with_operand: &InstructionWithOperand) -> Address {
+    let offset = instruction_with_operand.operand_u8().unwrap_or(0) as i8;
+    instruction_with_operand
+        .address()
+        .wrapping_add(instruction_with_operand.instruction().size() as Address)
+        .wrapping_add(offset as i16 as Address)
+}
+
+fn read_zero_page_u16_le<M: MemoryReadOnly>(memory: &M, address: u8) -> Address {
+    address::from_u8_lo_hi(
+        memory.read_u8_read_only(address as Address),
+        memory.read_u8_read_only(address.wrapping_add(1) as Address),
+    )
+}
+
+fn write_state<M: MemoryReadOnly>(
+    s: &mut String,
+    instruction_with_operand: &InstructionWithOperand,
+    cpu: &Cpu,
+    memory: &M,
+) {
+    let instruction = instruction_with_operand.instruction();
+    let operand_u8 = instruction_with_operand.operand_u8().unwrap_or(0);
+    let operand_u16 = instruction_with_operand.operand_u16_le().unwrap_or(0);
+    let value = |address: Address| memory.read_u8_read_only(address);
+    use AddressingMode::*;
+    match instruction.addressing_mode() {
+        Implied | Accumulator | Immediate | Relative => (),
+        ZeroPage => write!(s, " = {:02X}", value(operand_u8 as Address)).unwrap(),
+        ZeroPageXIndexed | ZeroPageYIndexed => {
+            let index = if instruction.addressing_mode() == ZeroPageXIndexed {
+                cpu.x
+            } else {
+                cpu.y
+            };
+            let address = operand_u8.wrapping_add(index);
+            write!(s, " @ {:02X} = {:02X}", address, value(address as Address)).unwrap();
+        }
+        Absolute => match instruction.instruction_type() {
+            InstructionType::Jmp | InstructionType::Jsr => (),
+            _ => write!(s, " = {:02X}", value(operand_u16)).unwrap(),
+        },
+        AbsoluteXIndexed | AbsoluteYIndexed => {
+            let index = if instruction.addressing_mode() == AbsoluteXIndexed {
+                cpu.x
+            } else {
+                cpu.y
+            };
+            let address = operand_u16.wrapping_add(index as Address);
+            write!(s, " @ {:04X} = {:02X}", address, value(address)).unwrap();
+        }
+        Indirect => {
+            // the high byte of the target is read from the start of the page if the pointer
+            // straddles a page boundary
+            let hi_address = (operand_u16 & 0xFF00) | (operand_u16.wrapping_add(1) & 0x00FF);
+            let target = address::from_u8_lo_hi(value(operand_u16), value(hi_address));
+            write!(s, " = {:04X}", target).unwrap();
+        }
+        XIndexedIndirect => {
+            let pointer = operand_u8.wrapping_add(cpu.x);
+            let address = read_zero_page_u16_le(memory, pointer);
+            write!(
+                s,
+                " @ {:02X} = {:04X} = {:02X}",
+                pointer,
+                address,
+                value(address)
+            )
+            .unwrap();
+        }
+        IndirectYIndexed => {
+            let base = read_zero_page_u16_le(memory, operand_u8);
+            let address = base.wrapping_add(cpu.y as Address);
+            write!(
+                s,
+                " = {:04X} @ {:04X} = {:02X}",
+                base,
+                address,
+                value(address)
+            )
+            .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
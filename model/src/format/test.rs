@@ -0,0 +1,216 @@
+use super::*;
+use crate::flat_memory::FlatMemory;
+use crate::machine::Memory;
+
+const PC: Address = 0xC000;
+
+fn memory_with_instruction(bytes: &[u8]) -> FlatMemory {
+    let mut memory = FlatMemory::new();
+    memory.load(PC, bytes);
+    memory
+}
+
+fn decode(memory: &FlatMemory) -> InstructionWithOperand {
+    InstructionWithOperand::decode(PC, memory).unwrap()
+}
+
+fn check(formatter: &Formatter, cases: &[(&[u8], &str)]) {
+    for &(bytes, expected) in cases {
+        let memory = memory_with_instruction(bytes);
+        assert_eq!(
+            formatter.instruction(&decode(&memory)),
+            expected,
+            "{:?} {:02X?}",
+            formatter.dialect(),
+            bytes
+        );
+    }
+}
+
+#[test]
+fn ca65() {
+    check(
+        &Formatter::new(Dialect::Ca65),
+        &[
+            (&[0xA9, 0xAB], "lda #$AB"),
+            (&[0xA5, 0x10], "lda $10"),
+            (&[0xAD, 0x10, 0x00], "lda a:$0010"),
+            (&[0xBD, 0x10, 0x00], "lda a:$0010,x"),
+            (&[0xAD, 0x00, 0x20], "lda $2000"),
+            (&[0x0A], "asl a"),
+            (&[0xB1, 0x10], "lda ($10),y"),
+            (&[0x6C, 0xFF, 0x02], "jmp ($02FF)"),
+            (&[0xD0, 0x02], "bne $C004"),
+            (&[0xD0, 0xFC], "bne $BFFE"),
+            // the lowest opcode of an unofficial instruction has a mnemonic
+            (&[0xA7, 0x10], "lax $10"),
+            (&[0xE7, 0x10], "isc $10"),
+            (&[0x80, 0x12], "nop #$12"),
+            (&[0x04, 0x10], "nop $10"),
+            // but other opcodes of the same instruction don't
+            (&[0x82, 0x12], ".byte $82,$12"),
+            (&[0x44, 0x10], ".byte $44,$10"),
+        ],
+    );
+}
+
+#[test]
+fn asm6() {
+    check(
+        &Formatter::new(Dialect::Asm6),
+        &[
+            (&[0xA5, 0x10], "lda $10"),
+            (&[0xAD, 0x00, 0x20], "lda $2000"),
+            // asm6 would assemble these with zero page addressing
+            (&[0xAD, 0x10, 0x00], ".db $AD,$10,$00"),
+            (&[0xBD, 0x10, 0x00], ".db $BD,$10,$00"),
+            (&[0xB9, 0xFF, 0x00], ".db $B9,$FF,$00"),
+            // it has no unofficial instructions
+            (&[0xA7, 0x10], ".db $A7,$10"),
+        ],
+    );
+}
+
+#[test]
+fn nesasm() {
+    check(
+        &Formatter::new(Dialect::Nesasm),
+        &[
+            (&[0xA5, 0x10], "lda <$10"),
+            (&[0xB5, 0x10], "lda <$10,x"),
+            (&[0xAD, 0x10, 0x00], "lda $0010"),
+            (&[0xB1, 0x10], "lda [<$10],y"),
+            (&[0xA1, 0x10], "lda [<$10,x]"),
+            (&[0x6C, 0x00, 0x02], "jmp [$0200]"),
+            (&[0xA7, 0x10], ".db $A7,$10"),
+        ],
+    );
+}
+
+#[test]
+fn nintendulator() {
+    check(
+        &Formatter::new(Dialect::Nintendulator),
+        &[
+            (&[0xA5, 0x10], "lda $10"),
+            (&[0xEA], "nop"),
+            // unofficial instructions are marked with a "*", whichever opcode they have
+            (&[0x04, 0x10], "*nop $10"),
+            (&[0x82, 0x12], "*nop #$12"),
+            (&[0xE7, 0x10], "*isb $10"),
+            (&[0xEB, 0x12], "*sbc #$12"),
+            (&[0xA7, 0x10], "*lax $10"),
+        ],
+    );
+}
+
+#[test]
+fn upper_case() {
+    check(
+        &Formatter::new(Dialect::Ca65).with_case(Case::Upper),
+        &[
+            (&[0xB5, 0x10], "LDA $10,X"),
+            (&[0xBE, 0x00, 0x02], "LDX $0200,Y"),
+            (&[0x4A], "LSR A"),
+            (&[0xAD, 0x10, 0x00], "LDA a:$0010"),
+        ],
+    );
+}
+
+#[test]
+fn symbols_and_registers() {
+    let symbols = [
+        (0x0010, "counter".to_string()),
+        (0xC004, "next".to_string()),
+        (0x2002, "status".to_string()),
+    ]
+    .into_iter()
+    .collect::<BTreeMap<_, _>>();
+    let formatter = Formatter::new(Dialect::Ca65).with_symbols(&symbols);
+    check(
+        &formatter,
+        &[
+            (&[0xA5, 0x10], "lda counter"),
+            (&[0xB1, 0x10], "lda (counter),y"),
+            (&[0xAD, 0x10, 0x00], "lda a:counter"),
+            (&[0xD0, 0x02], "bne next"),
+            (&[0x20, 0x04, 0xC0], "jsr next"),
+            (&[0x8D, 0x00, 0x20], "sta $2000"),
+            (&[0xAD, 0x02, 0x20], "lda status"),
+        ],
+    );
+    // symbols take precedence over register names
+    check(
+        &formatter.with_register_names(true),
+        &[
+            (&[0x8D, 0x00, 0x20], "sta PPUCTRL"),
+            (&[0x8D, 0x14, 0x40], "sta OAMDMA"),
+            (&[0xAD, 0x16, 0x40], "lda JOY1"),
+            (&[0xAD, 0x02, 0x20], "lda status"),
+            (&[0xAD, 0x03, 0x02], "lda $0203"),
+        ],
+    );
+    check(
+        &Formatter::new(Dialect::Nesasm).with_symbols(&symbols),
+        &[(&[0xA5, 0x10], "lda <counter")],
+    );
+}
+
+#[test]
+fn instruction_with_state() {
+    let formatter = Formatter::new(Dialect::Nintendulator).with_case(Case::Upper);
+    let cases: &[(&[u8], &str)] = &[
+        (&[0xA9, 0x12], "LDA #$12"),
+        (&[0xA5, 0x80], "LDA $80 = 00"),
+        (&[0xB5, 0x10], "LDA $10,X @ 12 = 77"),
+        (&[0xB5, 0xFF], "LDA $FF,X @ 01 = 66"),
+        (&[0xB6, 0x10], "LDX $10,Y @ 15 = 00"),
+        (&[0xAD, 0x00, 0x03], "LDA $0300 = 11"),
+        (&[0x8D, 0x00, 0x03], "STA $0300 = 11"),
+        (&[0xBD, 0xFE, 0x02], "LDA $02FE,X @ 0300 = 11"),
+        (&[0xB9, 0x00, 0x02], "LDA $0200,Y @ 0205 = 5A"),
+        (&[0xA1, 0x80], "LDA ($80,X) @ 82 = 0300 = 11"),
+        (&[0xB1, 0x84], "LDA ($84),Y = 0200 @ 0205 = 5A"),
+        (&[0x4C, 0x00, 0x03], "JMP $0300"),
+        (&[0x20, 0x00, 0x03], "JSR $0300"),
+        (&[0x6C, 0x00, 0x04], "JMP ($0400) = 5678"),
+        // the high byte of the target is read from the start of the page, not from $0500
+        (&[0x6C, 0xFF, 0x04], "JMP ($04FF) = 7856"),
+        (&[0xD0, 0x02], "BNE $C004"),
+        (&[0xE7, 0x80], "*ISB $80 = 00"),
+    ];
+    let mut cpu = Cpu::new();
+    cpu.x = 2;
+    cpu.y = 5;
+    for &(bytes, expected) in cases {
+        let mut memory = memory_with_instruction(bytes);
+        memory.write_u8(0x0001, 0x66);
+        memory.write_u8(0x0012, 0x77);
+        memory.write_u8(0x0082, 0x00);
+        memory.write_u8(0x0083, 0x03);
+        memory.write_u8(0x0084, 0x00);
+        memory.write_u8(0x0085, 0x02);
+        memory.write_u8(0x0205, 0x5A);
+        memory.write_u8(0x0300, 0x11);
+        memory.load(0x0400, &[0x78, 0x56]);
+        memory.write_u8(0x04FF, 0x56);
+        memory.write_u8(0x0500, 0x12);
+        let instruction_with_operand = decode(&memory);
+        assert_eq!(
+            formatter.instruction_with_state(&instruction_with_operand, &cpu, &memory),
+            expected,
+            "{:02X?}",
+            bytes
+        );
+    }
+}
+
+#[test]
+fn instructions_written_as_raw_bytes_have_no_state() {
+    let memory = memory_with_instruction(&[0xAD, 0x10, 0x00]);
+    let formatter = Formatter::new(Dialect::Asm6);
+    assert_eq!(
+        formatter.instruction_with_state(&decode(&memory), &Cpu::new(), &memory),
+        ".db $AD,$10,$00"
+    );
+}
@@ -0,0 +1,71 @@
+use crate::instruction::rts;
+use crate::machine::{Cpu, Memory, MemoryReadOnly};
+use crate::Address;
+use std::collections::HashMap;
+
+// What to do after a hook has run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    // execute the instruction at the hooked address as normal
+    Continue,
+    // return from the current subroutine, as if an RTS was executed at the hooked address
+    Return,
+    // continue execution at the given address, as if a JMP was executed at the hooked address
+    Jump(Address),
+}
+
+// Memory as seen by a hook
+pub trait HookMemory: Memory + MemoryReadOnly {}
+impl<M: Memory + MemoryReadOnly> HookMemory for M {}
+
+pub type Hook = Box<dyn FnMut(&mut Cpu, &mut dyn HookMemory) -> HookAction>;
+
+// Rust functions to call when execution reaches particular addresses, before the instruction at
+// that address is executed. A hook may change the CPU's registers and memory, which can be used
+// to replace a subroutine with native code, to log events, or to inject faults.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: HashMap<Address, Hook>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces any hook already at `address`
+    pub fn insert<F>(&mut self, address: Address, hook: F)
+    where
+        F: FnMut(&mut Cpu, &mut dyn HookMemory) -> HookAction + 'static,
+    {
+        self.hooks.insert(address, Box::new(hook));
+    }
+
+    pub fn remove(&mut self, address: Address) -> bool {
+        self.hooks.remove(&address).is_some()
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        self.hooks.contains_key(&address)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    // Runs the hook at the CPU's PC, if there is one. Returns the number of cycles taken if the
+    // hook replaced the instruction at the PC, and None if the instruction should be executed.
+    // If a hook changes the PC and continues, the instruction at the new PC is executed without
+    // running its hook.
+    pub fn run<M: Memory + MemoryReadOnly>(&mut self, cpu: &mut Cpu, memory: &mut M) -> Option<u8> {
+        let hook = self.hooks.get_mut(&cpu.pc)?;
+        match hook(cpu, memory) {
+            HookAction::Continue => None,
+            HookAction::Return => Some(rts::interpret(cpu, memory as &mut dyn HookMemory)),
+            HookAction::Jump(address) => {
+                cpu.pc = address;
+                Some(3)
+            }
+        }
+    }
+}
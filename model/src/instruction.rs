@@ -166,16 +166,14 @@ pub mod ahx {
     }
     impl AddressingMode for IndirectYIndexed {
         fn address_and_num_cycles<M: Memory>(cpu: &Cpu, memory: &mut M) -> (Address, u8) {
-            let (address, cross_page_boundary) =
-                Self::address_check_cross_page_boundary(cpu, memory);
-            (address, 5u8.wrapping_add(cross_page_boundary as u8))
+            let (address, _) = Self::address_check_cross_page_boundary(cpu, memory);
+            (address, 6)
         }
     }
     impl AddressingMode for AbsoluteYIndexed {
         fn address_and_num_cycles<M: Memory>(cpu: &Cpu, memory: &mut M) -> (Address, u8) {
-            let (address, cross_page_boundary) =
-                Self::address_check_cross_page_boundary(cpu, memory);
-            (address, 4u8.wrapping_add(cross_page_boundary as u8))
+            let (address, _) = Self::address_check_cross_page_boundary(cpu, memory);
+            (address, 5)
         }
     }
     pub struct Inst<A: AddressingMode>(pub A);
@@ -316,9 +314,11 @@ pub mod and {
     }
     impl AddressingMode for IndirectYIndexed {
         fn read_data_with_cycles<M: Memory>(cpu: &Cpu, memory: &mut M) -> DataWithCycles {
+            let (data, page_boundary_cross) =
+                Self::read_data_check_cross_page_boundary(cpu, memory);
             DataWithCycles {
-                data: Self::read_data(cpu, memory),
-                cycles: 5,
+                data,
+                cycles: 5u8 + page_boundary_cross as u8,
             }
         }
     }
@@ -809,7 +809,7 @@ pub mod cli {
 }
 pub mod clv {
     use super::*;
-    use opcode::cli::*;
+    use opcode::clv::*;
     pub struct Inst;
     impl AssemblerInstruction for Inst {
         type AddressingMode = Implied;
@@ -2265,9 +2265,11 @@ pub mod ora {
     }
     impl AddressingMode for IndirectYIndexed {
         fn read_data_with_cycles<M: Memory>(cpu: &Cpu, memory: &mut M) -> DataWithCycles {
+            let (data, page_boundary_cross) =
+                Self::read_data_check_cross_page_boundary(cpu, memory);
             DataWithCycles {
-                data: Self::read_data(cpu, memory),
-                cycles: 5,
+                data,
+                cycles: 5u8 + page_boundary_cross as u8,
             }
         }
     }
@@ -2813,7 +2815,7 @@ pub mod rts {
             IMPLIED
         }
     }
-    pub fn interpret<M: Memory>(cpu: &mut Cpu, memory: &mut M) -> u8 {
+    pub fn interpret<M: Memory + ?Sized>(cpu: &mut Cpu, memory: &mut M) -> u8 {
         let return_address_lo = cpu.pop_stack_u8(memory);
         let return_address_hi = cpu.pop_stack_u8(memory);
         cpu.pc = address::from_u8_lo_hi(return_address_lo, return_address_hi).wrapping_add(1);
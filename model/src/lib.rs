@@ -1,11 +1,19 @@
 pub mod addressing_mode;
 pub mod assembler_instruction;
 pub mod debug;
+pub mod flat_memory;
+pub mod format;
+pub mod hook;
 pub mod instruction;
 pub mod machine;
 pub mod opcode;
 pub mod operand;
+pub mod parse;
 pub mod status;
+pub mod timing;
+
+#[cfg(test)]
+mod test;
 
 pub use addressing_mode::Trait as AddressingMode;
 pub use assembler_instruction::Trait as AssemblerInstruction;
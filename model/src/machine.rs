@@ -1,4 +1,5 @@
 use crate::addressing_mode::*;
+use crate::hook::Hooks;
 use crate::instruction::*;
 pub use crate::{address, status, Address};
 use crate::{opcode, UnknownOpcode};
@@ -16,6 +17,12 @@ pub struct Cpu {
     pub status: StatusRegister,
 }
 
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Cpu {
     pub fn new() -> Self {
         Self {
@@ -45,11 +52,11 @@ impl Cpu {
         self.push_stack_u8(memory, self.status.masked_with_brk_and_expansion());
         self.pc = memory.read_u16_le(crate::interrupt_vector::NMI_LO);
     }
-    pub fn push_stack_u8<M: Memory>(&mut self, memory: &mut M, value: u8) {
+    pub fn push_stack_u8<M: Memory + ?Sized>(&mut self, memory: &mut M, value: u8) {
         memory.write_u8_stack(self.sp, value);
         self.sp = self.sp.wrapping_sub(1);
     }
-    pub fn pop_stack_u8<M: Memory>(&mut self, memory: &mut M) -> u8 {
+    pub fn pop_stack_u8<M: Memory + ?Sized>(&mut self, memory: &mut M) -> u8 {
         self.sp = self.sp.wrapping_add(1);
         memory.read_u8_stack(self.sp)
     }
@@ -67,6 +74,29 @@ impl Cpu {
         }
         Ok(cycle_count)
     }
+    // Executes a single instruction, or the hook which replaces it
+    pub fn step_with_hooks<M: Memory + MemoryReadOnly>(
+        &mut self,
+        memory: &mut M,
+        hooks: &mut Hooks,
+    ) -> Result<u8, UnknownOpcode> {
+        match hooks.run(self, memory) {
+            Some(num_cycles) => Ok(num_cycles),
+            None => self.step(memory),
+        }
+    }
+    pub fn run_for_cycles_with_hooks<M: Memory + MemoryReadOnly>(
+        &mut self,
+        memory: &mut M,
+        num_cycles: usize,
+        hooks: &mut Hooks,
+    ) -> Result<usize, UnknownOpcode> {
+        let mut cycle_count = 0;
+        while cycle_count < num_cycles {
+            cycle_count += self.step_with_hooks(memory, hooks)? as usize;
+        }
+        Ok(cycle_count)
+    }
     pub fn step<M: Memory>(&mut self, memory: &mut M) -> Result<u8, UnknownOpcode> {
         let opcode = memory.read_u8(self.pc);
         let cycles = match opcode {
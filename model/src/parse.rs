@@ -0,0 +1,227 @@
+use crate::debug::{AddressingMode, Instruction};
+use crate::format::{is_unofficial_opcode, Formatter};
+use crate::{address, Address};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownMnemonic(String),
+    InvalidOperand(String),
+    // the instruction exists, but not with the addressing mode of the operand
+    InvalidAddressingMode { mnemonic: String, operand: String },
+    BranchTargetOutOfRange(Address),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic \"{}\"", mnemonic),
+            Self::InvalidOperand(operand) => write!(f, "invalid operand \"{}\"", operand),
+            Self::InvalidAddressingMode { mnemonic, operand } => {
+                write!(f, "\"{}\" can't take the operand \"{}\"", mnemonic, operand)
+            }
+            Self::BranchTargetOutOfRange(target) => {
+                write!(f, "branch target ${:04X} is out of range", target)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(u8),
+    // an address, which is written with zero page addressing if it's on the zero page, unless
+    // prefixed with "a:"
+    Direct { address: Address, absolute: bool },
+    XIndexed { address: Address, absolute: bool },
+    YIndexed { address: Address, absolute: bool },
+    Indirect(Address),
+    XIndexedIndirect(u8),
+    IndirectYIndexed(u8),
+}
+
+impl Operand {
+    // The addressing modes the operand can be assembled with, most preferred first
+    fn addressing_modes(self) -> Vec<AddressingMode> {
+        use AddressingMode::*;
+        let with_zero_page = |address: Address, absolute: bool, zero_page, modes: &[_]| {
+            let mut addressing_modes = Vec::new();
+            if !absolute && address < 0x100 {
+                addressing_modes.push(zero_page);
+            }
+            addressing_modes.extend_from_slice(modes);
+            addressing_modes
+        };
+        match self {
+            Self::None => vec![Implied, Accumulator],
+            Self::Accumulator => vec![Accumulator],
+            Self::Immediate(_) => vec![Immediate],
+            Self::Direct { address, absolute } => {
+                with_zero_page(address, absolute, ZeroPage, &[Absolute, Relative])
+            }
+            Self::XIndexed { address, absolute } => {
+                with_zero_page(address, absolute, ZeroPageXIndexed, &[AbsoluteXIndexed])
+            }
+            Self::YIndexed { address, absolute } => {
+                with_zero_page(address, absolute, ZeroPageYIndexed, &[AbsoluteYIndexed])
+            }
+            Self::Indirect(_) => vec![Indirect],
+            Self::XIndexedIndirect(_) => vec![XIndexedIndirect],
+            Self::IndirectYIndexed(_) => vec![IndirectYIndexed],
+        }
+    }
+
+    fn value(self) -> Address {
+        match self {
+            Self::None | Self::Accumulator => 0,
+            Self::Immediate(value)
+            | Self::XIndexedIndirect(value)
+            | Self::IndirectYIndexed(value) => value as Address,
+            Self::Direct { address, .. }
+            | Self::XIndexed { address, .. }
+            | Self::YIndexed { address, .. }
+            | Self::Indirect(address) => address,
+        }
+    }
+}
+
+// Numbers are decimal, or hex with a "$" prefix, or binary with a "%" prefix
+pub fn parse_number(s: &str) -> Option<u16> {
+    if let Some(hex) = s.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = s.strip_prefix('%') {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        s.parse().ok()
+    }
+}
+
+fn parse_operand(s: &str) -> Result<Operand, ParseError> {
+    let invalid = || ParseError::InvalidOperand(s.to_string());
+    let number = |s: &str| parse_number(s).ok_or_else(invalid);
+    let byte = |s: &str| number(s).and_then(|value| u8::try_from(value).map_err(|_| invalid()));
+    let direct = |s: &str| match s.strip_prefix("a:") {
+        Some(s) => number(s).map(|address| (address, true)),
+        None => number(s).map(|address| (address, false)),
+    };
+    let operand = s.replace(' ', "").to_lowercase();
+    let operand = operand.as_str();
+    if operand.is_empty() {
+        return Ok(Operand::None);
+    }
+    if operand == "a" {
+        return Ok(Operand::Accumulator);
+    }
+    if let Some(value) = operand.strip_prefix('#') {
+        return byte(value).map(Operand::Immediate);
+    }
+    if let Some(inner) = operand.strip_prefix('(') {
+        if let Some(inner) = inner.strip_suffix("),y") {
+            return byte(inner).map(Operand::IndirectYIndexed);
+        }
+        if let Some(inner) = inner.strip_suffix(",x)") {
+            return byte(inner).map(Operand::XIndexedIndirect);
+        }
+        if let Some(inner) = inner.strip_suffix(')') {
+            return direct(inner).map(|(address, _)| Operand::Indirect(address));
+        }
+        return Err(invalid());
+    }
+    if let Some(address) = operand.strip_suffix(",x") {
+        let (address, absolute) = direct(address)?;
+        return Ok(Operand::XIndexed { address, absolute });
+    }
+    if let Some(address) = operand.strip_suffix(",y") {
+        let (address, absolute) = direct(address)?;
+        return Ok(Operand::YIndexed { address, absolute });
+    }
+    let (address, absolute) = direct(operand)?;
+    Ok(Operand::Direct { address, absolute })
+}
+
+// The opcode of an instruction, preferring official opcodes, and then the lowest opcode, as
+// assemblers do
+fn find_opcode(mnemonic: &str, addressing_mode: AddressingMode) -> Option<u8> {
+    let formatter = Formatter::default();
+    (0..=0xFF)
+        .filter_map(|opcode| {
+            Instruction::from_opcode(opcode)
+                .ok()
+                .map(|instruction| (opcode, instruction))
+        })
+        .filter(|(_, instruction)| {
+            instruction.addressing_mode() == addressing_mode
+                && formatter.mnemonic(instruction.instruction_type()) == mnemonic
+        })
+        .min_by_key(|&(opcode, instruction)| (is_unofficial_opcode(opcode, instruction), opcode))
+        .map(|(opcode, _)| opcode)
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    let formatter = Formatter::default();
+    (0..=0xFF).any(|opcode| {
+        Instruction::from_opcode(opcode)
+            .map(|instruction| formatter.mnemonic(instruction.instruction_type()) == mnemonic)
+            .unwrap_or(false)
+    })
+}
+
+// Assembles a line of assembly in the syntax instructions are formatted in by default (i.e.
+// ca65), into the bytes to store at an address. The address is used to compute the offsets of
+// branches. A line may also be a ".byte" directive followed by comma-separated values.
+pub fn assemble_line(line: &str, address: Address) -> Result<Vec<u8>, ParseError> {
+    let line = line.split(';').next().unwrap_or_default().trim();
+    let (mnemonic, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let mnemonic = mnemonic.to_lowercase();
+    if mnemonic == ".byte" {
+        return operand
+            .split(',')
+            .map(|value| {
+                parse_number(value.trim())
+                    .and_then(|value| u8::try_from(value).ok())
+                    .ok_or_else(|| ParseError::InvalidOperand(value.trim().to_string()))
+            })
+            .collect();
+    }
+    if !is_mnemonic(&mnemonic) {
+        return Err(ParseError::UnknownMnemonic(mnemonic));
+    }
+    let parsed_operand = parse_operand(operand)?;
+    let (opcode, addressing_mode) = parsed_operand
+        .addressing_modes()
+        .into_iter()
+        .find_map(|addressing_mode| {
+            find_opcode(&mnemonic, addressing_mode).map(|opcode| (opcode, addressing_mode))
+        })
+        .ok_or_else(|| ParseError::InvalidAddressingMode {
+            mnemonic: mnemonic.clone(),
+            operand: operand.trim().to_string(),
+        })?;
+    let value = parsed_operand.value();
+    let mut bytes = vec![opcode];
+    match addressing_mode {
+        AddressingMode::Implied | AddressingMode::Accumulator => (),
+        AddressingMode::Relative => {
+            let offset = value.wrapping_sub(address.wrapping_add(2)) as i16;
+            let offset =
+                i8::try_from(offset).map_err(|_| ParseError::BranchTargetOutOfRange(value))?;
+            bytes.push(offset as u8);
+        }
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteXIndexed
+        | AddressingMode::AbsoluteYIndexed
+        | AddressingMode::Indirect => {
+            bytes.push(address::lo(value));
+            bytes.push(address::hi(value));
+        }
+        AddressingMode::Immediate
+        | AddressingMode::ZeroPage
+        | AddressingMode::ZeroPageXIndexed
+        | AddressingMode::ZeroPageYIndexed
+        | AddressingMode::XIndexedIndirect
+        | AddressingMode::IndirectYIndexed => bytes.push(value as u8),
+    }
+    Ok(bytes)
+}
@@ -29,6 +29,12 @@ const MASK: u8 = !(flag::BRK | flag::EXPANSION);
 pub struct Register {
     raw: u8,
 }
+impl Default for Register {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Register {
     pub fn new() -> Self {
         Self {
@@ -38,6 +44,9 @@ impl Register {
     pub fn masked_with_brk_and_expansion(&self) -> u8 {
         self.raw | flag::BRK | flag::EXPANSION
     }
+    pub fn masked_with_expansion(&self) -> u8 {
+        self.raw | flag::EXPANSION
+    }
     pub fn set(&mut self, value: u8) {
         self.raw = value & MASK;
     }
@@ -100,6 +109,26 @@ impl Register {
     }
 }
 use std::fmt;
+// Flags which are set are shown in upper case, e.g. "nvdIZc"
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (is_set, flag) in [
+            (self.is_negative(), 'n'),
+            (self.is_overflow(), 'v'),
+            (self.is_decimal(), 'd'),
+            (self.is_interrupt_disable(), 'i'),
+            (self.is_zero(), 'z'),
+            (self.is_carry(), 'c'),
+        ] {
+            if is_set {
+                write!(f, "{}", flag.to_ascii_uppercase())?;
+            } else {
+                write!(f, "{}", flag)?;
+            }
+        }
+        Ok(())
+    }
+}
 impl fmt::Debug for Register {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -0,0 +1,138 @@
+use crate::debug::{AddressingMode, Instruction};
+use crate::flat_memory::FlatMemory;
+use crate::machine::{Cpu, Memory};
+use crate::Address;
+
+const PC: Address = 0x1080;
+const ZERO_PAGE_POINTER: u8 = 0x10;
+const BASE_ADDRESS: Address = 0x30F0;
+
+// Runs a single instruction with operands chosen such that indexed addressing
+// crosses a page boundary iff `page_cross`, returning the number of cycles and
+// the address of the next instruction
+fn step(opcode: u8, page_cross: bool, status: u8) -> (u8, Address) {
+    let mut memory = FlatMemory::new();
+    let mut cpu = Cpu::new();
+    cpu.pc = PC;
+    cpu.sp = 0xFD;
+    cpu.status.set(status);
+    let index = if page_cross { 0x20 } else { 0x01 };
+    cpu.x = index;
+    cpu.y = index;
+    let [lo, hi] = BASE_ADDRESS.to_le_bytes();
+    let instruction = Instruction::from_opcode(opcode).unwrap();
+    let operand = match instruction.addressing_mode() {
+        AddressingMode::Relative => {
+            // the branch target is on the next page iff `page_cross`
+            vec![if page_cross { 0x7F } else { 0x04 }]
+        }
+        AddressingMode::IndirectYIndexed => {
+            cpu.x = 0;
+            vec![ZERO_PAGE_POINTER]
+        }
+        AddressingMode::XIndexedIndirect => {
+            cpu.x = 0;
+            vec![ZERO_PAGE_POINTER]
+        }
+        AddressingMode::ZeroPage
+        | AddressingMode::ZeroPageXIndexed
+        | AddressingMode::ZeroPageYIndexed
+        | AddressingMode::Immediate => vec![ZERO_PAGE_POINTER],
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteXIndexed
+        | AddressingMode::AbsoluteYIndexed
+        | AddressingMode::Indirect => vec![lo, hi],
+        AddressingMode::Accumulator | AddressingMode::Implied => vec![],
+    };
+    memory.write_u8(ZERO_PAGE_POINTER as Address, lo);
+    memory.write_u8(ZERO_PAGE_POINTER as Address + 1, hi);
+    memory.write_u8(PC, opcode);
+    for (i, &byte) in operand.iter().enumerate() {
+        memory.write_u8(PC + 1 + i as Address, byte);
+    }
+    let cycles = cpu.step(&mut memory).unwrap();
+    (cycles, cpu.pc)
+}
+
+#[test]
+fn timing_matches_interpreter_for_every_opcode() {
+    let mut mismatches = Vec::new();
+    for opcode in 0..=255u8 {
+        let instruction = match Instruction::from_opcode(opcode) {
+            Ok(instruction) => instruction,
+            Err(_) => continue,
+        };
+        let timing = instruction.timing();
+        let next = PC + instruction.size() as Address;
+        if matches!(instruction.addressing_mode(), AddressingMode::Relative) {
+            // one of these two statuses takes the branch and the other doesn't
+            for status in [0x00, 0xFF] {
+                for page_cross in [false, true] {
+                    let (cycles, pc) = step(opcode, page_cross, status);
+                    let expected = if pc == next {
+                        timing.min_cycles()
+                    } else if page_cross {
+                        timing.max_cycles()
+                    } else {
+                        timing.min_cycles() + 1
+                    };
+                    if cycles != expected {
+                        mismatches.push((instruction, page_cross, status, cycles, expected));
+                    }
+                }
+            }
+        } else {
+            for page_cross in [false, true] {
+                let (cycles, _) = step(opcode, page_cross, 0);
+                let expected = if page_cross {
+                    timing.max_cycles()
+                } else {
+                    timing.min_cycles()
+                };
+                if cycles != expected {
+                    mismatches.push((instruction, page_cross, 0, cycles, expected));
+                }
+            }
+        }
+    }
+    assert!(
+        mismatches.is_empty(),
+        "(instruction, page cross, status, interpreter, table): {:#?}",
+        mismatches
+    );
+}
+
+#[test]
+fn every_branch_is_taken_by_exactly_one_status() {
+    for opcode in 0..=255u8 {
+        if let Ok(instruction) = Instruction::from_opcode(opcode) {
+            if matches!(instruction.addressing_mode(), AddressingMode::Relative) {
+                let next = PC + 2;
+                let taken = [0x00, 0xFF]
+                    .iter()
+                    .filter(|&&status| step(opcode, false, status).1 != next)
+                    .count();
+                assert_eq!(taken, 1, "{:?}", instruction.instruction_type());
+            }
+        }
+    }
+}
+
+#[test]
+fn and_ora_indirect_y_pay_for_page_crossing() {
+    // AND (zp),Y and ORA (zp),Y
+    for opcode in [0x31, 0x11] {
+        assert_eq!(step(opcode, false, 0).0, 5);
+        assert_eq!(step(opcode, true, 0).0, 6);
+    }
+}
+
+#[test]
+fn ahx_takes_a_fixed_number_of_cycles() {
+    // AHX (zp),Y
+    assert_eq!(step(0x93, false, 0).0, 6);
+    assert_eq!(step(0x93, true, 0).0, 6);
+    // AHX abs,Y
+    assert_eq!(step(0x9F, false, 0).0, 5);
+    assert_eq!(step(0x9F, true, 0).0, 5);
+}
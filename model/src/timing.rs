@@ -0,0 +1,112 @@
+use crate::debug::{AddressingMode, Instruction, InstructionType};
+use crate::UnknownOpcode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Penalty {
+    None,
+    // One extra cycle when the effective address is on a different page to the
+    // base address
+    PageCross,
+    // One extra cycle when the branch is taken, and another when the branch
+    // target is on a different page to the next instruction
+    Branch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub base_cycles: u8,
+    pub penalty: Penalty,
+}
+
+impl Timing {
+    pub fn min_cycles(&self) -> u8 {
+        self.base_cycles
+    }
+    pub fn max_cycles(&self) -> u8 {
+        match self.penalty {
+            Penalty::None => self.base_cycles,
+            Penalty::PageCross => self.base_cycles + 1,
+            Penalty::Branch => self.base_cycles + 2,
+        }
+    }
+}
+
+enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+fn access(instruction_type: InstructionType) -> Option<Access> {
+    use InstructionType::*;
+    match instruction_type {
+        Adc | And | Bit | Cmp | Cpx | Cpy | Eor | Ign | Lax | Lda | Ldx | Ldy | Ora | Sbc => {
+            Some(Access::Read)
+        }
+        Ahx | Sax | Sta | Stx | Sty | Sxa | Sya => Some(Access::Write),
+        Asl | Dcp | Dec | Inc | Isc | Lsr | Rla | Rol | Ror | Rra | Slo | Sre => {
+            Some(Access::ReadModifyWrite)
+        }
+        _ => None,
+    }
+}
+
+fn memory_timing(access: Access, addressing_mode: AddressingMode) -> Timing {
+    use AddressingMode::*;
+    let (base_cycles, penalty) = match (access, addressing_mode) {
+        (_, Accumulator) | (_, Immediate) | (_, Implied) => (2, Penalty::None),
+        (Access::Read, ZeroPage) | (Access::Write, ZeroPage) => (3, Penalty::None),
+        (Access::Read, ZeroPageXIndexed | ZeroPageYIndexed)
+        | (Access::Write, ZeroPageXIndexed | ZeroPageYIndexed) => (4, Penalty::None),
+        (Access::Read, Absolute) | (Access::Write, Absolute) => (4, Penalty::None),
+        (Access::Read, AbsoluteXIndexed | AbsoluteYIndexed) => (4, Penalty::PageCross),
+        (Access::Write, AbsoluteXIndexed | AbsoluteYIndexed) => (5, Penalty::None),
+        (Access::Read, IndirectYIndexed) => (5, Penalty::PageCross),
+        (Access::Write, IndirectYIndexed) => (6, Penalty::None),
+        (Access::Read, XIndexedIndirect) | (Access::Write, XIndexedIndirect) => (6, Penalty::None),
+        (Access::ReadModifyWrite, ZeroPage) => (5, Penalty::None),
+        (Access::ReadModifyWrite, ZeroPageXIndexed | ZeroPageYIndexed) => (6, Penalty::None),
+        (Access::ReadModifyWrite, Absolute) => (6, Penalty::None),
+        (Access::ReadModifyWrite, AbsoluteXIndexed | AbsoluteYIndexed) => (7, Penalty::None),
+        (Access::ReadModifyWrite, XIndexedIndirect | IndirectYIndexed) => (8, Penalty::None),
+        (_, Indirect) | (_, Relative) => {
+            unreachable!("no memory access instructions use this mode")
+        }
+    };
+    Timing {
+        base_cycles,
+        penalty,
+    }
+}
+
+impl Instruction {
+    pub fn timing(&self) -> Timing {
+        use InstructionType::*;
+        let fixed = |base_cycles| Timing {
+            base_cycles,
+            penalty: Penalty::None,
+        };
+        if let Some(access) = access(self.instruction_type()) {
+            return memory_timing(access, self.addressing_mode());
+        }
+        match self.instruction_type() {
+            Bcc | Bcs | Beq | Bmi | Bne | Bpl | Bvc | Bvs => Timing {
+                base_cycles: 2,
+                penalty: Penalty::Branch,
+            },
+            Brk => fixed(7),
+            Jmp => match self.addressing_mode() {
+                AddressingMode::Indirect => fixed(5),
+                _ => fixed(3),
+            },
+            Jsr | Rti | Rts => fixed(6),
+            Pha | Php => fixed(3),
+            Pla | Plp => fixed(4),
+            _ => fixed(2),
+        }
+    }
+}
+
+pub fn from_opcode(opcode: u8) -> Result<Timing, UnknownOpcode> {
+    Instruction::from_opcode(opcode).map(|instruction| instruction.timing())
+}
@@ -0,0 +1,637 @@
+use mos6502_assembler::{SourceLine, SymbolFile};
+use mos6502_model::Address;
+use nes_emulator_core::debugger::{
+    Breakpoint, BreakpointId, CallKind, Condition, Debugger, StopReason,
+};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+pub mod protocol;
+mod target;
+
+#[cfg(test)]
+mod test;
+
+use target::Target;
+
+// There's a single CPU, so a single thread
+const THREAD_ID: u64 = 1;
+
+const REGISTERS_REFERENCE: u64 = 1;
+const ZERO_PAGE_REFERENCE: u64 = 2;
+const STACK_REFERENCE: u64 = 3;
+
+const STACK_PAGE: Address = 0x0100;
+
+fn instruction_reference(address: Address) -> String {
+    format!("0x{:04X}", address)
+}
+
+// Addresses in launch arguments and instruction references are numbers, or hex strings starting
+// with "0x" or "$"
+fn parse_address(value: &Value) -> Result<Address, String> {
+    if let Some(number) = value.as_u64() {
+        return Address::try_from(number).map_err(|_| format!("address out of range: {}", number));
+    }
+    let s = value
+        .as_str()
+        .ok_or_else(|| format!("expected an address: {}", value))?;
+    let hex = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix('$'))
+        .ok_or_else(|| {
+            format!(
+                "expected a hex address starting with \"0x\" or \"$\": {}",
+                s
+            )
+        })?;
+    Address::from_str_radix(hex, 16).map_err(|e| format!("invalid address \"{}\": {}", s, e))
+}
+
+// Creates an execute breakpoint from the condition and hit condition of a breakpoint in a request
+fn breakpoint_at(address: Address, request_breakpoint: &Value) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::execute(address);
+    if let Some(condition) = request_breakpoint["condition"].as_str() {
+        breakpoint = breakpoint.with_condition(condition.parse::<Condition>()?);
+    }
+    if let Some(hit_condition) = request_breakpoint["hitCondition"].as_str() {
+        let hit_count = hit_condition
+            .trim()
+            .parse()
+            .map_err(|_| format!("expected a number of hits: \"{}\"", hit_condition))?;
+        breakpoint = breakpoint.with_hit_count(hit_count);
+    }
+    Ok(breakpoint)
+}
+
+enum Running {
+    Continue,
+    // a step the debugger finishes by itself
+    Instruction,
+    // instructions are stepped until reaching a different source line, or a different call depth
+    Line {
+        into: bool,
+        start: SourceLine,
+        depth: usize,
+    },
+}
+
+struct Session {
+    target: Target,
+    debugger: Debugger,
+    symbol_file: SymbolFile,
+    // source files in the symbol file are relative to this directory
+    source_root: PathBuf,
+    stop_on_entry: bool,
+    running: Option<Running>,
+    // the breakpoints in the debugger which make up each breakpoint reported to the client
+    breakpoints: BTreeMap<u64, Vec<BreakpointId>>,
+    next_breakpoint_id: u64,
+    source_breakpoints: HashMap<String, Vec<u64>>,
+    function_breakpoints: Vec<u64>,
+    instruction_breakpoints: Vec<u64>,
+}
+
+impl Session {
+    fn current_line(&self) -> Option<&SourceLine> {
+        self.symbol_file.source_line(self.target.cpu().pc)
+    }
+
+    fn add_breakpoint(&mut self, addresses: &[Address], request_breakpoint: &Value) -> Value {
+        let breakpoints = match addresses
+            .iter()
+            .map(|&address| breakpoint_at(address, request_breakpoint))
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(breakpoints) => breakpoints,
+            Err(message) => return json!({ "verified": false, "message": message }),
+        };
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        let debugger_ids = breakpoints
+            .into_iter()
+            .map(|breakpoint| self.debugger.add_breakpoint(breakpoint))
+            .collect();
+        self.breakpoints.insert(id, debugger_ids);
+        json!({
+            "id": id,
+            "verified": true,
+            "instructionReference": instruction_reference(addresses[0]),
+        })
+    }
+
+    fn remove_breakpoints(&mut self, ids: Vec<u64>) {
+        for id in ids {
+            for debugger_id in self.breakpoints.remove(&id).unwrap_or_default() {
+                self.debugger.remove_breakpoint(debugger_id);
+            }
+        }
+    }
+
+    fn breakpoint_containing(&self, debugger_id: BreakpointId) -> Option<u64> {
+        self.breakpoints
+            .iter()
+            .find(|(_, debugger_ids)| debugger_ids.contains(&debugger_id))
+            .map(|(&id, _)| id)
+    }
+
+    fn function_name(&self, address: Address) -> String {
+        self.symbol_file
+            .label_at(address)
+            .map(|label| label.to_string())
+            .unwrap_or_else(|| format!("${:04X}", address))
+    }
+
+    fn stack_frame(&self, id: usize, name: String, pc: Address) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": instruction_reference(pc),
+        });
+        if let Some(source_line) = self.symbol_file.source_line(pc) {
+            let path = self.source_root.join(&source_line.file);
+            let name = Path::new(&source_line.file)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| source_line.file.clone());
+            frame["source"] = json!({ "name": name, "path": path.to_string_lossy() });
+            frame["line"] = json!(source_line.line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    // The innermost frame is at the current instruction, and each frame after it is at the
+    // instruction which called the frame before it
+    fn stack_frames(&self) -> Vec<Value> {
+        let call_stack = self.debugger.call_stack();
+        let mut pc = self.target.cpu().pc;
+        let mut frames = Vec::new();
+        for call_frame in call_stack.iter().rev() {
+            let mut name = self.function_name(call_frame.function);
+            if call_frame.kind == CallKind::Interrupt {
+                name.push_str(" (interrupt)");
+            }
+            frames.push(self.stack_frame(frames.len(), name, pc));
+            pc = call_frame.caller;
+        }
+        frames.push(self.stack_frame(frames.len(), "main".to_string(), pc));
+        frames
+    }
+
+    fn variables(&self, reference: u64) -> Vec<Value> {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let byte = |name: String, address: Address| {
+            variable(name, format!("${:02X}", self.target.read_u8(address)))
+        };
+        let cpu = self.target.cpu();
+        match reference {
+            REGISTERS_REFERENCE => vec![
+                variable("PC".to_string(), format!("${:04X}", cpu.pc)),
+                variable("A".to_string(), format!("${:02X}", cpu.acc)),
+                variable("X".to_string(), format!("${:02X}", cpu.x)),
+                variable("Y".to_string(), format!("${:02X}", cpu.y)),
+                variable("SP".to_string(), format!("${:02X}", cpu.sp)),
+                variable(
+                    "P".to_string(),
+                    format!("${:02X} {}", cpu.status.masked_with_expansion(), cpu.status),
+                ),
+                variable("cycles".to_string(), self.debugger.cycle().to_string()),
+            ],
+            ZERO_PAGE_REFERENCE => (0x00..=0xFF)
+                .map(|address| byte(format!("${:02X}", address), address))
+                .collect(),
+            // from the top of the stack to the bottom
+            STACK_REFERENCE => (cpu.sp as Address + 1..=0xFF)
+                .map(|offset| {
+                    let address = STACK_PAGE + offset;
+                    byte(format!("${:04X}", address), address)
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Handles requests from a single client, writing responses and events
+pub struct Server<W: Write> {
+    writer: W,
+    seq: u64,
+    session: Option<Session>,
+    // events to send after the response to the current request
+    pending_events: Vec<Value>,
+}
+
+impl<W: Write> Server<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            seq: 1,
+            session: None,
+            pending_events: Vec::new(),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|session| session.running.is_some())
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        protocol::write_message(&mut self.writer, &message)
+    }
+
+    fn event(event: &str, body: Value) -> Value {
+        json!({ "type": "event", "event": event, "body": body })
+    }
+
+    fn session(&mut self) -> Result<&mut Session, String> {
+        self.session
+            .as_mut()
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    fn stopped_session(&mut self) -> Result<&mut Session, String> {
+        let session = self.session()?;
+        if session.running.is_some() {
+            return Err("the program is running".to_string());
+        }
+        Ok(session)
+    }
+
+    // Handles a message from the client, returning false when the session has ended
+    pub fn handle(&mut self, message: &Value) -> io::Result<bool> {
+        if message["type"] != "request" {
+            return Ok(true);
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let arguments = &message["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsHitConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSteppingGranularity": true,
+            })),
+            "launch" => self.launch(arguments),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(arguments),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(arguments),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "cpu" }] })),
+            "stackTrace" => self.stack_trace(arguments),
+            "scopes" => Ok(json!({
+                "scopes": [
+                    { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                    { "name": "Zero Page", "variablesReference": ZERO_PAGE_REFERENCE, "expensive": false },
+                    { "name": "Stack", "variablesReference": STACK_REFERENCE, "expensive": false },
+                ]
+            })),
+            "variables" => self.variables(arguments),
+            "continue" => self.continue_(),
+            "next" => self.step(arguments, false),
+            "stepIn" => self.step(arguments, true),
+            "stepOut" => self.step_out(),
+            "pause" => self.pause(),
+            "disconnect" => Ok(json!({})),
+            _ => Err(format!("unsupported request: {}", command)),
+        };
+        let mut response = json!({
+            "type": "response",
+            "request_seq": message["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)?;
+        for event in std::mem::take(&mut self.pending_events) {
+            self.send(event)?;
+        }
+        Ok(command != "disconnect")
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<Value, String> {
+        let program_path = arguments["program"]
+            .as_str()
+            .ok_or("missing \"program\" argument")?;
+        let program = std::fs::read(program_path)
+            .map_err(|e| format!("failed to read {}: {}", program_path, e))?;
+        let is_nes = match arguments["target"].as_str() {
+            Some("nes") => true,
+            Some("flat") => false,
+            Some(other) => return Err(format!("unknown target \"{}\"", other)),
+            None => program_path.ends_with(".nes"),
+        };
+        let target = if is_nes {
+            Target::nes(&program)?
+        } else {
+            // by default the program ends at the top of memory, where the interrupt vectors are
+            let load_address = match &arguments["loadAddress"] {
+                Value::Null => (0x10000 - program.len().min(0x10000)) as Address,
+                value => parse_address(value)?,
+            };
+            let start_address = match &arguments["startAddress"] {
+                Value::Null => None,
+                value => Some(parse_address(value)?),
+            };
+            Target::flat(&program, load_address, start_address)
+        };
+        let symbol_file = match arguments["symbols"].as_str() {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read {}: {}", path, e))?;
+                SymbolFile::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+            }
+            None => SymbolFile::new(),
+        };
+        let source_root = match arguments["sourceRoot"].as_str() {
+            Some(source_root) => PathBuf::from(source_root),
+            None => std::env::current_dir().map_err(|e| e.to_string())?,
+        };
+        self.session = Some(Session {
+            target,
+            debugger: Debugger::new(),
+            symbol_file,
+            source_root,
+            stop_on_entry: arguments["stopOnEntry"].as_bool().unwrap_or(false),
+            running: None,
+            breakpoints: BTreeMap::new(),
+            next_breakpoint_id: 1,
+            source_breakpoints: HashMap::new(),
+            function_breakpoints: Vec::new(),
+            instruction_breakpoints: Vec::new(),
+        });
+        // breakpoints can't be resolved until the symbol file is loaded, so configuration only
+        // starts after launching
+        self.pending_events
+            .push(Self::event("initialized", json!({})));
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let path = arguments["source"]["path"]
+            .as_str()
+            .ok_or("missing source path")?
+            .to_string();
+        let previous = session.source_breakpoints.remove(&path).unwrap_or_default();
+        session.remove_breakpoints(previous);
+        let mut ids = Vec::new();
+        let mut breakpoints = Vec::new();
+        for request_breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = request_breakpoint["line"].as_u64().unwrap_or_default();
+            let addresses = session.symbol_file.addresses_of_line(&path, line as u32);
+            let mut breakpoint = if addresses.is_empty() {
+                json!({ "verified": false, "message": "no instructions at this line" })
+            } else {
+                session.add_breakpoint(&addresses, request_breakpoint)
+            };
+            if let Some(id) = breakpoint["id"].as_u64() {
+                ids.push(id);
+            }
+            breakpoint["line"] = json!(line);
+            breakpoints.push(breakpoint);
+        }
+        session.source_breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_function_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let previous = std::mem::take(&mut session.function_breakpoints);
+        session.remove_breakpoints(previous);
+        let mut breakpoints = Vec::new();
+        for request_breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let name = request_breakpoint["name"].as_str().unwrap_or_default();
+            let breakpoint = match session.symbol_file.address_of_label(name) {
+                Some(address) => session.add_breakpoint(&[address], request_breakpoint),
+                None => {
+                    json!({ "verified": false, "message": format!("unknown label \"{}\"", name) })
+                }
+            };
+            if let Some(id) = breakpoint["id"].as_u64() {
+                session.function_breakpoints.push(id);
+            }
+            breakpoints.push(breakpoint);
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session()?;
+        let previous = std::mem::take(&mut session.instruction_breakpoints);
+        session.remove_breakpoints(previous);
+        let mut breakpoints = Vec::new();
+        for request_breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let offset = request_breakpoint["offset"].as_i64().unwrap_or_default();
+            let breakpoint = match parse_address(&request_breakpoint["instructionReference"]) {
+                Ok(address) => {
+                    let address = address.wrapping_add(offset as Address);
+                    session.add_breakpoint(&[address], request_breakpoint)
+                }
+                Err(message) => json!({ "verified": false, "message": message }),
+            };
+            if let Some(id) = breakpoint["id"].as_u64() {
+                session.instruction_breakpoints.push(id);
+            }
+            breakpoints.push(breakpoint);
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn configuration_done(&mut self) -> Result<Value, String> {
+        let session = self.session()?;
+        if session.stop_on_entry {
+            self.pending_events.push(Self::event(
+                "stopped",
+                json!({ "reason": "entry", "threadId": THREAD_ID, "allThreadsStopped": true }),
+            ));
+        } else {
+            session.running = Some(Running::Continue);
+        }
+        Ok(json!({}))
+    }
+
+    fn stack_trace(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.stopped_session()?;
+        let frames = session.stack_frames();
+        let total_frames = frames.len();
+        let start_frame = arguments["startFrame"].as_u64().unwrap_or_default() as usize;
+        let levels = match arguments["levels"].as_u64() {
+            None | Some(0) => total_frames,
+            Some(levels) => levels as usize,
+        };
+        let frames = frames
+            .into_iter()
+            .skip(start_frame)
+            .take(levels)
+            .collect::<Vec<_>>();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total_frames }))
+    }
+
+    fn variables(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.stopped_session()?;
+        let reference = arguments["variablesReference"].as_u64().unwrap_or_default();
+        Ok(json!({ "variables": session.variables(reference) }))
+    }
+
+    fn continue_(&mut self) -> Result<Value, String> {
+        let session = self.stopped_session()?;
+        session.running = Some(Running::Continue);
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    // Steps by source line unless the client asks for instructions, or the current instruction
+    // has no source line
+    fn step(&mut self, arguments: &Value, into: bool) -> Result<Value, String> {
+        let session = self.stopped_session()?;
+        let by_instruction = arguments["granularity"] == "instruction";
+        if into {
+            session.debugger.step_into();
+        } else {
+            session.debugger.step_over();
+        }
+        session.running = Some(match session.current_line() {
+            Some(start) if !by_instruction => Running::Line {
+                into,
+                start: start.clone(),
+                depth: session.debugger.call_stack().len(),
+            },
+            _ => Running::Instruction,
+        });
+        Ok(json!({}))
+    }
+
+    fn step_out(&mut self) -> Result<Value, String> {
+        let session = self.stopped_session()?;
+        session.debugger.step_out();
+        session.running = Some(Running::Instruction);
+        Ok(json!({}))
+    }
+
+    fn pause(&mut self) -> Result<Value, String> {
+        let session = self.session()?;
+        session.running = None;
+        session.debugger.cancel_step();
+        self.pending_events.push(Self::event(
+            "stopped",
+            json!({ "reason": "pause", "threadId": THREAD_ID, "allThreadsStopped": true }),
+        ));
+        Ok(json!({}))
+    }
+
+    // Runs the program for a short while if it's running, sending an event if it stops
+    pub fn run(&mut self) -> io::Result<()> {
+        let Some(session) = self.session.as_mut() else {
+            return Ok(());
+        };
+        let Some(running) = session.running.as_ref() else {
+            return Ok(());
+        };
+        let Some(reason) = session.target.run(&mut session.debugger) else {
+            return Ok(());
+        };
+        if let (
+            StopReason::Step,
+            &Running::Line {
+                into,
+                ref start,
+                depth,
+            },
+        ) = (reason, running)
+        {
+            let current_depth = session.debugger.call_stack().len();
+            let is_complete = current_depth < depth
+                || session.current_line().is_some_and(|current| {
+                    (current != start || current_depth != depth) && (into || current_depth <= depth)
+                });
+            if !is_complete {
+                if !into && current_depth > depth {
+                    // an interrupt happened during the step
+                    session.debugger.step_out();
+                } else if into {
+                    session.debugger.step_into();
+                } else {
+                    session.debugger.step_over();
+                }
+                return Ok(());
+            }
+        }
+        session.running = None;
+        let mut body = match reason {
+            StopReason::Breakpoint { id, .. } => json!({
+                "reason": "breakpoint",
+                "hitBreakpointIds": session.breakpoint_containing(id).into_iter().collect::<Vec<_>>(),
+            }),
+            StopReason::Step => json!({ "reason": "step" }),
+            StopReason::UnknownOpcode(opcode) => json!({
+                "reason": "exception",
+                "description": format!("unknown opcode ${:02X}", opcode),
+                "text": format!("unknown opcode ${:02X}", opcode),
+            }),
+            StopReason::FrameComplete => json!({ "reason": "pause" }),
+            StopReason::DebugPortBreak => json!({
+                "reason": "breakpoint",
+                "description": "debug port break",
+            }),
+        };
+        body["threadId"] = json!(THREAD_ID);
+        body["allThreadsStopped"] = json!(true);
+        self.send(Self::event("stopped", body))
+    }
+}
+
+// Serves a single client, reading requests from `reader` on another thread so the program can run
+// while waiting for requests. Returns when the client disconnects.
+pub fn serve<R: Read + Send + 'static, W: Write>(reader: R, writer: W) -> io::Result<()> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reader = BufReader::new(reader);
+        loop {
+            let message = protocol::read_message(&mut reader);
+            let is_end = !matches!(message, Ok(Some(_)));
+            if sender.send(message).is_err() || is_end {
+                break;
+            }
+        }
+    });
+    let mut server = Server::new(writer);
+    loop {
+        let message = if server.is_running() {
+            match receiver.try_recv() {
+                Ok(message) => Some(message),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        } else {
+            match receiver.recv() {
+                Ok(message) => Some(message),
+                Err(_) => return Ok(()),
+            }
+        };
+        if let Some(message) = message {
+            let Some(message) = message? else {
+                return Ok(());
+            };
+            if !server.handle(&message)? {
+                return Ok(());
+            }
+        }
+        server.run()?;
+    }
+}
@@ -0,0 +1,31 @@
+use std::io;
+use std::net::TcpListener;
+
+struct Args {
+    port: Option<u16>,
+}
+
+impl Args {
+    fn parser() -> impl meap::Parser<Item = Self> {
+        meap::let_map! {
+            let {
+                port = opt_opt::<u16, _>("PORT", 'p').name("port").desc("accept a single connection on this port of localhost instead of using stdin and stdout");
+            } in {
+                Self { port }
+            }
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    use meap::Parser;
+    let Args { port } = Args::parser().with_help_default().parse_env_or_exit();
+    match port {
+        None => mos6502_dap::serve(io::stdin(), io::stdout()),
+        Some(port) => {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            let (stream, _) = listener.accept()?;
+            mos6502_dap::serve(stream.try_clone()?, stream)
+        }
+    }
+}
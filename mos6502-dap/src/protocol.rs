@@ -0,0 +1,47 @@
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+
+const CONTENT_LENGTH: &str = "Content-Length:";
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+// Reads a message framed with a Content-Length header. Returns `None` at the end of the input.
+pub fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            // a blank line ends the header, unless it's stray whitespace between messages
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(length) = line.strip_prefix(CONTENT_LENGTH) {
+            content_length = Some(length.trim().parse::<usize>().map_err(invalid_data)?);
+        }
+    }
+    let mut content = vec![0; content_length.unwrap()];
+    reader.read_exact(&mut content)?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(invalid_data)
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(
+        writer,
+        "{} {}\r\n\r\n{}",
+        CONTENT_LENGTH,
+        content.len(),
+        content
+    )?;
+    writer.flush()
+}
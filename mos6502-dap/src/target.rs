@@ -0,0 +1,71 @@
+use ines::Ines;
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::machine::{Cpu, MemoryReadOnly};
+use mos6502_model::Address;
+use nes_emulator_core::debugger::{Debugger, StopReason};
+use nes_emulator_core::dynamic_nes::DynamicNes;
+use nes_render_output::NoRenderOutput;
+
+// The number of instructions to run a flat memory program for between checking for requests
+const FLAT_INSTRUCTIONS_PER_RUN: usize = 10_000;
+
+// The machine being debugged
+pub enum Target {
+    Nes(Box<DynamicNes>),
+    Flat { cpu: Cpu, memory: FlatMemory },
+}
+
+impl Target {
+    pub fn nes(rom: &[u8]) -> Result<Self, String> {
+        let ines = Ines::parse(rom).map_err(|e| format!("failed to parse rom: {:?}", e))?;
+        let dynamic_nes =
+            DynamicNes::from_ines(&ines).map_err(|e| format!("failed to load rom: {:?}", e))?;
+        Ok(Self::Nes(Box::new(dynamic_nes)))
+    }
+
+    // Loads a program into 64KB of RAM. Without a start address, the CPU starts at the address in
+    // the reset vector.
+    pub fn flat(program: &[u8], load_address: Address, start_address: Option<Address>) -> Self {
+        let mut memory = FlatMemory::new();
+        memory.load(load_address, program);
+        let mut cpu = Cpu::new();
+        match start_address {
+            Some(start_address) => cpu.pc = start_address,
+            None => cpu.start(&mut memory),
+        }
+        Self::Flat { cpu, memory }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        match self {
+            Self::Nes(dynamic_nes) => dynamic_nes.cpu(),
+            Self::Flat { cpu, .. } => cpu,
+        }
+    }
+
+    pub fn read_u8(&self, address: Address) -> u8 {
+        match self {
+            Self::Nes(dynamic_nes) => dynamic_nes.read_u8_read_only(address),
+            Self::Flat { memory, .. } => memory.read_u8_read_only(address),
+        }
+    }
+
+    // Runs for a frame on a NES, or for a fixed number of instructions on a bare CPU, returning
+    // early if the debugger stops emulation
+    pub fn run(&mut self, debugger: &mut Debugger) -> Option<StopReason> {
+        match self {
+            Self::Nes(dynamic_nes) => {
+                match dynamic_nes
+                    .run_until_break(debugger, &mut NoRenderOutput)
+                    .reason
+                {
+                    StopReason::FrameComplete => None,
+                    reason => Some(reason),
+                }
+            }
+            Self::Flat { cpu, memory } => {
+                (0..FLAT_INSTRUCTIONS_PER_RUN).find_map(|_| debugger.step(cpu, memory).1)
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use crate::protocol::{read_message, write_message};
+use ines::Ines;
+use mos6502_assembler::Block;
+use mos6502_model::interrupt_vector;
+use samples::{Factorial, Sample, PRG_START};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::io::{self, BufReader, PipeReader, PipeWriter};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+const FACTORIAL_SOURCE: &str = "samples/src/factorial.rs";
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("..")
+}
+
+// The line number of the first line of the factorial sample containing some text
+fn factorial_line(text: &str) -> u64 {
+    let source = std::fs::read_to_string(workspace_root().join(FACTORIAL_SOURCE)).unwrap();
+    source.lines().position(|line| line.contains(text)).unwrap() as u64 + 1
+}
+
+// A client which runs a server on another thread, checking that every request succeeds
+struct Client {
+    writer: PipeWriter,
+    reader: BufReader<PipeReader>,
+    seq: u64,
+    events: VecDeque<Value>,
+    server: JoinHandle<io::Result<()>>,
+}
+
+impl Client {
+    fn new() -> Self {
+        let (server_reader, writer) = io::pipe().unwrap();
+        let (reader, server_writer) = io::pipe().unwrap();
+        let server = thread::spawn(move || crate::serve(server_reader, server_writer));
+        Self {
+            writer,
+            reader: BufReader::new(reader),
+            seq: 1,
+            events: VecDeque::new(),
+            server,
+        }
+    }
+
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        let seq = self.seq;
+        self.seq += 1;
+        let request = json!({
+            "seq": seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        });
+        write_message(&mut self.writer, &request).unwrap();
+        loop {
+            let message = read_message(&mut self.reader).unwrap().unwrap();
+            if message["type"] == "response" {
+                assert_eq!(message["request_seq"], seq);
+                assert_eq!(message["success"], true, "{}", message);
+                return message["body"].clone();
+            }
+            self.events.push_back(message);
+        }
+    }
+
+    fn expect_event(&mut self, event: &str) -> Value {
+        let message = match self.events.pop_front() {
+            Some(message) => message,
+            None => read_message(&mut self.reader).unwrap().unwrap(),
+        };
+        assert_eq!(message["event"], event, "{}", message);
+        message["body"].clone()
+    }
+
+    fn expect_stopped(&mut self, reason: &str) {
+        let body = self.expect_event("stopped");
+        assert_eq!(body["reason"], reason, "{}", body);
+    }
+
+    fn stack_trace(&mut self) -> Vec<Value> {
+        let body = self.request("stackTrace", json!({ "threadId": 1 }));
+        body["stackFrames"].as_array().unwrap().clone()
+    }
+
+    fn variable(&mut self, variables_reference: u64, name: &str) -> String {
+        let body = self.request(
+            "variables",
+            json!({ "variablesReference": variables_reference }),
+        );
+        let variable = body["variables"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|variable| variable["name"] == name)
+            .unwrap()
+            .clone();
+        variable["value"].as_str().unwrap().to_string()
+    }
+
+    fn disconnect(mut self) {
+        self.request("disconnect", json!({}));
+        self.server.join().unwrap().unwrap();
+    }
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("mos6502-dap-test-{}-{}", std::process::id(), name))
+}
+
+// Writes the factorial sample as a 16KB image ending with the interrupt vectors, returning the
+// paths of the program and its symbol file
+fn write_factorial() -> (PathBuf, PathBuf) {
+    let mut block = Block::new();
+    Factorial::program(&mut block);
+    block.set_offset(interrupt_vector::START_LO - PRG_START);
+    block.literal_offset_le(0);
+    let mut program = Vec::new();
+    let assembled_block = block.assemble(PRG_START, 0x4000, &mut program).unwrap();
+    let program_path = temp_path("factorial.bin");
+    let symbols_path = temp_path("factorial.sym");
+    std::fs::write(&program_path, program).unwrap();
+    let mut symbols = Vec::new();
+    assembled_block.symbol_file().write(&mut symbols).unwrap();
+    std::fs::write(&symbols_path, symbols).unwrap();
+    (program_path, symbols_path)
+}
+
+#[test]
+fn flat_memory_source_stepping() {
+    let (program_path, symbols_path) = write_factorial();
+    let source_path = workspace_root().join(FACTORIAL_SOURCE);
+    let factorial = factorial_line("b.label(\"factorial\")") + 1;
+    let multiply_call = factorial_line("b.inst(Jsr(Absolute), \"multiply\")");
+    let recursive_call = multiply_call - 1;
+    let mut client = Client::new();
+    let capabilities = client.request("initialize", json!({ "adapterID": "mos6502" }));
+    assert_eq!(capabilities["supportsConfigurationDoneRequest"], true);
+    client.request(
+        "launch",
+        json!({
+            "program": program_path,
+            "symbols": symbols_path,
+            "sourceRoot": workspace_root(),
+            "stopOnEntry": true,
+        }),
+    );
+    client.expect_event("initialized");
+    let body = client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": source_path }, "breakpoints": [{ "line": factorial }] }),
+    );
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+    client.request("configurationDone", json!({}));
+    client.expect_stopped("entry");
+    let frames = client.stack_trace();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(
+        frames[0]["line"],
+        factorial_line("b.inst(Ldx(Immediate), 0)")
+    );
+
+    // the first call, from the top level
+    client.request("continue", json!({ "threadId": 1 }));
+    client.expect_stopped("breakpoint");
+    let frames = client.stack_trace();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0]["name"], "factorial");
+    assert_eq!(frames[0]["line"], factorial);
+    assert_eq!(frames[0]["source"]["path"], source_path.to_str().unwrap());
+
+    // the recursive call
+    client.request("continue", json!({ "threadId": 1 }));
+    client.expect_stopped("breakpoint");
+    let frames = client.stack_trace();
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[1]["name"], "factorial");
+    assert_eq!(frames[1]["line"], recursive_call);
+    assert_eq!(frames[2]["name"], "main");
+
+    client.request(
+        "setBreakpoints",
+        json!({ "source": { "path": source_path }, "breakpoints": [] }),
+    );
+    client.request("next", json!({ "threadId": 1 }));
+    client.expect_stopped("step");
+    assert_eq!(client.stack_trace()[0]["line"], factorial + 1);
+    let pc = client.variable(1, "PC");
+    client.request(
+        "stepIn",
+        json!({ "threadId": 1, "granularity": "instruction" }),
+    );
+    client.expect_stopped("step");
+    let stepped_pc = client.variable(1, "PC");
+    let address = |pc: &str| u16::from_str_radix(&pc[1..], 16).unwrap();
+    assert_eq!(address(&stepped_pc), address(&pc) + 2);
+
+    // stepping out of multiply returns to the instruction after the call
+    let body = client.request(
+        "setFunctionBreakpoints",
+        json!({ "breakpoints": [{ "name": "multiply" }] }),
+    );
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+    client.request("continue", json!({ "threadId": 1 }));
+    client.expect_stopped("breakpoint");
+    assert_eq!(client.stack_trace()[0]["name"], "multiply");
+    client.request("stepOut", json!({ "threadId": 1 }));
+    client.expect_stopped("step");
+    let frames = client.stack_trace();
+    assert_eq!(frames[0]["name"], "factorial");
+    assert_eq!(frames[0]["line"], multiply_call + 1);
+
+    // the program ends in an infinite loop after storing 5! at the start of the zero page
+    client.request("setFunctionBreakpoints", json!({ "breakpoints": [] }));
+    client.request("continue", json!({ "threadId": 1 }));
+    client.request("pause", json!({ "threadId": 1 }));
+    client.expect_stopped("pause");
+    assert_eq!(
+        client.stack_trace()[0]["line"],
+        factorial_line("b.inst(Jmp(Absolute), \"loop\")")
+    );
+    assert_eq!(client.variable(2, "$00"), "$78");
+    client.disconnect();
+    std::fs::remove_file(program_path).unwrap();
+    std::fs::remove_file(symbols_path).unwrap();
+}
+
+#[test]
+fn nes_pause_and_instruction_breakpoint() {
+    let rom_path = workspace_root().join("test-assets/conway.nes");
+    let ines = Ines::parse(&std::fs::read(&rom_path).unwrap()).unwrap();
+    // the reset vector is the second to last vector at the end of prg rom
+    let vector_offset = ines.prg_rom.len() - 4;
+    let reset = u16::from_le_bytes([ines.prg_rom[vector_offset], ines.prg_rom[vector_offset + 1]]);
+    let mut client = Client::new();
+    client.request("initialize", json!({ "adapterID": "mos6502" }));
+    client.request(
+        "launch",
+        json!({ "program": rom_path, "stopOnEntry": true }),
+    );
+    client.expect_event("initialized");
+    client.request("configurationDone", json!({}));
+    client.expect_stopped("entry");
+    assert_eq!(client.variable(1, "PC"), format!("${:04X}", reset));
+
+    // the program spends most of its time waiting for vblank, so pausing stops in a loop which
+    // is soon reached again
+    client.request("continue", json!({ "threadId": 1 }));
+    client.request("pause", json!({ "threadId": 1 }));
+    client.expect_stopped("pause");
+    let pc = client.stack_trace()[0]["instructionPointerReference"].clone();
+    let body = client.request(
+        "setInstructionBreakpoints",
+        json!({ "breakpoints": [{ "instructionReference": pc }] }),
+    );
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+    client.request("continue", json!({ "threadId": 1 }));
+    client.expect_stopped("breakpoint");
+    assert_eq!(client.stack_trace()[0]["instructionPointerReference"], pc);
+    client.disconnect();
+}
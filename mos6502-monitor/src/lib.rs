@@ -0,0 +1,475 @@
+use mos6502_model::debug::{InstructionType, InstructionWithOperand};
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::format::Formatter;
+use mos6502_model::machine::{Cpu, MemoryReadOnly};
+use mos6502_model::{address, opcode, parse, Address};
+use std::fmt::Write;
+
+#[cfg(test)]
+mod test;
+
+const DISASSEMBLY_LINES: usize = 16;
+const DUMP_BYTES_PER_LINE: usize = 16;
+const DUMP_LINES: usize = 8;
+// "g" gives up after this many instructions, in case the program never stops
+const MAX_GO_INSTRUCTIONS: u64 = 10_000_000;
+// "g" calls routines as if from a JSR at this address, and stops when they return to it
+const RETURN_ADDRESS: Address = 0xFFFF;
+
+pub const HELP: &str = "\
+a ADDR [INSTRUCTION]      assemble lines starting at ADDR until an empty line
+d [START [END]]           disassemble
+m [START [END]]           dump memory
+f START END BYTE...       fill memory with a repeating pattern of bytes
+t START END DEST          move (transfer) memory
+r [REG=VALUE...]          show or set registers (pc, a, x, y, sp, p)
+g [ADDR]                  call a routine, running until it returns or reaches a BRK
+z [COUNT]                 step COUNT instructions, tracing each one
+trace [on|off]            show or set whether g traces each instruction
+l FILE ADDR               load a binary file into memory
+s FILE START END          save memory to a binary file
+x                         exit
+Numbers in commands are hex, with an optional \"$\" prefix. Instructions are in ca65 syntax,
+where hex numbers need the \"$\" prefix.";
+
+// Addresses and bytes in commands are hex, with an optional "$" prefix
+fn parse_hex(s: &str) -> Result<u16, String> {
+    u16::from_str_radix(s.strip_prefix('$').unwrap_or(s), 16)
+        .map_err(|_| format!("expected a hex number but got \"{}\"", s))
+}
+
+fn parse_hex_u8(s: &str) -> Result<u8, String> {
+    parse_hex(s).and_then(|value| {
+        u8::try_from(value).map_err(|_| format!("expected a byte but got \"{}\"", s))
+    })
+}
+
+fn argument<'a>(arguments: &[&'a str], index: usize, name: &str) -> Result<&'a str, String> {
+    arguments
+        .get(index)
+        .cloned()
+        .ok_or_else(|| format!("missing {}", name))
+}
+
+// A classic machine code monitor for a bare CPU with 64KB of RAM. Each command is a line of
+// text, and produces text to show to the user.
+pub struct Monitor {
+    cpu: Cpu,
+    memory: FlatMemory,
+    formatter: Formatter<'static>,
+    cycles: u64,
+    // where the next line is assembled, while assembling
+    assembling_at: Option<Address>,
+    // where "d" and "m" continue from without a start address
+    next_disassembly: Address,
+    next_dump: Address,
+    trace: bool,
+    exited: bool,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self {
+            cpu: Cpu::new(),
+            memory: FlatMemory::new(),
+            formatter: Formatter::default(),
+            cycles: 0,
+            assembling_at: None,
+            next_disassembly: 0,
+            next_dump: 0,
+            trace: false,
+            exited: false,
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn memory(&self) -> &FlatMemory {
+        &self.memory
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    pub fn prompt(&self) -> String {
+        match self.assembling_at {
+            Some(address) => format!("{:04X}  ", address),
+            None => format!("({:04X}) ", self.cpu.pc),
+        }
+    }
+
+    // Runs a command, returning its output
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        if let Some(address) = self.assembling_at {
+            if line.trim().is_empty() {
+                self.assembling_at = None;
+                return Ok(String::new());
+            }
+            return self.assemble(address, line);
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+        let arguments = words.collect::<Vec<_>>();
+        match command {
+            "a" => {
+                let address = parse_hex(argument(&arguments, 0, "address")?)?;
+                self.assembling_at = Some(address);
+                if arguments.len() > 1 {
+                    self.assemble(address, &arguments[1..].join(" "))
+                } else {
+                    Ok(String::new())
+                }
+            }
+            "d" => self.disassemble(&arguments),
+            "m" => self.dump(&arguments),
+            "f" => self.fill(&arguments),
+            "t" => self.transfer(&arguments),
+            "r" => self.registers(&arguments),
+            "g" => self.go(&arguments),
+            "z" => self.step(&arguments),
+            "trace" => {
+                match arguments.first().cloned() {
+                    None => (),
+                    Some("on") => self.trace = true,
+                    Some("off") => self.trace = false,
+                    Some(other) => return Err(format!("expected on or off but got \"{}\"", other)),
+                }
+                Ok(format!("trace {}\n", if self.trace { "on" } else { "off" }))
+            }
+            "l" => self.load(&arguments),
+            "s" => self.save(&arguments),
+            "x" => {
+                self.exited = true;
+                Ok(String::new())
+            }
+            "help" | "?" => Ok(format!("{}\n", HELP)),
+            other => Err(format!("unknown command \"{}\" (try \"help\")", other)),
+        }
+    }
+
+    // Assembles a line at an address, moving on to the next line's address
+    fn assemble(&mut self, address: Address, line: &str) -> Result<String, String> {
+        let bytes = parse::assemble_line(line, address).map_err(|e| e.to_string())?;
+        self.memory.load(address, &bytes);
+        self.assembling_at = Some(address.wrapping_add(bytes.len() as Address));
+        let mut output = String::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let line_address = address.wrapping_add(offset as Address);
+            let next = self.disassemble_line(&mut output, line_address);
+            offset += next.wrapping_sub(line_address) as usize;
+        }
+        Ok(output)
+    }
+
+    // Formats the instruction at an address, returning the address after it. Unknown opcodes are
+    // shown as bytes.
+    fn disassemble_line(&self, output: &mut String, address: Address) -> Address {
+        let (bytes, text) = match InstructionWithOperand::decode(address, &self.memory) {
+            Ok(instruction_with_operand) => (
+                std::iter::once(instruction_with_operand.opcode())
+                    .chain(instruction_with_operand.operand().iter().cloned())
+                    .collect::<Vec<_>>(),
+                self.formatter.instruction(&instruction_with_operand),
+            ),
+            Err(unknown_opcode) => (
+                vec![unknown_opcode.0],
+                format!(".byte ${:02X}", unknown_opcode.0),
+            ),
+        };
+        let hex = bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(output, "{:04X}  {:<9} {}", address, hex, text).unwrap();
+        address.wrapping_add(bytes.len() as Address)
+    }
+
+    // A range of addresses from optional start and end arguments. Without an end, each command
+    // shows a fixed amount from the start.
+    fn range(
+        arguments: &[&str],
+        default_start: Address,
+    ) -> Result<(Address, Option<Address>), String> {
+        let start = match arguments.first() {
+            Some(start) => parse_hex(start)?,
+            None => default_start,
+        };
+        let end = arguments.get(1).map(|end| parse_hex(end)).transpose()?;
+        if let Some(end) = end {
+            if end < start {
+                return Err(format!("${:04X} is before ${:04X}", end, start));
+            }
+        }
+        Ok((start, end))
+    }
+
+    fn disassemble(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let (start, end) = Self::range(arguments, self.next_disassembly)?;
+        let mut output = String::new();
+        let mut address = start;
+        let mut num_lines = 0;
+        loop {
+            let next = self.disassemble_line(&mut output, address);
+            num_lines += 1;
+            let is_done = match end {
+                Some(end) => next > end || next < address,
+                None => num_lines == DISASSEMBLY_LINES,
+            };
+            address = next;
+            if is_done {
+                break;
+            }
+        }
+        self.next_disassembly = address;
+        Ok(output)
+    }
+
+    fn dump(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let (start, end) = Self::range(arguments, self.next_dump)?;
+        let end = end.unwrap_or_else(|| {
+            start.saturating_add((DUMP_BYTES_PER_LINE * DUMP_LINES) as Address - 1)
+        });
+        let mut output = String::new();
+        let bytes = &self.memory.bytes()[start as usize..=end as usize];
+        for (i, line) in bytes.chunks(DUMP_BYTES_PER_LINE).enumerate() {
+            let hex = line
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let ascii = line
+                .iter()
+                .map(|&byte| {
+                    if byte.is_ascii_graphic() || byte == b' ' {
+                        byte as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect::<String>();
+            let address = start as usize + i * DUMP_BYTES_PER_LINE;
+            writeln!(
+                output,
+                "{:04X}  {:<width$}  {}",
+                address,
+                hex,
+                ascii,
+                width = DUMP_BYTES_PER_LINE * 3 - 1
+            )
+            .unwrap();
+        }
+        self.next_dump = end.wrapping_add(1);
+        Ok(output)
+    }
+
+    fn fill(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let start = parse_hex(argument(arguments, 0, "start address")?)?;
+        let end = parse_hex(argument(arguments, 1, "end address")?)?;
+        let pattern = arguments[2.min(arguments.len())..]
+            .iter()
+            .map(|byte| parse_hex_u8(byte))
+            .collect::<Result<Vec<_>, _>>()?;
+        if pattern.is_empty() {
+            return Err("missing bytes to fill with".to_string());
+        }
+        if end < start {
+            return Err(format!("${:04X} is before ${:04X}", end, start));
+        }
+        let bytes = &mut self.memory.bytes_mut()[start as usize..=end as usize];
+        for (byte, &value) in bytes.iter_mut().zip(pattern.iter().cycle()) {
+            *byte = value;
+        }
+        Ok(String::new())
+    }
+
+    fn transfer(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let start = parse_hex(argument(arguments, 0, "start address")?)?;
+        let end = parse_hex(argument(arguments, 1, "end address")?)?;
+        let destination = parse_hex(argument(arguments, 2, "destination address")?)?;
+        if end < start {
+            return Err(format!("${:04X} is before ${:04X}", end, start));
+        }
+        // copied first so overlapping ranges are moved correctly
+        let bytes = self.memory.bytes()[start as usize..=end as usize].to_vec();
+        self.memory.load(destination, &bytes);
+        Ok(String::new())
+    }
+
+    fn registers_line(&self) -> String {
+        format!(
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{} CYC:{}\n",
+            self.cpu.pc,
+            self.cpu.acc,
+            self.cpu.x,
+            self.cpu.y,
+            self.cpu.sp,
+            self.cpu.status,
+            self.cycles
+        )
+    }
+
+    fn registers(&mut self, arguments: &[&str]) -> Result<String, String> {
+        for assignment in arguments {
+            let (register, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| format!("expected REG=VALUE but got \"{}\"", assignment))?;
+            match register.to_lowercase().as_str() {
+                "pc" => self.cpu.pc = parse_hex(value)?,
+                "a" => self.cpu.acc = parse_hex_u8(value)?,
+                "x" => self.cpu.x = parse_hex_u8(value)?,
+                "y" => self.cpu.y = parse_hex_u8(value)?,
+                "sp" => self.cpu.sp = parse_hex_u8(value)?,
+                "p" => self.cpu.status.set(parse_hex_u8(value)?),
+                other => return Err(format!("unknown register \"{}\"", other)),
+            }
+        }
+        Ok(self.registers_line())
+    }
+
+    fn trace_line(&self, output: &mut String, instruction_with_operand: &InstructionWithOperand) {
+        let bytes = std::iter::once(instruction_with_operand.opcode())
+            .chain(instruction_with_operand.operand().iter().cloned())
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = self.formatter.instruction_with_state(
+            instruction_with_operand,
+            &self.cpu,
+            &self.memory,
+        );
+        writeln!(
+            output,
+            "{:04X}  {:<9} {:<32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.cpu.pc,
+            bytes,
+            text,
+            self.cpu.acc,
+            self.cpu.x,
+            self.cpu.y,
+            self.cpu.status.masked_with_expansion(),
+            self.cpu.sp,
+            self.cycles
+        )
+        .unwrap();
+    }
+
+    // Executes an instruction, tracing it if `trace` is set
+    fn step_instruction(
+        &mut self,
+        output: &mut String,
+        trace: bool,
+    ) -> Result<InstructionType, String> {
+        let instruction_with_operand = InstructionWithOperand::next(&self.cpu, &self.memory)
+            .map_err(|unknown_opcode| {
+                format!(
+                    "unknown opcode ${:02X} at ${:04X}",
+                    unknown_opcode.0, self.cpu.pc
+                )
+            })?;
+        if trace {
+            self.trace_line(output, &instruction_with_operand);
+        }
+        self.cycles += self.cpu.step(&mut self.memory).unwrap() as u64;
+        Ok(instruction_with_operand.instruction().instruction_type())
+    }
+
+    // Calls the routine at the given address or the PC as if with a JSR, so it can return to
+    // the monitor
+    fn go(&mut self, arguments: &[&str]) -> Result<String, String> {
+        if let Some(address) = arguments.first() {
+            self.cpu.pc = parse_hex(address)?;
+        }
+        let start = self.cpu.pc;
+        let stack_pointer = self.cpu.sp;
+        // JSR pushes the address of its last byte, which RTS increments
+        let return_address = RETURN_ADDRESS.wrapping_sub(1);
+        self.cpu
+            .push_stack_u8(&mut self.memory, address::hi(return_address));
+        self.cpu
+            .push_stack_u8(&mut self.memory, address::lo(return_address));
+        let mut output = String::new();
+        for _ in 0..MAX_GO_INSTRUCTIONS {
+            if self.cpu.pc == RETURN_ADDRESS && self.cpu.sp == stack_pointer {
+                writeln!(output, "returned from ${:04X}", start).unwrap();
+                self.cpu.pc = start;
+                break;
+            }
+            if self.memory.read_u8_read_only(self.cpu.pc) == opcode::brk::IMPLIED {
+                writeln!(output, "BRK at ${:04X}", self.cpu.pc).unwrap();
+                break;
+            }
+            let result = self.step_instruction(&mut output, self.trace);
+            let instruction_type = match result {
+                Ok(instruction_type) => instruction_type,
+                Err(message) => {
+                    writeln!(output, "{}", message).unwrap();
+                    break;
+                }
+            };
+            // the routine returned somewhere else, e.g. by changing its return address
+            let is_return = matches!(
+                instruction_type,
+                InstructionType::Rts | InstructionType::Rti
+            );
+            if is_return && (self.cpu.sp.wrapping_sub(stack_pointer) as i8) > 0 {
+                writeln!(output, "returned to ${:04X}", self.cpu.pc).unwrap();
+                break;
+            }
+        }
+        output.push_str(&self.registers_line());
+        Ok(output)
+    }
+
+    fn step(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let count = match arguments.first() {
+            Some(count) => parse_hex(count)?,
+            None => 1,
+        };
+        let mut output = String::new();
+        for _ in 0..count {
+            if let Err(message) = self.step_instruction(&mut output, true) {
+                writeln!(output, "{}", message).unwrap();
+                break;
+            }
+        }
+        output.push_str(&self.registers_line());
+        Ok(output)
+    }
+
+    fn load(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let path = argument(arguments, 0, "file")?;
+        let address = parse_hex(argument(arguments, 1, "address")?)?;
+        let bytes = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+        self.memory.load(address, &bytes);
+        Ok(format!(
+            "loaded ${:04X}-${:04X}\n",
+            address,
+            address.wrapping_add(bytes.len().saturating_sub(1) as Address)
+        ))
+    }
+
+    fn save(&mut self, arguments: &[&str]) -> Result<String, String> {
+        let path = argument(arguments, 0, "file")?;
+        let start = parse_hex(argument(arguments, 1, "start address")?)?;
+        let end = parse_hex(argument(arguments, 2, "end address")?)?;
+        if end < start {
+            return Err(format!("${:04X} is before ${:04X}", end, start));
+        }
+        std::fs::write(path, &self.memory.bytes()[start as usize..=end as usize])
+            .map_err(|e| format!("failed to write {}: {}", path, e))?;
+        Ok(String::new())
+    }
+}
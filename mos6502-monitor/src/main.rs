@@ -0,0 +1,28 @@
+use mos6502_monitor::Monitor;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+fn main() -> io::Result<()> {
+    let mut monitor = Monitor::new();
+    let stdin = io::stdin();
+    // prompts are only shown interactively, so scripts piped to stdin produce clean output
+    let interactive = stdin.is_terminal();
+    let mut stdout = io::stdout();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            write!(stdout, "{}", monitor.prompt())?;
+            stdout.flush()?;
+        }
+        let Some(line) = lines.next() else {
+            break;
+        };
+        match monitor.execute(&line?) {
+            Ok(output) => write!(stdout, "{}", output)?,
+            Err(message) => writeln!(stdout, "error: {}", message)?,
+        }
+        if monitor.has_exited() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,146 @@
+use crate::Monitor;
+use mos6502_model::debug::InstructionWithOperand;
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::format::Formatter;
+use mos6502_model::parse::assemble_line;
+
+fn run(monitor: &mut Monitor, commands: &[&str]) -> String {
+    commands
+        .iter()
+        .map(|command| monitor.execute(command).unwrap())
+        .collect()
+}
+
+#[test]
+fn every_opcode_reassembles() {
+    let address = 0xC000;
+    for opcode in 0..=0xFF {
+        // the second operand puts absolute addresses on the zero page
+        for operand in [[0x12, 0x34], [0x12, 0x00]] {
+            let mut memory = FlatMemory::new();
+            memory.load(address, &[opcode, operand[0], operand[1]]);
+            let Ok(instruction_with_operand) = InstructionWithOperand::decode(address, &memory)
+            else {
+                continue;
+            };
+            let text = Formatter::default().instruction(&instruction_with_operand);
+            let size = instruction_with_operand.instruction().size();
+            assert_eq!(
+                assemble_line(&text, address),
+                Ok(memory.bytes()[address as usize..address as usize + size].to_vec()),
+                "{}",
+                text
+            );
+        }
+    }
+}
+
+#[test]
+fn assemble_and_run_a_routine() {
+    let mut monitor = Monitor::new();
+    // adds the bytes at $0200..=$0203 into $10
+    let output = run(
+        &mut monitor,
+        &[
+            "a c000",
+            "ldx #3",
+            "lda #0",
+            "clc",
+            "adc $0200,x",
+            "dex",
+            "bpl $c005",
+            "sta $10",
+            "rts",
+            "",
+            "f 200 203 01 02",
+            "t 200 201 202",
+        ],
+    );
+    assert!(output.contains("C005  7D 00 02  adc $0200,x"), "{}", output);
+    assert!(output.contains("C009  10 FA     bpl $C005"), "{}", output);
+    assert_eq!(&monitor.memory().bytes()[0x200..0x204], &[1, 2, 1, 2]);
+    let stack_pointer = monitor.cpu().sp;
+    let output = run(&mut monitor, &["g c000"]);
+    assert!(output.starts_with("returned from $C000"), "{}", output);
+    assert_eq!(monitor.cpu().sp, stack_pointer);
+    assert_eq!(monitor.memory().bytes()[0x10], 6);
+    assert!(run(&mut monitor, &["m 10 10"]).starts_with("0010  06"));
+
+    // stepping traces each instruction with the registers before it executes
+    let output = run(&mut monitor, &["r pc=c000 a=ff", "z 2"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(
+        lines[1].starts_with("C000  A2 03     ldx #$03"),
+        "{}",
+        output
+    );
+    assert!(lines[1].contains("A:FF X:FF"), "{}", output);
+    assert!(lines[2].contains("A:FF X:03"), "{}", output);
+    assert_eq!(monitor.cpu().pc, 0xC004);
+
+    assert!(monitor.execute("a c000 lda ($1234),y").is_err());
+    assert!(monitor.execute("").is_ok());
+    assert!(monitor.execute("q").is_err());
+}
+
+#[test]
+fn go_stops_when_the_routine_returns_to_the_monitor() {
+    let mut monitor = Monitor::new();
+    let stack_pointer = monitor.cpu().sp;
+    // calls a routine at $C010 which also returns with an RTS
+    run(
+        &mut monitor,
+        &[
+            "a c000",
+            "jsr $c010",
+            "inc $10",
+            "rts",
+            "",
+            "a c010",
+            "inc $10",
+            "rts",
+            "",
+        ],
+    );
+    let output = run(&mut monitor, &["g c000"]);
+    assert!(output.starts_with("returned from $C000\n"), "{}", output);
+    assert_eq!(monitor.memory().bytes()[0x10], 2);
+    assert_eq!(monitor.cpu().sp, stack_pointer);
+    assert_eq!(monitor.cpu().pc, 0xC000);
+    // running again without an address calls the same routine
+    run(&mut monitor, &["g"]);
+    assert_eq!(monitor.memory().bytes()[0x10], 4);
+    assert_eq!(monitor.cpu().sp, stack_pointer);
+
+    run(&mut monitor, &["a c100", "inc $11", "brk", ""]);
+    let output = run(&mut monitor, &["g c100"]);
+    assert!(output.starts_with("BRK at $C102\n"), "{}", output);
+    assert_eq!(monitor.memory().bytes()[0x11], 1);
+
+    // a routine which pulls its return address off the stack returns somewhere else
+    run(
+        &mut monitor,
+        &["r sp=fd", "a c200", "pla", "pla", "rts", ""],
+    );
+    let output = run(&mut monitor, &["g c200"]);
+    assert!(output.starts_with("returned to $"), "{}", output);
+}
+
+#[test]
+fn save_and_load() {
+    let path = std::env::temp_dir().join(format!("mos6502-monitor-test-{}", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut monitor = Monitor::new();
+    run(
+        &mut monitor,
+        &["f 300 30f de ad be ef", &format!("s {} 300 30f", path)],
+    );
+    let mut other = Monitor::new();
+    let output = run(&mut other, &[&format!("l {} 8000", path)]);
+    assert_eq!(output, "loaded $8000-$800F\n");
+    assert_eq!(
+        &other.memory().bytes()[0x8000..0x8010],
+        &monitor.memory().bytes()[0x300..0x310]
+    );
+    std::fs::remove_file(path).unwrap();
+}
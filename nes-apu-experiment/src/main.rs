@@ -188,7 +188,7 @@ fn chr_rom() -> Vec<u8> {
     let mut chr_rom = vec![0; ines::CHR_ROM_BLOCK_BYTES];
     // Leave tile 0 as all 0s to make it transparent.
     // Tile 1 will be all 1s
-    let tile_1_byte_index = 16 * 1;
+    let tile_1_byte_index = 16;
     for row in 0..8 {
         chr_rom[tile_1_byte_index + row] = 0xFF;
     }
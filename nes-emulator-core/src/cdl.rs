@@ -0,0 +1,328 @@
+use crate::nes::{RomMapping, RunForCycles};
+use crate::timing;
+use analyser::{BankedAddress, Observations};
+use ines::Ines;
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::io::{self, Write};
+
+// How each byte of PRG ROM was used
+pub mod prg_rom_flag {
+    // executed as the first byte of an instruction
+    pub const OPCODE: u8 = 1 << 0;
+    // read as the operand of an instruction
+    pub const OPERAND: u8 = 1 << 1;
+    // read or modified by an instruction
+    pub const DATA: u8 = 1 << 2;
+    // read as part of the pointer of a JMP (ptr)
+    pub const POINTER: u8 = 1 << 3;
+    // executed after being jumped to by a JMP (ptr)
+    pub const INDIRECT_CODE: u8 = 1 << 4;
+    // read through a pointer with (zp),Y or (zp,X) addressing
+    pub const INDIRECT_DATA: u8 = 1 << 5;
+
+    pub const CODE: u8 = OPCODE | OPERAND;
+    pub const ANY_DATA: u8 = DATA | POINTER | INDIRECT_DATA;
+}
+
+// How each byte of CHR ROM was used
+pub mod chr_rom_flag {
+    // part of a tile drawn by the PPU
+    pub const RENDERED: u8 = 1 << 0;
+}
+
+// Bits of the FCEUX code/data log format
+mod fceux {
+    pub mod prg_rom_flag {
+        pub const CODE: u8 = 1 << 0;
+        pub const DATA: u8 = 1 << 1;
+        // bits 2 and 3 hold which 8K window of the CPU address space the byte was mapped into
+        pub const WINDOW_SHIFT: u8 = 2;
+        pub const INDIRECT_CODE: u8 = 1 << 4;
+        pub const INDIRECT_DATA: u8 = 1 << 5;
+    }
+    pub mod chr_rom_flag {
+        pub const RENDERED: u8 = 1 << 0;
+    }
+}
+
+// Records how a program uses each byte of the cartridge's ROM while it runs. PRG ROM is logged
+// instruction by instruction. CHR ROM is logged by sampling the tiles in the name tables and OAM
+// about once per frame, so tiles which are only shown between samples (e.g. by changing banks
+// mid-frame) can be missed.
+#[derive(Debug, Clone)]
+pub struct CodeDataLogger {
+    prg_rom: Vec<u8>,
+    chr_rom: Vec<u8>,
+    // Where each byte of PRG ROM was mapped the last time it was used
+    prg_rom_locations: Vec<Option<BankedAddress>>,
+    cycles_since_chr_rom_sample: u32,
+}
+
+fn reads_memory(instruction_type: InstructionType) -> bool {
+    use InstructionType::*;
+    matches!(
+        instruction_type,
+        Adc | And
+            | Asl
+            | Bit
+            | Cmp
+            | Cpx
+            | Cpy
+            | Dcp
+            | Dec
+            | Eor
+            | Ign
+            | Inc
+            | Isc
+            | Lax
+            | Lda
+            | Ldx
+            | Ldy
+            | Lsr
+            | Ora
+            | Rla
+            | Rol
+            | Ror
+            | Rra
+            | Sbc
+            | Slo
+            | Sre
+    )
+}
+
+fn read_zero_page_u16_le<M: MemoryReadOnly>(memory: &M, address: u8) -> Address {
+    let lo = memory.read_u8_read_only(address as Address);
+    let hi = memory.read_u8_read_only(address.wrapping_add(1) as Address);
+    ((hi as Address) << 8) | lo as Address
+}
+
+// Returns the address of the byte an instruction is about to read, and whether that address
+// was read from a pointer. Zero page reads are ignored as they can't touch ROM.
+pub(crate) fn data_read_address<M: MemoryReadOnly>(
+    cpu: &Cpu,
+    memory: &M,
+    instruction_with_operand: &InstructionWithOperand,
+) -> Option<(Address, bool)> {
+    let instruction = instruction_with_operand.instruction();
+    if !reads_memory(instruction.instruction_type()) {
+        return None;
+    }
+    match instruction.addressing_mode() {
+        AddressingMode::Absolute => Some((instruction_with_operand.operand_u16_le()?, false)),
+        AddressingMode::AbsoluteXIndexed => Some((
+            instruction_with_operand
+                .operand_u16_le()?
+                .wrapping_add(cpu.x as Address),
+            false,
+        )),
+        AddressingMode::AbsoluteYIndexed => Some((
+            instruction_with_operand
+                .operand_u16_le()?
+                .wrapping_add(cpu.y as Address),
+            false,
+        )),
+        AddressingMode::IndirectYIndexed => {
+            let pointer = instruction_with_operand.operand_u8()?;
+            let base = read_zero_page_u16_le(memory, pointer);
+            Some((base.wrapping_add(cpu.y as Address), true))
+        }
+        AddressingMode::XIndexedIndirect => {
+            let pointer = instruction_with_operand.operand_u8()?.wrapping_add(cpu.x);
+            Some((read_zero_page_u16_le(memory, pointer), true))
+        }
+        _ => None,
+    }
+}
+
+// Returns the addresses of the bytes of the pointer read by a JMP (ptr), including the bug where
+// the high byte is read from the start of the page when the pointer straddles a page boundary
+fn jump_indirect_pointer(
+    instruction_with_operand: &InstructionWithOperand,
+) -> Option<[Address; 2]> {
+    let instruction = instruction_with_operand.instruction();
+    if instruction.instruction_type() != InstructionType::Jmp
+        || instruction.addressing_mode() != AddressingMode::Indirect
+    {
+        return None;
+    }
+    let lo = instruction_with_operand.operand_u16_le()?;
+    let hi = (lo & 0xFF00) | (lo.wrapping_add(1) & 0x00FF);
+    Some([lo, hi])
+}
+
+impl CodeDataLogger {
+    pub fn new(prg_rom_bytes: usize, chr_rom_bytes: usize) -> Self {
+        Self {
+            prg_rom: vec![0; prg_rom_bytes],
+            chr_rom: vec![0; chr_rom_bytes],
+            prg_rom_locations: vec![None; prg_rom_bytes],
+            cycles_since_chr_rom_sample: 0,
+        }
+    }
+
+    pub fn from_ines(ines: &Ines) -> Self {
+        Self::new(ines.prg_rom.len(), ines.chr_rom.len())
+    }
+
+    // Flags from `prg_rom_flag` for each byte of PRG ROM
+    pub fn prg_rom_flags(&self) -> &[u8] {
+        &self.prg_rom
+    }
+
+    // Flags from `chr_rom_flag` for each byte of CHR ROM
+    pub fn chr_rom_flags(&self) -> &[u8] {
+        &self.chr_rom
+    }
+
+    fn mark_prg_rom<M: RomMapping>(&mut self, memory: &M, address: Address, flags: u8) {
+        if self.prg_rom.is_empty() {
+            return;
+        }
+        if let Some(offset) = memory.prg_rom_offset(address) {
+            // ROMs smaller than the address space are mirrored
+            let offset = offset % self.prg_rom.len();
+            self.prg_rom[offset] |= flags;
+            self.prg_rom_locations[offset] = memory
+                .prg_rom_bank(address)
+                .map(|bank| BankedAddress { bank, address });
+        }
+    }
+
+    fn sample_chr_rom<M: RomMapping>(&mut self, memory: &M) {
+        if self.chr_rom.is_empty() {
+            return;
+        }
+        let chr_rom = &mut self.chr_rom;
+        let chr_rom_bytes = chr_rom.len();
+        memory.for_each_chr_rom_offset_in_use(|offset| {
+            chr_rom[offset % chr_rom_bytes] |= chr_rom_flag::RENDERED;
+        });
+    }
+
+    // Executes a single instruction, logging the ROM it uses
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        let instruction_with_operand = InstructionWithOperand::next(cpu, memory).ok();
+        let mut is_jump_indirect = false;
+        if let Some(instruction_with_operand) = instruction_with_operand.as_ref() {
+            let address = instruction_with_operand.address();
+            self.mark_prg_rom(memory, address, prg_rom_flag::OPCODE);
+            for i in 1..instruction_with_operand.instruction().size() {
+                self.mark_prg_rom(
+                    memory,
+                    address.wrapping_add(i as Address),
+                    prg_rom_flag::OPERAND,
+                );
+            }
+            if let Some((data_address, through_pointer)) =
+                data_read_address(cpu, memory, instruction_with_operand)
+            {
+                let flags = if through_pointer {
+                    prg_rom_flag::DATA | prg_rom_flag::INDIRECT_DATA
+                } else {
+                    prg_rom_flag::DATA
+                };
+                self.mark_prg_rom(memory, data_address, flags);
+            }
+            if let Some(pointer) = jump_indirect_pointer(instruction_with_operand) {
+                for pointer_address in pointer {
+                    self.mark_prg_rom(memory, pointer_address, prg_rom_flag::POINTER);
+                }
+                is_jump_indirect = true;
+            }
+        }
+        let num_cycles = cpu.step(memory)?;
+        if is_jump_indirect {
+            self.mark_prg_rom(memory, cpu.pc, prg_rom_flag::INDIRECT_CODE);
+        }
+        self.cycles_since_chr_rom_sample += num_cycles as u32;
+        if self.cycles_since_chr_rom_sample >= timing::ntsc::APPROX_CPU_CYCLES_PER_FRAME {
+            self.cycles_since_chr_rom_sample -= timing::ntsc::APPROX_CPU_CYCLES_PER_FRAME;
+            self.sample_chr_rom(memory);
+        }
+        Ok(num_cycles)
+    }
+
+    // Writes the log in the format of FCEUX's .cdl files: one byte per byte of PRG ROM followed
+    // by one byte per byte of CHR ROM. Unlike FCEUX, which logs each pattern fetch made by the
+    // PPU, CHR ROM usage here is an approximation built from the once-per-frame samples, so it
+    // can miss tiles which FCEUX would mark as rendered.
+    pub fn write_fceux_cdl<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let prg_rom = self
+            .prg_rom
+            .iter()
+            .zip(self.prg_rom_locations.iter())
+            .map(|(&flags, location)| {
+                let mut fceux_flags = 0;
+                if flags & prg_rom_flag::CODE != 0 {
+                    fceux_flags |= fceux::prg_rom_flag::CODE;
+                }
+                if flags & prg_rom_flag::ANY_DATA != 0 {
+                    fceux_flags |= fceux::prg_rom_flag::DATA;
+                }
+                if flags & prg_rom_flag::INDIRECT_CODE != 0 {
+                    fceux_flags |= fceux::prg_rom_flag::INDIRECT_CODE;
+                }
+                if flags & prg_rom_flag::INDIRECT_DATA != 0 {
+                    fceux_flags |= fceux::prg_rom_flag::INDIRECT_DATA;
+                }
+                if let Some(location) = location {
+                    let window = ((location.address >> 13) & 3) as u8;
+                    fceux_flags |= window << fceux::prg_rom_flag::WINDOW_SHIFT;
+                }
+                fceux_flags
+            })
+            .collect::<Vec<_>>();
+        let chr_rom = self
+            .chr_rom
+            .iter()
+            .map(|&flags| {
+                if flags & chr_rom_flag::RENDERED != 0 {
+                    fceux::chr_rom_flag::RENDERED
+                } else {
+                    0
+                }
+            })
+            .collect::<Vec<_>>();
+        writer.write_all(&prg_rom)?;
+        writer.write_all(&chr_rom)
+    }
+
+    // Adds the bytes known to be code or data to `observations`, so analysis can treat them as
+    // such regardless of what it would otherwise infer
+    pub fn add_to_observations(&self, observations: &mut Observations) {
+        for (&flags, location) in self.prg_rom.iter().zip(self.prg_rom_locations.iter()) {
+            let location = match location {
+                Some(location) => *location,
+                None => continue,
+            };
+            if flags & prg_rom_flag::OPCODE != 0 {
+                observations.opcodes.insert(location);
+            } else if flags & prg_rom_flag::CODE == 0 && flags & prg_rom_flag::ANY_DATA != 0 {
+                observations.data.insert(location);
+            }
+        }
+    }
+}
+
+impl RunForCycles for CodeDataLogger {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        let mut count = 0;
+        while count < num_cycles {
+            count += self.step(cpu, memory).unwrap() as u32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
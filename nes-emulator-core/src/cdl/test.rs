@@ -0,0 +1,75 @@
+use super::*;
+use crate::test::*;
+use mos6502_assembler::{AssembledBlock, LabelOffsetHi, LabelOffsetLo};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_render_output::NoRenderOutput;
+
+fn log_one_frame() -> (CodeDataLogger, AssembledBlock) {
+    let (prg_rom, a) = prg_rom(|b| {
+        b.inst(Ldx(Immediate), 1);
+        b.inst(Lda(AbsoluteXIndexed), "table");
+        b.inst(Lda(Immediate), LabelOffsetLo("indirect_table"));
+        b.inst(Sta(ZeroPage), 0x00);
+        b.inst(Lda(Immediate), LabelOffsetHi("indirect_table"));
+        b.inst(Sta(ZeroPage), 0x01);
+        b.inst(Ldy(Immediate), 0);
+        b.inst(Lda(IndirectYIndexed), 0x00);
+        b.inst(Jmp(Indirect), "pointer");
+        b.label("never_run");
+        b.inst(Nop, ());
+        b.label("target");
+        b.inst(Jmp(Absolute), "target");
+        b.label("table");
+        b.literal_bytes([1, 2]);
+        b.label("indirect_table");
+        b.literal_byte(3);
+        b.label("pointer");
+        b.label_offset_le("target");
+    });
+    let mut nes = nes(&prg_rom);
+    let mut code_data_logger = CodeDataLogger::new(prg_rom.len(), 0);
+    nes.run_for_frame_general(&mut code_data_logger, &mut NoRenderOutput, None);
+    (code_data_logger, a)
+}
+
+#[test]
+fn prg_rom_flags() {
+    let (code_data_logger, a) = log_one_frame();
+    let flags = |label: &str, offset: Address| {
+        let address = a.address_of_label(label).unwrap() + offset;
+        code_data_logger.prg_rom_flags()[(address - PRG_START) as usize]
+    };
+    use prg_rom_flag::*;
+    assert_eq!(flags("reset", 0), OPCODE);
+    assert_eq!(flags("reset", 1), OPERAND);
+    assert_eq!(flags("never_run", 0), 0);
+    assert_eq!(flags("target", 0), OPCODE | INDIRECT_CODE);
+    assert_eq!(flags("table", 0), 0);
+    assert_eq!(flags("table", 1), DATA);
+    assert_eq!(flags("indirect_table", 0), DATA | INDIRECT_DATA);
+    assert_eq!(flags("pointer", 0), POINTER);
+    assert_eq!(flags("pointer", 1), POINTER);
+}
+
+#[test]
+fn fceux_prg_rom_flags() {
+    let (code_data_logger, a) = log_one_frame();
+    let mut cdl = Vec::new();
+    code_data_logger.write_fceux_cdl(&mut cdl).unwrap();
+    let flags = |label: &str, offset: Address| {
+        let address = a.address_of_label(label).unwrap() + offset;
+        cdl[(address - PRG_START) as usize]
+    };
+    // bytes at 0xC000..=0xDFFF are in the third 8K window of the CPU address space
+    let window = 2 << fceux::prg_rom_flag::WINDOW_SHIFT;
+    use fceux::prg_rom_flag::*;
+    assert_eq!(flags("reset", 0), CODE | window);
+    assert_eq!(flags("reset", 1), CODE | window);
+    assert_eq!(flags("never_run", 0), 0);
+    assert_eq!(flags("target", 0), CODE | INDIRECT_CODE | window);
+    assert_eq!(flags("table", 0), 0);
+    assert_eq!(flags("table", 1), DATA | window);
+    assert_eq!(flags("indirect_table", 0), DATA | INDIRECT_DATA | window);
+    assert_eq!(flags("pointer", 0), DATA | window);
+    assert_eq!(cdl.len(), ines::PRG_ROM_BLOCK_BYTES);
+}
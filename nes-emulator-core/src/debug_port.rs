@@ -0,0 +1,52 @@
+use mos6502_model::Address;
+
+// The registers are defined in `nes_specs` so test programs can use them without depending on the
+// emulator
+pub use nes_specs::debug_port::{register, ADDRESS_RANGE};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugPortEvent {
+    Exit(u8),
+    Break,
+    Snapshot(u8),
+}
+
+// Collects what a program writes to the debug port, until the frontend takes it
+#[derive(Debug, Clone, Default)]
+pub struct DebugPort {
+    output: Vec<u8>,
+    events: Vec<DebugPortEvent>,
+}
+
+impl DebugPort {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn write_u8(&mut self, address: Address, data: u8) {
+        match address {
+            register::OUTPUT => self.output.push(data),
+            register::EXIT => self.events.push(DebugPortEvent::Exit(data)),
+            register::BREAK => self.events.push(DebugPortEvent::Break),
+            register::SNAPSHOT => self.events.push(DebugPortEvent::Snapshot(data)),
+            _ => (),
+        }
+    }
+
+    // Removes any breaks from the pending events, returning true if there were any
+    pub(crate) fn take_break(&mut self) -> bool {
+        let num_events = self.events.len();
+        self.events.retain(|&event| event != DebugPortEvent::Break);
+        self.events.len() != num_events
+    }
+
+    // The characters printed since this was last called
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    // The events which have happened since this was last called, in order
+    pub fn take_events(&mut self) -> Vec<DebugPortEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
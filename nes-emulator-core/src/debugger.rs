@@ -0,0 +1,645 @@
+use crate::mapper::{Mapper, PpuAddress};
+use crate::nes::{FrameProgress, NesDevicesWithOam, RomMapping};
+use mos6502_model::debug::{InstructionType, InstructionWithOperand};
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::hook::Hooks;
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+const PPU_ADDRESS_MASK: u16 = 0x3FFF;
+const PPU_DATA_REGISTER: Address = 0x2007;
+
+fn is_ppu_data_register(address: Address) -> bool {
+    (0x2000..=0x3FFF).contains(&address) && address % 8 == PPU_DATA_REGISTER % 8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+}
+
+impl Register {
+    fn value(self, cpu: &Cpu) -> u8 {
+        match self {
+            Self::A => cpu.acc,
+            Self::X => cpu.x,
+            Self::Y => cpu.y,
+            Self::Sp => cpu.sp,
+            Self::P => cpu.status.masked_with_expansion(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// A comparison of a register with a value, written like "A==#$10", "X!=3" or "SP<$F0"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u8,
+}
+
+impl Condition {
+    pub fn is_met(&self, cpu: &Cpu) -> bool {
+        let register = self.register.value(cpu);
+        match self.comparison {
+            Comparison::Eq => register == self.value,
+            Comparison::Ne => register != self.value,
+            Comparison::Lt => register < self.value,
+            Comparison::Le => register <= self.value,
+            Comparison::Gt => register > self.value,
+            Comparison::Ge => register >= self.value,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Self::A => "A",
+            Self::X => "X",
+            Self::Y => "Y",
+            Self::Sp => "SP",
+            Self::P => "P",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self {
+            Self::Eq => "==",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        };
+        write!(f, "{}", operator)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}{}#${:02X}",
+            self.register, self.comparison, self.value
+        )
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.replace(' ', "");
+        // two character operators are checked first so "<=" isn't parsed as "<"
+        let (index, operator, comparison) = [
+            ("==", Comparison::Eq),
+            ("!=", Comparison::Ne),
+            ("<=", Comparison::Le),
+            (">=", Comparison::Ge),
+            ("<", Comparison::Lt),
+            (">", Comparison::Gt),
+        ]
+        .into_iter()
+        .find_map(|(operator, comparison)| {
+            s.find(operator).map(|index| (index, operator, comparison))
+        })
+        .ok_or_else(|| format!("expected a comparison in \"{}\"", s))?;
+        let register = match s[..index].to_ascii_uppercase().as_str() {
+            "A" => Register::A,
+            "X" => Register::X,
+            "Y" => Register::Y,
+            "SP" => Register::Sp,
+            "P" => Register::P,
+            other => return Err(format!("unknown register \"{}\"", other)),
+        };
+        let value = &s[index + operator.len()..];
+        let literal = value.strip_prefix('#').unwrap_or(value);
+        let value = match literal.strip_prefix('$') {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => literal.parse(),
+        }
+        .map_err(|e| format!("invalid value \"{}\": {}", value, e))?;
+        Ok(Self {
+            register,
+            comparison,
+            value,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    CpuRead,
+    CpuWrite,
+    // reads and writes of video memory through PPUDATA
+    PpuRead,
+    PpuWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointKind {
+    // stops before the instruction at an address is executed
+    Execute,
+    // stops after the instruction which accessed an address
+    Access(AccessKind),
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub kind: BreakpointKind,
+    // CPU addresses, or PPU addresses for PPU reads and writes
+    pub addresses: RangeInclusive<Address>,
+    pub condition: Option<Condition>,
+    // the number of hits before the first break, counting only hits which meet the condition
+    pub hit_count: u64,
+    pub enabled: bool,
+    hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakpointKind, addresses: RangeInclusive<Address>) -> Self {
+        Self {
+            kind,
+            addresses,
+            condition: None,
+            hit_count: 1,
+            enabled: true,
+            hits: 0,
+        }
+    }
+
+    pub fn execute(address: Address) -> Self {
+        Self::new(BreakpointKind::Execute, address..=address)
+    }
+
+    pub fn access(access_kind: AccessKind, addresses: RangeInclusive<Address>) -> Self {
+        Self::new(BreakpointKind::Access(access_kind), addresses)
+    }
+
+    pub fn with_condition(self, condition: Condition) -> Self {
+        Self {
+            condition: Some(condition),
+            ..self
+        }
+    }
+
+    pub fn with_hit_count(self, hit_count: u64) -> Self {
+        Self { hit_count, ..self }
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    // Counts a hit if the condition is met, returning true if emulation should stop
+    fn hit(&mut self, cpu: &Cpu) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(condition) = self.condition {
+            if !condition.is_met(cpu) {
+                return false;
+            }
+        }
+        self.hits += 1;
+        self.hits >= self.hit_count
+    }
+}
+
+pub type BreakpointId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub address: Address,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint {
+        id: BreakpointId,
+        // the access which hit the breakpoint, for breakpoints on memory accesses
+        access: Option<MemoryAccess>,
+    },
+    // a step requested with `step_into`, `step_over` or `step_out` has finished
+    Step,
+    // the end of the frame was reached without stopping
+    FrameComplete,
+    UnknownOpcode(u8),
+    // the program wrote to the debug port's break register
+    DebugPortBreak,
+}
+
+// Where emulation stopped. The scanline counts the pre-render scanline as 261, and the dot is
+// derived from the number of cycles the CPU has run for since the start of the scanline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub frame: u64,
+    pub cycle: u64,
+    pub pc: Address,
+    pub scanline: u16,
+    pub dot: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Break {
+    pub reason: StopReason,
+    pub position: Position,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Interrupt,
+}
+
+// An entry in the call stack, tracked the same way as the profiler's shadow stack: frames are
+// popped when the stack pointer rises above their return address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    // the target of the JSR, or the interrupt handler
+    pub function: Address,
+    // the JSR or BRK instruction, or the instruction which was about to run when the interrupt
+    // happened
+    pub caller: Address,
+    // the stack pointer's value before the return address was pushed
+    return_stack_pointer: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StepMode {
+    Into,
+    Over,
+    Out,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    mode: StepMode,
+    // the stack pointer before the step's first instruction
+    stack_pointer: Option<u8>,
+    // where a JSR stepped over returns to
+    return_address: Option<Address>,
+}
+
+// Memory which the debugger can run code in
+pub trait DebugMemory: Memory + MemoryReadOnly {
+    // The address in video memory which the next access through PPUDATA will use, or `None` if
+    // there's no PPU
+    fn ppu_data_address(&self) -> Option<PpuAddress>;
+}
+
+impl<M: Mapper> DebugMemory for NesDevicesWithOam<M> {
+    fn ppu_data_address(&self) -> Option<PpuAddress> {
+        Some(self.ppu().ppu_address() & PPU_ADDRESS_MASK)
+    }
+}
+
+impl DebugMemory for FlatMemory {
+    fn ppu_data_address(&self) -> Option<PpuAddress> {
+        None
+    }
+}
+
+// Records each memory access made by an instruction
+struct WatchedMemory<'a, M: DebugMemory> {
+    memory: &'a mut M,
+    accesses: &'a mut Vec<MemoryAccess>,
+}
+
+impl<M: DebugMemory> WatchedMemory<'_, M> {
+    fn ppu_data_address(&self, address: Address) -> Option<PpuAddress> {
+        if is_ppu_data_register(address) {
+            self.memory.ppu_data_address()
+        } else {
+            None
+        }
+    }
+    fn record(&mut self, kind: AccessKind, address: Address, value: u8) {
+        self.accesses.push(MemoryAccess {
+            kind,
+            address,
+            value,
+        });
+    }
+}
+
+impl<M: DebugMemory> Memory for WatchedMemory<'_, M> {
+    fn read_u8(&mut self, address: Address) -> u8 {
+        let ppu_address = self.ppu_data_address(address);
+        let value = self.memory.read_u8(address);
+        if let Some(ppu_address) = ppu_address {
+            self.record(AccessKind::PpuRead, ppu_address, value);
+        }
+        self.record(AccessKind::CpuRead, address, value);
+        value
+    }
+    fn write_u8(&mut self, address: Address, data: u8) {
+        if let Some(ppu_address) = self.ppu_data_address(address) {
+            self.record(AccessKind::PpuWrite, ppu_address, data);
+        }
+        self.record(AccessKind::CpuWrite, address, data);
+        self.memory.write_u8(address, data);
+    }
+}
+
+// Breakpoints and stepping for `Nes::run_until_break`, or for a bare CPU run with `step`. A
+// frame run with `run_until_break` must be finished with `run_until_break` (i.e. until it returns
+// `StopReason::FrameComplete`) before running frames any other way.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: BTreeMap<BreakpointId, Breakpoint>,
+    next_breakpoint_id: BreakpointId,
+    step: Option<Step>,
+    // set after stopping at an execute breakpoint, so resuming executes the instruction rather
+    // than stopping again
+    resuming_at: Option<Address>,
+    accesses: Vec<MemoryAccess>,
+    call_stack: Vec<CallFrame>,
+    // where the CPU will be after the last instruction, unless an interrupt happens
+    next_pc: Option<Address>,
+    pub(crate) frame_progress: FrameProgress,
+    pub(crate) frame: u64,
+    pub(crate) cycle: u64,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        let id = self.next_breakpoint_id;
+        self.next_breakpoint_id += 1;
+        self.breakpoints.insert(id, breakpoint);
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> Option<Breakpoint> {
+        self.breakpoints.remove(&id)
+    }
+
+    pub fn breakpoint_mut(&mut self, id: BreakpointId) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&id)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(&id, breakpoint)| (id, breakpoint))
+    }
+
+    // Stops after the next instruction
+    pub fn step_into(&mut self) {
+        self.start_step(StepMode::Into);
+    }
+
+    // Stops after the next instruction, or if it's a JSR, when the subroutine returns
+    pub fn step_over(&mut self) {
+        self.start_step(StepMode::Over);
+    }
+
+    // Stops after the current subroutine or interrupt handler returns
+    pub fn step_out(&mut self) {
+        self.start_step(StepMode::Out);
+    }
+
+    // Abandons a step in progress
+    pub fn cancel_step(&mut self) {
+        self.step = None;
+    }
+
+    fn start_step(&mut self, mode: StepMode) {
+        self.step = Some(Step {
+            mode,
+            stack_pointer: None,
+            return_address: None,
+        });
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // The innermost call is last
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    fn push_call(&mut self, kind: CallKind, function: Address, caller: Address, stack_pointer: u8) {
+        self.call_stack.push(CallFrame {
+            kind,
+            function,
+            caller,
+            return_stack_pointer: stack_pointer,
+        });
+    }
+
+    fn unwind_call_stack(&mut self, stack_pointer: u8) {
+        while let Some(frame) = self.call_stack.last() {
+            if (frame.return_stack_pointer.wrapping_sub(stack_pointer) as i8) > 0 {
+                break;
+            }
+            self.call_stack.pop();
+        }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    fn check_execute_breakpoints(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        if self.resuming_at.take() == Some(cpu.pc) {
+            return None;
+        }
+        let id = self
+            .breakpoints
+            .iter_mut()
+            .filter(|(_, breakpoint)| {
+                breakpoint.kind == BreakpointKind::Execute && breakpoint.addresses.contains(&cpu.pc)
+            })
+            .fold(None, |stop, (&id, breakpoint)| {
+                // every matching breakpoint counts the hit, and the first one stops
+                if breakpoint.hit(cpu) {
+                    stop.or(Some(id))
+                } else {
+                    stop
+                }
+            })?;
+        self.resuming_at = Some(cpu.pc);
+        Some(StopReason::Breakpoint { id, access: None })
+    }
+
+    fn check_access_breakpoints(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        let mut stop = None;
+        for access in self.accesses.iter() {
+            for (&id, breakpoint) in self.breakpoints.iter_mut() {
+                if breakpoint.kind == BreakpointKind::Access(access.kind)
+                    && breakpoint.addresses.contains(&access.address)
+                    && breakpoint.hit(cpu)
+                    && stop.is_none()
+                {
+                    stop = Some(StopReason::Breakpoint {
+                        id,
+                        access: Some(*access),
+                    });
+                }
+            }
+        }
+        stop
+    }
+
+    fn is_step_complete(&mut self, cpu: &Cpu, instruction_type: Option<InstructionType>) -> bool {
+        let step = match self.step.as_ref() {
+            Some(step) => step,
+            None => return false,
+        };
+        // the stack pointer has risen above where it was at the start of the step
+        let has_returned = |stack_pointer: Option<u8>| {
+            stack_pointer
+                .map(|stack_pointer| (cpu.sp.wrapping_sub(stack_pointer) as i8) > 0)
+                .unwrap_or(false)
+        };
+        match step.mode {
+            StepMode::Into => true,
+            StepMode::Over => match step.return_address {
+                None => true,
+                Some(return_address) => {
+                    cpu.pc == return_address && Some(cpu.sp) == step.stack_pointer
+                }
+            },
+            StepMode::Out => {
+                matches!(
+                    instruction_type,
+                    Some(InstructionType::Rts) | Some(InstructionType::Rti)
+                ) && has_returned(step.stack_pointer)
+            }
+        }
+    }
+
+    // Executes a single instruction, unless an execute breakpoint stops emulation first. Returns
+    // the number of cycles taken and why emulation should stop, if it should. This is how
+    // `Nes::run_until_break` runs the CPU, and can be used directly to debug a bare CPU.
+    pub fn step<M: DebugMemory>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> (u8, Option<StopReason>) {
+        self.step_with_hooks(cpu, memory, &mut Hooks::new())
+    }
+
+    // Like `step`, but runs the hook at the PC, if there is one, once execute breakpoints have
+    // been checked. Memory accessed by hooks doesn't trigger watchpoints. A hook which replaces an
+    // instruction counts as a single instruction when stepping, and as an RTS if it returns.
+    pub fn step_with_hooks<M: DebugMemory>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        hooks: &mut Hooks,
+    ) -> (u8, Option<StopReason>) {
+        if let Some(next_pc) = self.next_pc {
+            if next_pc != cpu.pc {
+                // the CPU was interrupted since the last instruction, pushing the return address
+                // and status register
+                self.push_call(CallKind::Interrupt, cpu.pc, next_pc, cpu.sp.wrapping_add(3));
+            }
+        }
+        self.next_pc = Some(cpu.pc);
+        if let Some(stop) = self.check_execute_breakpoints(cpu) {
+            self.step = None;
+            return (0, Some(stop));
+        }
+        self.resuming_at = None;
+        let stack_pointer_before_hook = cpu.sp;
+        if let Some(num_cycles) = hooks.run(cpu, memory) {
+            self.cycle += num_cycles as u64;
+            self.unwind_call_stack(cpu.sp);
+            self.next_pc = Some(cpu.pc);
+            if let Some(step) = self.step.as_mut() {
+                step.stack_pointer.get_or_insert(stack_pointer_before_hook);
+            }
+            let has_returned = cpu.sp != stack_pointer_before_hook;
+            let instruction_type = has_returned.then_some(InstructionType::Rts);
+            if self.is_step_complete(cpu, instruction_type) {
+                self.step = None;
+                return (num_cycles, Some(StopReason::Step));
+            }
+            return (num_cycles, None);
+        }
+        let instruction_with_operand = InstructionWithOperand::next(cpu, memory).ok();
+        let pc_before = cpu.pc;
+        let stack_pointer_before = cpu.sp;
+        if let Some(step) = self.step.as_mut() {
+            if step.stack_pointer.is_none() {
+                step.stack_pointer = Some(cpu.sp);
+                if let Some(instruction_with_operand) = instruction_with_operand.as_ref() {
+                    if instruction_with_operand.instruction().instruction_type()
+                        == InstructionType::Jsr
+                    {
+                        let size = instruction_with_operand.instruction().size() as Address;
+                        step.return_address = Some(cpu.pc.wrapping_add(size));
+                    }
+                }
+            }
+        }
+        self.accesses.clear();
+        let result = cpu.step(&mut WatchedMemory {
+            memory,
+            accesses: &mut self.accesses,
+        });
+        let num_cycles = match result {
+            Ok(num_cycles) => num_cycles,
+            Err(unknown_opcode) => return (0, Some(StopReason::UnknownOpcode(unknown_opcode.0))),
+        };
+        self.cycle += num_cycles as u64;
+        let instruction_type = instruction_with_operand.map(|instruction_with_operand| {
+            instruction_with_operand.instruction().instruction_type()
+        });
+        self.unwind_call_stack(cpu.sp);
+        match instruction_type {
+            Some(InstructionType::Jsr) => {
+                self.push_call(CallKind::Call, cpu.pc, pc_before, stack_pointer_before)
+            }
+            Some(InstructionType::Brk) => {
+                self.push_call(CallKind::Interrupt, cpu.pc, pc_before, stack_pointer_before)
+            }
+            _ => (),
+        }
+        self.next_pc = Some(cpu.pc);
+        if let Some(stop) = self.check_access_breakpoints(cpu) {
+            // stopping at a breakpoint abandons any step in progress
+            self.step = None;
+            return (num_cycles, Some(stop));
+        }
+        if self.is_step_complete(cpu, instruction_type) {
+            self.step = None;
+            return (num_cycles, Some(StopReason::Step));
+        }
+        (num_cycles, None)
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,418 @@
+use super::*;
+use crate::mapper::nrom::{self, Nrom};
+use crate::nes::Nes;
+use crate::test::*;
+use mos6502_assembler::{Addr, AssembledBlock, LabelRelativeOffset};
+use mos6502_model::hook::{HookAction, Hooks};
+use mos6502_model::machine::MemoryReadOnly;
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_headless_frame::Frame;
+use nes_render_output::NoRenderOutput;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+const NUM_FRAMES: usize = 5;
+const COUNTER: u8 = 0x10;
+
+fn registers(cpu: &Cpu) -> (Address, u8, u8, u8, u8, u8) {
+    (
+        cpu.pc,
+        cpu.acc,
+        cpu.x,
+        cpu.y,
+        cpu.sp,
+        cpu.status.masked_with_expansion(),
+    )
+}
+
+fn ram(nes: &TestNes) -> Vec<u8> {
+    (0..0x800)
+        .map(|address| nes.devices_with_oam().read_u8_read_only(address))
+        .collect()
+}
+
+fn frame_hash(frame: &Frame) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    frame.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Runs until the debugger stops for any reason other than the end of a frame
+fn run_until_stop(nes: &mut TestNes, debugger: &mut Debugger) -> Break {
+    loop {
+        let stop = nes.run_until_break(debugger, &mut NoRenderOutput);
+        if stop.reason != StopReason::FrameComplete {
+            return stop;
+        }
+        assert!(debugger.frame() < NUM_FRAMES as u64, "didn't stop");
+    }
+}
+
+// Draws a background of solid tiles with sprite zero on top, then waits for sprite zero hits
+// and scrolls partway through each frame, so frames include every stage of a frame
+fn rendering_nes() -> TestNes {
+    let (prg_rom, _) = prg_rom_with_nmi(
+        |b| {
+            b.inst(Lda(Immediate), 0x3F);
+            b.inst(Sta(Absolute), Addr(0x2006));
+            b.inst(Lda(Immediate), 0x00);
+            b.inst(Sta(Absolute), Addr(0x2006));
+            b.inst(Lda(Immediate), 0x0F);
+            b.inst(Sta(Absolute), Addr(0x2007));
+            b.inst(Lda(Immediate), 0x30);
+            b.inst(Sta(Absolute), Addr(0x2007));
+            b.inst(Lda(Immediate), 0x20);
+            b.inst(Sta(Absolute), Addr(0x2006));
+            b.inst(Lda(Immediate), 0x00);
+            b.inst(Sta(Absolute), Addr(0x2006));
+            b.inst(Lda(Immediate), 1);
+            b.inst(Ldx(Immediate), 0);
+            b.label("fill");
+            b.inst(Sta(Absolute), Addr(0x2007));
+            b.inst(Dex, ());
+            b.inst(Bne, LabelRelativeOffset("fill"));
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(Absolute), Addr(0x2003));
+            for byte in [20, 1, 0, 40] {
+                b.inst(Lda(Immediate), byte);
+                b.inst(Sta(Absolute), Addr(0x2004));
+            }
+            b.inst(Lda(Immediate), 0x80);
+            b.inst(Sta(Absolute), Addr(0x2000));
+            b.inst(Lda(Immediate), 0x1E);
+            b.inst(Sta(Absolute), Addr(0x2001));
+            b.label("main");
+            b.inst(Bit(Absolute), Addr(0x2002));
+            b.inst(Bvc, LabelRelativeOffset("main"));
+            b.inst(Inc(ZeroPage), COUNTER);
+            b.inst(Lda(ZeroPage), COUNTER);
+            b.inst(Sta(Absolute), Addr(0x2005));
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(Absolute), Addr(0x2005));
+            b.label("wait_for_vblank");
+            b.inst(Lda(ZeroPage), COUNTER + 1);
+            b.inst(Beq, LabelRelativeOffset("wait_for_vblank"));
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), COUNTER + 1);
+            b.inst(Jmp(Absolute), "main");
+        },
+        |b| {
+            b.inst(Inc(ZeroPage), COUNTER + 1);
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(Absolute), Addr(0x2005));
+            b.inst(Sta(Absolute), Addr(0x2005));
+            b.inst(Rti, ());
+        },
+    );
+    // tile 1 is solid
+    let mut chr_rom = vec![0; ines::CHR_ROM_BLOCK_BYTES];
+    chr_rom[16..32].fill(0xFF);
+    Nes::new(Nrom::new(nrom::Vertical, &prg_rom, &chr_rom).unwrap())
+}
+
+#[test]
+fn frames_match_run_for_frame() {
+    let mut expected_nes = rendering_nes();
+    let mut nes = rendering_nes();
+    let mut debugger = Debugger::new();
+    let mut expected_frame = Frame::new();
+    let mut frame = Frame::new();
+    for frame_index in 0..NUM_FRAMES {
+        expected_nes.run_for_frame(&mut expected_frame, None);
+        let stop = nes.run_until_break(&mut debugger, &mut frame);
+        assert_eq!(stop.reason, StopReason::FrameComplete);
+        assert_eq!(debugger.frame(), frame_index as u64 + 1);
+        assert_eq!(registers(nes.cpu()), registers(expected_nes.cpu()));
+        assert_eq!(ram(&nes), ram(&expected_nes));
+        assert_eq!(frame_hash(&frame), frame_hash(&expected_frame));
+    }
+    // sprite zero was hit each frame after rendering was enabled
+    assert!(ram(&nes)[COUNTER as usize] >= NUM_FRAMES as u8 - 1);
+}
+
+// Counts with X from 0 to 9, storing and loading each count and calling a subroutine, then
+// writes 0..8 to video memory at $2000
+fn counting_nes() -> (TestNes, AssembledBlock) {
+    let (prg_rom, assembled_block) = prg_rom(|b| {
+        b.inst(Ldx(Immediate), 0);
+        b.label("count");
+        b.inst(Stx(ZeroPage), COUNTER);
+        b.label("after_store");
+        b.inst(Lda(ZeroPage), COUNTER);
+        b.inst(Jsr(Absolute), "subroutine");
+        b.inst(Inx, ());
+        b.inst(Cpx(Immediate), 10);
+        b.inst(Bne, LabelRelativeOffset("count"));
+        b.inst(Lda(Immediate), 0x20);
+        b.inst(Sta(Absolute), Addr(0x2006));
+        b.inst(Lda(Immediate), 0x00);
+        b.inst(Sta(Absolute), Addr(0x2006));
+        b.inst(Ldy(Immediate), 0);
+        b.label("write_video_memory");
+        b.inst(Sty(Absolute), Addr(0x2007));
+        b.inst(Iny, ());
+        b.inst(Cpy(Immediate), 8);
+        b.inst(Bne, LabelRelativeOffset("write_video_memory"));
+        b.infinite_loop();
+        b.label("subroutine");
+        b.inst(Rts, ());
+    });
+    (nes(&prg_rom), assembled_block)
+}
+
+fn condition(s: &str) -> Condition {
+    s.parse().unwrap()
+}
+
+#[test]
+fn execute_breakpoint_with_condition_and_hit_count() {
+    let (mut nes, assembled_block) = counting_nes();
+    let subroutine = assembled_block.address_of_label("subroutine").unwrap();
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint(
+        Breakpoint::execute(subroutine)
+            .with_condition(condition("X>=2"))
+            .with_hit_count(3),
+    );
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    assert_eq!(stop.reason, StopReason::Breakpoint { id, access: None });
+    assert_eq!(stop.position.pc, subroutine);
+    // stopped before the subroutine runs
+    assert_eq!(nes.cpu().pc, subroutine);
+    assert_eq!(nes.cpu().x, 4);
+    assert_eq!(debugger.breakpoints().next().unwrap().1.hits(), 3);
+}
+
+#[test]
+fn resuming_from_an_execute_breakpoint_runs_the_instruction() {
+    let (mut nes, assembled_block) = counting_nes();
+    let subroutine = assembled_block.address_of_label("subroutine").unwrap();
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint(Breakpoint::execute(subroutine));
+    for x in 0..3 {
+        let stop = run_until_stop(&mut nes, &mut debugger);
+        assert_eq!(stop.reason, StopReason::Breakpoint { id, access: None });
+        assert_eq!(nes.cpu().pc, subroutine);
+        assert_eq!(nes.cpu().x, x);
+    }
+    // the instruction at the breakpoint runs when stepping from it
+    debugger.step_into();
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    assert_eq!(stop.reason, StopReason::Step);
+    assert_ne!(nes.cpu().pc, subroutine);
+}
+
+#[test]
+fn write_breakpoint_with_condition_and_hit_count() {
+    let (mut nes, assembled_block) = counting_nes();
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint(
+        Breakpoint::access(
+            AccessKind::CpuWrite,
+            COUNTER as Address..=COUNTER as Address,
+        )
+        .with_condition(condition("X>=5"))
+        .with_hit_count(2),
+    );
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    let access = MemoryAccess {
+        kind: AccessKind::CpuWrite,
+        address: COUNTER as Address,
+        value: 6,
+    };
+    assert_eq!(
+        stop.reason,
+        StopReason::Breakpoint {
+            id,
+            access: Some(access)
+        }
+    );
+    // stopped after the write
+    assert_eq!(
+        nes.cpu().pc,
+        assembled_block.address_of_label("after_store").unwrap()
+    );
+}
+
+#[test]
+fn read_breakpoint_with_condition_and_hit_count() {
+    let (mut nes, _) = counting_nes();
+    let mut debugger = Debugger::new();
+    // the condition is checked after the instruction, so sees the value loaded into A
+    let id = debugger.add_breakpoint(
+        Breakpoint::access(AccessKind::CpuRead, COUNTER as Address..=COUNTER as Address)
+            .with_condition(condition("A!=0"))
+            .with_hit_count(3),
+    );
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    let access = MemoryAccess {
+        kind: AccessKind::CpuRead,
+        address: COUNTER as Address,
+        value: 3,
+    };
+    assert_eq!(
+        stop.reason,
+        StopReason::Breakpoint {
+            id,
+            access: Some(access)
+        }
+    );
+    assert_eq!(nes.cpu().acc, 3);
+}
+
+#[test]
+fn ppu_write_breakpoint_with_condition_and_hit_count() {
+    let (mut nes, _) = counting_nes();
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint(
+        Breakpoint::access(AccessKind::PpuWrite, 0x2000..=0x20FF)
+            .with_condition(condition("Y>=3"))
+            .with_hit_count(2),
+    );
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    let access = MemoryAccess {
+        kind: AccessKind::PpuWrite,
+        address: 0x2004,
+        value: 4,
+    };
+    assert_eq!(
+        stop.reason,
+        StopReason::Breakpoint {
+            id,
+            access: Some(access)
+        }
+    );
+}
+
+// Calls a recursive subroutine which calls itself until X reaches 0
+fn recursive_nes() -> (TestNes, AssembledBlock) {
+    let (prg_rom, assembled_block) = prg_rom(|b| {
+        b.inst(Ldx(Immediate), 3);
+        b.inst(Jsr(Absolute), "recurse");
+        b.label("after_call");
+        b.infinite_loop();
+        b.label("recurse");
+        b.inst(Dex, ());
+        b.inst(Beq, LabelRelativeOffset("return"));
+        b.label("recursive_call");
+        b.inst(Jsr(Absolute), "recurse");
+        b.label("return");
+        b.inst(Rts, ());
+    });
+    (nes(&prg_rom), assembled_block)
+}
+
+// Runs to the first instruction at `label` executed with a condition met, and removes the
+// breakpoint which stopped there
+fn run_to(
+    nes: &mut TestNes,
+    debugger: &mut Debugger,
+    assembled_block: &AssembledBlock,
+    label: &str,
+    condition_str: &str,
+) {
+    let address = assembled_block.address_of_label(label).unwrap();
+    let id = debugger
+        .add_breakpoint(Breakpoint::execute(address).with_condition(condition(condition_str)));
+    let stop = run_until_stop(nes, debugger);
+    assert_eq!(stop.reason, StopReason::Breakpoint { id, access: None });
+    debugger.remove_breakpoint(id);
+}
+
+#[test]
+fn step_over_skips_calls() {
+    let (mut nes, assembled_block) = recursive_nes();
+    let mut debugger = Debugger::new();
+    debugger.step_over();
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    assert_eq!(stop.reason, StopReason::Step);
+    // LDX isn't a call, so only it was run
+    assert_eq!(nes.cpu().x, 3);
+    debugger.step_over();
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    assert_eq!(stop.reason, StopReason::Step);
+    assert_eq!(
+        nes.cpu().pc,
+        assembled_block.address_of_label("after_call").unwrap()
+    );
+    assert_eq!(nes.cpu().x, 0);
+    assert!(debugger.call_stack().is_empty());
+}
+
+#[test]
+fn step_over_a_recursive_call_stops_at_the_same_depth() {
+    let (mut nes, assembled_block) = recursive_nes();
+    let mut debugger = Debugger::new();
+    run_to(
+        &mut nes,
+        &mut debugger,
+        &assembled_block,
+        "recursive_call",
+        "X==2",
+    );
+    let stack_pointer = nes.cpu().sp;
+    let call_depth = debugger.call_stack().len();
+    debugger.step_over();
+    let stop = run_until_stop(&mut nes, &mut debugger);
+    assert_eq!(stop.reason, StopReason::Step);
+    // the deeper call returns to the same address first, with a lower stack pointer
+    assert_eq!(
+        nes.cpu().pc,
+        assembled_block.address_of_label("return").unwrap()
+    );
+    assert_eq!(nes.cpu().sp, stack_pointer);
+    assert_eq!(nes.cpu().x, 0);
+    assert_eq!(debugger.call_stack().len(), call_depth);
+}
+
+#[test]
+fn step_out_returns_one_call_at_a_time() {
+    let (mut nes, assembled_block) = recursive_nes();
+    let recurse = assembled_block.address_of_label("recurse").unwrap();
+    let mut debugger = Debugger::new();
+    run_to(&mut nes, &mut debugger, &assembled_block, "return", "X==0");
+    assert_eq!(debugger.call_stack().len(), 3);
+    assert!(debugger
+        .call_stack()
+        .iter()
+        .all(|frame| frame.kind == CallKind::Call && frame.function == recurse));
+    for call_depth in (0..3).rev() {
+        let stack_pointer = nes.cpu().sp;
+        debugger.step_out();
+        let stop = run_until_stop(&mut nes, &mut debugger);
+        assert_eq!(stop.reason, StopReason::Step);
+        assert_eq!(nes.cpu().sp, stack_pointer.wrapping_add(2));
+        assert_eq!(debugger.call_stack().len(), call_depth);
+    }
+    assert_eq!(
+        nes.cpu().pc,
+        assembled_block.address_of_label("after_call").unwrap()
+    );
+}
+
+#[test]
+fn hooks_run_after_execute_breakpoints() {
+    let (mut nes, assembled_block) = recursive_nes();
+    let recurse = assembled_block.address_of_label("recurse").unwrap();
+    let mut hooks = Hooks::new();
+    hooks.insert(recurse, |cpu, _| {
+        cpu.x = 0;
+        HookAction::Return
+    });
+    let mut debugger = Debugger::new();
+    let id = debugger.add_breakpoint(Breakpoint::execute(recurse));
+    let stop = nes.run_until_break_with_hooks(&mut debugger, &mut hooks, &mut NoRenderOutput);
+    assert_eq!(stop.reason, StopReason::Breakpoint { id, access: None });
+    assert_eq!(nes.cpu().x, 3);
+    assert_eq!(debugger.call_stack().len(), 1);
+    // the hook stands in for the whole subroutine, so stepping out runs only the hook
+    debugger.step_out();
+    let stop = nes.run_until_break_with_hooks(&mut debugger, &mut hooks, &mut NoRenderOutput);
+    assert_eq!(stop.reason, StopReason::Step);
+    assert_eq!(
+        nes.cpu().pc,
+        assembled_block.address_of_label("after_call").unwrap()
+    );
+    assert_eq!(nes.cpu().x, 0);
+    assert!(debugger.call_stack().is_empty());
+}
@@ -1,8 +1,14 @@
+use crate::debug_port::DebugPort;
+use crate::debugger::{Break, Debugger};
 use crate::mapper::{self, mmc1, nrom, PersistentState, PersistentStateError};
 use crate::nes::{Controller, Nes, RunForCycles, RunForCyclesRegular};
-use analyser::{Analysis, MemoryMap};
+use analyser::{Analysis, Bank, BankedAnalysis, BankedMemoryMap, MemoryMap, Observations};
 use ines::Ines;
-use mos6502_model::{machine::MemoryReadOnly, Address};
+use mos6502_model::{
+    hook::Hooks,
+    machine::{Cpu, MemoryReadOnly},
+    Address,
+};
 use nes_render_output::RenderOutput;
 use serde::{Deserialize, Serialize};
 
@@ -28,10 +34,10 @@ impl From<mapper::Error> for Error {
 
 impl DynamicNes {
     pub fn from_ines(ines: &Ines) -> Result<Self, Error> {
-        let &Ines {
-            ref header,
-            ref prg_rom,
-            ref chr_rom,
+        let Ines {
+            header,
+            prg_rom,
+            chr_rom,
         } = ines;
         use ines::Mapper::*;
         use ines::Mirroring::*;
@@ -43,13 +49,11 @@ impl DynamicNes {
         let dynamic_nes = match mapper {
             Nrom => match mirroring {
                 Horizontal => {
-                    D::NromHorizontal(Nes::new(Nrom::new(nrom::Horizontal, &prg_rom, &chr_rom)?))
-                }
-                Vertical => {
-                    D::NromVertical(Nes::new(Nrom::new(nrom::Vertical, &prg_rom, &chr_rom)?))
+                    D::NromHorizontal(Nes::new(Nrom::new(nrom::Horizontal, prg_rom, chr_rom)?))
                 }
+                Vertical => D::NromVertical(Nes::new(Nrom::new(nrom::Vertical, prg_rom, chr_rom)?)),
             },
-            Mmc1 => D::Mmc1(Nes::new(Mmc1::new(&prg_rom, &chr_rom)?)),
+            Mmc1 => D::Mmc1(Nes::new(Mmc1::new(prg_rom, chr_rom)?)),
         };
         Ok(dynamic_nes)
     }
@@ -81,6 +85,75 @@ impl DynamicNes {
         self.run_for_frame_general(&mut RunForCyclesRegular, render_output);
     }
 
+    pub fn run_for_frame_with_hooks<RO: RenderOutput>(
+        &mut self,
+        hooks: &mut Hooks,
+        render_output: &mut RO,
+    ) {
+        self.run_for_frame_general(hooks, render_output);
+    }
+
+    pub fn run_until_break<O: RenderOutput>(
+        &mut self,
+        debugger: &mut Debugger,
+        render_output: &mut O,
+    ) -> Break {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.run_until_break(debugger, render_output),
+            DynamicNes::NromVertical(n) => n.run_until_break(debugger, render_output),
+            DynamicNes::Mmc1(n) => n.run_until_break(debugger, render_output),
+        }
+    }
+
+    pub fn run_until_break_with_hooks<O: RenderOutput>(
+        &mut self,
+        debugger: &mut Debugger,
+        hooks: &mut Hooks,
+        render_output: &mut O,
+    ) -> Break {
+        match self {
+            DynamicNes::NromHorizontal(n) => {
+                n.run_until_break_with_hooks(debugger, hooks, render_output)
+            }
+            DynamicNes::NromVertical(n) => {
+                n.run_until_break_with_hooks(debugger, hooks, render_output)
+            }
+            DynamicNes::Mmc1(n) => n.run_until_break_with_hooks(debugger, hooks, render_output),
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.cpu(),
+            DynamicNes::NromVertical(n) => n.cpu(),
+            DynamicNes::Mmc1(n) => n.cpu(),
+        }
+    }
+
+    pub fn randomise_ram(&mut self, seed: u64) {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.randomise_ram(seed),
+            DynamicNes::NromVertical(n) => n.randomise_ram(seed),
+            DynamicNes::Mmc1(n) => n.randomise_ram(seed),
+        }
+    }
+
+    pub fn enable_debug_port(&mut self) {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.enable_debug_port(),
+            DynamicNes::NromVertical(n) => n.enable_debug_port(),
+            DynamicNes::Mmc1(n) => n.enable_debug_port(),
+        }
+    }
+
+    pub fn debug_port_mut(&mut self) -> Option<&mut DebugPort> {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.debug_port_mut(),
+            DynamicNes::NromVertical(n) => n.debug_port_mut(),
+            DynamicNes::Mmc1(n) => n.debug_port_mut(),
+        }
+    }
+
     pub fn controller1_mut(&mut self) -> &mut Controller {
         match self {
             DynamicNes::NromHorizontal(n) => n.controller1_mut(),
@@ -92,6 +165,15 @@ impl DynamicNes {
     pub fn analyse(&self) -> Analysis {
         Analysis::analyse(self, self, None)
     }
+
+    pub fn analyse_with_observations(&self, observations: &Observations) -> Analysis {
+        Analysis::analyse_with_observations(self, self, None, observations)
+    }
+
+    // Analyses every PRG ROM bank rather than just those currently mapped
+    pub fn analyse_banked(&self, observations: &Observations) -> BankedAnalysis {
+        BankedAnalysis::analyse(self, self, None, observations)
+    }
 }
 
 impl MemoryMap for DynamicNes {
@@ -104,6 +186,37 @@ impl MemoryMap for DynamicNes {
     }
 }
 
+impl BankedMemoryMap for DynamicNes {
+    fn switchable_banks(&self) -> Vec<Bank> {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.mapper().switchable_banks(),
+            DynamicNes::NromVertical(n) => n.mapper().switchable_banks(),
+            DynamicNes::Mmc1(n) => n.mapper().switchable_banks(),
+        }
+    }
+    fn bank_at(&self, selected: Bank, address: Address) -> Option<Bank> {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.mapper().bank_at(selected, address),
+            DynamicNes::NromVertical(n) => n.mapper().bank_at(selected, address),
+            DynamicNes::Mmc1(n) => n.mapper().bank_at(selected, address),
+        }
+    }
+    fn read_banked_u8(&self, bank: Bank, address: Address) -> u8 {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.mapper().read_banked_u8(bank, address),
+            DynamicNes::NromVertical(n) => n.mapper().read_banked_u8(bank, address),
+            DynamicNes::Mmc1(n) => n.mapper().read_banked_u8(bank, address),
+        }
+    }
+    fn bank_selected_by_writes(&self, writes: &[(Address, u8)]) -> Option<Bank> {
+        match self {
+            DynamicNes::NromHorizontal(n) => n.mapper().bank_selected_by_writes(writes),
+            DynamicNes::NromVertical(n) => n.mapper().bank_selected_by_writes(writes),
+            DynamicNes::Mmc1(n) => n.mapper().bank_selected_by_writes(writes),
+        }
+    }
+}
+
 impl MemoryReadOnly for DynamicNes {
     fn read_u8_read_only(&self, a: Address) -> u8 {
         match self {
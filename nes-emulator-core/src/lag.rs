@@ -0,0 +1,222 @@
+use crate::cdl::data_read_address;
+use crate::nes::{RomMapping, RunForCycles};
+use crate::observer::{self, Observed, Observer};
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::collections::BTreeMap;
+
+// Longest loop, in bytes, which can be recognised as a loop waiting for an interrupt
+const MAX_SPIN_LOOP_BYTES: Address = 16;
+
+const CONTROLLER_PORTS: [Address; 2] = [0x4016, 0x4017];
+
+// What happened during a single frame
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    pub cycles: u64,
+    // cycles spent in loops waiting for an interrupt
+    pub idle_cycles: u64,
+    pub controller_polled: bool,
+    // the addresses interrupted by each NMI during the frame
+    pub nmi_return_addresses: Vec<Address>,
+    // true if an NMI interrupted code other than a spin loop, meaning the game's logic for the
+    // previous frame hadn't finished when the next frame started
+    pub nmi_interrupted_logic: bool,
+}
+
+impl FrameStats {
+    pub const CSV_HEADER: &'static str =
+        "frame,cycles,idle_cycles,controller_polled,nmi_return_addresses,lag";
+
+    pub fn is_lag(&self) -> bool {
+        !self.controller_polled || self.nmi_interrupted_logic
+    }
+
+    pub fn busy_cycles(&self) -> u64 {
+        self.cycles - self.idle_cycles
+    }
+
+    pub fn csv_row(&self, frame: u64) -> String {
+        let nmi_return_addresses = self
+            .nmi_return_addresses
+            .iter()
+            .map(|address| format!("{:04X}", address))
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!(
+            "{},{},{},{},{},{}",
+            frame,
+            self.cycles,
+            self.idle_cycles,
+            self.controller_polled,
+            nmi_return_addresses,
+            self.is_lag()
+        )
+    }
+}
+
+// Returns true if the loop from `start` to the branch or jump at `site` does nothing but wait,
+// i.e. it only reads memory and never writes to memory, calls functions or touches the stack
+fn is_spin_loop<M: MemoryReadOnly>(memory: &M, start: Address, site: Address) -> bool {
+    if site < start || site - start > MAX_SPIN_LOOP_BYTES {
+        return false;
+    }
+    let mut address = start;
+    while address < site {
+        let instruction_with_operand = match InstructionWithOperand::decode(address, memory) {
+            Ok(instruction_with_operand) => instruction_with_operand,
+            Err(_) => return false,
+        };
+        let instruction = instruction_with_operand.instruction();
+        use InstructionType::*;
+        let only_reads = match instruction.instruction_type() {
+            Asl | Lsr | Rol | Ror => instruction.addressing_mode() == AddressingMode::Accumulator,
+            Lda | Ldx | Ldy | Bit | Cmp | Cpx | Cpy | And | Ora | Eor | Nop | Ign | Skb | Bcc
+            | Bcs | Beq | Bmi | Bne | Bpl | Bvc | Bvs | Clc | Sec | Clv | Tax | Tay | Txa | Tya => {
+                true
+            }
+            _ => false,
+        };
+        if !only_reads {
+            return false;
+        }
+        address = address.wrapping_add(instruction.size() as Address);
+    }
+    address == site
+}
+
+// Detects lag frames, where the game either didn't read the controller, or was still running
+// the previous frame's logic when the NMI for the next frame arrived. Code is considered idle
+// while it's in a short loop which only reads memory (e.g. waiting for the NMI handler to set a
+// flag, or an infinite loop in games which do all their work in the NMI handler). Such loops are
+// recognised when their backwards branch or jump is first taken.
+#[derive(Debug, Clone, Default)]
+pub struct LagDetector {
+    // maps the first address of each spin loop to the address of the branch or jump at its end
+    spin_loops: BTreeMap<Address, Address>,
+    // loops which were found not to be spin loops, by the address of their branch or jump
+    non_spin_loop_sites: BTreeMap<Address, Address>,
+    next_pc: Option<Address>,
+    // the address of the instruction being executed, and whether it's a branch or jump
+    site: Address,
+    is_branch_or_jump: bool,
+    current_frame: FrameStats,
+}
+
+impl LagDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn is_idle(&self, address: Address) -> bool {
+        self.spin_loops
+            .range(..=address)
+            .next_back()
+            .map(|(_, &site)| address <= site)
+            .unwrap_or(false)
+    }
+
+    // Records a taken backwards branch or jump, returning true if it closes a spin loop
+    fn record_loop<M: MemoryReadOnly>(
+        &mut self,
+        memory: &M,
+        site: Address,
+        start: Address,
+    ) -> bool {
+        if self.spin_loops.get(&start) == Some(&site) {
+            return true;
+        }
+        if self.non_spin_loop_sites.get(&site) == Some(&start) {
+            return false;
+        }
+        if is_spin_loop(memory, start, site) {
+            self.spin_loops.insert(start, site);
+            true
+        } else {
+            self.non_spin_loop_sites.insert(site, start);
+            false
+        }
+    }
+
+    // Pairs of the first and last address of each loop recognised as waiting for an interrupt.
+    // The first address of the loop run most often is usually the game's main loop.
+    pub fn spin_loops(&self) -> impl Iterator<Item = (Address, Address)> + '_ {
+        self.spin_loops.iter().map(|(&start, &site)| (start, site))
+    }
+
+    // Executes a single instruction, recording whether it polls the controller or waits
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        observer::step(self, cpu, memory)
+    }
+
+    // Finishes recording the current frame and returns what happened during it. Call this after
+    // running each frame.
+    pub fn end_frame(&mut self) -> FrameStats {
+        std::mem::take(&mut self.current_frame)
+    }
+}
+
+impl Observer for LagDetector {
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        if self.next_pc.is_some() && self.next_pc != Some(cpu.pc) {
+            if let Some(return_address) = cpu.retrieve_nmi_return_address_during_nmi(memory) {
+                self.current_frame.nmi_return_addresses.push(return_address);
+                if !self.is_idle(return_address) {
+                    self.current_frame.nmi_interrupted_logic = true;
+                }
+            }
+        }
+        let instruction_with_operand = InstructionWithOperand::next(cpu, memory).ok();
+        let mut is_branch_or_jump = false;
+        if let Some(instruction_with_operand) = instruction_with_operand.as_ref() {
+            if let Some((address, _)) = data_read_address(cpu, memory, instruction_with_operand) {
+                if CONTROLLER_PORTS.contains(&address) {
+                    self.current_frame.controller_polled = true;
+                }
+            }
+            let instruction = instruction_with_operand.instruction();
+            is_branch_or_jump = instruction.addressing_mode() == AddressingMode::Relative
+                || (instruction.instruction_type() == InstructionType::Jmp
+                    && instruction.addressing_mode() == AddressingMode::Absolute);
+        }
+        self.site = cpu.pc;
+        self.is_branch_or_jump = is_branch_or_jump;
+    }
+
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &Cpu,
+        memory: &M,
+        num_cycles: u8,
+    ) {
+        let site = self.site;
+        let mut is_idle = self.is_idle(site);
+        if !is_idle && self.is_branch_or_jump && cpu.pc <= site {
+            is_idle = self.record_loop(memory, site, cpu.pc);
+        }
+        self.current_frame.cycles += num_cycles as u64;
+        if is_idle {
+            self.current_frame.idle_cycles += num_cycles as u64;
+        }
+        self.next_pc = Some(cpu.pc);
+    }
+}
+
+impl RunForCycles for LagDetector {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,93 @@
+use super::*;
+use crate::test::*;
+use mos6502_assembler::{Addr, LabelRelativeOffset};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_render_output::NoRenderOutput;
+
+const NUM_FRAMES: u64 = 12;
+// the frame during which the main loop starts its long piece of work
+const BUSY_FRAME: u8 = 4;
+
+// Runs a program which waits for each NMI in a spin loop, then polls the controller. After the
+// NMI in `BUSY_FRAME`, it works for long enough that the following two NMIs interrupt it.
+fn frame_stats() -> (Vec<FrameStats>, LagDetector) {
+    let (prg_rom, _) = prg_rom_with_nmi(
+        |b| {
+            b.inst(Lda(Immediate), 0x80);
+            b.inst(Sta(Absolute), Addr(0x2000));
+            b.label("main");
+            b.label("wait_for_nmi");
+            b.inst(Lda(ZeroPage), 0x00);
+            b.inst(Beq, LabelRelativeOffset("wait_for_nmi"));
+            b.inst(Lda(Immediate), 0);
+            b.inst(Sta(ZeroPage), 0x00);
+            b.inst(Lda(Absolute), Addr(0x4016));
+            b.inst(Inc(ZeroPage), 0x01);
+            b.inst(Lda(ZeroPage), 0x01);
+            b.inst(Cmp(Immediate), BUSY_FRAME + 1);
+            b.inst(Bne, LabelRelativeOffset("main"));
+            // about 2.5 frames of work
+            b.inst(Ldx(Immediate), 36);
+            b.label("outer");
+            b.inst(Ldy(Immediate), 0);
+            b.label("inner");
+            b.inst(Sta(ZeroPage), 0x02);
+            b.inst(Dey, ());
+            b.inst(Bne, LabelRelativeOffset("inner"));
+            b.inst(Dex, ());
+            b.inst(Bne, LabelRelativeOffset("outer"));
+            b.inst(Jmp(Absolute), "main");
+        },
+        |b| {
+            b.inst(Inc(ZeroPage), 0x00);
+            b.inst(Rti, ());
+        },
+    );
+    let mut nes = nes(&prg_rom);
+    let mut lag_detector = LagDetector::new();
+    let frame_stats = (0..NUM_FRAMES)
+        .map(|_| {
+            nes.run_for_frame_general(&mut lag_detector, &mut NoRenderOutput, None);
+            lag_detector.end_frame()
+        })
+        .collect::<Vec<_>>();
+    (frame_stats, lag_detector)
+}
+
+#[test]
+fn busy_frames_are_lag_frames() {
+    let (frame_stats, _) = frame_stats();
+    // NMIs are taken at the end of each frame, so the main loop first runs in frame 0, and the
+    // work started in `BUSY_FRAME` is interrupted by the NMIs of the two frames which follow
+    let busy_frames = (BUSY_FRAME as u64 + 1)..=(BUSY_FRAME as u64 + 2);
+    let lag_frames = frame_stats
+        .iter()
+        .enumerate()
+        .filter(|(_, stats)| stats.is_lag())
+        .map(|(frame, _)| frame as u64)
+        .collect::<Vec<_>>();
+    assert_eq!(lag_frames, busy_frames.clone().collect::<Vec<_>>());
+    for frame in busy_frames {
+        let stats = &frame_stats[frame as usize];
+        assert!(stats.nmi_interrupted_logic);
+        assert!(!stats.controller_polled);
+    }
+}
+
+#[test]
+fn idle_frames_spend_most_cycles_waiting() {
+    let (frame_stats, lag_detector) = frame_stats();
+    let spin_loops = lag_detector.spin_loops().collect::<Vec<_>>();
+    assert_eq!(spin_loops.len(), 1);
+    let (start, site) = spin_loops[0];
+    // frames in which the main loop does some of its long piece of work
+    let working_frames = BUSY_FRAME as usize..=BUSY_FRAME as usize + 3;
+    for (frame, stats) in frame_stats.iter().enumerate() {
+        assert_eq!(stats.nmi_return_addresses.len(), 1);
+        if !working_frames.contains(&frame) {
+            assert!(stats.nmi_return_addresses[0] >= start);
+            assert!(stats.nmi_return_addresses[0] <= site);
+            assert!(stats.idle_cycles * 10 > stats.cycles * 9);
+        }
+    }
+}
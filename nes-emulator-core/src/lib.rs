@@ -1,6 +1,17 @@
 mod apu;
+pub mod cdl;
+pub mod debug_port;
+pub mod debugger;
 pub mod dynamic_nes;
+pub mod lag;
 pub mod mapper;
 pub mod nes;
+pub mod observer;
 pub mod ppu;
+pub mod ppu_lint;
+pub mod profiler;
+pub mod sanitizer;
+#[cfg(test)]
+mod test;
 mod timing;
+pub mod trace;
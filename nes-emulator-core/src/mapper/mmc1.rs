@@ -6,7 +6,7 @@ use crate::mapper::{NameTableChoice, PaletteRam, PatternTableChoice};
 use crate::mapper::{PersistentState, PersistentStateError};
 use crate::nes::Nes;
 use crate::ppu::{name_table_mirroring, NAME_TABLE_BYTES};
-use analyser::MemoryMap;
+use analyser::{Bank, BankedMemoryMap, MemoryMap};
 use mos6502_model::{machine::MemoryReadOnly, Address};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
@@ -67,13 +67,25 @@ struct ChrRomBank {
     rom: [u8; CHR_ROM_BANK_BYTES],
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[allow(clippy::enum_variant_names)]
 enum PrgRomBankMode {
     SwitchBoth,
     SwitchLower,
     SwitchUpper,
 }
 
+impl PrgRomBankMode {
+    fn from_control_register(data: u8) -> Self {
+        match data.wrapping_shr(2) & 3 {
+            0 | 1 => PrgRomBankMode::SwitchBoth,
+            2 => PrgRomBankMode::SwitchUpper,
+            3 => PrgRomBankMode::SwitchLower,
+            _ => unreachable!(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum ChrRomBankMode {
     SwitchTogether,
@@ -208,18 +220,12 @@ impl Mmc1 {
             3 => Mirroring::Horizontal,
             _ => unreachable!(),
         };
-        self.prg_rom_bank_mode = match data.wrapping_shr(2) & 3 {
-            0 | 1 => PrgRomBankMode::SwitchBoth,
-            2 => {
-                self.prg_rom_bank0 = 0;
-                PrgRomBankMode::SwitchUpper
-            }
-            3 => {
-                self.prg_rom_bank1 = self.prg_rom_banks.len() - 1;
-                PrgRomBankMode::SwitchLower
-            }
-            _ => unreachable!(),
-        };
+        self.prg_rom_bank_mode = PrgRomBankMode::from_control_register(data);
+        match self.prg_rom_bank_mode {
+            PrgRomBankMode::SwitchBoth => (),
+            PrgRomBankMode::SwitchUpper => self.prg_rom_bank0 = 0,
+            PrgRomBankMode::SwitchLower => self.prg_rom_bank1 = self.prg_rom_banks.len() - 1,
+        }
         self.chr_rom_bank_mode = match data.wrapping_shr(4) & 1 {
             0 => ChrRomBankMode::SwitchTogether,
             1 => ChrRomBankMode::SwitchSeperate,
@@ -342,6 +348,14 @@ impl PpuMapper for Mmc1 {
     fn ppu_palette_ram(&self) -> &[u8] {
         &self.palette_ram.ram
     }
+    fn ppu_chr_rom_offset(&self, address: PpuAddress) -> Option<usize> {
+        let bank = match address % 0x4000 {
+            0x0000..=0x0FFF => self.chr_rom_bank0,
+            0x1000..=0x1FFF => self.chr_rom_bank1,
+            _ => return None,
+        };
+        Some(bank * CHR_ROM_BANK_BYTES + (address & 0x0FFF) as usize)
+    }
 }
 
 impl CpuMapper for Mmc1 {
@@ -374,6 +388,20 @@ impl CpuMapper for Mmc1 {
             }
         }
     }
+    fn cpu_prg_rom_bank(&self, address: Address) -> Option<usize> {
+        match address {
+            0x8000..=0xBFFF => Some(self.prg_rom_bank0),
+            0xC000..=0xFFFF => Some(self.prg_rom_bank1),
+            _ => None,
+        }
+    }
+    fn cpu_prg_rom_offset(&self, address: Address) -> Option<usize> {
+        self.cpu_prg_rom_bank(address)
+            .map(|bank| bank * PRG_ROM_BANK_BYTES + (address & 0x3FFF) as usize)
+    }
+    fn cpu_is_register(&self, address: Address) -> bool {
+        address >= 0x8000
+    }
 }
 
 impl Mapper for Mmc1 {
@@ -388,7 +416,7 @@ impl Mapper for Mmc1 {
         persistent_state: &PersistentState,
     ) -> Result<(), PersistentStateError> {
         match persistent_state {
-            PersistentState::BatteryBackedRam(data) => self.prg_ram.copy_from_slice(&data),
+            PersistentState::BatteryBackedRam(data) => self.prg_ram.copy_from_slice(data),
         }
         Ok(())
     }
@@ -413,3 +441,73 @@ impl MemoryMap for Mmc1 {
         }
     }
 }
+
+// Analysis uses the PRG ROM bank mode the mapper is in when it's analysed. In the power-on mode
+// the last bank is fixed at 0xC000 and any other bank can be switched in at 0x8000. In 32KB mode
+// the selected bank is always even, and is mapped at 0x8000 with the following bank at 0xC000.
+impl BankedMemoryMap for Mmc1 {
+    fn switchable_banks(&self) -> Vec<Bank> {
+        let num_banks = self.prg_rom_banks.len();
+        if num_banks <= 2 {
+            // the entire ROM is always mapped
+            return vec![0];
+        }
+        match self.prg_rom_bank_mode {
+            PrgRomBankMode::SwitchLower => (0..(num_banks - 1)).collect(),
+            PrgRomBankMode::SwitchUpper => (1..num_banks).collect(),
+            PrgRomBankMode::SwitchBoth => (0..num_banks).step_by(2).collect(),
+        }
+    }
+    fn bank_at(&self, selected: Bank, address: Address) -> Option<Bank> {
+        let last = self.prg_rom_banks.len() - 1;
+        let (lower, upper) = if self.prg_rom_banks.len() <= 2 {
+            (0, last)
+        } else {
+            match self.prg_rom_bank_mode {
+                PrgRomBankMode::SwitchLower => (selected, last),
+                PrgRomBankMode::SwitchUpper => (0, selected),
+                PrgRomBankMode::SwitchBoth => (selected & !1, (selected | 1).min(last)),
+            }
+        };
+        match address {
+            0x8000..=0xBFFF => Some(lower),
+            0xC000..=0xFFFF => Some(upper),
+            _ => None,
+        }
+    }
+    fn read_banked_u8(&self, bank: Bank, address: Address) -> u8 {
+        self.prg_rom_banks[bank].rom[(address as usize) % PRG_ROM_BANK_BYTES]
+    }
+    fn bank_selected_by_writes(&self, writes: &[(Address, u8)]) -> Option<Bank> {
+        let mut shift_register = 0;
+        let mut num_shift_register_writes = 0;
+        let mut selected = None;
+        for &(address, data) in writes {
+            if address < 0x8000 {
+                continue;
+            }
+            if data & 1 << 7 != 0 {
+                num_shift_register_writes = 0;
+                shift_register = 0;
+            } else if num_shift_register_writes == MAX_NUM_SHIFT_REGISTER_WRITES {
+                let value = ((data & 1) << 4) | shift_register;
+                if (address.wrapping_shr(13) & 3) as u8 == registers::PRG_BANK {
+                    let bank = match self.prg_rom_bank_mode {
+                        PrgRomBankMode::SwitchBoth => value & 0xE,
+                        PrgRomBankMode::SwitchLower | PrgRomBankMode::SwitchUpper => value & 0xF,
+                    };
+                    selected = Some(bank as Bank % self.prg_rom_banks.len());
+                }
+                num_shift_register_writes = 0;
+                shift_register = 0;
+            } else {
+                shift_register |= (data & 1) << num_shift_register_writes;
+                num_shift_register_writes += 1;
+            }
+        }
+        selected
+    }
+}
+
+#[cfg(test)]
+mod test;
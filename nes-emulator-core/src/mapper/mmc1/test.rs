@@ -0,0 +1,75 @@
+use super::*;
+
+const NUM_PRG_ROM_BANKS: usize = 4;
+
+fn mmc1() -> Mmc1 {
+    let prg_rom_raw = (0..NUM_PRG_ROM_BANKS)
+        .flat_map(|bank| std::iter::repeat_n(bank as u8, PRG_ROM_BANK_BYTES))
+        .collect::<Vec<_>>();
+    Mmc1::new(&prg_rom_raw, &[0; 2 * CHR_ROM_BANK_BYTES]).unwrap()
+}
+
+// The writes which load `value` into the register at `address` through the shift register
+fn serial_writes(address: Address, value: u8) -> Vec<(Address, u8)> {
+    (0..5).map(|i| (address, (value >> i) & 1)).collect()
+}
+
+fn write_all(mmc1: &mut Mmc1, writes: &[(Address, u8)]) {
+    for &(address, data) in writes {
+        mmc1.cpu_write_u8(address, data);
+    }
+}
+
+// Checks that selecting each bank with the mapper in the mode set by `control` maps the same
+// banks the analysis expects, and returns the banks the analysis considers switchable
+fn check_analysis_matches_mapper(control: u8) -> Vec<Bank> {
+    let mut mmc1 = mmc1();
+    write_all(&mut mmc1, &serial_writes(0x8000, control));
+    for value in 0..NUM_PRG_ROM_BANKS as u8 {
+        let writes = serial_writes(0xE000, value);
+        let selected = mmc1.bank_selected_by_writes(&writes).unwrap();
+        write_all(&mut mmc1, &writes);
+        for address in [0x8000, 0xBFFF, 0xC000, 0xFFFF] {
+            let bank = mmc1.bank_at(selected, address);
+            assert_eq!(
+                bank,
+                mmc1.cpu_prg_rom_bank(address),
+                "control {:02X}, value {}, address {:04X}",
+                control,
+                value,
+                address
+            );
+            assert_eq!(
+                mmc1.read_banked_u8(bank.unwrap(), address),
+                mmc1.cpu_read_u8_read_only(address)
+            );
+        }
+    }
+    mmc1.switchable_banks()
+}
+
+#[test]
+fn switch_lower_bank_mode_matches_analysis() {
+    assert_eq!(check_analysis_matches_mapper(0x0C), vec![0, 1, 2]);
+    assert_eq!(mmc1().switchable_banks(), vec![0, 1, 2]);
+}
+
+#[test]
+fn switch_upper_bank_mode_matches_analysis() {
+    assert_eq!(check_analysis_matches_mapper(0x08), vec![1, 2, 3]);
+}
+
+#[test]
+fn switch_both_bank_mode_matches_analysis() {
+    assert_eq!(check_analysis_matches_mapper(0x00), vec![0, 2]);
+    assert_eq!(check_analysis_matches_mapper(0x04), vec![0, 2]);
+}
+
+#[test]
+fn reset_write_clears_the_shift_register() {
+    let mmc1 = mmc1();
+    let mut writes = vec![(0xE000, 1), (0xE000, 0x80)];
+    writes.extend(serial_writes(0xE000, 2));
+    assert_eq!(mmc1.bank_selected_by_writes(&writes), Some(2));
+    assert_eq!(mmc1.bank_selected_by_writes(&writes[..4]), None);
+}
@@ -35,12 +35,20 @@ pub trait PpuMapper {
     fn ppu_pattern_table(&self, choice: PatternTableChoice) -> &[u8];
     fn ppu_name_table(&self, choice: NameTableChoice) -> &[u8];
     fn ppu_palette_ram(&self) -> &[u8];
+    // Returns the offset into CHR ROM of the pattern table byte at the given address
+    fn ppu_chr_rom_offset(&self, address: PpuAddress) -> Option<usize>;
 }
 
 pub trait CpuMapper {
     fn cpu_read_u8(&mut self, address: Address) -> u8;
     fn cpu_write_u8(&mut self, address: Address, data: u8);
     fn cpu_read_u8_read_only(&self, address: Address) -> u8;
+    // Returns the index of the PRG ROM bank currently mapped at the given address
+    fn cpu_prg_rom_bank(&self, address: Address) -> Option<usize>;
+    // Returns the offset into PRG ROM of the byte currently mapped at the given address
+    fn cpu_prg_rom_offset(&self, address: Address) -> Option<usize>;
+    // Returns true if writing to the given address configures the mapper
+    fn cpu_is_register(&self, address: Address) -> bool;
 }
 
 #[derive(Debug)]
@@ -7,7 +7,7 @@ use crate::mapper::{NameTableChoice, PaletteRam, PatternTableChoice};
 use crate::mapper::{PersistentState, PersistentStateError};
 use crate::nes::Nes;
 use crate::ppu::{name_table_mirroring, NAME_TABLE_BYTES};
-use analyser::MemoryMap;
+use analyser::{Bank, BankedMemoryMap, MemoryMap};
 use mos6502_model::{machine::MemoryReadOnly, Address};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
@@ -72,17 +72,17 @@ impl<M: Mirroring> Nrom<M> {
         let mut chr_rom = [0; CHR_ROM_BYTES];
         const HALF_PRG_ROM_BYTES: usize = PRG_ROM_BYTES / 2;
         match prg_rom_raw.len() {
-            PRG_ROM_BYTES => (&mut prg_rom).copy_from_slice(prg_rom_raw),
+            PRG_ROM_BYTES => prg_rom.copy_from_slice(prg_rom_raw),
             HALF_PRG_ROM_BYTES => {
                 // copy the prg data into each half of prg_rom
-                (&mut prg_rom[0..HALF_PRG_ROM_BYTES]).copy_from_slice(prg_rom_raw);
-                (&mut prg_rom[HALF_PRG_ROM_BYTES..]).copy_from_slice(prg_rom_raw);
+                prg_rom[0..HALF_PRG_ROM_BYTES].copy_from_slice(prg_rom_raw);
+                prg_rom[HALF_PRG_ROM_BYTES..].copy_from_slice(prg_rom_raw);
             }
             _ => return Err(Error::UnexpectedPrgRomSize),
         }
         match chr_rom_raw.len() {
             0 => (),
-            CHR_ROM_BYTES => (&mut chr_rom).copy_from_slice(chr_rom_raw),
+            CHR_ROM_BYTES => chr_rom.copy_from_slice(chr_rom_raw),
             _ => return Err(Error::UnexpectedChrRomSize),
         }
         let name_table_ram = [0; NAME_TABLE_RAM_BYTES];
@@ -136,6 +136,12 @@ impl<M: Mirroring> PpuMapper for Nrom<M> {
     fn ppu_palette_ram(&self) -> &[u8] {
         &self.palette_ram.ram
     }
+    fn ppu_chr_rom_offset(&self, address: PpuAddress) -> Option<usize> {
+        match address % 0x4000 {
+            address @ 0x0000..=0x1FFF => Some(address as usize),
+            _ => None,
+        }
+    }
 }
 
 impl<M: Mirroring> CpuMapper for Nrom<M> {
@@ -161,6 +167,23 @@ impl<M: Mirroring> CpuMapper for Nrom<M> {
             }
         }
     }
+    fn cpu_prg_rom_bank(&self, address: Address) -> Option<usize> {
+        if address >= 0x8000 {
+            Some(0)
+        } else {
+            None
+        }
+    }
+    fn cpu_prg_rom_offset(&self, address: Address) -> Option<usize> {
+        if address >= 0x8000 {
+            Some(address as usize % 0x8000)
+        } else {
+            None
+        }
+    }
+    fn cpu_is_register(&self, _address: Address) -> bool {
+        false
+    }
 }
 
 impl<M: Mirroring> Mapper for Nrom<M> {
@@ -201,3 +224,18 @@ impl<M: Mirroring> MemoryMap for Nrom<M> {
         }
     }
 }
+
+impl<M: Mirroring> BankedMemoryMap for Nrom<M> {
+    fn switchable_banks(&self) -> Vec<Bank> {
+        vec![0]
+    }
+    fn bank_at(&self, _selected: Bank, address: Address) -> Option<Bank> {
+        self.cpu_prg_rom_bank(address)
+    }
+    fn read_banked_u8(&self, _bank: Bank, address: Address) -> u8 {
+        self.cpu_read_u8_read_only(address)
+    }
+    fn bank_selected_by_writes(&self, _writes: &[(Address, u8)]) -> Option<Bank> {
+        None
+    }
+}
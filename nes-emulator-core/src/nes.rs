@@ -1,9 +1,13 @@
 use crate::apu::Apu;
+use crate::debug_port::DebugPort;
+use crate::debugger::{Break, Debugger, Position, StopReason};
 use crate::dynamic_nes::DynamicNes;
 use crate::mapper::{Mapper, PersistentState, PersistentStateError};
-use crate::ppu::{Oam, Ppu, ScanlineIter};
+use crate::observer::{Observed, Observer};
+use crate::ppu::{Oam, Ppu, Scanline, SpriteZero};
 use crate::timing;
 use mos6502_model::debug::InstructionWithOperand;
+use mos6502_model::hook::Hooks;
 use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
 use nes_name_table_debug::NameTableFrame;
 use nes_render_output::RenderOutput;
@@ -21,6 +25,9 @@ struct NesDevices<M: Mapper> {
     apu: Apu,
     controller1: Controller,
     mapper: M,
+    // not part of saved state, so it must be enabled again after loading
+    #[serde(skip)]
+    debug_port: Option<DebugPort>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -132,7 +139,7 @@ impl Controller {
 
 impl<M: Mapper> Memory for NesDevices<M> {
     fn read_u8(&mut self, address: Address) -> u8 {
-        let data = match address {
+        match address {
             0..=0x1FFF => self.ram[address as usize % RAM_BYTES],
             0x2000..=0x3FFF => match address % 8 {
                 0 => 0,
@@ -147,8 +154,7 @@ impl<M: Mapper> Memory for NesDevices<M> {
             0x4016 => self.controller1.shift_read(),
             0x4000..=0x401F => 0,
             cartridge_address => self.mapper.cpu_read_u8(cartridge_address),
-        };
-        data
+        }
     }
     fn read_u8_zero_page(&mut self, address: u8) -> u8 {
         self.ram[address as usize]
@@ -176,6 +182,11 @@ impl<M: Mapper> Memory for NesDevices<M> {
                     self.controller1.clear_strobe();
                 }
             }
+            0x4018..=0x401F => {
+                if let Some(debug_port) = self.debug_port.as_mut() {
+                    debug_port.write_u8(address, data);
+                }
+            }
             0x4000..=0x401F => {}
             cartridge_address => self.mapper.cpu_write_u8(cartridge_address, data),
         }
@@ -218,12 +229,11 @@ impl<M: Mapper> Memory for NesDevicesWithOam<M> {
 
 impl<M: Mapper> MemoryReadOnly for NesDevices<M> {
     fn read_u8_read_only(&self, address: Address) -> u8 {
-        let data = match address {
+        match address {
             0..=0x1FFF => self.ram[address as usize % RAM_BYTES],
             0x2000..=0x401F => 0,
             cartridge_address => self.mapper.cpu_read_u8_read_only(cartridge_address),
-        };
-        data
+        }
     }
 }
 
@@ -233,13 +243,62 @@ impl<M: Mapper> MemoryReadOnly for NesDevicesWithOam<M> {
     }
 }
 
+// Lets code observing the CPU find out how the cartridge's ROM is mapped and used, and what the
+// PPU is doing
+pub trait RomMapping {
+    fn prg_rom_bank(&self, address: Address) -> Option<usize>;
+    fn prg_rom_offset(&self, address: Address) -> Option<usize>;
+    fn is_mapper_register(&self, address: Address) -> bool;
+    // Calls `f` with the offset into CHR ROM of each pattern byte used to draw the current frame
+    fn for_each_chr_rom_offset_in_use<F: FnMut(usize)>(&self, f: F);
+    fn ppu(&self) -> &Ppu;
+    fn oam(&self) -> &Oam;
+}
+
+impl<M: Mapper> RomMapping for NesDevicesWithOam<M> {
+    fn prg_rom_bank(&self, address: Address) -> Option<usize> {
+        self.devices.mapper.cpu_prg_rom_bank(address)
+    }
+    fn prg_rom_offset(&self, address: Address) -> Option<usize> {
+        self.devices.mapper.cpu_prg_rom_offset(address)
+    }
+    fn is_mapper_register(&self, address: Address) -> bool {
+        self.devices.mapper.cpu_is_register(address)
+    }
+    fn for_each_chr_rom_offset_in_use<F: FnMut(usize)>(&self, mut f: F) {
+        let mapper = &self.devices.mapper;
+        self.devices
+            .ppu
+            .for_each_pattern_address_in_use(mapper, &self.oam, |address| {
+                if let Some(offset) = mapper.ppu_chr_rom_offset(address) {
+                    f(offset);
+                }
+            });
+    }
+    fn ppu(&self) -> &Ppu {
+        &self.devices.ppu
+    }
+    fn oam(&self) -> &Oam {
+        &self.oam
+    }
+}
+
 pub trait RunForCycles {
-    fn run_for_cycles<M: Memory + MemoryReadOnly>(
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
         &mut self,
         cpu: &mut Cpu,
         memory: &mut M,
         num_cycles: u32,
     );
+
+    // Called before the CPU runs each stage of a frame
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _stage: FrameStage,
+        _cpu: &Cpu,
+        _memory: &M,
+    ) {
+    }
 }
 
 pub struct RunForCyclesRegular;
@@ -247,7 +306,7 @@ pub struct RunForCyclesRegular;
 pub struct RunForCyclesDebug;
 
 impl RunForCycles for RunForCyclesRegular {
-    fn run_for_cycles<M: Memory + MemoryReadOnly>(
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
         &mut self,
         cpu: &mut Cpu,
         memory: &mut M,
@@ -257,23 +316,100 @@ impl RunForCycles for RunForCyclesRegular {
     }
 }
 
+// Prints each instruction before it's executed
+impl Observer for RunForCyclesDebug {
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        if let Ok(instruction_with_operand) = InstructionWithOperand::next(cpu, memory) {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+            let _ = writeln!(handle, "{}", instruction_with_operand);
+        }
+    }
+}
+
 impl RunForCycles for RunForCyclesDebug {
-    fn run_for_cycles<M: Memory + MemoryReadOnly>(
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+}
+
+impl RunForCycles for Hooks {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
         &mut self,
         cpu: &mut Cpu,
         memory: &mut M,
         num_cycles: u32,
     ) {
-        let mut count = 0;
-        while count < num_cycles {
-            if let Ok(instruction_with_operand) = InstructionWithOperand::next(cpu, memory) {
-                let stdout = io::stdout();
-                let mut handle = stdout.lock();
-                let _ = writeln!(handle, "{}", instruction_with_operand);
+        cpu.run_for_cycles_with_hooks(memory, num_cycles as usize, self)
+            .unwrap();
+    }
+}
+
+// The parts of a frame between which the PPU does its work, in the order they're run by
+// `Nes::run_for_frame_general`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FrameStage {
+    #[default]
+    PreRender,
+    Scanline(u8),
+    // the rest of a scanline after a sprite zero hit
+    AfterSpriteZeroHit {
+        scanline: u8,
+        num_cycles: u32,
+    },
+    PostRender,
+    VBlank,
+}
+
+impl FrameStage {
+    fn num_cycles(self) -> u32 {
+        match self {
+            Self::PreRender | Self::Scanline(_) | Self::PostRender => {
+                timing::ntsc::APPROX_CPU_CYCLES_PER_SCANLINE
             }
-            count += cpu.step(memory).unwrap() as u32;
+            Self::AfterSpriteZeroHit { num_cycles, .. } => num_cycles,
+            Self::VBlank => timing::ntsc::APPROX_CPU_CYCLES_PER_VBLANK,
         }
     }
+
+    // The scanline and dot reached after running for `cycles` cycles of this stage
+    pub fn ppu_position(self, cycles: u32) -> (u16, u16) {
+        const PPU_CYCLES_PER_SCANLINE: u32 = 341;
+        let ppu_cycles = cycles * timing::ntsc::NUM_PPU_CYCLES_PER_CPU_CYCLE;
+        let (first_scanline, first_dot) = match self {
+            Self::PreRender => (261, 0),
+            Self::Scanline(scanline) => (scanline as u32, 0),
+            Self::AfterSpriteZeroHit {
+                scanline,
+                num_cycles,
+            } => (
+                scanline as u32,
+                PPU_CYCLES_PER_SCANLINE
+                    .saturating_sub(num_cycles * timing::ntsc::NUM_PPU_CYCLES_PER_CPU_CYCLE),
+            ),
+            Self::PostRender => (240, 0),
+            Self::VBlank => (241, 0),
+        };
+        let dot = first_dot + ppu_cycles;
+        (
+            (first_scanline + dot / PPU_CYCLES_PER_SCANLINE) as u16,
+            (dot % PPU_CYCLES_PER_SCANLINE) as u16,
+        )
+    }
+}
+
+// How far through a frame `Nes::run_for_frame_general` or `Nes::run_until_break` has got
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FrameProgress {
+    stage: FrameStage,
+    // cycles run so far during the current stage
+    cycles: u32,
+    sprite_zero: Option<SpriteZero>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -296,6 +432,7 @@ impl<M: Mapper> Nes<M> {
                     apu: Apu::new(),
                     controller1: Controller::new(),
                     mapper,
+                    debug_port: None,
                 },
                 oam: Oam::new(),
             },
@@ -309,74 +446,155 @@ impl<M: Mapper> Nes<M> {
         pixels: &mut O,
         mut name_table_frame: Option<&mut NameTableFrame>,
     ) {
-        // pre-render scanline
-        run.run_for_cycles(
-            &mut self.cpu,
-            &mut self.devices,
-            timing::ntsc::APPROX_CPU_CYCLES_PER_SCANLINE,
-        );
-        self.devices.devices.ppu.render_sprites(
-            &self.devices.devices.mapper,
-            &self.devices.oam,
-            pixels,
-        );
-        if let Some(ref mut name_table_frame) = name_table_frame {
-            self.devices
-                .devices
-                .ppu
-                .debug_render_name_table_frame(&self.devices.devices.mapper, name_table_frame);
-        }
-        let sprite_zero = self
-            .devices
-            .devices
-            .ppu
-            .sprite_zero(&self.devices.oam, &mut self.devices.devices.mapper);
-        for scanline in ScanlineIter::new() {
-            if let Some(ref mut name_table_frame) = name_table_frame {
+        let mut progress = FrameProgress::default();
+        loop {
+            if let (Some(name_table_frame), FrameStage::Scanline(scanline)) =
+                (name_table_frame.as_deref_mut(), progress.stage)
+            {
                 name_table_frame.set_scroll(
-                    scanline.index(),
+                    scanline,
                     self.devices.devices.ppu.scroll_x(),
                     self.devices.devices.ppu.scroll_y(),
                 );
             }
+            run.before_frame_stage(progress.stage, &self.cpu, &self.devices);
             run.run_for_cycles(
                 &mut self.cpu,
                 &mut self.devices,
-                timing::ntsc::APPROX_CPU_CYCLES_PER_SCANLINE,
+                progress.stage.num_cycles(),
             );
-            if let Some(sprite_zero_hit) = self.devices.devices.ppu.render_background_scanline(
-                scanline,
-                &sprite_zero,
-                &self.devices.devices.mapper,
-                pixels,
-            ) {
-                let pixels_after_sprite_zero_hit =
-                    nes_specs::SCREEN_WIDTH_PX - sprite_zero_hit.screen_pixel_x() as u16;
-                let approx_cpu_cycles_after_sprite_zero_hit = pixels_after_sprite_zero_hit as u32
-                    / timing::ntsc::NUM_PPU_CYCLES_PER_CPU_CYCLE;
-                run.run_for_cycles(
-                    &mut self.cpu,
-                    &mut self.devices,
-                    approx_cpu_cycles_after_sprite_zero_hit,
-                );
+            if progress.stage == FrameStage::PreRender {
+                if let Some(ref mut name_table_frame) = name_table_frame {
+                    self.devices.devices.ppu.debug_render_name_table_frame(
+                        &self.devices.devices.mapper,
+                        name_table_frame,
+                    );
+                }
+            }
+            if self.finish_frame_stage(&mut progress, pixels) {
+                break;
             }
         }
-        // post-render scanline
-        run.run_for_cycles(
-            &mut self.cpu,
-            &mut self.devices,
-            timing::ntsc::APPROX_CPU_CYCLES_PER_SCANLINE,
-        );
-        if self.devices.devices.ppu.is_vblank_nmi_enabled() {
-            self.cpu.nmi(&mut self.devices);
+    }
+    fn position(&self, debugger: &Debugger) -> Position {
+        let progress = &debugger.frame_progress;
+        let (scanline, dot) = progress.stage.ppu_position(progress.cycles);
+        Position {
+            frame: debugger.frame,
+            cycle: debugger.cycle,
+            pc: self.cpu.pc,
+            scanline,
+            dot,
+        }
+    }
+    // Does the PPU's work at the end of the current stage of the frame and moves on to the next
+    // stage, returning true if the frame is complete
+    fn finish_frame_stage<O: RenderOutput>(
+        &mut self,
+        progress: &mut FrameProgress,
+        pixels: &mut O,
+    ) -> bool {
+        let next_scanline = |scanline: u8| {
+            if scanline as u16 + 1 == nes_specs::SCREEN_HEIGHT_PX {
+                FrameStage::PostRender
+            } else {
+                FrameStage::Scanline(scanline + 1)
+            }
+        };
+        let ppu = &mut self.devices.devices.ppu;
+        let mapper = &mut self.devices.devices.mapper;
+        progress.cycles = 0;
+        progress.stage = match progress.stage {
+            FrameStage::PreRender => {
+                ppu.render_sprites(mapper, &self.devices.oam, pixels);
+                progress.sprite_zero = Some(ppu.sprite_zero(&self.devices.oam, mapper));
+                FrameStage::Scanline(0)
+            }
+            FrameStage::Scanline(scanline) => {
+                let sprite_zero = progress
+                    .sprite_zero
+                    .get_or_insert_with(|| ppu.sprite_zero(&self.devices.oam, mapper));
+                match ppu.render_background_scanline(
+                    Scanline::new(scanline),
+                    sprite_zero,
+                    mapper,
+                    pixels,
+                ) {
+                    Some(sprite_zero_hit) => {
+                        let pixels_after_sprite_zero_hit =
+                            nes_specs::SCREEN_WIDTH_PX - sprite_zero_hit.screen_pixel_x() as u16;
+                        FrameStage::AfterSpriteZeroHit {
+                            scanline,
+                            num_cycles: pixels_after_sprite_zero_hit as u32
+                                / timing::ntsc::NUM_PPU_CYCLES_PER_CPU_CYCLE,
+                        }
+                    }
+                    None => next_scanline(scanline),
+                }
+            }
+            FrameStage::AfterSpriteZeroHit { scanline, .. } => next_scanline(scanline),
+            FrameStage::PostRender => {
+                if ppu.is_vblank_nmi_enabled() {
+                    self.cpu.nmi(&mut self.devices);
+                }
+                self.devices.devices.ppu.before_vblank();
+                FrameStage::VBlank
+            }
+            FrameStage::VBlank => {
+                ppu.after_vblank();
+                progress.sprite_zero = None;
+                FrameStage::PreRender
+            }
+        };
+        progress.stage == FrameStage::PreRender
+    }
+    // Runs until a breakpoint or step set up in `debugger` stops emulation, or the end of the
+    // frame. Frames are run the same way as `run_for_frame_general`, without the name table
+    // debug frame, and `pixels` should be the same for each call during a frame.
+    pub fn run_until_break<O: RenderOutput>(
+        &mut self,
+        debugger: &mut Debugger,
+        pixels: &mut O,
+    ) -> Break {
+        self.run_until_break_with_hooks(debugger, &mut Hooks::new(), pixels)
+    }
+    // Like `run_until_break`, calling any hooks at addresses reached by the CPU
+    pub fn run_until_break_with_hooks<O: RenderOutput>(
+        &mut self,
+        debugger: &mut Debugger,
+        hooks: &mut Hooks,
+        pixels: &mut O,
+    ) -> Break {
+        loop {
+            while debugger.frame_progress.cycles < debugger.frame_progress.stage.num_cycles() {
+                let (num_cycles, stop) =
+                    debugger.step_with_hooks(&mut self.cpu, &mut self.devices, hooks);
+                debugger.frame_progress.cycles += num_cycles as u32;
+                if let Some(reason) = stop {
+                    return Break {
+                        reason,
+                        position: self.position(debugger),
+                    };
+                }
+                let debug_port = self.devices.devices.debug_port.as_mut();
+                if debug_port.is_some_and(DebugPort::take_break) {
+                    return Break {
+                        reason: StopReason::DebugPortBreak,
+                        position: self.position(debugger),
+                    };
+                }
+            }
+            let mut progress = std::mem::take(&mut debugger.frame_progress);
+            let is_frame_complete = self.finish_frame_stage(&mut progress, pixels);
+            debugger.frame_progress = progress;
+            if is_frame_complete {
+                debugger.frame += 1;
+                return Break {
+                    reason: StopReason::FrameComplete,
+                    position: self.position(debugger),
+                };
+            }
         }
-        self.devices.devices.ppu.before_vblank();
-        run.run_for_cycles(
-            &mut self.cpu,
-            &mut self.devices,
-            timing::ntsc::APPROX_CPU_CYCLES_PER_VBLANK,
-        );
-        self.devices.devices.ppu.after_vblank();
     }
     pub fn run_for_frame<O: RenderOutput>(
         &mut self,
@@ -385,6 +603,15 @@ impl<M: Mapper> Nes<M> {
     ) {
         self.run_for_frame_general(&mut RunForCyclesRegular, pixels, name_table_frame);
     }
+    // Runs a frame, calling any hooks at addresses reached by the CPU
+    pub fn run_for_frame_with_hooks<O: RenderOutput>(
+        &mut self,
+        hooks: &mut Hooks,
+        pixels: &mut O,
+        name_table_frame: Option<&mut NameTableFrame>,
+    ) {
+        self.run_for_frame_general(hooks, pixels, name_table_frame);
+    }
     pub fn run_for_frame_debug<O: RenderOutput>(
         &mut self,
         pixels: &mut O,
@@ -419,6 +646,30 @@ impl<M: Mapper> Nes<M> {
     pub fn devices_with_oam(&self) -> &NesDevicesWithOam<M> {
         &self.devices
     }
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+    // Maps the emulator-only debug port into 0x4018..=0x401F
+    pub fn enable_debug_port(&mut self) {
+        self.devices.devices.debug_port = Some(DebugPort::new());
+    }
+    pub fn debug_port_mut(&mut self) -> Option<&mut DebugPort> {
+        self.devices.devices.debug_port.as_mut()
+    }
+    // Fills RAM with a pseudorandom pattern, as real hardware doesn't clear RAM on power-on
+    pub fn randomise_ram(&mut self, seed: u64) {
+        // xorshift64, which gets stuck at 0
+        let mut state = seed.max(1);
+        for byte in self.devices.devices.ram.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = (state >> 32) as u8;
+        }
+    }
+    pub fn oam(&self) -> &Oam {
+        &self.devices.oam
+    }
 }
 
 pub mod controller1 {
@@ -0,0 +1,306 @@
+use crate::nes::{FrameStage, RomMapping, RunForCycles};
+use mos6502_model::hook::Hooks;
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+
+// Watches the CPU run an instruction at a time. Unlike a `RunForCycles`, an observer doesn't
+// execute instructions itself, so several observers can watch the same run by combining them in
+// a tuple, and an observer can be turned off by wrapping it in an `Option`. Run observers with
+// `step` or `Observed`, or with `step_with_hooks` or `Hooked` to run hooks as well.
+pub trait Observer {
+    // Called before the CPU runs each stage of a frame, when run by `Nes::run_for_frame_general`
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _stage: FrameStage,
+        _cpu: &Cpu,
+        _memory: &M,
+    ) {
+    }
+
+    // Called before each instruction is executed
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, _cpu: &Cpu, _memory: &M) {}
+
+    // Called before each read of memory made by the instruction at `pc`
+    fn before_read<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _pc: Address,
+        _memory: &M,
+        _address: Address,
+    ) {
+    }
+
+    // Called before each write to memory made by the instruction at `pc`
+    fn before_write<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _pc: Address,
+        _memory: &M,
+        _address: Address,
+        _data: u8,
+    ) {
+    }
+
+    // Called after each instruction is executed, with the number of cycles it took
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _cpu: &Cpu,
+        _memory: &M,
+        _num_cycles: u8,
+    ) {
+    }
+}
+
+impl<O: Observer> Observer for &mut O {
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        (**self).before_frame_stage(stage, cpu, memory);
+    }
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        (**self).before_instruction(cpu, memory);
+    }
+    fn before_read<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+    ) {
+        (**self).before_read(pc, memory, address);
+    }
+    fn before_write<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+        data: u8,
+    ) {
+        (**self).before_write(pc, memory, address, data);
+    }
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &Cpu,
+        memory: &M,
+        num_cycles: u8,
+    ) {
+        (**self).after_instruction(cpu, memory, num_cycles);
+    }
+}
+
+impl<O: Observer> Observer for Option<O> {
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        if let Some(observer) = self {
+            observer.before_frame_stage(stage, cpu, memory);
+        }
+    }
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        if let Some(observer) = self {
+            observer.before_instruction(cpu, memory);
+        }
+    }
+    fn before_read<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+    ) {
+        if let Some(observer) = self {
+            observer.before_read(pc, memory, address);
+        }
+    }
+    fn before_write<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+        data: u8,
+    ) {
+        if let Some(observer) = self {
+            observer.before_write(pc, memory, address, data);
+        }
+    }
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &Cpu,
+        memory: &M,
+        num_cycles: u8,
+    ) {
+        if let Some(observer) = self {
+            observer.after_instruction(cpu, memory, num_cycles);
+        }
+    }
+}
+
+// Observers in a tuple are called in order
+macro_rules! impl_observer_for_tuple {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: Observer),*> Observer for ($($name,)*) {
+            fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+                &mut self,
+                stage: FrameStage,
+                cpu: &Cpu,
+                memory: &M,
+            ) {
+                let ($($name,)*) = self;
+                $($name.before_frame_stage(stage, cpu, memory);)*
+            }
+            fn before_instruction<M: MemoryReadOnly + RomMapping>(
+                &mut self,
+                cpu: &Cpu,
+                memory: &M,
+            ) {
+                let ($($name,)*) = self;
+                $($name.before_instruction(cpu, memory);)*
+            }
+            fn before_read<M: MemoryReadOnly + RomMapping>(
+                &mut self,
+                pc: Address,
+                memory: &M,
+                address: Address,
+            ) {
+                let ($($name,)*) = self;
+                $($name.before_read(pc, memory, address);)*
+            }
+            fn before_write<M: MemoryReadOnly + RomMapping>(
+                &mut self,
+                pc: Address,
+                memory: &M,
+                address: Address,
+                data: u8,
+            ) {
+                let ($($name,)*) = self;
+                $($name.before_write(pc, memory, address, data);)*
+            }
+            fn after_instruction<M: MemoryReadOnly + RomMapping>(
+                &mut self,
+                cpu: &Cpu,
+                memory: &M,
+                num_cycles: u8,
+            ) {
+                let ($($name,)*) = self;
+                $($name.after_instruction(cpu, memory, num_cycles);)*
+            }
+        }
+    };
+}
+
+impl_observer_for_tuple!(A, B);
+impl_observer_for_tuple!(A, B, C);
+impl_observer_for_tuple!(A, B, C, D);
+impl_observer_for_tuple!(A, B, C, D, E);
+impl_observer_for_tuple!(A, B, C, D, E, F);
+
+// Memory which tells an observer about each access before passing it on
+struct Watched<'a, M, O> {
+    memory: &'a mut M,
+    observer: &'a mut O,
+    pc: Address,
+}
+
+impl<M: Memory + MemoryReadOnly + RomMapping, O: Observer> Memory for Watched<'_, M, O> {
+    fn read_u8(&mut self, address: Address) -> u8 {
+        self.observer.before_read(self.pc, self.memory, address);
+        self.memory.read_u8(address)
+    }
+    fn write_u8(&mut self, address: Address, data: u8) {
+        self.observer
+            .before_write(self.pc, self.memory, address, data);
+        self.memory.write_u8(address, data);
+    }
+}
+
+// Executes a single instruction while `observer` watches
+pub fn step<O: Observer, M: Memory + MemoryReadOnly + RomMapping>(
+    observer: &mut O,
+    cpu: &mut Cpu,
+    memory: &mut M,
+) -> Result<u8, UnknownOpcode> {
+    observer.before_instruction(cpu, memory);
+    let pc = cpu.pc;
+    let num_cycles = cpu.step(&mut Watched {
+        memory,
+        observer,
+        pc,
+    })?;
+    observer.after_instruction(cpu, memory, num_cycles);
+    Ok(num_cycles)
+}
+
+// Executes a single instruction, or the hook which replaces it, while `observer` watches. Memory
+// accessed by hooks isn't observed. Observers see a hook which replaces an instruction as that
+// instruction, with the CPU as the hook left it and the cycles the hook took.
+pub fn step_with_hooks<O: Observer, M: Memory + MemoryReadOnly + RomMapping>(
+    observer: &mut O,
+    hooks: &mut Hooks,
+    cpu: &mut Cpu,
+    memory: &mut M,
+) -> Result<u8, UnknownOpcode> {
+    if hooks.contains(cpu.pc) {
+        let cpu_before_hook = cpu.clone();
+        if let Some(num_cycles) = hooks.run(cpu, memory) {
+            observer.before_instruction(&cpu_before_hook, memory);
+            observer.after_instruction(cpu, memory, num_cycles);
+            return Ok(num_cycles);
+        }
+    }
+    step(observer, cpu, memory)
+}
+
+// Runs the CPU while an observer watches
+pub struct Observed<O>(pub O);
+
+impl<O: Observer> RunForCycles for Observed<O> {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        let mut count = 0;
+        while count < num_cycles {
+            count += step(&mut self.0, cpu, memory).unwrap() as u32;
+        }
+    }
+
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        self.0.before_frame_stage(stage, cpu, memory);
+    }
+}
+
+// Runs the CPU with hooks while an observer watches
+pub struct Hooked<'a, O>(pub &'a mut Hooks, pub O);
+
+impl<O: Observer> RunForCycles for Hooked<'_, O> {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        let mut count = 0;
+        while count < num_cycles {
+            count += step_with_hooks(&mut self.1, self.0, cpu, memory).unwrap() as u32;
+        }
+    }
+
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        self.1.before_frame_stage(stage, cpu, memory);
+    }
+}
@@ -68,6 +68,12 @@ struct OamEntry {
     position_y: u8,
 }
 
+impl Default for Oam {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Oam {
     pub fn new() -> Self {
         Self {
@@ -82,6 +88,9 @@ impl Oam {
             *oam_byte = memory.read_u8(address)
         }
     }
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
     fn get(&self, sprite_index: usize) -> Option<OamEntry> {
         let oam_entry_address = sprite_index * OAM_SPRITE_BYTES;
         let oam_entry = &self.ram[oam_entry_address..oam_entry_address + OAM_SPRITE_BYTES];
@@ -124,6 +133,7 @@ impl OamEntry {
         };
         (tile_offset, pattern_table_choice)
     }
+    #[allow(clippy::too_many_arguments)]
     fn render_pixel_row<O: RenderOutput>(
         &self,
         pixel_row_lo: u8,
@@ -249,8 +259,8 @@ impl NameTableEntry {
         let tile_2x2_block_coord_x = name_table_relative_tile_x / 2;
         let tile_2x2_block_coord_y = name_table_relative_tile_y / 2;
         let attribute_shift_to_select_2x2_tile_block = match (
-            tile_2x2_block_coord_y % 2 == 0,
-            tile_2x2_block_coord_x % 2 == 0,
+            tile_2x2_block_coord_y.is_multiple_of(2),
+            tile_2x2_block_coord_x.is_multiple_of(2),
         ) {
             (true, true) => 0,
             (true, false) => 2,
@@ -271,6 +281,7 @@ impl NameTableEntry {
             palette,
         }
     }
+    #[allow(clippy::too_many_arguments)]
     fn render_pixel_row<O: RenderOutput>(
         &self,
         scanline: u8,
@@ -352,7 +363,7 @@ impl ScrollStateAddress {
     }
     fn set_address_hi(&mut self, data: u8) {
         self.0 = (self.0 & !(0x3F << 8)) | ((data as u16 & 0x3F) << 8);
-        self.0 = self.0 & !(0x1 << 14);
+        self.0 &= !(0x1 << 14);
     }
     fn set_address_lo(&mut self, data: u8) {
         self.0 = (self.0 & !0xFF) | data as u16;
@@ -481,6 +492,9 @@ impl ScrollState {
 pub struct Scanline(u8);
 
 impl Scanline {
+    pub(crate) fn new(index: u8) -> Self {
+        Self(index)
+    }
     pub fn index(&self) -> u8 {
         self.0
     }
@@ -490,6 +504,12 @@ pub struct ScanlineIter {
     scanline: u8,
 }
 
+impl Default for ScanlineIter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ScanlineIter {
     pub fn new() -> Self {
         Self { scanline: 0 }
@@ -532,7 +552,7 @@ pub struct Ppu {
 pub type PpuAddress = u16;
 pub const PALETTE_START: PpuAddress = 0x3F00;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct SpriteZero {
     opaque_pixel_map: u128,
     top_left_x: u8,
@@ -624,6 +644,12 @@ impl PpuDebug {
     }
 }
 
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Ppu {
     pub fn new() -> Self {
         Self {
@@ -652,6 +678,40 @@ impl Ppu {
     pub fn is_vblank_nmi_enabled(&self) -> bool {
         self.vblank_nmi
     }
+    pub fn is_rendering_enabled(&self) -> bool {
+        self.show_background || self.show_sprites
+    }
+    pub fn is_showing_sprites(&self) -> bool {
+        self.show_sprites
+    }
+    pub fn is_vblank_flag_set(&self) -> bool {
+        self.vblank_flag
+    }
+    // True after an odd number of writes to PPUSCROLL and PPUADDR since PPUSTATUS was last read
+    pub fn is_address_latch_half_set(&self) -> bool {
+        !self.scroll_state.first_write_toggle
+    }
+    // The number of sprites in `oam` covering each visible scanline. Only the first 8 sprites on
+    // a scanline are drawn by real hardware.
+    pub fn num_sprites_per_scanline(&self, oam: &Oam) -> Vec<usize> {
+        let sprite_height = match self.sprite_size {
+            SpriteSize::Small => 8,
+            SpriteSize::Large => 16,
+        };
+        let mut num_sprites = vec![0; nes_specs::SCREEN_HEIGHT_PX as usize];
+        for oam_entry in (0..OAM_NUM_SPRITES).filter_map(|sprite_index| oam.get(sprite_index)) {
+            // `position_y` already includes the 1 scanline delay before sprites are drawn
+            let top = oam_entry.position_y as usize;
+            for count in num_sprites.iter_mut().skip(top).take(sprite_height) {
+                *count += 1;
+            }
+        }
+        num_sprites
+    }
+    // The address in video memory which will be accessed through PPUDATA
+    pub fn ppu_address(&self) -> PpuAddress {
+        self.scroll_state.ppu_address()
+    }
     pub fn write_control(&mut self, data: u8) {
         self.address_increment = if data & control::flag::ADDRESS_INCREMENT != 0 {
             32
@@ -792,8 +852,8 @@ impl Ppu {
                 continue;
             };
             let pattern_offset = oam_entry.offset_within_pattern_table_8x8();
-            let pattern_lo = &sprite_pattern_table
-                [pattern_offset as usize + 0x0..=pattern_offset as usize + 0x7];
+            let pattern_lo =
+                &sprite_pattern_table[(pattern_offset as usize)..=pattern_offset as usize + 0x7];
             let pattern_hi = &sprite_pattern_table
                 [pattern_offset as usize + 0x8..=pattern_offset as usize + 0xF];
             let palette_base = oam_entry.palette_base();
@@ -825,8 +885,8 @@ impl Ppu {
             let (pattern_offset, pattern_table_choice) =
                 oam_entry.offset_within_pattern_table_8x16();
             let sprite_pattern_table = memory.ppu_pattern_table(pattern_table_choice);
-            let pattern_top_lo = &sprite_pattern_table
-                [pattern_offset as usize + 0x00..=pattern_offset as usize + 0x07];
+            let pattern_top_lo =
+                &sprite_pattern_table[(pattern_offset as usize)..=pattern_offset as usize + 0x07];
             let pattern_top_hi = &sprite_pattern_table
                 [pattern_offset as usize + 0x08..=pattern_offset as usize + 0x0F];
             let pattern_bottom_lo = &sprite_pattern_table
@@ -1010,7 +1070,7 @@ impl Ppu {
         let sprite_pattern_table = memory.ppu_pattern_table(self.sprite_pattern_table);
         let pattern_offset = oam_entry.offset_within_pattern_table_8x8();
         let pattern_lo =
-            &sprite_pattern_table[pattern_offset as usize + 0x0..=pattern_offset as usize + 0x7];
+            &sprite_pattern_table[(pattern_offset as usize)..=pattern_offset as usize + 0x7];
         let pattern_hi =
             &sprite_pattern_table[pattern_offset as usize + 0x8..=pattern_offset as usize + 0xF];
         let opaque_pixel_map = Self::sprite_opaque_pixel_map_8x8(pattern_lo, pattern_hi) as u128;
@@ -1026,7 +1086,7 @@ impl Ppu {
         let (pattern_offset, pattern_table_choice) = oam_entry.offset_within_pattern_table_8x16();
         let sprite_pattern_table = memory.ppu_pattern_table(pattern_table_choice);
         let pattern_top_lo =
-            &sprite_pattern_table[pattern_offset as usize + 0x00..=pattern_offset as usize + 0x07];
+            &sprite_pattern_table[(pattern_offset as usize)..=pattern_offset as usize + 0x07];
         let pattern_top_hi =
             &sprite_pattern_table[pattern_offset as usize + 0x08..=pattern_offset as usize + 0x0F];
         let pattern_bottom_lo =
@@ -1079,6 +1139,68 @@ impl Ppu {
             SpriteSize::Large => self.render_sprites_8x16(memory, oam, pixels),
         }
     }
+    // Calls `f` with the address of each pattern table byte used to draw the tiles currently in
+    // the name tables and OAM. Every tile in every name table is counted, regardless of which
+    // part of the name tables is scrolled into view.
+    pub fn for_each_pattern_address_in_use<M: PpuMapper, F: FnMut(PpuAddress)>(
+        &self,
+        memory: &M,
+        oam: &Oam,
+        mut f: F,
+    ) {
+        let mut tiles_in_use = [[false; 256]; 2];
+        if self.show_background {
+            let background_tiles = &mut tiles_in_use[self.background_pattern_table as usize];
+            for name_table_choice in [
+                NameTableChoice::TopLeft,
+                NameTableChoice::TopRight,
+                NameTableChoice::BottomLeft,
+                NameTableChoice::BottomRight,
+            ] {
+                let name_table = memory.ppu_name_table(name_table_choice);
+                for &pattern_index in &name_table[..ATTRIBUTE_TABLE_START_INDEX] {
+                    background_tiles[pattern_index as usize] = true;
+                }
+            }
+        }
+        if self.show_sprites {
+            for sprite_index in 0..OAM_NUM_SPRITES {
+                let oam_entry = if let Some(oam_entry) = oam.get(sprite_index) {
+                    oam_entry
+                } else {
+                    continue;
+                };
+                match self.sprite_size {
+                    SpriteSize::Small => {
+                        tiles_in_use[self.sprite_pattern_table as usize]
+                            [oam_entry.tile_index as usize] = true;
+                    }
+                    SpriteSize::Large => {
+                        let (pattern_offset, pattern_table_choice) =
+                            oam_entry.offset_within_pattern_table_8x16();
+                        let tile_index = (pattern_offset / PATTERN_BYTES as u16) as usize;
+                        tiles_in_use[pattern_table_choice as usize][tile_index] = true;
+                        tiles_in_use[pattern_table_choice as usize][tile_index + 1] = true;
+                    }
+                }
+            }
+        }
+        for (pattern_table_choice, tiles) in [
+            PatternTableChoice::PatternTable0,
+            PatternTableChoice::PatternTable1,
+        ]
+        .into_iter()
+        .zip(tiles_in_use.iter())
+        {
+            for (tile_index, _) in tiles.iter().enumerate().filter(|(_, &in_use)| in_use) {
+                let tile_address =
+                    pattern_table_choice.base_address() + tile_index as u16 * PATTERN_BYTES as u16;
+                for i in 0..PATTERN_BYTES as PpuAddress {
+                    f(tile_address + i);
+                }
+            }
+        }
+    }
 }
 
 pub mod control {
@@ -0,0 +1,271 @@
+use crate::nes::{FrameStage, RomMapping, RunForCycles};
+use crate::observer::{self, Observed, Observer};
+use crate::ppu::PALETTE_START;
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::collections::HashMap;
+use std::fmt;
+
+const MAX_SPRITES_PER_SCANLINE: usize = 8;
+// the post-render scanline and vblank, during which the PPU doesn't access video memory
+const IDLE_SCANLINES: std::ops::RangeInclusive<u16> = 240..=260;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    // a read or write of PPUDATA while the PPU is drawing the screen
+    DataAccessDuringRendering,
+    // a write to the palette through PPUDATA while the PPU is drawing the screen
+    PaletteWriteDuringRendering,
+    // a frame started after an odd number of writes to PPUSCROLL and PPUADDR, so the next write
+    // to either will be taken as the second half of a pair
+    AddressLatchHalfSet,
+    // OAM DMA while the PPU is drawing the screen
+    OamDmaOutsideVblank,
+    // enabling NMI while the vblank flag is set, which triggers an NMI immediately on hardware
+    NmiEnabledDuringVblank,
+    // more sprites on a scanline than the PPU can draw, so some of them disappear
+    TooManySprites,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DataAccessDuringRendering => write!(f, "PPUDATA accessed during rendering"),
+            Self::PaletteWriteDuringRendering => write!(f, "palette written during rendering"),
+            Self::AddressLatchHalfSet => {
+                write!(f, "frame started with the PPUSCROLL/PPUADDR latch half set")
+            }
+            Self::OamDmaOutsideVblank => write!(f, "OAM DMA outside vblank while rendering"),
+            Self::NmiEnabledDuringVblank => {
+                write!(f, "NMI enabled while the vblank flag was set")
+            }
+            Self::TooManySprites => write!(
+                f,
+                "more than {} sprites on a scanline",
+                MAX_SPRITES_PER_SCANLINE
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Warning {
+    pub kind: WarningKind,
+    // address of the instruction responsible
+    pub pc: Address,
+    // where the PPU was when the warning first happened
+    pub frame: u64,
+    pub scanline: u16,
+    // number of times the warning has happened
+    pub count: u64,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {}, scanline {}, pc ${:04X}: {}",
+            self.frame, self.scanline, self.pc, self.kind
+        )?;
+        if self.count > 1 {
+            write!(f, " ({} times)", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks how the CPU uses the PPU for mistakes which the emulator tolerates but real hardware
+// doesn't. It needs to watch whole frames run by `Nes::run_for_frame_general` to know where the
+// PPU is. Each warning is reported once per instruction, with a count of how many times it
+// happened.
+#[derive(Debug, Clone, Default)]
+pub struct PpuLinter {
+    warnings: Vec<Warning>,
+    warning_indices: HashMap<(WarningKind, Address), usize>,
+    // index into `warnings` of the first warning found during the current frame
+    frame_warnings_start: usize,
+    frame: u64,
+    // the last instructions to write to PPUSCROLL or PPUADDR, and to OAM
+    last_latch_write_pc: Option<Address>,
+    last_oam_write_pc: Option<Address>,
+    // the stage of the frame being run, the cycles run so far during it, and the scanline reached
+    // before the current instruction
+    stage: FrameStage,
+    cycles: u32,
+    scanline: u16,
+}
+
+impl PpuLinter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn warn(&mut self, kind: WarningKind, pc: Address, scanline: u16) {
+        if let Some(&index) = self.warning_indices.get(&(kind, pc)) {
+            self.warnings[index].count += 1;
+        } else {
+            self.warning_indices.insert((kind, pc), self.warnings.len());
+            self.warnings.push(Warning {
+                kind,
+                pc,
+                frame: self.frame,
+                scanline,
+                count: 1,
+            });
+        }
+    }
+
+    // Every warning found so far, in the order they were first found
+    pub fn warnings(&self) -> &[Warning] {
+        &self.warnings
+    }
+
+    // Executes a single instruction, checking its accesses to the PPU
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        observer::step(self, cpu, memory)
+    }
+
+    // Called at the start of each frame, before the CPU runs
+    fn check_address_latch<M: RomMapping>(&mut self, memory: &M, pc: Address) {
+        if memory.ppu().is_address_latch_half_set() {
+            let pc = self.last_latch_write_pc.unwrap_or(pc);
+            self.warn(WarningKind::AddressLatchHalfSet, pc, 261);
+        }
+    }
+
+    // Called just before sprites are drawn for the frame
+    fn check_sprites<M: RomMapping>(&mut self, memory: &M, pc: Address) {
+        let ppu = memory.ppu();
+        if !ppu.is_showing_sprites() {
+            return;
+        }
+        let num_sprites_per_scanline = ppu.num_sprites_per_scanline(memory.oam());
+        if let Some(scanline) = num_sprites_per_scanline
+            .iter()
+            .position(|&num_sprites| num_sprites > MAX_SPRITES_PER_SCANLINE)
+        {
+            let pc = self.last_oam_write_pc.unwrap_or(pc);
+            self.warn(WarningKind::TooManySprites, pc, scanline as u16);
+        }
+    }
+
+    fn is_rendering<M: RomMapping>(&self, memory: &M) -> bool {
+        memory.ppu().is_rendering_enabled() && !IDLE_SCANLINES.contains(&self.scanline)
+    }
+
+    // Returns the warnings first found during the current frame. Call this after running each
+    // frame.
+    pub fn end_frame(&mut self) -> &[Warning] {
+        let start = self.frame_warnings_start;
+        self.frame_warnings_start = self.warnings.len();
+        self.frame += 1;
+        &self.warnings[start..]
+    }
+}
+
+impl Observer for PpuLinter {
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        self.stage = stage;
+        self.cycles = 0;
+        match stage {
+            FrameStage::PreRender => self.check_address_latch(memory, cpu.pc),
+            // sprites are drawn at the end of the pre-render scanline
+            FrameStage::Scanline(0) => self.check_sprites(memory, cpu.pc),
+            _ => (),
+        }
+    }
+
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, _cpu: &Cpu, _memory: &M) {
+        (self.scanline, _) = self.stage.ppu_position(self.cycles);
+    }
+
+    fn before_read<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+    ) {
+        if (0x2000..=0x3FFF).contains(&address) && address % 8 == 7 && self.is_rendering(memory) {
+            self.warn(WarningKind::DataAccessDuringRendering, pc, self.scanline);
+        }
+    }
+
+    fn before_write<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+        data: u8,
+    ) {
+        let scanline = self.scanline;
+        match address {
+            0x2000..=0x3FFF => match address % 8 {
+                0 => {
+                    let ppu = memory.ppu();
+                    let enables_nmi = data & (1 << 7) != 0 && !ppu.is_vblank_nmi_enabled();
+                    if enables_nmi && ppu.is_vblank_flag_set() {
+                        self.warn(WarningKind::NmiEnabledDuringVblank, pc, scanline);
+                    }
+                }
+                4 => self.last_oam_write_pc = Some(pc),
+                5 | 6 => self.last_latch_write_pc = Some(pc),
+                7 if self.is_rendering(memory) => {
+                    if memory.ppu().ppu_address() >= PALETTE_START {
+                        self.warn(WarningKind::PaletteWriteDuringRendering, pc, scanline);
+                    } else {
+                        self.warn(WarningKind::DataAccessDuringRendering, pc, scanline);
+                    }
+                }
+                _ => (),
+            },
+            0x4014 => {
+                if self.is_rendering(memory) {
+                    self.warn(WarningKind::OamDmaOutsideVblank, pc, scanline);
+                }
+                self.last_oam_write_pc = Some(pc);
+            }
+            _ => (),
+        }
+    }
+
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _cpu: &Cpu,
+        _memory: &M,
+        num_cycles: u8,
+    ) {
+        self.cycles += num_cycles as u32;
+    }
+}
+
+impl RunForCycles for PpuLinter {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+
+    fn before_frame_stage<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        stage: FrameStage,
+        cpu: &Cpu,
+        memory: &M,
+    ) {
+        Observer::before_frame_stage(self, stage, cpu, memory);
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,230 @@
+use super::*;
+use crate::ppu::{Oam, Ppu};
+use crate::sanitizer::Sanitizer;
+use crate::test::*;
+use mos6502_assembler::{Addr, AssembledBlock, Block, LabelRelativeOffset};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_render_output::NoRenderOutput;
+
+const NUM_FRAMES: usize = 2;
+
+const SHOW_BACKGROUND: u8 = 1 << 3;
+const SHOW_SPRITES: u8 = 1 << 4;
+
+fn run<R: RunForCycles>(run: &mut R, prg_rom: &[u8]) {
+    let mut nes = nes(prg_rom);
+    for _ in 0..NUM_FRAMES {
+        nes.run_for_frame_general(run, &mut NoRenderOutput, None);
+    }
+}
+
+// Runs `program` for a few frames, returning each warning found along with the frame and
+// scanline it was first found on, and the address of the instruction responsible
+fn warnings<P: FnOnce(&mut Block)>(
+    program: P,
+) -> (Vec<(WarningKind, u64, u16, Address)>, AssembledBlock) {
+    let (prg_rom, assembled_block) = prg_rom(program);
+    let mut linter = PpuLinter::new();
+    let mut nes = nes(&prg_rom);
+    for _ in 0..NUM_FRAMES {
+        nes.run_for_frame_general(&mut linter, &mut NoRenderOutput, None);
+        linter.end_frame();
+    }
+    let warnings = linter
+        .warnings()
+        .iter()
+        .map(|warning| (warning.kind, warning.frame, warning.scanline, warning.pc))
+        .collect();
+    (warnings, assembled_block)
+}
+
+fn set_mask(b: &mut Block, mask: u8) {
+    b.inst(Lda(Immediate), mask);
+    b.inst(Sta(Absolute), Addr(0x2001));
+}
+
+#[test]
+fn data_access_during_rendering() {
+    let (warnings, assembled_block) = warnings(|b| {
+        set_mask(b, SHOW_BACKGROUND);
+        b.label("read");
+        b.inst(Lda(Absolute), Addr(0x2007));
+        b.infinite_loop();
+    });
+    let read = assembled_block.address_of_label("read").unwrap();
+    assert_eq!(
+        warnings,
+        [(WarningKind::DataAccessDuringRendering, 0, 261, read)]
+    );
+}
+
+#[test]
+fn palette_write_during_rendering() {
+    let (warnings, assembled_block) = warnings(|b| {
+        set_mask(b, SHOW_BACKGROUND);
+        b.inst(Lda(Immediate), 0x3F);
+        b.inst(Sta(Absolute), Addr(0x2006));
+        b.inst(Lda(Immediate), 0x00);
+        b.inst(Sta(Absolute), Addr(0x2006));
+        b.label("write");
+        b.inst(Sta(Absolute), Addr(0x2007));
+        b.infinite_loop();
+    });
+    let write = assembled_block.address_of_label("write").unwrap();
+    assert_eq!(
+        warnings,
+        [(WarningKind::PaletteWriteDuringRendering, 0, 261, write)]
+    );
+}
+
+#[test]
+fn address_latch_half_set() {
+    let (warnings, assembled_block) = warnings(|b| {
+        b.inst(Lda(Immediate), 0x00);
+        b.label("write");
+        b.inst(Sta(Absolute), Addr(0x2005));
+        b.infinite_loop();
+    });
+    let write = assembled_block.address_of_label("write").unwrap();
+    // reported when the next frame starts
+    assert_eq!(
+        warnings,
+        [(WarningKind::AddressLatchHalfSet, 1, 261, write)]
+    );
+}
+
+#[test]
+fn oam_dma_outside_vblank() {
+    let (warnings, assembled_block) = warnings(|b| {
+        set_mask(b, SHOW_BACKGROUND);
+        b.inst(Lda(Immediate), 0x02);
+        b.label("dma");
+        b.inst(Sta(Absolute), Addr(0x4014));
+        b.infinite_loop();
+    });
+    let dma = assembled_block.address_of_label("dma").unwrap();
+    assert_eq!(warnings, [(WarningKind::OamDmaOutsideVblank, 0, 261, dma)]);
+}
+
+#[test]
+fn nmi_enabled_during_vblank() {
+    let (warnings, assembled_block) = warnings(|b| {
+        b.label("loop");
+        b.inst(Lda(Immediate), 0x80);
+        b.label("enable");
+        b.inst(Sta(Absolute), Addr(0x2000));
+        b.inst(Lda(Immediate), 0x00);
+        b.inst(Sta(Absolute), Addr(0x2000));
+        b.inst(Jmp(Absolute), "loop");
+    });
+    let enable = assembled_block.address_of_label("enable").unwrap();
+    assert_eq!(
+        warnings,
+        [(WarningKind::NmiEnabledDuringVblank, 0, 241, enable)]
+    );
+}
+
+#[test]
+fn too_many_sprites() {
+    let (warnings, assembled_block) = warnings(|b| {
+        // hide every sprite, then put 9 at Y coordinate 100
+        b.inst(Ldx(Immediate), 0x00);
+        b.inst(Lda(Immediate), 0xFF);
+        b.label("hide");
+        b.inst(Sta(Absolute), Addr(0x2004));
+        b.inst(Inx, ());
+        b.inst(Bne, LabelRelativeOffset("hide"));
+        b.inst(Ldx(Immediate), 9 * 4);
+        b.inst(Lda(Immediate), 100);
+        b.label("place");
+        b.inst(Sta(Absolute), Addr(0x2004));
+        b.inst(Dex, ());
+        b.inst(Bne, LabelRelativeOffset("place"));
+        set_mask(b, SHOW_SPRITES);
+        b.infinite_loop();
+    });
+    let place = assembled_block.address_of_label("place").unwrap();
+    // sprites are drawn on the scanline after their Y coordinate
+    assert_eq!(warnings, [(WarningKind::TooManySprites, 1, 101, place)]);
+}
+
+#[test]
+fn watching_with_other_observers_finds_the_same_warnings() {
+    let (prg_rom, _) = prg_rom(|b| {
+        set_mask(b, SHOW_BACKGROUND);
+        b.inst(Lda(Absolute), Addr(0x2007));
+        b.inst(Lda(Immediate), 0x80);
+        b.inst(Sta(Absolute), Addr(0x2000));
+        b.infinite_loop();
+    });
+    let mut alone = PpuLinter::new();
+    run(&mut alone, &prg_rom);
+    let mut linter = PpuLinter::new();
+    run(&mut Observed((Sanitizer::new(), &mut linter)), &prg_rom);
+    let warnings = |linter: &PpuLinter| {
+        linter
+            .warnings()
+            .iter()
+            .map(|warning| (warning.kind, warning.scanline, warning.pc))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(warnings(&alone).len(), 1);
+    assert_eq!(warnings(&linter), warnings(&alone));
+}
+
+// Just enough of a NES for the linter to look at the PPU
+#[derive(Default)]
+struct PpuOnly {
+    ppu: Ppu,
+    oam: Oam,
+}
+
+impl MemoryReadOnly for PpuOnly {
+    fn read_u8_read_only(&self, _address: Address) -> u8 {
+        0
+    }
+}
+
+impl RomMapping for PpuOnly {
+    fn prg_rom_bank(&self, _address: Address) -> Option<usize> {
+        None
+    }
+    fn prg_rom_offset(&self, _address: Address) -> Option<usize> {
+        None
+    }
+    fn is_mapper_register(&self, _address: Address) -> bool {
+        false
+    }
+    fn for_each_chr_rom_offset_in_use<F: FnMut(usize)>(&self, _f: F) {}
+    fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+    fn oam(&self) -> &Oam {
+        &self.oam
+    }
+}
+
+#[test]
+fn the_post_render_scanline_is_not_rendering() {
+    let mut memory = PpuOnly::default();
+    memory.ppu.write_mask(SHOW_BACKGROUND);
+    let cpu = Cpu::new();
+    let mut linter = PpuLinter::new();
+    for stage in [
+        FrameStage::Scanline(239),
+        FrameStage::PostRender,
+        FrameStage::VBlank,
+    ] {
+        Observer::before_frame_stage(&mut linter, stage, &cpu, &memory);
+        linter.before_instruction(&cpu, &memory);
+        // a different instruction each time, so every write that's a mistake gets reported
+        let (scanline, _) = stage.ppu_position(0);
+        linter.before_write(PRG_START + scanline, &memory, 0x2007, 0);
+    }
+    let scanlines = linter
+        .warnings()
+        .iter()
+        .map(|warning| warning.scanline)
+        .collect::<Vec<_>>();
+    assert_eq!(scanlines, [239]);
+}
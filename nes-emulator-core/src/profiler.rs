@@ -0,0 +1,270 @@
+use crate::nes::{RomMapping, RunForCycles};
+use crate::observer::{self, Observed, Observer};
+use mos6502_model::debug::{InstructionType, InstructionWithOperand};
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+// Cycles spent in a function over some period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionCycles {
+    pub calls: u64,
+    // cycles spent in the function and everything it calls
+    pub inclusive: u64,
+    // cycles spent in the function itself
+    pub exclusive: u64,
+}
+
+impl FunctionCycles {
+    fn add(&mut self, other: &Self) {
+        self.calls += other.calls;
+        self.inclusive += other.inclusive;
+        self.exclusive += other.exclusive;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameProfile {
+    pub cycles: u64,
+    pub functions: BTreeMap<Address, FunctionCycles>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StackFrameKind {
+    // the code running when profiling started, which is never returned from
+    Root,
+    Call,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct StackFrame {
+    kind: StackFrameKind,
+    // the stack pointer's value before the return address was pushed
+    return_stack_pointer: u8,
+}
+
+// Attributes the cycles taken by each instruction to the functions on a shadow call stack.
+// Functions are identified by the address they were entered at: the target of a JSR, or the
+// handler of an interrupt. Rather than matching RTS and RTI instructions to the calls they return
+// from, frames are popped when the stack pointer rises above their return address, so functions
+// which discard their return address, or return to somewhere other than their caller, don't leave
+// the shadow stack out of sync.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    stack: Vec<StackFrame>,
+    // the function of each frame on `stack`
+    stack_functions: Vec<Address>,
+    // where the CPU will be after the last instruction, unless an interrupt happens
+    next_pc: Option<Address>,
+    // the type of the instruction being executed, and the stack pointer before it
+    instruction_type: Option<InstructionType>,
+    stack_pointer: u8,
+    current_frame: FrameProfile,
+    frames: Vec<FrameProfile>,
+    // the number of cycles spent with each sequence of functions on the stack
+    stacks: BTreeMap<Vec<Address>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, kind: StackFrameKind, function: Address, return_stack_pointer: u8) {
+        self.stack.push(StackFrame {
+            kind,
+            return_stack_pointer,
+        });
+        self.stack_functions.push(function);
+        self.current_frame
+            .functions
+            .entry(function)
+            .or_default()
+            .calls += 1;
+    }
+
+    // Pops every frame whose return address is no longer on the stack
+    fn unwind(&mut self, stack_pointer: u8) {
+        while let Some(frame) = self.stack.last() {
+            let is_unwound = frame.kind != StackFrameKind::Root
+                && (frame.return_stack_pointer.wrapping_sub(stack_pointer) as i8) <= 0;
+            if !is_unwound {
+                break;
+            }
+            self.stack.pop();
+            self.stack_functions.pop();
+        }
+    }
+
+    fn attribute(&mut self, num_cycles: u64) {
+        self.current_frame.cycles += num_cycles;
+        for (i, &function) in self.stack_functions.iter().enumerate() {
+            // recursive functions only count once towards their inclusive cycles
+            if !self.stack_functions[..i].contains(&function) {
+                self.current_frame
+                    .functions
+                    .entry(function)
+                    .or_default()
+                    .inclusive += num_cycles;
+            }
+        }
+        if let Some(&function) = self.stack_functions.last() {
+            self.current_frame
+                .functions
+                .entry(function)
+                .or_default()
+                .exclusive += num_cycles;
+        }
+        match self.stacks.get_mut(self.stack_functions.as_slice()) {
+            Some(cycles) => *cycles += num_cycles,
+            None => {
+                self.stacks.insert(self.stack_functions.clone(), num_cycles);
+            }
+        }
+    }
+
+    // Executes a single instruction, attributing its cycles to the functions on the stack
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        observer::step(self, cpu, memory)
+    }
+
+    // Finishes recording the current frame. Call this after running each frame.
+    pub fn end_frame(&mut self) {
+        let frame = std::mem::take(&mut self.current_frame);
+        self.frames.push(frame);
+    }
+
+    // Profiles of each completed frame
+    pub fn frames(&self) -> &[FrameProfile] {
+        &self.frames
+    }
+
+    // Totals for each function over every completed frame
+    pub fn functions(&self) -> BTreeMap<Address, FunctionCycles> {
+        let mut functions: BTreeMap<Address, FunctionCycles> = BTreeMap::new();
+        for frame in self.frames.iter() {
+            for (&address, function_cycles) in frame.functions.iter() {
+                functions.entry(address).or_default().add(function_cycles);
+            }
+        }
+        functions
+    }
+
+    // Writes the cycles spent in each call stack in the collapsed stack format read by
+    // flamegraph tools, with one line per stack of the form "C000;C0A0;C6C7 1234"
+    pub fn write_collapsed_stacks<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        for (stack, cycles) in self.stacks.iter() {
+            let stack = stack
+                .iter()
+                .map(|function| format!("{:04X}", function))
+                .collect::<Vec<_>>()
+                .join(";");
+            writeln!(writer, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+
+    // A table of the `n` functions with the most exclusive cycles over every completed frame
+    pub fn top_functions_table(&self, n: usize) -> String {
+        let functions = self.functions();
+        let total_cycles = self
+            .frames
+            .iter()
+            .map(|frame| frame.cycles)
+            .sum::<u64>()
+            .max(1);
+        let max_inclusive_per_frame = |address: Address| {
+            self.frames
+                .iter()
+                .filter_map(|frame| frame.functions.get(&address))
+                .map(|function_cycles| function_cycles.inclusive)
+                .max()
+                .unwrap_or(0)
+        };
+        let mut by_exclusive = functions.iter().collect::<Vec<_>>();
+        by_exclusive
+            .sort_by_key(|(_, function_cycles)| std::cmp::Reverse(function_cycles.exclusive));
+        let mut s = String::new();
+        writeln!(
+            &mut s,
+            "{:<8} {:>10} {:>12} {:>7} {:>12} {:>7} {:>14}",
+            "function", "calls", "exclusive", "%", "inclusive", "%", "max/frame"
+        )
+        .unwrap();
+        for (&address, function_cycles) in by_exclusive.into_iter().take(n) {
+            writeln!(
+                &mut s,
+                "{:<8} {:>10} {:>12} {:>6.2}% {:>12} {:>6.2}% {:>14}",
+                format!("{:04X}", address),
+                function_cycles.calls,
+                function_cycles.exclusive,
+                function_cycles.exclusive as f64 * 100. / total_cycles as f64,
+                function_cycles.inclusive,
+                function_cycles.inclusive as f64 * 100. / total_cycles as f64,
+                max_inclusive_per_frame(address),
+            )
+            .unwrap();
+        }
+        s
+    }
+}
+
+impl Observer for Profiler {
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        if self.stack.is_empty() {
+            self.push(StackFrameKind::Root, cpu.pc, cpu.sp);
+        } else if self.next_pc != Some(cpu.pc) {
+            // the CPU was interrupted since the last instruction, pushing the return address and
+            // status register
+            self.push(StackFrameKind::Interrupt, cpu.pc, cpu.sp.wrapping_add(3));
+        }
+        self.instruction_type =
+            InstructionWithOperand::next(cpu, memory)
+                .ok()
+                .map(|instruction_with_operand| {
+                    instruction_with_operand.instruction().instruction_type()
+                });
+        self.stack_pointer = cpu.sp;
+    }
+
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &Cpu,
+        _memory: &M,
+        num_cycles: u8,
+    ) {
+        self.attribute(num_cycles as u64);
+        self.unwind(cpu.sp);
+        match self.instruction_type {
+            Some(InstructionType::Jsr) => {
+                self.push(StackFrameKind::Call, cpu.pc, self.stack_pointer)
+            }
+            Some(InstructionType::Brk) => {
+                self.push(StackFrameKind::Interrupt, cpu.pc, self.stack_pointer)
+            }
+            _ => (),
+        }
+        self.next_pc = Some(cpu.pc);
+    }
+}
+
+impl RunForCycles for Profiler {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,205 @@
+use super::*;
+use crate::test::*;
+use mos6502_assembler::{Addr, AssembledBlock, LabelRelativeOffset};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_render_output::NoRenderOutput;
+
+const NUM_FRAMES: usize = 3;
+
+fn profile() -> (Profiler, AssembledBlock) {
+    let (prg_rom, a) = prg_rom_with_nmi(
+        |b| {
+            b.inst(Sei, ());
+            b.inst(Ldx(Immediate), 0xFF);
+            b.inst(Txs, ());
+            b.inst(Jsr(Absolute), "outer");
+            b.inst(Jsr(Absolute), "drops_return_address");
+            b.label("after_drop");
+            b.inst(Ldx(Immediate), 2);
+            b.inst(Jsr(Absolute), "recurse");
+            // enable NMIs
+            b.inst(Lda(Immediate), 0x80);
+            b.inst(Sta(Absolute), Addr(0x2000));
+            b.label("spin");
+            b.inst(Jmp(Absolute), "spin");
+            b.label("outer");
+            b.inst(Jsr(Absolute), "inner");
+            b.inst(Rts, ());
+            b.label("inner");
+            b.inst(Nop, ());
+            b.inst(Rts, ());
+            b.label("drops_return_address");
+            b.inst(Pla, ());
+            b.inst(Pla, ());
+            b.inst(Jmp(Absolute), "after_drop");
+            b.label("recurse");
+            b.inst(Dex, ());
+            b.inst(Beq, LabelRelativeOffset("recurse_done"));
+            b.inst(Jsr(Absolute), "recurse");
+            b.label("recurse_done");
+            b.inst(Rts, ());
+        },
+        |b| {
+            b.inst(Jsr(Absolute), "inner");
+            b.inst(Rti, ());
+        },
+    );
+    let mut nes = nes(&prg_rom);
+    let mut profiler = Profiler::new();
+    for _ in 0..NUM_FRAMES {
+        nes.run_for_frame_general(&mut profiler, &mut NoRenderOutput, None);
+        profiler.end_frame();
+    }
+    (profiler, a)
+}
+
+fn function_cycles(profile: &FrameProfile, a: &AssembledBlock, label: &str) -> FunctionCycles {
+    profile.functions[&a.address_of_label(label).unwrap()]
+}
+
+fn cycles(calls: u64, inclusive: u64, exclusive: u64) -> FunctionCycles {
+    FunctionCycles {
+        calls,
+        inclusive,
+        exclusive,
+    }
+}
+
+#[test]
+fn nested_calls() {
+    let (profiler, a) = profile();
+    let frame = &profiler.frames()[0];
+    // JSR (6) is charged to the caller, RTS (6) to the callee
+    assert_eq!(function_cycles(frame, &a, "outer"), cycles(1, 20, 12));
+    // called once from "outer" and once from the NMI handler
+    assert_eq!(function_cycles(frame, &a, "inner"), cycles(2, 16, 16));
+}
+
+#[test]
+fn interrupt_handler_is_a_function() {
+    let (profiler, a) = profile();
+    for frame in profiler.frames() {
+        assert_eq!(function_cycles(frame, &a, "nmi"), cycles(1, 20, 12));
+    }
+    let frame = &profiler.frames()[1];
+    assert_eq!(function_cycles(frame, &a, "inner"), cycles(1, 8, 8));
+    assert_eq!(frame.functions.len(), 3);
+}
+
+#[test]
+fn dropped_return_address() {
+    let (profiler, a) = profile();
+    let frame = &profiler.frames()[0];
+    // the frame is popped by the second PLA, so the JMP back is charged to the caller
+    assert_eq!(
+        function_cycles(frame, &a, "drops_return_address"),
+        cycles(1, 8, 8)
+    );
+}
+
+#[test]
+fn recursion_counts_once_towards_inclusive_cycles() {
+    let (profiler, a) = profile();
+    let frame = &profiler.frames()[0];
+    // outer call: DEX, BEQ (not taken), JSR; inner call: DEX, BEQ (taken), RTS; outer RTS
+    assert_eq!(function_cycles(frame, &a, "recurse"), cycles(2, 27, 27));
+}
+
+#[test]
+fn root_takes_the_remaining_cycles() {
+    let (profiler, a) = profile();
+    for (i, frame) in profiler.frames().iter().enumerate() {
+        let others = frame
+            .functions
+            .iter()
+            .filter(|(&address, _)| address != PRG_START)
+            .map(|(_, function_cycles)| function_cycles.exclusive)
+            .sum::<u64>();
+        let calls = if i == 0 { 1 } else { 0 };
+        assert_eq!(
+            function_cycles(frame, &a, "reset"),
+            cycles(calls, frame.cycles, frame.cycles - others)
+        );
+    }
+}
+
+#[test]
+fn functions_total_every_frame() {
+    let (profiler, a) = profile();
+    let functions = profiler.functions();
+    let function_cycles = |label: &str| functions[&a.address_of_label(label).unwrap()];
+    assert_eq!(function_cycles("nmi"), cycles(3, 60, 36));
+    assert_eq!(function_cycles("inner"), cycles(4, 32, 32));
+    assert_eq!(function_cycles("recurse"), cycles(2, 27, 27));
+}
+
+#[test]
+fn collapsed_stacks() {
+    let (profiler, a) = profile();
+    let mut collapsed_stacks = Vec::new();
+    profiler
+        .write_collapsed_stacks(&mut collapsed_stacks)
+        .unwrap();
+    let collapsed_stacks = String::from_utf8(collapsed_stacks).unwrap();
+    let root_cycles = profiler.functions()[&PRG_START].exclusive;
+    let address = |label: &str| format!("{:04X}", a.address_of_label(label).unwrap());
+    let (root, outer, inner, drops_return_address, recurse, nmi) = (
+        address("reset"),
+        address("outer"),
+        address("inner"),
+        address("drops_return_address"),
+        address("recurse"),
+        address("nmi"),
+    );
+    let expected = [
+        format!("{root} {root_cycles}"),
+        format!("{root};{outer} 12"),
+        format!("{root};{outer};{inner} 8"),
+        format!("{root};{drops_return_address} 8"),
+        format!("{root};{recurse} 16"),
+        format!("{root};{recurse};{recurse} 11"),
+        format!("{root};{nmi} 36"),
+        format!("{root};{nmi};{inner} 24"),
+    ];
+    assert_eq!(collapsed_stacks.lines().collect::<Vec<_>>(), expected);
+}
+
+#[test]
+fn top_functions_table() {
+    let (profiler, a) = profile();
+    let table = profiler.top_functions_table(3);
+    let mut lines = table.lines();
+    assert_eq!(
+        lines.next().unwrap().split_whitespace().collect::<Vec<_>>(),
+        [
+            "function",
+            "calls",
+            "exclusive",
+            "%",
+            "inclusive",
+            "%",
+            "max/frame"
+        ]
+    );
+    let rows = lines
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 3);
+    let root = profiler.functions()[&PRG_START];
+    let max_root_per_frame = profiler.frames().iter().map(|frame| frame.cycles).max();
+    assert_eq!(rows[0][0], "C000");
+    assert_eq!(rows[0][1], "1");
+    assert_eq!(rows[0][2], root.exclusive.to_string());
+    assert_eq!(rows[0][5], "100.00%");
+    assert_eq!(rows[0][6], max_root_per_frame.unwrap().to_string());
+    let nmi = format!("{:04X}", a.address_of_label("nmi").unwrap());
+    assert_eq!(rows[1][0], nmi);
+    assert_eq!(rows[1][1..3], ["3", "36"]);
+    assert_eq!(rows[1][4], "60");
+    assert_eq!(rows[1][6], "20");
+    let inner = format!("{:04X}", a.address_of_label("inner").unwrap());
+    assert_eq!(rows[2][0], inner);
+    assert_eq!(rows[2][1..3], ["4", "32"]);
+    assert_eq!(rows[2][4], "32");
+    assert_eq!(rows[2][6], "16");
+}
@@ -0,0 +1,252 @@
+use crate::nes::{RomMapping, RunForCycles};
+use crate::observer::{self, Observed, Observer};
+use mos6502_model::debug::{Instruction, InstructionType};
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::collections::HashMap;
+use std::fmt;
+
+const RAM_BYTES: usize = 0x800;
+const PRG_RAM_START: Address = 0x6000;
+const PRG_RAM_BYTES: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IssueKind {
+    // a read from RAM or PRG RAM, at the given address, which hadn't been written to
+    UninitialisedRead(Address),
+    // a write to the given address in PRG ROM which doesn't configure the mapper
+    RomWrite(Address),
+    // a push which wrapped the stack pointer from 0x00 to 0xFF
+    StackOverflow,
+    // a pull which wrapped the stack pointer from 0xFF to 0x00
+    StackUnderflow,
+    ExecuteFromRam,
+    ExecuteFromIo,
+}
+
+impl fmt::Display for IssueKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UninitialisedRead(address) => {
+                write!(f, "read of uninitialised memory at ${:04X}", address)
+            }
+            Self::RomWrite(address) => write!(f, "write to ROM at ${:04X}", address),
+            Self::StackOverflow => write!(f, "stack pointer wrapped around on push"),
+            Self::StackUnderflow => write!(f, "stack pointer wrapped around on pull"),
+            Self::ExecuteFromRam => write!(f, "execution from RAM"),
+            Self::ExecuteFromIo => write!(f, "execution from I/O space"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Issue {
+    pub kind: IssueKind,
+    // address of the instruction which caused the issue
+    pub pc: Address,
+    // the frame the issue first happened in
+    pub frame: u64,
+    // number of times the issue has happened
+    pub count: u64,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "frame {}, pc ${:04X}: {}",
+            self.frame, self.pc, self.kind
+        )?;
+        if self.count > 1 {
+            write!(f, " ({} times)", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+// Tracks which bytes of RAM and PRG RAM have been written to, reporting reads of bytes which
+// haven't, along with other accesses which are likely to be bugs. Everything is considered
+// uninitialised when the sanitizer is created. Each issue is reported once per instruction
+// (and address), with a count of how many times it happened.
+#[derive(Debug, Clone)]
+pub struct Sanitizer {
+    ram_initialised: Vec<bool>,
+    prg_ram_initialised: Vec<bool>,
+    issues: Vec<Issue>,
+    issue_indices: HashMap<(IssueKind, Address), usize>,
+    // index into `issues` of the first issue found during the current frame
+    frame_issues_start: usize,
+    frame: u64,
+    next_pc: Option<Address>,
+    // the address and type of the instruction being executed, and the stack pointer before it
+    pc: Address,
+    instruction_type: Option<InstructionType>,
+    stack_pointer: u8,
+}
+
+impl Default for Sanitizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sanitizer {
+    pub fn new() -> Self {
+        Self {
+            ram_initialised: vec![false; RAM_BYTES],
+            prg_ram_initialised: vec![false; PRG_RAM_BYTES],
+            issues: Vec::new(),
+            issue_indices: HashMap::new(),
+            frame_issues_start: 0,
+            frame: 0,
+            next_pc: None,
+            pc: 0,
+            instruction_type: None,
+            stack_pointer: 0,
+        }
+    }
+
+    // For battery-backed PRG RAM whose contents were loaded from a save
+    pub fn assume_prg_ram_initialised(&mut self) {
+        self.prg_ram_initialised.fill(true);
+    }
+
+    fn initialised(&mut self, address: Address) -> Option<&mut bool> {
+        match address {
+            0x0000..=0x1FFF => Some(&mut self.ram_initialised[address as usize % RAM_BYTES]),
+            0x6000..=0x7FFF => {
+                Some(&mut self.prg_ram_initialised[(address - PRG_RAM_START) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    fn report(&mut self, kind: IssueKind, pc: Address) {
+        if let Some(&index) = self.issue_indices.get(&(kind, pc)) {
+            self.issues[index].count += 1;
+        } else {
+            self.issue_indices.insert((kind, pc), self.issues.len());
+            self.issues.push(Issue {
+                kind,
+                pc,
+                frame: self.frame,
+                count: 1,
+            });
+        }
+    }
+
+    fn check_read(&mut self, pc: Address, address: Address) {
+        if matches!(self.initialised(address), Some(&mut false)) {
+            self.report(IssueKind::UninitialisedRead(address), pc);
+        }
+    }
+
+    fn check_write(&mut self, pc: Address, address: Address, is_mapper_register: bool) {
+        if let Some(initialised) = self.initialised(address) {
+            *initialised = true;
+        } else if address >= 0x8000 && !is_mapper_register {
+            self.report(IssueKind::RomWrite(address), pc);
+        }
+    }
+
+    // Every issue found so far, in the order they were first found
+    pub fn issues(&self) -> &[Issue] {
+        &self.issues
+    }
+
+    // Executes a single instruction, checking each memory access it makes
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        observer::step(self, cpu, memory)
+    }
+
+    // Returns the issues first found during the current frame. Call this after running each
+    // frame.
+    pub fn end_frame(&mut self) -> &[Issue] {
+        let start = self.frame_issues_start;
+        self.frame_issues_start = self.issues.len();
+        self.frame += 1;
+        &self.issues[start..]
+    }
+}
+
+impl Observer for Sanitizer {
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        // interrupts are taken between instructions, so the return address and status they push
+        // need to be marked as initialised here
+        if self.next_pc.is_some()
+            && self.next_pc != Some(cpu.pc)
+            && cpu.retrieve_nmi_return_address_during_nmi(memory).is_some()
+        {
+            for offset in 1..=3 {
+                self.ram_initialised[0x0100 | cpu.sp.wrapping_add(offset) as usize] = true;
+            }
+        }
+        let pc = cpu.pc;
+        match pc {
+            0x0000..=0x1FFF | 0x6000..=0x7FFF => self.report(IssueKind::ExecuteFromRam, pc),
+            0x2000..=0x5FFF => self.report(IssueKind::ExecuteFromIo, pc),
+            _ => (),
+        }
+        self.pc = pc;
+        self.instruction_type = Instruction::from_opcode(memory.read_u8_read_only(pc))
+            .ok()
+            .map(|instruction| instruction.instruction_type());
+        self.stack_pointer = cpu.sp;
+    }
+
+    fn before_read<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        _memory: &M,
+        address: Address,
+    ) {
+        self.check_read(pc, address);
+    }
+
+    fn before_write<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        pc: Address,
+        memory: &M,
+        address: Address,
+        _data: u8,
+    ) {
+        self.check_write(pc, address, memory.is_mapper_register(address));
+    }
+
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &Cpu,
+        _memory: &M,
+        _num_cycles: u8,
+    ) {
+        let pc = self.pc;
+        let sp = self.stack_pointer;
+        use InstructionType::*;
+        match self.instruction_type {
+            Some(Pha | Php | Jsr | Brk) if cpu.sp > sp => self.report(IssueKind::StackOverflow, pc),
+            Some(Pla | Plp | Rts | Rti) if cpu.sp < sp => {
+                self.report(IssueKind::StackUnderflow, pc)
+            }
+            _ => (),
+        }
+        self.next_pc = Some(cpu.pc);
+    }
+}
+
+impl RunForCycles for Sanitizer {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test;
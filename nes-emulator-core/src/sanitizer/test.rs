@@ -0,0 +1,174 @@
+use super::*;
+use crate::mapper::mmc1::Mmc1;
+use crate::mapper::Mapper;
+use crate::nes::Nes;
+use crate::test::*;
+use mos6502_assembler::{Addr, AssembledBlock, Block};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*, opcode};
+use nes_render_output::NoRenderOutput;
+
+const NUM_FRAMES: usize = 3;
+
+// Runs for a few frames with a sanitizer, returning each issue found along with the address of
+// the instruction which caused it
+fn run<M: Mapper>(nes: &mut Nes<M>) -> Vec<(IssueKind, Address)> {
+    let mut sanitizer = Sanitizer::new();
+    for _ in 0..NUM_FRAMES {
+        nes.run_for_frame_general(&mut sanitizer, &mut NoRenderOutput, None);
+        sanitizer.end_frame();
+    }
+    sanitizer
+        .issues()
+        .iter()
+        .map(|issue| (issue.kind, issue.pc))
+        .collect()
+}
+
+fn issues<P: FnOnce(&mut Block)>(program: P) -> (Vec<(IssueKind, Address)>, AssembledBlock) {
+    let (prg_rom, assembled_block) = prg_rom(program);
+    (run(&mut nes(&prg_rom)), assembled_block)
+}
+
+#[test]
+fn uninitialised_zero_page_and_stack_reads() {
+    let (issues, assembled_block) = issues(|b| {
+        b.inst(Ldx(Immediate), 0xFD);
+        b.inst(Txs, ());
+        b.label("read_zero_page");
+        b.inst(Lda(ZeroPage), 0x10);
+        b.inst(Sta(ZeroPage), 0x11);
+        b.inst(Lda(ZeroPage), 0x11);
+        b.label("pull");
+        b.inst(Pla, ());
+        b.inst(Pha, ());
+        b.inst(Pla, ());
+        b.infinite_loop();
+    });
+    let address = |label| assembled_block.address_of_label(label).unwrap();
+    assert_eq!(
+        issues,
+        [
+            (
+                IssueKind::UninitialisedRead(0x0010),
+                address("read_zero_page")
+            ),
+            (IssueKind::UninitialisedRead(0x01FE), address("pull")),
+        ]
+    );
+}
+
+#[test]
+fn values_pushed_by_nmis_are_initialised() {
+    let (prg_rom, _) = prg_rom_with_nmi(
+        |b| {
+            b.inst(Lda(Immediate), 0x80);
+            b.inst(Sta(Absolute), Addr(0x2000));
+            b.infinite_loop();
+        },
+        |b| b.inst(Rti, ()),
+    );
+    let mut nes = nes(&prg_rom);
+    // the RTI would read the return address and status from uninitialised memory otherwise
+    assert_eq!(run(&mut nes), []);
+}
+
+fn write_to_rom(b: &mut Block) {
+    b.inst(Lda(Immediate), 0);
+    b.label("write");
+    b.inst(Sta(Absolute), Addr(0x8000));
+    b.infinite_loop();
+}
+
+#[test]
+fn rom_writes_are_reported_unless_they_configure_the_mapper() {
+    let (issues, assembled_block) = issues(write_to_rom);
+    let write = assembled_block.address_of_label("write").unwrap();
+    assert_eq!(issues, [(IssueKind::RomWrite(0x8000), write)]);
+    // the same bank twice, so the program is mapped at $C000 whichever bank is selected
+    let prg_rom = prg_rom(write_to_rom).0.repeat(2);
+    let mut mmc1 = Nes::new(Mmc1::new(&prg_rom, &[0; ines::CHR_ROM_BLOCK_BYTES]).unwrap());
+    assert_eq!(run(&mut mmc1), []);
+}
+
+#[test]
+fn stack_pointer_wraps_around() {
+    let (issues, assembled_block) = issues(|b| {
+        b.inst(Ldx(Immediate), 0x00);
+        b.inst(Txs, ());
+        b.label("push");
+        b.inst(Pha, ());
+        b.inst(Ldx(Immediate), 0xFF);
+        b.inst(Txs, ());
+        b.label("pull");
+        b.inst(Pla, ());
+        b.infinite_loop();
+    });
+    let address = |label| assembled_block.address_of_label(label).unwrap();
+    assert_eq!(
+        issues,
+        [
+            (IssueKind::StackOverflow, address("push")),
+            (IssueKind::StackUnderflow, address("pull")),
+        ]
+    );
+}
+
+#[test]
+fn execution_from_ram_and_io() {
+    let (issues, _) = issues(|b| {
+        b.inst(Lda(Immediate), opcode::rts::IMPLIED);
+        b.inst(Sta(Absolute), Addr(0x0300));
+        b.inst(Jsr(Absolute), Addr(0x0300));
+        // the BRK read from I/O space goes on to run uninitialised RAM, so only the first
+        // issues are checked
+        b.inst(Jmp(Absolute), Addr(0x4000));
+    });
+    assert_eq!(
+        issues[0..2],
+        [
+            (IssueKind::ExecuteFromRam, 0x0300),
+            (IssueKind::ExecuteFromIo, 0x4000),
+        ]
+    );
+}
+
+#[test]
+fn issues_are_counted_once_per_instruction_and_reported_in_the_frame_found() {
+    let (prg_rom, assembled_block) = prg_rom(|b| {
+        b.label("loop");
+        b.inst(Lda(ZeroPage), 0x10);
+        b.inst(Jmp(Absolute), "loop");
+    });
+    let mut nes = nes(&prg_rom);
+    let mut sanitizer = Sanitizer::new();
+    nes.run_for_frame_general(&mut sanitizer, &mut NoRenderOutput, None);
+    assert_eq!(sanitizer.end_frame().len(), 1);
+    nes.run_for_frame_general(&mut sanitizer, &mut NoRenderOutput, None);
+    assert!(sanitizer.end_frame().is_empty());
+    let issue = &sanitizer.issues()[0];
+    assert_eq!(issue.kind, IssueKind::UninitialisedRead(0x0010));
+    assert_eq!(issue.pc, assembled_block.address_of_label("loop").unwrap());
+    assert_eq!(issue.frame, 0);
+    assert!(issue.count > 1000);
+}
+
+#[test]
+fn randomised_ram_depends_only_on_the_seed() {
+    let ram = |seed| {
+        let (prg_rom, _) = prg_rom(|b| b.infinite_loop());
+        let mut nes = nes(&prg_rom);
+        nes.randomise_ram(seed);
+        (0..0x800)
+            .map(|address| nes.devices_with_oam().read_u8_read_only(address))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(ram(1), ram(1));
+    assert_eq!(ram(0x1234), ram(0x1234));
+    assert_ne!(ram(1), ram(2));
+    for seed in [0, 1, u64::MAX] {
+        let ram = ram(seed);
+        // 2KB of well distributed bytes uses most byte values
+        let num_distinct = ram.iter().collect::<std::collections::HashSet<_>>().len();
+        assert!(num_distinct > 200, "seed {}: {}", seed, num_distinct);
+    }
+}
@@ -0,0 +1,39 @@
+// Helpers for tests which run small programs assembled with the Block DSL
+use crate::mapper::nrom::{self, Nrom};
+use crate::nes::Nes;
+use mos6502_assembler::{AssembledBlock, Block};
+use mos6502_model::{assembler_instruction::*, interrupt_vector, Address};
+
+pub const PRG_START: Address = 0xC000;
+
+pub type TestNes = Nes<Nrom<nrom::Vertical>>;
+
+// Assembles a 16KB NROM PRG ROM which starts running `program` at the label "reset", and
+// handles NMIs by running `nmi`
+pub fn prg_rom_with_nmi<P: FnOnce(&mut Block), N: FnOnce(&mut Block)>(
+    program: P,
+    nmi: N,
+) -> (Vec<u8>, AssembledBlock) {
+    let mut b = Block::new();
+    b.label("reset");
+    program(&mut b);
+    b.label("nmi");
+    nmi(&mut b);
+    b.set_offset(interrupt_vector::NMI_LO - PRG_START);
+    b.label_offset_le("nmi");
+    b.label_offset_le("reset");
+    let mut prg_rom = Vec::new();
+    let assembled_block = b
+        .assemble(PRG_START, ines::PRG_ROM_BLOCK_BYTES, &mut prg_rom)
+        .unwrap();
+    (prg_rom, assembled_block)
+}
+
+// Like `prg_rom_with_nmi`, for programs which don't enable NMIs
+pub fn prg_rom<P: FnOnce(&mut Block)>(program: P) -> (Vec<u8>, AssembledBlock) {
+    prg_rom_with_nmi(program, |b| b.inst(Rti, ()))
+}
+
+pub fn nes(prg_rom: &[u8]) -> TestNes {
+    Nes::new(Nrom::new(nrom::Vertical, prg_rom, &[0; ines::CHR_ROM_BLOCK_BYTES]).unwrap())
+}
@@ -0,0 +1,239 @@
+use crate::nes::{RomMapping, RunForCycles};
+use crate::observer::{self, Observed, Observer};
+use crate::timing;
+use mos6502_model::debug::InstructionWithOperand;
+use mos6502_model::format::{Case, Dialect, Formatter};
+use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
+use mos6502_model::UnknownOpcode;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+// Nintendulator counts the 7 cycles of the reset sequence before the first instruction
+const CYCLES_BEFORE_FIRST_INSTRUCTION: u64 = 7;
+
+const PPU_CYCLES_PER_SCANLINE: u64 = 341;
+const SCANLINES_PER_FRAME: u64 = 262;
+const PRE_RENDER_SCANLINE: u64 = 261;
+
+// A condition which starts the trace the first time it's met
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    // the program counter reaches an address
+    Pc(Address),
+    // an instruction with an opcode is about to be executed
+    Opcode(u8),
+    // the cycle count reaches a value
+    Cycle(u64),
+}
+
+impl FromStr for Trigger {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected KIND:VALUE but got \"{}\"", s))?;
+        let error = |e| format!("invalid value \"{}\": {}", value, e);
+        match kind {
+            "pc" => Address::from_str_radix(value, 16)
+                .map(Self::Pc)
+                .map_err(error),
+            "opcode" => u8::from_str_radix(value, 16)
+                .map(Self::Opcode)
+                .map_err(error),
+            "cycle" => value.parse().map(Self::Cycle).map_err(error),
+            _ => Err(format!(
+                "unknown trigger \"{}\" (expected pc, opcode or cycle)",
+                kind
+            )),
+        }
+    }
+}
+
+// Which instructions are logged. Frames are counted by calls to `TraceLogger::end_frame`.
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    pub pc_range: Option<RangeInclusive<Address>>,
+    pub frame_range: Option<RangeInclusive<u64>>,
+    pub trigger: Option<Trigger>,
+}
+
+// Writes a line for each instruction executed in the format of Nintendulator's trace logs (and
+// nestest.log), e.g.
+// "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+// so traces can be diffed against logs from other emulators. This emulator's PPU renders a
+// scanline at a time, so the PPU position is derived from the number of cycles since the start
+// of the frame, counting the pre-render scanline as scanline 261.
+pub struct TraceLogger<W: Write> {
+    writer: W,
+    filter: TraceFilter,
+    formatter: Formatter<'static>,
+    cycles: u64,
+    frame: u64,
+    frame_cycles: u64,
+    triggered: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> TraceLogger<W> {
+    pub fn new(writer: W, filter: TraceFilter) -> Self {
+        let triggered = filter.trigger.is_none();
+        Self {
+            writer,
+            filter,
+            formatter: Formatter::new(Dialect::Nintendulator).with_case(Case::Upper),
+            cycles: CYCLES_BEFORE_FIRST_INSTRUCTION,
+            frame: 0,
+            frame_cycles: 0,
+            triggered,
+            error: None,
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // The approximate scanline and dot the PPU is on
+    pub fn ppu_position(&self) -> (u64, u64) {
+        let ppu_cycles = self.frame_cycles * timing::ntsc::NUM_PPU_CYCLES_PER_CPU_CYCLE as u64;
+        let scanline =
+            (PRE_RENDER_SCANLINE + ppu_cycles / PPU_CYCLES_PER_SCANLINE) % SCANLINES_PER_FRAME;
+        (scanline, ppu_cycles % PPU_CYCLES_PER_SCANLINE)
+    }
+
+    fn is_triggered_by(&self, cpu: &Cpu, opcode: u8) -> bool {
+        match self.filter.trigger {
+            None => true,
+            Some(Trigger::Pc(address)) => cpu.pc == address,
+            Some(Trigger::Opcode(trigger_opcode)) => opcode == trigger_opcode,
+            Some(Trigger::Cycle(cycle)) => self.cycles >= cycle,
+        }
+    }
+
+    fn is_logged(&self, cpu: &Cpu) -> bool {
+        self.triggered
+            && self
+                .filter
+                .pc_range
+                .as_ref()
+                .map(|pc_range| pc_range.contains(&cpu.pc))
+                .unwrap_or(true)
+            && self
+                .filter
+                .frame_range
+                .as_ref()
+                .map(|frame_range| frame_range.contains(&self.frame))
+                .unwrap_or(true)
+    }
+
+    fn write_line<M: MemoryReadOnly>(
+        &mut self,
+        cpu: &Cpu,
+        memory: &M,
+        instruction_with_operand: &InstructionWithOperand,
+    ) -> io::Result<()> {
+        let bytes = std::iter::once(instruction_with_operand.opcode())
+            .chain(instruction_with_operand.operand().iter().cloned())
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = self
+            .formatter
+            .instruction_with_state(instruction_with_operand, cpu, memory);
+        // unofficial instructions are marked with a "*" in the column before the mnemonic
+        let text = if text.starts_with('*') {
+            text
+        } else {
+            format!(" {}", text)
+        };
+        let (scanline, dot) = self.ppu_position();
+        writeln!(
+            self.writer,
+            "{:04X}  {:<9}{:<33}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+            cpu.pc,
+            bytes,
+            text,
+            cpu.acc,
+            cpu.x,
+            cpu.y,
+            cpu.status.masked_with_expansion(),
+            cpu.sp,
+            scanline,
+            dot,
+            self.cycles
+        )
+    }
+
+    // Executes a single instruction, logging it if it passes the filter
+    pub fn step<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+    ) -> Result<u8, UnknownOpcode> {
+        observer::step(self, cpu, memory)
+    }
+
+    // Call this after running each frame
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+        self.frame_cycles = 0;
+    }
+
+    // Flushes the trace, returning the first error encountered while writing
+    pub fn flush(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.writer.flush()
+    }
+
+    // Flushes the trace and returns the writer, or the first error encountered while writing
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Observer for TraceLogger<W> {
+    fn before_instruction<M: MemoryReadOnly + RomMapping>(&mut self, cpu: &Cpu, memory: &M) {
+        if let Ok(instruction_with_operand) = InstructionWithOperand::next(cpu, memory) {
+            if !self.triggered && self.is_triggered_by(cpu, instruction_with_operand.opcode()) {
+                self.triggered = true;
+            }
+            if self.error.is_none() && self.is_logged(cpu) {
+                if let Err(error) = self.write_line(cpu, memory, &instruction_with_operand) {
+                    self.error = Some(error);
+                }
+            }
+        }
+    }
+
+    fn after_instruction<M: MemoryReadOnly + RomMapping>(
+        &mut self,
+        _cpu: &Cpu,
+        _memory: &M,
+        num_cycles: u8,
+    ) {
+        self.cycles += num_cycles as u64;
+        self.frame_cycles += num_cycles as u64;
+    }
+}
+
+impl<W: Write> RunForCycles for TraceLogger<W> {
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut M,
+        num_cycles: u32,
+    ) {
+        Observed(self).run_for_cycles(cpu, memory, num_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test;
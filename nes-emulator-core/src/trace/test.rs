@@ -0,0 +1,193 @@
+use super::*;
+use crate::observer::Hooked;
+use crate::profiler::Profiler;
+use crate::test::*;
+use mos6502_assembler::{Addr, LabelOffsetHi, LabelOffsetLo};
+use mos6502_model::hook::{HookAction, Hooks};
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use nes_render_output::NoRenderOutput;
+
+fn trace(filter: TraceFilter, num_frames: usize) -> String {
+    let (prg_rom, _) = prg_rom(|b| {
+        b.inst(Sei, ());
+        b.inst(Ldx(Immediate), 0xFF);
+        b.inst(Txs, ());
+        b.inst(Lda(Immediate), 0x00);
+        b.inst(Sta(ZeroPage), 0x80);
+        b.inst(Lda(Immediate), 0x02);
+        b.inst(Sta(ZeroPage), 0x81);
+        b.inst(Lda(Immediate), 0x5A);
+        b.inst(Sta(Absolute), Addr(0x0205));
+        b.inst(Ldy(Immediate), 0x05);
+        b.inst(Lda(IndirectYIndexed), 0x80);
+        // nop $10
+        b.literal_bytes([0x04, 0x10]);
+        // isb $80
+        b.literal_bytes([0xE7, 0x80]);
+        b.inst(Lda(Immediate), LabelOffsetLo("main"));
+        b.inst(Sta(Absolute), Addr(0x03FF));
+        b.inst(Lda(Immediate), LabelOffsetHi("main"));
+        b.inst(Sta(Absolute), Addr(0x0300));
+        b.inst(Sec, ());
+        b.inst(Jmp(Indirect), Addr(0x03FF));
+        b.label("main");
+        b.inst(Inx, ());
+        b.inst(Jmp(Absolute), "main");
+    });
+    let mut nes = nes(&prg_rom);
+    let mut trace_logger = TraceLogger::new(Vec::new(), filter);
+    for _ in 0..num_frames {
+        nes.run_for_frame_general(&mut trace_logger, &mut NoRenderOutput, None);
+        trace_logger.end_frame();
+    }
+    String::from_utf8(trace_logger.finish().unwrap()).unwrap()
+}
+
+const EXPECTED_PREFIX: &str = "\
+C000  78        SEI                             A:00 X:00 Y:00 P:24 SP:FF PPU:261,  0 CYC:7
+C001  A2 FF     LDX #$FF                        A:00 X:00 Y:00 P:24 SP:FF PPU:261,  6 CYC:9
+C003  9A        TXS                             A:00 X:FF Y:00 P:A4 SP:FF PPU:261, 12 CYC:11
+C004  A9 00     LDA #$00                        A:00 X:FF Y:00 P:A4 SP:FF PPU:261, 18 CYC:13
+C006  85 80     STA $80 = 00                    A:00 X:FF Y:00 P:26 SP:FF PPU:261, 24 CYC:15
+C008  A9 02     LDA #$02                        A:00 X:FF Y:00 P:26 SP:FF PPU:261, 33 CYC:18
+C00A  85 81     STA $81 = 00                    A:02 X:FF Y:00 P:24 SP:FF PPU:261, 39 CYC:20
+C00C  A9 5A     LDA #$5A                        A:02 X:FF Y:00 P:24 SP:FF PPU:261, 48 CYC:23
+C00E  8D 05 02  STA $0205 = 00                  A:5A X:FF Y:00 P:24 SP:FF PPU:261, 54 CYC:25
+C011  A0 05     LDY #$05                        A:5A X:FF Y:00 P:24 SP:FF PPU:261, 66 CYC:29
+C013  B1 80     LDA ($80),Y = 0200 @ 0205 = 5A  A:5A X:FF Y:05 P:24 SP:FF PPU:261, 72 CYC:31
+C015  04 10    *NOP $10 = 00                    A:5A X:FF Y:05 P:24 SP:FF PPU:261, 87 CYC:36
+C017  E7 80    *ISB $80 = 00                    A:5A X:FF Y:05 P:24 SP:FF PPU:261, 96 CYC:39
+C019  A9 27     LDA #$27                        A:58 X:FF Y:05 P:25 SP:FF PPU:261,111 CYC:44
+C01B  8D FF 03  STA $03FF = 00                  A:27 X:FF Y:05 P:25 SP:FF PPU:261,117 CYC:46
+C01E  A9 C0     LDA #$C0                        A:27 X:FF Y:05 P:25 SP:FF PPU:261,129 CYC:50
+C020  8D 00 03  STA $0300 = 00                  A:C0 X:FF Y:05 P:A5 SP:FF PPU:261,135 CYC:52
+C023  38        SEC                             A:C0 X:FF Y:05 P:A5 SP:FF PPU:261,147 CYC:56
+C024  6C FF 03  JMP ($03FF) = C027              A:C0 X:FF Y:05 P:A5 SP:FF PPU:261,153 CYC:58
+C027  E8        INX                             A:C0 X:FF Y:05 P:A5 SP:FF PPU:261,168 CYC:63
+C028  4C 27 C0  JMP $C027                       A:C0 X:00 Y:05 P:27 SP:FF PPU:261,174 CYC:65
+C027  E8        INX                             A:C0 X:00 Y:05 P:27 SP:FF PPU:261,183 CYC:68
+";
+
+#[test]
+fn trace_matches_expected_log() {
+    let trace = trace(TraceFilter::default(), 1);
+    assert!(
+        trace.starts_with(EXPECTED_PREFIX),
+        "trace starts with:\n{}",
+        &trace[..EXPECTED_PREFIX.len()]
+    );
+    // the PPU position wraps from the pre-render scanline to scanline 0
+    assert!(trace.contains("PPU:  0,  "));
+}
+
+#[test]
+fn pc_range_filter() {
+    let filter = TraceFilter {
+        pc_range: Some(0xC013..=0xC017),
+        ..Default::default()
+    };
+    let trace = trace(filter, 1);
+    let addresses = trace.lines().map(|line| &line[..4]).collect::<Vec<_>>();
+    assert_eq!(addresses, ["C013", "C015", "C017"]);
+}
+
+#[test]
+fn frame_range_filter() {
+    let full = trace(TraceFilter::default(), 3);
+    let frame = |frame| {
+        let filter = TraceFilter {
+            frame_range: Some(frame..=frame),
+            ..Default::default()
+        };
+        trace(filter, 3)
+    };
+    let frames = (0..3).map(frame).collect::<Vec<_>>();
+    assert!(frames.iter().all(|frame| !frame.is_empty()));
+    assert!(frames[0].starts_with(EXPECTED_PREFIX));
+    assert!(frames[1].starts_with("C02"));
+    assert_eq!(frames.concat(), full);
+}
+
+#[test]
+fn trigger_filter() {
+    let full = trace(TraceFilter::default(), 1);
+    let triggered_by = |trigger| {
+        let filter = TraceFilter {
+            trigger: Some(trigger),
+            ..Default::default()
+        };
+        trace(filter, 1)
+    };
+    let suffix_from_line = |line| full.split_at(full.find(line).unwrap()).1;
+    assert_eq!(
+        triggered_by(Trigger::Pc(0xC024)),
+        suffix_from_line("C024  6C FF 03")
+    );
+    assert_eq!(
+        triggered_by(Trigger::Opcode(0xE7)),
+        suffix_from_line("C017  E7 80")
+    );
+    // the first instruction which starts at or after the cycle
+    assert_eq!(
+        triggered_by(Trigger::Cycle(60)),
+        suffix_from_line("C027  E8")
+    );
+    // the trigger is only checked until it's first met, but the other filters always apply
+    let filter = TraceFilter {
+        pc_range: Some(0xC027..=0xC027),
+        trigger: Some(Trigger::Pc(0xC028)),
+        ..Default::default()
+    };
+    let trace = trace(filter, 1);
+    assert!(trace.starts_with(
+        "C027  E8        INX                             A:C0 X:00 Y:05 P:27 SP:FF PPU:261,183 CYC:68\n"
+    ));
+    assert!(trace.lines().all(|line| line.starts_with("C027")));
+}
+
+#[test]
+fn trigger_from_str() {
+    assert_eq!("pc:C0FF".parse(), Ok(Trigger::Pc(0xC0FF)));
+    assert_eq!("opcode:e7".parse(), Ok(Trigger::Opcode(0xE7)));
+    assert_eq!("cycle:1000".parse(), Ok(Trigger::Cycle(1000)));
+    assert!("pc:10000".parse::<Trigger>().is_err());
+    assert!("frame:1".parse::<Trigger>().is_err());
+    assert!("C000".parse::<Trigger>().is_err());
+}
+
+#[test]
+fn hooks_with_other_observers() {
+    let (prg_rom, a) = prg_rom(|b| {
+        b.inst(Jsr(Absolute), "subroutine");
+        b.inst(Sta(ZeroPage), 0x80);
+        b.infinite_loop();
+        b.label("subroutine");
+        b.inst(Lda(Immediate), 0x01);
+        b.inst(Rts, ());
+    });
+    let subroutine = a.address_of_label("subroutine").unwrap();
+    let mut hooks = Hooks::new();
+    hooks.insert(subroutine, |cpu, _| {
+        cpu.acc = 0x42;
+        HookAction::Return
+    });
+    let mut nes = nes(&prg_rom);
+    let mut trace_logger = TraceLogger::new(Vec::new(), TraceFilter::default());
+    let mut profiler = Profiler::new();
+    let mut run = Hooked(&mut hooks, (&mut trace_logger, &mut profiler));
+    nes.run_for_frame_general(&mut run, &mut NoRenderOutput, None);
+    profiler.end_frame();
+    let trace = String::from_utf8(trace_logger.finish().unwrap()).unwrap();
+    // the hook is logged as the instruction it replaced, taking as long as an RTS
+    assert!(trace.starts_with(
+        "\
+C000  20 08 C0  JSR $C008                       A:00 X:00 Y:00 P:24 SP:FF PPU:261,  0 CYC:7
+C008  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:261, 18 CYC:13
+C003  85 80     STA $80 = 00                    A:42 X:00 Y:00 P:24 SP:FF PPU:261, 36 CYC:19
+"
+    ));
+    assert_eq!(nes.devices_with_oam().read_u8_read_only(0x80), 0x42);
+    let functions = &profiler.frames()[0].functions;
+    assert_eq!(functions[&subroutine].calls, 1);
+    assert_eq!(functions[&subroutine].exclusive, 6);
+}
@@ -0,0 +1,153 @@
+use crate::Config;
+use nes_emulator_core::{
+    lag::{FrameStats, LagDetector},
+    mapper::Mapper,
+    nes::{Nes, RunForCyclesDebug},
+    observer::Observed,
+    ppu_lint::{PpuLinter, Warning},
+    profiler::Profiler,
+    sanitizer::{Issue, Sanitizer},
+    trace::TraceLogger,
+};
+use nes_name_table_debug::NameTableFrame;
+use nes_render_output::RenderOutput;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+pub type FileTraceLogger = TraceLogger<BufWriter<File>>;
+
+// Detects lag frames, recording each frame's stats to the lag csv file if there is one
+pub struct LagRecorder {
+    lag_detector: LagDetector,
+    csv: Option<File>,
+}
+
+impl LagRecorder {
+    pub fn new(config: &Config) -> Option<Self> {
+        if !config.detect_lag() {
+            return None;
+        }
+        let csv = config.lag_csv_filename.as_ref().map(|lag_csv_filename| {
+            let mut file = File::create(lag_csv_filename).expect("Failed to create lag csv file");
+            writeln!(file, "{}", FrameStats::CSV_HEADER).expect("Failed to write lag csv file");
+            file
+        });
+        Some(Self {
+            lag_detector: LagDetector::new(),
+            csv,
+        })
+    }
+
+    // Call this after running each frame
+    fn end_frame(&mut self, frame_count: u64) -> FrameStats {
+        let frame_stats = self.lag_detector.end_frame();
+        if let Some(csv) = self.csv.as_mut() {
+            writeln!(csv, "{}", frame_stats.csv_row(frame_count))
+                .expect("Failed to write lag csv file");
+        }
+        frame_stats
+    }
+}
+
+fn print_sanitizer_issues(issues: &[Issue]) {
+    for issue in issues {
+        eprintln!("sanitizer: {}", issue);
+    }
+}
+
+fn print_ppu_lint_warnings(warnings: &[Warning]) {
+    for warning in warnings {
+        eprintln!("ppu lint: {}", warning);
+    }
+}
+
+// The tools which watch each instruction the CPU runs. Any combination of them can be enabled at
+// once.
+#[derive(Default)]
+pub struct Instruments {
+    pub profiler: Option<Profiler>,
+    pub lag_recorder: Option<LagRecorder>,
+    pub trace_logger: Option<FileTraceLogger>,
+    pub sanitizer: Option<Sanitizer>,
+    pub ppu_linter: Option<PpuLinter>,
+    // print each instruction before it's executed
+    pub debug: bool,
+}
+
+impl Instruments {
+    fn is_enabled(&self) -> bool {
+        self.profiler.is_some()
+            || self.lag_recorder.is_some()
+            || self.trace_logger.is_some()
+            || self.sanitizer.is_some()
+            || self.ppu_linter.is_some()
+            || self.debug
+    }
+
+    // Runs a frame while every enabled instrument watches
+    pub fn run_for_frame<M: Mapper, O: RenderOutput>(
+        &mut self,
+        nes: &mut Nes<M>,
+        pixels: &mut O,
+        name_table_frame: Option<&mut NameTableFrame>,
+    ) {
+        if self.is_enabled() {
+            let mut observed = Observed((
+                self.profiler.as_mut(),
+                self.lag_recorder
+                    .as_mut()
+                    .map(|lag_recorder| &mut lag_recorder.lag_detector),
+                self.trace_logger.as_mut(),
+                self.sanitizer.as_mut(),
+                self.ppu_linter.as_mut(),
+                self.debug.then_some(RunForCyclesDebug),
+            ));
+            nes.run_for_frame_general(&mut observed, pixels, name_table_frame);
+        } else {
+            nes.run_for_frame(pixels, name_table_frame);
+        }
+    }
+
+    // Call this after running each frame. Prints anything found during the frame, and returns
+    // the frame's stats if lag frames are being detected.
+    pub fn end_frame(&mut self, frame_count: u64) -> Option<FrameStats> {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.end_frame();
+        }
+        if let Some(trace_logger) = self.trace_logger.as_mut() {
+            trace_logger.end_frame();
+            trace_logger.flush().expect("Failed to write trace file");
+        }
+        if let Some(sanitizer) = self.sanitizer.as_mut() {
+            print_sanitizer_issues(sanitizer.end_frame());
+        }
+        if let Some(ppu_linter) = self.ppu_linter.as_mut() {
+            print_ppu_lint_warnings(ppu_linter.end_frame());
+        }
+        self.lag_recorder
+            .as_mut()
+            .map(|lag_recorder| lag_recorder.end_frame(frame_count))
+    }
+
+    // Prints everything found over the whole run, and writes the profile to `profile_filename`
+    pub fn finish(self, profile_filename: Option<&str>) {
+        if let Some(sanitizer) = self.sanitizer {
+            eprintln!("sanitizer found {} issues:", sanitizer.issues().len());
+            print_sanitizer_issues(sanitizer.issues());
+        }
+        if let Some(ppu_linter) = self.ppu_linter {
+            eprintln!("ppu lint found {} warnings:", ppu_linter.warnings().len());
+            print_ppu_lint_warnings(ppu_linter.warnings());
+        }
+        if let Some(trace_logger) = self.trace_logger {
+            trace_logger.finish().expect("Failed to write trace file");
+        }
+        if let (Some(profiler), Some(profile_filename)) = (self.profiler, profile_filename) {
+            let file = File::create(profile_filename).expect("Failed to create profile file");
+            profiler
+                .write_collapsed_stacks(file)
+                .expect("Failed to write profile file");
+            print!("{}", profiler.top_functions_table(20));
+        }
+    }
+}
@@ -1,38 +1,55 @@
 use gif_renderer::Rgb24;
 use ines::Ines;
+use instruments::{Instruments, LagRecorder};
 use nes_emulator_core::{
+    debug_port::DebugPortEvent,
     dynamic_nes::{DynamicNes, Error},
+    lag::FrameStats,
     mapper::{Mapper, PersistentState},
     nes::{self, Nes},
+    ppu_lint::PpuLinter,
+    profiler::Profiler,
+    sanitizer::Sanitizer,
+    trace::{TraceFilter, TraceLogger, Trigger},
 };
 use nes_name_table_debug::NameTableFrame;
 use nes_render_output::{NoRenderOutput, RenderOutput, RenderOutputPair};
 use std::collections::hash_map::DefaultHasher;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::{self, Read, Write};
-use std::ops::DerefMut;
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::{DerefMut, RangeInclusive};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::thread;
 use std::time::{Duration, Instant};
 
+mod instruments;
+mod tui;
+
 #[derive(Clone)]
 enum Frontend {
     Graphical,
     HeadlessPrintingFinalFrameHash { num_frames: u64 },
+    Tui,
 }
 
 impl Frontend {
     fn parser() -> impl meap::Parser<Item = Self> {
-        use meap::prelude::*;
-        opt_opt::<u64, _>("INT", 'e')
-            .name("headless-num-frames")
-            .desc("run with headless frontend, exiting after a specified number of frames")
-            .map(|maybe_num_frames| {
-                maybe_num_frames
-                    .map(|num_frames| Self::HeadlessPrintingFinalFrameHash { num_frames })
-                    .unwrap_or(Self::Graphical)
-            })
+        meap::choose_at_most_one!(
+            opt_opt::<u64, _>("INT", 'e')
+                .name("headless-num-frames")
+                .desc("run with headless frontend, exiting after a specified number of frames")
+                .map(|maybe_num_frames| {
+                    maybe_num_frames
+                        .map(|num_frames| Self::HeadlessPrintingFinalFrameHash { num_frames })
+                }),
+            flag('u')
+                .name("tui")
+                .desc("run with a debugger in the terminal instead of opening a window")
+                .some_if(Self::Tui),
+        )
+        .with_default_general(Self::Graphical)
     }
 }
 
@@ -59,6 +76,38 @@ impl Input {
     }
 }
 
+// A range of values written as FIRST-LAST, parsed with `parse`
+fn parse_range<T, E>(
+    s: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+) -> Result<RangeInclusive<T>, String> {
+    let (first, last) = s
+        .split_once('-')
+        .ok_or_else(|| format!("expected FIRST-LAST but got \"{}\"", s))?;
+    match (parse(first), parse(last)) {
+        (Ok(first), Ok(last)) => Ok(first..=last),
+        _ => Err(format!("invalid range \"{}\"", s)),
+    }
+}
+
+struct AddressRange(RangeInclusive<u16>);
+
+impl FromStr for AddressRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_range(s, |address| u16::from_str_radix(address, 16)).map(Self)
+    }
+}
+
+struct FrameRange(RangeInclusive<u64>);
+
+impl FromStr for FrameRange {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_range(s, |frame| frame.parse::<u64>()).map(Self)
+    }
+}
+
 struct Args {
     input: Input,
     autosave_after_frames: Option<u64>,
@@ -71,6 +120,15 @@ struct Args {
     debug: bool,
     persistent_state_filename: Option<String>,
     zoom: f64,
+    profile_filename: Option<String>,
+    lag_csv_filename: Option<String>,
+    lag_overlay: bool,
+    trace_filename: Option<String>,
+    trace_filter: TraceFilter,
+    sanitize: bool,
+    random_ram_seed: Option<u64>,
+    ppu_lint: bool,
+    debug_port: bool,
 }
 
 impl Args {
@@ -89,6 +147,17 @@ impl Args {
                 debug = flag('d').name("debug").desc("enable debugging printouts");
                 persistent_state_filename = opt_opt::<String, _>("PATH", 'p').name("persistent-state-filename").desc("file to store persistent state");
                 zoom = opt_opt::<f64, _>("FLOAT", 'z').name("zoom").desc("real pixels per pixel").with_default(1.);
+                lag_csv_filename = opt_opt::<String, _>("PATH", 'v').name("lag-csv").desc("csv file to record lag frames and idle cycles into");
+                lag_overlay = flag('o').name("lag-overlay").desc("show the cpu time used by each frame, in red for lag frames");
+                profile_filename = opt_opt::<String, _>("PATH", 'c').name("profile").desc("when running headless, profile the cpu and write collapsed stacks for flamegraph tools to this file");
+                trace_filename = opt_opt::<String, _>("PATH", 't').name("trace").desc("file to write a trace of each instruction executed into, in the format of nintendulator's logs");
+                trace_pc_range = opt_opt::<AddressRange, _>("ADDR-ADDR", 'x').name("trace-pc-range").desc("only trace instructions in this range of addresses, in hex (e.g. C000-C0FF)");
+                trace_frame_range = opt_opt::<FrameRange, _>("INT-INT", 'y').name("trace-frames").desc("only trace during this range of frames (e.g. 10-20)");
+                trace_trigger = opt_opt::<Trigger, _>("KIND:VALUE", 'w').name("trace-trigger").desc("start tracing when a condition is first met: pc:ADDR, opcode:BYTE (in hex) or cycle:INT");
+                sanitize = flag('i').name("sanitize").desc("report reads of uninitialised ram, writes to rom, stack pointer wrap-around and execution from ram or i/o space");
+                ppu_lint = flag('j').name("ppu-lint").desc("warn about uses of the ppu which work in the emulator but not on real hardware");
+                debug_port = flag('b').name("debug-port").desc("map a device into 4018-401F which test programs can print to, exit with a status, break or save snapshots through (each saved beside the state file to save, named after its tag)");
+                random_ram_seed = opt_opt::<u64, _>("INT", 'm').name("random-ram-seed").desc("fill ram with a pseudorandom pattern generated from this seed on power-on, rather than zeroes");
             } in {
                 Self {
                     input,
@@ -102,6 +171,19 @@ impl Args {
                     debug,
                     persistent_state_filename,
                     zoom,
+                    profile_filename,
+                    lag_csv_filename,
+                    lag_overlay,
+                    trace_filename,
+                    trace_filter: TraceFilter {
+                        pc_range: trace_pc_range.map(|AddressRange(range)| range),
+                        frame_range: trace_frame_range.map(|FrameRange(range)| range),
+                        trigger: trace_trigger,
+                    },
+                    sanitize,
+                    random_ram_seed,
+                    ppu_lint,
+                    debug_port,
                 }
             }
         }
@@ -128,7 +210,7 @@ fn dynamic_nes_from_args(args: &Args) -> Result<DynamicNes, Error> {
             buffer
         }
         Input::StateFile(state_filename) => {
-            return load(&state_filename).map_err(Error::DeserializeError);
+            return load(state_filename).map_err(Error::DeserializeError);
         }
     };
     DynamicNes::from_ines(&Ines::parse(&rom_buffer).map_err(Error::InesParseError)?)
@@ -166,6 +248,9 @@ struct Config {
     persistent_state_filename: Option<PathBuf>,
     zoom: f64,
     name_table_gif_renderer: Option<String>,
+    lag_csv_filename: Option<PathBuf>,
+    lag_overlay: bool,
+    debug_port: bool,
 }
 
 impl Config {
@@ -183,6 +268,7 @@ impl Config {
             .map(|gif_filename| gif_filename.into());
         let persistent_state_filename = args.persistent_state_filename.as_ref().map(|f| f.into());
         let name_table_gif_renderer = args.name_table_gif_filename.clone();
+        let lag_csv_filename = args.lag_csv_filename.as_ref().map(|f| f.into());
         Self {
             save_config,
             gif_filename,
@@ -192,8 +278,14 @@ impl Config {
             persistent_state_filename,
             zoom: args.zoom,
             name_table_gif_renderer,
+            lag_csv_filename,
+            lag_overlay: args.lag_overlay,
+            debug_port: args.debug_port,
         }
     }
+    fn detect_lag(&self) -> bool {
+        self.lag_csv_filename.is_some() || self.lag_overlay
+    }
     fn save_filename(&self) -> Option<&PathBuf> {
         self.save_config
             .as_ref()
@@ -218,6 +310,49 @@ fn save<M: Mapper + serde::Serialize, P: AsRef<Path>>(nes: &Nes<M>, path: Option
     }
 }
 
+// Where a snapshot taken through the debug port is saved: the save state file with the snapshot's
+// tag added to its name, so each tag gets its own file
+fn snapshot_filename(save_filename: &Path, tag: u8) -> PathBuf {
+    let mut file_name = save_filename.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(format!("-snapshot-{}", tag));
+    if let Some(extension) = save_filename.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    save_filename.with_file_name(file_name)
+}
+
+// Prints what the program wrote to the debug port and saves any snapshots it asked for, returning
+// the exit status it wrote, if any
+fn handle_debug_port<M: Mapper + serde::Serialize>(
+    nes: &mut Nes<M>,
+    config: &Config,
+    frame: u64,
+) -> Option<u8> {
+    let debug_port = nes.debug_port_mut()?;
+    let output = debug_port.take_output();
+    let events = debug_port.take_events();
+    io::stderr()
+        .write_all(&output)
+        .expect("Failed to write debug port output");
+    let mut exit_status = None;
+    for event in events {
+        match event {
+            DebugPortEvent::Exit(status) => {
+                eprintln!("debug port: exit with status {} in frame {}", status, frame);
+                exit_status.get_or_insert(status);
+            }
+            DebugPortEvent::Break => eprintln!("debug port: break in frame {}", frame),
+            DebugPortEvent::Snapshot(tag) => {
+                eprintln!("debug port: snapshot {} in frame {}", tag, frame);
+                let save_filename = config.save_filename();
+                save(nes, save_filename.map(|path| snapshot_filename(path, tag)));
+            }
+        }
+    }
+    exit_status
+}
+
 fn load<P: AsRef<Path>>(path: P) -> Result<DynamicNes, bincode::Error> {
     let mut state_file = File::open(path).expect("Failed to open state file");
     let mut bytes = Vec::new();
@@ -251,11 +386,13 @@ fn load_persistent_state<P: AsRef<Path>>(
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 enum Stop {
     Quit,
     Load(DynamicNes),
 }
 
+#[allow(clippy::large_enum_variant)]
 enum MetaAction {
     Stop(Stop),
     PrintInfo,
@@ -268,8 +405,8 @@ fn handle_event<M: Mapper + serde::ser::Serialize, P: AsRef<Path> + Copy, Q: AsR
     event: graphical_frontend::input::Event,
 ) -> Option<MetaAction> {
     use graphical_frontend::input;
-    match event {
-        input::Event::WindowEvent { event, .. } => match event {
+    if let input::Event::WindowEvent { event, .. } = event {
+        match event {
             input::WindowEvent::CloseRequested => {
                 return Some(MetaAction::Stop(Stop::Quit));
             }
@@ -301,10 +438,10 @@ fn handle_event<M: Mapper + serde::ser::Serialize, P: AsRef<Path> + Copy, Q: AsR
                             input::VirtualKeyCode::B => {
                                 nes::controller1::press::b(nes);
                             }
-                            input::VirtualKeyCode::S => save(&nes, save_state_path),
+                            input::VirtualKeyCode::S => save(nes, save_state_path),
                             input::VirtualKeyCode::L => {
                                 if let Some(save_state_path) = save_state_path.as_ref() {
-                                    if let Some(dynamic_nes) = load(save_state_path).ok() {
+                                    if let Ok(dynamic_nes) = load(save_state_path) {
                                         return Some(MetaAction::Stop(Stop::Load(dynamic_nes)));
                                     }
                                 }
@@ -359,8 +496,7 @@ fn handle_event<M: Mapper + serde::ser::Serialize, P: AsRef<Path> + Copy, Q: AsR
                 }
             },
             _ => (),
-        },
-        _ => (),
+        }
     }
     None
 }
@@ -391,10 +527,10 @@ impl NameTableGifRenderer {
 
 fn run_nes_for_frame<M: Mapper, O: RenderOutput>(
     nes: &mut Nes<M>,
-    config: &mut Config,
     pixels: &mut O,
     gif_renderer: Option<&mut gif_renderer::Renderer<File>>,
     mut name_table_gif_renderer: Option<&mut NameTableGifRenderer>,
+    instruments: &mut Instruments,
 ) {
     let name_table_frame = name_table_gif_renderer
         .as_mut()
@@ -402,11 +538,7 @@ fn run_nes_for_frame<M: Mapper, O: RenderOutput>(
     if let Some(gif_renderer) = gif_renderer {
         let mut gif_frame = gif_renderer::Frame::new();
         let mut render_output = RenderOutputPair::new(pixels, &mut gif_frame);
-        if config.debug {
-            nes.run_for_frame_debug(&mut render_output, name_table_frame);
-        } else {
-            nes.run_for_frame(&mut render_output, name_table_frame);
-        }
+        instruments.run_for_frame(nes, &mut render_output, name_table_frame);
         #[cfg(feature = "ppu_debug")]
         {
             for ((x, y), age) in nes.ppu().debug().pixel_ages() {
@@ -415,17 +547,31 @@ fn run_nes_for_frame<M: Mapper, O: RenderOutput>(
         }
         gif_renderer.add(&gif_frame);
     } else {
-        if config.debug {
-            nes.run_for_frame_debug(pixels, name_table_frame);
-        } else {
-            nes.run_for_frame(pixels, name_table_frame);
-        }
+        instruments.run_for_frame(nes, pixels, name_table_frame);
     }
     if let Some(name_table_gif_renderer) = name_table_gif_renderer {
         name_table_gif_renderer.render();
     }
 }
 
+// Draws a bar along the top of the screen whose length is the proportion of the frame the cpu
+// spent busy
+fn render_lag_overlay<O: RenderOutput>(frame_stats: &FrameStats, pixels: &mut O) {
+    const RED: u8 = 0x16;
+    const GREEN: u8 = 0x2A;
+    const HEIGHT_PX: u16 = 4;
+    let colour = if frame_stats.is_lag() { RED } else { GREEN };
+    let width_px = (frame_stats.busy_cycles() * nes_specs::SCREEN_WIDTH_PX as u64)
+        .checked_div(frame_stats.cycles)
+        .unwrap_or(0)
+        .max(1) as u16;
+    for y in 0..HEIGHT_PX {
+        for x in 0..width_px.min(nes_specs::SCREEN_WIDTH_PX) {
+            pixels.set_pixel_colour_sprite_front(x, y, colour);
+        }
+    }
+}
+
 struct RunGraphical {
     dynamic_nes: DynamicNes,
     meta: RunGraphicalMeta,
@@ -437,6 +583,7 @@ struct RunGraphicalMeta {
     gif_renderer: Option<gif_renderer::Renderer<File>>,
     name_table_gif_renderer: Option<NameTableGifRenderer>,
     print_info: bool,
+    instruments: Instruments,
 }
 
 impl RunGraphicalMeta {
@@ -454,7 +601,7 @@ impl RunGraphicalMeta {
         }
         if let Some(autosave_config) = self.config.autosave_config() {
             if self.frame_count == autosave_config.autosave_after_frames {
-                save(&nes, Some(&autosave_config.filename));
+                save(nes, Some(&autosave_config.filename));
             }
         }
         if self.print_info {
@@ -462,10 +609,10 @@ impl RunGraphicalMeta {
             let mut render_output = RenderOutputPair::new(&mut pixels, &mut memory_only_frame);
             run_nes_for_frame(
                 nes,
-                &mut self.config,
                 &mut render_output,
                 self.gif_renderer.as_mut(),
                 self.name_table_gif_renderer.as_mut(),
+                &mut self.instruments,
             );
             let mut hasher = DefaultHasher::new();
             memory_only_frame.hash(&mut hasher);
@@ -476,12 +623,19 @@ impl RunGraphicalMeta {
         } else {
             run_nes_for_frame(
                 nes,
-                &mut self.config,
                 &mut pixels,
                 self.gif_renderer.as_mut(),
                 self.name_table_gif_renderer.as_mut(),
+                &mut self.instruments,
             );
         }
+        if let Some(frame_stats) = self.instruments.end_frame(self.frame_count) {
+            if self.config.lag_overlay {
+                render_lag_overlay(&frame_stats, &mut pixels);
+            }
+        }
+        // the window stays open after the program exits, so the final frame can be seen
+        handle_debug_port(nes, &self.config, self.frame_count);
         if let Some((frame_duration, frame_start)) = realtime_frame_timing {
             if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
                 thread::sleep(remaining);
@@ -514,6 +668,9 @@ impl graphical_frontend::AppTrait for RunGraphical {
                 Stop::Quit => Some(graphical_frontend::ControlFlow::Quit),
                 Stop::Load(dynamic_nes) => {
                     self.dynamic_nes = dynamic_nes;
+                    if self.meta.config.debug_port {
+                        self.dynamic_nes.enable_debug_port();
+                    }
                     None
                 }
             },
@@ -529,24 +686,68 @@ impl graphical_frontend::AppTrait for RunGraphical {
     }
 }
 
-fn run_headless_hashing_final_frame_gen<M: Mapper>(mut nes: Nes<M>, num_frames: u64) -> u64 {
+fn run_headless_for_frame<M: Mapper, O: RenderOutput>(
+    nes: &mut Nes<M>,
+    frame_count: u64,
+    pixels: &mut O,
+    instruments: &mut Instruments,
+) {
+    instruments.run_for_frame(nes, pixels, None);
+    instruments.end_frame(frame_count);
+}
+
+// How a headless run ended
+enum HeadlessOutcome {
+    FinalFrameHash(u64),
+    // the program wrote an exit status to the debug port
+    Exit(u8),
+}
+
+fn run_headless_hashing_final_frame_gen<M: Mapper + serde::Serialize>(
+    mut nes: Nes<M>,
+    config: &Config,
+    num_frames: u64,
+    instruments: &mut Instruments,
+) -> HeadlessOutcome {
     if let Some(n) = num_frames.checked_sub(1) {
-        for _ in 0..n {
-            nes.run_for_frame(&mut NoRenderOutput, None);
+        for frame_count in 0..n {
+            run_headless_for_frame(&mut nes, frame_count, &mut NoRenderOutput, instruments);
+            if let Some(status) = handle_debug_port(&mut nes, config, frame_count) {
+                return HeadlessOutcome::Exit(status);
+            }
         }
     }
     let mut frame = nes_headless_frame::Frame::new();
-    nes.run_for_frame(&mut frame, None);
+    run_headless_for_frame(
+        &mut nes,
+        num_frames.saturating_sub(1),
+        &mut frame,
+        instruments,
+    );
+    if let Some(status) = handle_debug_port(&mut nes, config, num_frames.saturating_sub(1)) {
+        return HeadlessOutcome::Exit(status);
+    }
     let mut hasher = DefaultHasher::new();
     frame.hash(&mut hasher);
-    hasher.finish()
+    HeadlessOutcome::FinalFrameHash(hasher.finish())
 }
 
-fn run_headless_hashing_final_frame(dynamic_nes: DynamicNes, num_frames: u64) -> u64 {
+fn run_headless_hashing_final_frame(
+    dynamic_nes: DynamicNes,
+    config: &Config,
+    num_frames: u64,
+    instruments: &mut Instruments,
+) -> HeadlessOutcome {
     match dynamic_nes {
-        DynamicNes::NromHorizontal(n) => run_headless_hashing_final_frame_gen(n, num_frames),
-        DynamicNes::NromVertical(n) => run_headless_hashing_final_frame_gen(n, num_frames),
-        DynamicNes::Mmc1(n) => run_headless_hashing_final_frame_gen(n, num_frames),
+        DynamicNes::NromHorizontal(n) => {
+            run_headless_hashing_final_frame_gen(n, config, num_frames, instruments)
+        }
+        DynamicNes::NromVertical(n) => {
+            run_headless_hashing_final_frame_gen(n, config, num_frames, instruments)
+        }
+        DynamicNes::Mmc1(n) => {
+            run_headless_hashing_final_frame_gen(n, config, num_frames, instruments)
+        }
     }
 }
 
@@ -555,12 +756,74 @@ fn main() {
     env_logger::init();
     let args = Args::parser().with_help_default().parse_env_or_exit();
     let config = Config::from_args(&args);
+    if args.random_ram_seed.is_some() && matches!(args.input, Input::StateFile(_)) {
+        eprintln!("--random-ram-seed can't be combined with --load-state-file");
+        std::process::exit(1);
+    }
     let mut dynamic_nes = dynamic_nes_from_args(&args).unwrap();
-    let Args { frontend, .. } = args;
+    let Args {
+        frontend,
+        profile_filename,
+        trace_filename,
+        trace_filter,
+        sanitize,
+        random_ram_seed,
+        ppu_lint,
+        ..
+    } = args;
+    if matches!(frontend, Frontend::Tui)
+        && (config.debug
+            || sanitize
+            || ppu_lint
+            || trace_filename.is_some()
+            || profile_filename.is_some()
+            || config.detect_lag())
+    {
+        eprintln!(
+            "--tui can't be combined with --debug, --sanitize, --ppu-lint, --trace, --profile, --lag-csv or --lag-overlay"
+        );
+        std::process::exit(1);
+    }
+    if let Some(random_ram_seed) = random_ram_seed {
+        dynamic_nes.randomise_ram(random_ram_seed);
+    }
+    if config.debug_port {
+        dynamic_nes.enable_debug_port();
+    }
+    let mut instruments = Instruments {
+        profiler: None,
+        lag_recorder: LagRecorder::new(&config),
+        trace_logger: trace_filename.map(|trace_filename| {
+            let file = File::create(trace_filename).expect("Failed to create trace file");
+            TraceLogger::new(BufWriter::new(file), trace_filter)
+        }),
+        sanitizer: sanitize.then(Sanitizer::new),
+        ppu_linter: ppu_lint.then(PpuLinter::new),
+        debug: config.debug,
+    };
     match frontend {
         Frontend::HeadlessPrintingFinalFrameHash { num_frames } => {
-            let final_frame_hash = run_headless_hashing_final_frame(dynamic_nes, num_frames);
-            println!("{}", final_frame_hash);
+            instruments.profiler = profile_filename.as_ref().map(|_| Profiler::new());
+            let outcome = run_headless_hashing_final_frame(
+                dynamic_nes,
+                &config,
+                num_frames,
+                &mut instruments,
+            );
+            let exit_status = match outcome {
+                HeadlessOutcome::FinalFrameHash(final_frame_hash) => {
+                    println!("{}", final_frame_hash);
+                    None
+                }
+                HeadlessOutcome::Exit(status) => Some(status),
+            };
+            instruments.finish(profile_filename.as_deref());
+            if let Some(status) = exit_status {
+                std::process::exit(status as i32);
+            }
+        }
+        Frontend::Tui => {
+            tui::run(dynamic_nes).expect("Failed to run terminal debugger");
         }
         Frontend::Graphical => {
             let graphical_frontend = graphical_frontend::Frontend::new(config.zoom);
@@ -570,13 +833,16 @@ fn main() {
             let name_table_gif_renderer = config
                 .name_table_gif_renderer
                 .as_ref()
-                .map(|f| NameTableGifRenderer::new(f));
+                .map(NameTableGifRenderer::new);
             if let Some(persistent_state_filename) = config.persistent_state_filename.as_ref() {
                 if let Some(Ok(persistent_state)) = load_persistent_state(persistent_state_filename)
                 {
                     dynamic_nes
                         .load_persistent_state(&persistent_state)
                         .unwrap();
+                    if let Some(sanitizer) = instruments.sanitizer.as_mut() {
+                        sanitizer.assume_prg_ram_initialised();
+                    }
                 }
             }
             let run_graphical = RunGraphical {
@@ -587,6 +853,7 @@ fn main() {
                     gif_renderer,
                     name_table_gif_renderer,
                     print_info: false,
+                    instruments,
                 },
             };
             graphical_frontend.run(run_graphical);
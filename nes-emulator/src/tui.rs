@@ -0,0 +1,610 @@
+// A debugger which runs in the terminal, for machines where the graphical frontend can't open a
+// window (e.g. over ssh). Emulation is paused at startup and controlled with commands typed into
+// the prompt at the bottom of the screen.
+
+use mos6502_model::debug::InstructionWithOperand;
+use mos6502_model::format::{Dialect, Formatter};
+use mos6502_model::machine::{Address, MemoryReadOnly};
+use nes_emulator_core::{
+    debug_port::DebugPortEvent,
+    debugger::{
+        AccessKind, Break, Breakpoint, BreakpointKind, CallKind, Condition, Debugger, StopReason,
+    },
+    dynamic_nes::DynamicNes,
+    mapper::Mapper,
+    nes::Nes,
+};
+use nes_render_output::NoRenderOutput;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::io;
+use std::time::Duration;
+
+const NUM_MESSAGES: usize = 6;
+const RAM_BYTES_PER_ROW: u16 = 16;
+const OAM_SPRITES_PER_ROW: usize = 4;
+const OAM_BYTES_PER_ROW: usize = OAM_SPRITES_PER_ROW * 4;
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const HELP: &[&str] = &[
+    "s/step  n/next  o/out  c/continue  f/frame  q/quit  (enter repeats the last command)",
+    "b/break, r/read, w/write, pr/ppuread, pw/ppuwrite ADDR[-ADDR] [COND] [HITS]",
+    "  e.g. \"b C048 A==#$10\", \"w 0300-03FF 3\", \"pw 3F00-3F1F\"",
+    "d/delete ID  t/toggle ID  m/mem ADDR  (esc pauses while running)",
+];
+
+fn parse_address(s: &str) -> Result<Address, String> {
+    let hex = s.strip_prefix('$').unwrap_or(s);
+    Address::from_str_radix(hex, 16).map_err(|_| format!("invalid address \"{}\"", s))
+}
+
+// Parses the arguments of a breakpoint command: ADDR[-ADDR] [COND] [HITS]
+fn parse_breakpoint(kind: BreakpointKind, args: &[&str]) -> Result<Breakpoint, String> {
+    let (addresses, rest) = args
+        .split_first()
+        .ok_or_else(|| "expected an address".to_string())?;
+    let addresses = match addresses.split_once('-') {
+        Some((first, last)) => parse_address(first)?..=parse_address(last)?,
+        None => {
+            let address = parse_address(addresses)?;
+            address..=address
+        }
+    };
+    let mut breakpoint = Breakpoint::new(kind, addresses);
+    for arg in rest {
+        breakpoint = match arg.parse::<u64>() {
+            Ok(hit_count) => breakpoint.with_hit_count(hit_count),
+            Err(_) => breakpoint.with_condition(arg.parse::<Condition>()?),
+        };
+    }
+    Ok(breakpoint)
+}
+
+fn describe_breakpoint(breakpoint: &Breakpoint) -> String {
+    let kind = match breakpoint.kind {
+        BreakpointKind::Execute => "exec",
+        BreakpointKind::Access(AccessKind::CpuRead) => "read",
+        BreakpointKind::Access(AccessKind::CpuWrite) => "write",
+        BreakpointKind::Access(AccessKind::PpuRead) => "ppuread",
+        BreakpointKind::Access(AccessKind::PpuWrite) => "ppuwrite",
+    };
+    let (first, last) = (*breakpoint.addresses.start(), *breakpoint.addresses.end());
+    let mut description = if first == last {
+        format!("{:<8} {:04X}", kind, first)
+    } else {
+        format!("{:<8} {:04X}-{:04X}", kind, first, last)
+    };
+    if let Some(condition) = breakpoint.condition {
+        description.push_str(&format!(" {}", condition));
+    }
+    description.push_str(&format!(" {}/{}", breakpoint.hits(), breakpoint.hit_count));
+    if !breakpoint.enabled {
+        description.push_str(" off");
+    }
+    description
+}
+
+fn describe_break(break_: &Break) -> String {
+    let position = &break_.position;
+    let reason = match break_.reason {
+        StopReason::Breakpoint { id, access: None } => format!("breakpoint {}", id),
+        StopReason::Breakpoint {
+            id,
+            access: Some(access),
+        } => {
+            let kind = match access.kind {
+                AccessKind::CpuRead => "read",
+                AccessKind::CpuWrite => "write",
+                AccessKind::PpuRead => "ppu read",
+                AccessKind::PpuWrite => "ppu write",
+            };
+            format!(
+                "breakpoint {} ({} {:04X} = {:02X})",
+                id, kind, access.address, access.value
+            )
+        }
+        StopReason::Step => "step".to_string(),
+        StopReason::FrameComplete => "end of frame".to_string(),
+        StopReason::UnknownOpcode(opcode) => format!("unknown opcode {:02X}", opcode),
+        StopReason::DebugPortBreak => "debug port break".to_string(),
+    };
+    format!(
+        "{} at {:04X} (frame {}, scanline {}, dot {})",
+        reason, position.pc, position.frame, position.scanline, position.dot
+    )
+}
+
+// Finds up to `count` instructions leading up to `address`. Code can't be reliably decoded
+// backwards, so this tries starting further back first, and takes the instructions from the
+// first start address which decodes forwards to land exactly on `address`.
+fn instructions_before<M: MemoryReadOnly>(
+    memory: &M,
+    address: Address,
+    count: usize,
+) -> Vec<InstructionWithOperand> {
+    const MAX_INSTRUCTION_BYTES: usize = 3;
+    for offset in (1..=(count * MAX_INSTRUCTION_BYTES) as Address).rev() {
+        let mut current = match address.checked_sub(offset) {
+            Some(current) => current,
+            None => continue,
+        };
+        let mut instructions = Vec::new();
+        while current < address {
+            match InstructionWithOperand::decode(current, memory) {
+                Ok(instruction_with_operand) => {
+                    current += instruction_with_operand.instruction().size() as Address;
+                    instructions.push(instruction_with_operand);
+                }
+                Err(_) => break,
+            }
+        }
+        if current == address {
+            let skip = instructions.len().saturating_sub(count);
+            return instructions.split_off(skip);
+        }
+    }
+    Vec::new()
+}
+
+fn pane(title: &str) -> Block<'static> {
+    Block::bordered().title(format!(" {} ", title))
+}
+
+struct Tui {
+    dynamic_nes: DynamicNes,
+    debugger: Debugger,
+    last_break: Option<Break>,
+    input: String,
+    last_command: String,
+    messages: Vec<String>,
+    ram_view_address: Address,
+    running: bool,
+    quit: bool,
+}
+
+impl Tui {
+    fn new(dynamic_nes: DynamicNes) -> Self {
+        Self {
+            dynamic_nes,
+            debugger: Debugger::new(),
+            last_break: None,
+            input: String::new(),
+            last_command: String::new(),
+            messages: vec!["type \"help\" for a list of commands".to_string()],
+            ram_view_address: 0,
+            running: false,
+            quit: false,
+        }
+    }
+
+    fn message(&mut self, message: String) {
+        self.messages.push(message);
+        let excess = self.messages.len().saturating_sub(NUM_MESSAGES);
+        self.messages.drain(..excess);
+    }
+
+    fn run_until_break(&mut self) -> Break {
+        let break_ = self
+            .dynamic_nes
+            .run_until_break(&mut self.debugger, &mut NoRenderOutput);
+        self.last_break = Some(break_);
+        self.show_debug_port_output();
+        break_
+    }
+
+    // Shows what the program wrote to the debug port as messages. Breaks are reported by
+    // `run_until_break`, and snapshots aren't supported here.
+    fn show_debug_port_output(&mut self) {
+        let Some(debug_port) = self.dynamic_nes.debug_port_mut() else {
+            return;
+        };
+        let output = debug_port.take_output();
+        let events = debug_port.take_events();
+        for line in String::from_utf8_lossy(&output).lines() {
+            self.message(format!("debug port: {}", line));
+        }
+        for event in events {
+            match event {
+                DebugPortEvent::Exit(status) => {
+                    self.message(format!("debug port: exit with status {}", status))
+                }
+                DebugPortEvent::Snapshot(tag) => {
+                    self.message(format!("debug port: snapshot {} (ignored)", tag))
+                }
+                DebugPortEvent::Break => (),
+            }
+        }
+    }
+
+    // Runs until the next break which isn't the end of a frame, one frame at a time so the
+    // screen can be redrawn and input handled in between
+    fn run_while_running(&mut self) {
+        let break_ = self.run_until_break();
+        if break_.reason != StopReason::FrameComplete {
+            self.running = false;
+            self.message(describe_break(&break_));
+        }
+    }
+
+    fn run_and_report(&mut self) {
+        let break_ = self.run_until_break();
+        self.message(describe_break(&break_));
+    }
+
+    fn add_breakpoint(&mut self, kind: BreakpointKind, args: &[&str]) -> Result<(), String> {
+        let breakpoint = parse_breakpoint(kind, args)?;
+        let description = describe_breakpoint(&breakpoint);
+        let id = self.debugger.add_breakpoint(breakpoint);
+        self.message(format!("breakpoint {}: {}", id, description));
+        Ok(())
+    }
+
+    fn breakpoint_id(&self, args: &[&str]) -> Result<usize, String> {
+        let id = args
+            .first()
+            .ok_or_else(|| "expected a breakpoint id".to_string())?;
+        id.parse()
+            .map_err(|_| format!("invalid breakpoint id \"{}\"", id))
+    }
+
+    fn run_command(&mut self, command: &str) -> Result<(), String> {
+        let words = command.split_whitespace().collect::<Vec<_>>();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(()),
+        };
+        match name {
+            "s" | "step" => {
+                self.debugger.step_into();
+                self.running = true;
+            }
+            "n" | "next" => {
+                self.debugger.step_over();
+                self.running = true;
+            }
+            "o" | "out" => {
+                if self.debugger.call_stack().is_empty() {
+                    return Err("not in a subroutine or interrupt handler".to_string());
+                }
+                self.debugger.step_out();
+                self.running = true;
+            }
+            "c" | "continue" => self.running = true,
+            "f" | "frame" => self.run_and_report(),
+            "b" | "break" => self.add_breakpoint(BreakpointKind::Execute, args)?,
+            "r" | "read" => {
+                self.add_breakpoint(BreakpointKind::Access(AccessKind::CpuRead), args)?
+            }
+            "w" | "write" => {
+                self.add_breakpoint(BreakpointKind::Access(AccessKind::CpuWrite), args)?
+            }
+            "pr" | "ppuread" => {
+                self.add_breakpoint(BreakpointKind::Access(AccessKind::PpuRead), args)?
+            }
+            "pw" | "ppuwrite" => {
+                self.add_breakpoint(BreakpointKind::Access(AccessKind::PpuWrite), args)?
+            }
+            "d" | "delete" => {
+                let id = self.breakpoint_id(args)?;
+                self.debugger
+                    .remove_breakpoint(id)
+                    .ok_or_else(|| format!("no breakpoint {}", id))?;
+                self.message(format!("deleted breakpoint {}", id));
+            }
+            "t" | "toggle" => {
+                let id = self.breakpoint_id(args)?;
+                let breakpoint = self
+                    .debugger
+                    .breakpoint_mut(id)
+                    .ok_or_else(|| format!("no breakpoint {}", id))?;
+                breakpoint.enabled = !breakpoint.enabled;
+            }
+            "m" | "mem" => {
+                let address = args
+                    .first()
+                    .ok_or_else(|| "expected an address".to_string())?;
+                self.ram_view_address = parse_address(address)? & !(RAM_BYTES_PER_ROW - 1);
+            }
+            "q" | "quit" => self.quit = true,
+            "h" | "help" | "?" => {
+                for line in HELP {
+                    self.message(line.to_string());
+                }
+            }
+            _ => return Err(format!("unknown command \"{}\"", name)),
+        }
+        Ok(())
+    }
+
+    fn submit_input(&mut self) {
+        let input = std::mem::take(&mut self.input);
+        let command = if input.trim().is_empty() {
+            self.last_command.clone()
+        } else {
+            input
+        };
+        if let Err(error) = self.run_command(&command) {
+            self.message(error);
+        }
+        self.last_command = command;
+    }
+
+    fn handle_event(&mut self, event: Event) {
+        let key = match event {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => return,
+        };
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Esc if self.running => {
+                self.running = false;
+                self.message("paused".to_string());
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter => self.submit_input(),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            _ => (),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main_area, messages_area, input_area] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(NUM_MESSAGES as u16 + 2),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+        match &self.dynamic_nes {
+            DynamicNes::NromHorizontal(n) => self.draw_nes(frame, main_area, n),
+            DynamicNes::NromVertical(n) => self.draw_nes(frame, main_area, n),
+            DynamicNes::Mmc1(n) => self.draw_nes(frame, main_area, n),
+        }
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| Line::from(message.as_str()))
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(messages).block(pane("Messages")),
+            messages_area,
+        );
+        let title = if self.running { "Running" } else { "Command" };
+        frame.render_widget(
+            Paragraph::new(format!("> {}", self.input)).block(pane(title)),
+            input_area,
+        );
+    }
+
+    fn draw_nes<M: Mapper>(&self, frame: &mut Frame, area: Rect, nes: &Nes<M>) {
+        let [left_area, disassembly_area, right_area] = Layout::horizontal([
+            Constraint::Length(36),
+            Constraint::Min(40),
+            Constraint::Length(56),
+        ])
+        .areas(area);
+        let [registers_area, call_stack_area, breakpoints_area] = Layout::vertical([
+            Constraint::Length(7),
+            Constraint::Percentage(50),
+            Constraint::Percentage(50),
+        ])
+        .areas(left_area);
+        let [ram_area, palette_area, oam_area] = Layout::vertical([
+            Constraint::Min(6),
+            Constraint::Length(4),
+            Constraint::Length(18),
+        ])
+        .areas(right_area);
+        frame.render_widget(self.registers(nes), registers_area);
+        frame.render_widget(self.call_stack(), call_stack_area);
+        frame.render_widget(self.breakpoints(), breakpoints_area);
+        frame.render_widget(
+            self.disassembly(nes, disassembly_area.height.saturating_sub(2) as usize),
+            disassembly_area,
+        );
+        frame.render_widget(self.ram(nes, ram_area.height.saturating_sub(2)), ram_area);
+        frame.render_widget(Self::palette(nes), palette_area);
+        frame.render_widget(Self::oam(nes), oam_area);
+    }
+
+    fn registers<M: Mapper>(&self, nes: &Nes<M>) -> Paragraph<'_> {
+        let cpu = nes.cpu();
+        let mut lines = vec![
+            Line::from(format!(
+                "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X}",
+                cpu.pc, cpu.acc, cpu.x, cpu.y, cpu.sp
+            )),
+            Line::from(format!(
+                "P:{:02X} {}",
+                cpu.status.masked_with_expansion(),
+                cpu.status
+            )),
+            Line::from(format!(
+                "frame {} cycle {}",
+                self.debugger.frame(),
+                self.debugger.cycle()
+            )),
+        ];
+        if let Some(break_) = self.last_break.as_ref() {
+            lines.push(Line::from(format!(
+                "scanline {} dot {}",
+                break_.position.scanline, break_.position.dot
+            )));
+        }
+        Paragraph::new(lines).block(pane("Registers"))
+    }
+
+    fn call_stack(&self) -> Paragraph<'_> {
+        let lines = self
+            .debugger
+            .call_stack()
+            .iter()
+            .rev()
+            .map(|call_frame| {
+                let kind = match call_frame.kind {
+                    CallKind::Call => "call",
+                    CallKind::Interrupt => "interrupt",
+                };
+                Line::from(format!(
+                    "{:04X} {} from {:04X}",
+                    call_frame.function, kind, call_frame.caller
+                ))
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("Call Stack"))
+    }
+
+    fn breakpoints(&self) -> Paragraph<'_> {
+        let lines = self
+            .debugger
+            .breakpoints()
+            .map(|(id, breakpoint)| {
+                Line::from(format!("{:>2} {}", id, describe_breakpoint(breakpoint)))
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("Breakpoints"))
+    }
+
+    fn disassembly<M: Mapper>(&self, nes: &Nes<M>, height: usize) -> Paragraph<'_> {
+        let memory = nes.devices_with_oam();
+        let pc = nes.cpu().pc;
+        let formatter = Formatter::new(Dialect::Ca65).with_register_names(true);
+        let is_breakpoint = |address: Address| {
+            self.debugger.breakpoints().any(|(_, breakpoint)| {
+                breakpoint.enabled
+                    && breakpoint.kind == BreakpointKind::Execute
+                    && breakpoint.addresses.contains(&address)
+            })
+        };
+        let mut instructions = instructions_before(memory, pc, height / 3);
+        let mut address = pc;
+        while instructions.len() < height {
+            match InstructionWithOperand::decode(address, memory) {
+                Ok(instruction_with_operand) => {
+                    address = address
+                        .wrapping_add(instruction_with_operand.instruction().size() as Address);
+                    instructions.push(instruction_with_operand);
+                }
+                Err(_) => break,
+            }
+        }
+        let lines = instructions
+            .iter()
+            .map(|instruction_with_operand| {
+                let address = instruction_with_operand.address();
+                let bytes = std::iter::once(instruction_with_operand.opcode())
+                    .chain(instruction_with_operand.operand().iter().cloned())
+                    .map(|byte| format!("{:02X}", byte))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let line = Line::from(format!(
+                    "{}{} {:04X}  {:<9} {}",
+                    if address == pc { ">" } else { " " },
+                    if is_breakpoint(address) { "*" } else { " " },
+                    address,
+                    bytes,
+                    formatter.instruction(instruction_with_operand)
+                ));
+                if address == pc {
+                    line.style(Style::new().add_modifier(Modifier::REVERSED))
+                } else {
+                    line
+                }
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("Disassembly"))
+    }
+
+    fn ram<M: Mapper>(&self, nes: &Nes<M>, height: u16) -> Paragraph<'_> {
+        let memory = nes.devices_with_oam();
+        let lines = (0..height)
+            .map(|row| {
+                let row_address = self
+                    .ram_view_address
+                    .wrapping_add(row.wrapping_mul(RAM_BYTES_PER_ROW));
+                let bytes = (0..RAM_BYTES_PER_ROW)
+                    .map(|i| {
+                        format!(
+                            "{:02X}",
+                            memory.read_u8_read_only(row_address.wrapping_add(i))
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Line::from(format!("{:04X}  {}", row_address, bytes))
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("Memory"))
+    }
+
+    fn palette<M: Mapper>(nes: &Nes<M>) -> Paragraph<'_> {
+        let palette_ram = nes.mapper().ppu_palette_ram();
+        let lines = palette_ram
+            .chunks(16)
+            .zip(["bg", "sp"])
+            .map(|(colours, name)| {
+                let colours = colours
+                    .iter()
+                    .map(|colour| format!("{:02X}", colour))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Line::from(format!("{}  {}", name, colours))
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("Palette"))
+    }
+
+    // Each row starts with the index of its first sprite, followed by the Y, tile, attribute and
+    // X bytes of each sprite
+    fn oam<M: Mapper>(nes: &Nes<M>) -> Paragraph<'_> {
+        let lines = nes
+            .oam()
+            .ram()
+            .chunks(OAM_BYTES_PER_ROW)
+            .enumerate()
+            .map(|(row, bytes)| {
+                let sprites = bytes
+                    .chunks(4)
+                    .map(|sprite| {
+                        sprite
+                            .iter()
+                            .map(|byte| format!("{:02X}", byte))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .collect::<Vec<_>>()
+                    .join("  ");
+                Line::from(format!("{:02}  {}", row * OAM_SPRITES_PER_ROW, sprites))
+            })
+            .collect::<Vec<_>>();
+        Paragraph::new(lines).block(pane("OAM"))
+    }
+
+    fn run(mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            if self.running {
+                self.run_while_running();
+                while event::poll(Duration::ZERO)? {
+                    self.handle_event(event::read()?);
+                }
+            } else if event::poll(INPUT_POLL_INTERVAL)? {
+                self.handle_event(event::read()?);
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn run(dynamic_nes: DynamicNes) -> io::Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let result = Tui::new(dynamic_nes).run(&mut terminal);
+    ratatui::try_restore()?;
+    result
+}
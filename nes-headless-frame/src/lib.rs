@@ -17,6 +17,12 @@ pub struct Frame {
     background_pixel_ages: [u64; nes_specs::SCREEN_TOTAL_PX as usize],
 }
 
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Frame {
     pub fn new() -> Self {
         Self {
@@ -12,6 +12,12 @@ pub struct NameTableFrame {
     scroll_by_scanline: [Scroll; nes_specs::SCREEN_HEIGHT_PX as usize],
 }
 
+impl Default for NameTableFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NameTableFrame {
     pub fn new() -> Self {
         Self {
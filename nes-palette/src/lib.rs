@@ -30,7 +30,7 @@ impl Palette {
         let mut bytes = [0; NUM_COLOURS * 3];
         let mut i = 0;
         for colour in self.colours.iter() {
-            bytes[i + 0] = colour.r;
+            bytes[i] = colour.r;
             bytes[i + 1] = colour.g;
             bytes[i + 2] = colour.b;
             i += 3;
@@ -0,0 +1,27 @@
+use nes_samples::debug_port;
+use nes_samples::single_block::*;
+
+// Adds up the numbers 1 to 10, and reports the result through the emulator's debug port. Run
+// with --debug-port to see the output, and get the exit status of the emulator.
+pub fn program(b: &mut Block) {
+    debug_port::print(b, "sum of 1..=10\n");
+    b.inst(Lda(Immediate), 0);
+    b.inst(Ldx(Immediate), 10);
+    b.label("loop");
+    b.inst(Stx(ZeroPage), 1);
+    b.inst(Clc, ());
+    b.inst(Adc(ZeroPage), 1);
+    b.inst(Dex, ());
+    b.inst(Bne, LabelRelativeOffset("loop"));
+    b.inst(Sta(ZeroPage), 0);
+    debug_port::print(b, "result: $");
+    debug_port::print_hex_at(b, 0);
+    debug_port::print(b, "\n");
+    debug_port::assert_eq(b, 0, 55, 1);
+    debug_port::pass(b);
+    debug_port::print_hex_subroutine(b);
+}
+
+pub fn main() {
+    with_block(program);
+}
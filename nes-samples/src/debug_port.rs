@@ -0,0 +1,89 @@
+// Code for test programs to report through the emulator's debug port (enabled with
+// --debug-port). These are all macros, which expand to code inline, apart from
+// `print_hex_subroutine`.
+use mos6502_assembler::*;
+use mos6502_model::{addressing_mode::*, assembler_instruction::*, Address};
+pub use nes_specs::debug_port::register;
+
+pub const PRINT_HEX_SUBROUTINE: &str = "debug-port-print-hex";
+
+// Prints a string to the host's log. Clobbers A.
+pub fn print<S: AsRef<str>>(b: &mut Block, s: S) {
+    for byte in s.as_ref().bytes() {
+        b.inst(Lda(Immediate), byte);
+        b.inst(Sta(Absolute), Addr(register::OUTPUT));
+    }
+}
+
+// Prints A as 2 hex digits by calling `print_hex_subroutine`. Clobbers A and X.
+pub fn print_hex(b: &mut Block) {
+    b.inst(Jsr(Absolute), PRINT_HEX_SUBROUTINE);
+}
+
+// Prints the byte at an address as 2 hex digits. Clobbers A and X.
+pub fn print_hex_at(b: &mut Block, address: Address) {
+    b.inst(Lda(Absolute), Addr(address));
+    print_hex(b);
+}
+
+// Declares the subroutine called by `print_hex`, which must appear once in any program using it
+pub fn print_hex_subroutine(b: &mut Block) {
+    b.scope(PRINT_HEX_SUBROUTINE, |b| {
+        b.label(PRINT_HEX_SUBROUTINE);
+        b.inst(Pha, ());
+        b.inst(Lsr(Accumulator), ());
+        b.inst(Lsr(Accumulator), ());
+        b.inst(Lsr(Accumulator), ());
+        b.inst(Lsr(Accumulator), ());
+        b.inst(Tax, ());
+        b.inst(Lda(AbsoluteXIndexed), "@digits");
+        b.inst(Sta(Absolute), Addr(register::OUTPUT));
+        b.inst(Pla, ());
+        b.inst(And(Immediate), 0x0F);
+        b.inst(Tax, ());
+        b.inst(Lda(AbsoluteXIndexed), "@digits");
+        b.inst(Sta(Absolute), Addr(register::OUTPUT));
+        b.inst(Rts, ());
+        b.label("@digits");
+        b.literal_bytes(b"0123456789ABCDEF");
+    });
+}
+
+// Ends the run with an exit status, and stops the program in case the emulator keeps running
+pub fn exit(b: &mut Block, status: u8) {
+    b.inst(Lda(Immediate), status);
+    b.inst(Sta(Absolute), Addr(register::EXIT));
+    b.infinite_loop();
+}
+
+pub fn pass(b: &mut Block) {
+    print(b, "pass\n");
+    exit(b, 0);
+}
+
+pub fn fail(b: &mut Block, status: u8) {
+    print(b, "fail\n");
+    exit(b, status);
+}
+
+// Fails with `status` unless the byte at `address` is `expected`. Clobbers A.
+pub fn assert_eq(b: &mut Block, address: Address, expected: u8, status: u8) {
+    b.scope("debug-port-assert-eq", |b| {
+        b.inst(Lda(Absolute), Addr(address));
+        b.inst(Cmp(Immediate), expected);
+        b.inst(Beq, LabelRelativeOffset("@ok"));
+        fail(b, status);
+        b.label("@ok");
+    });
+}
+
+// Stops in the debugger, when running under one
+pub fn breakpoint(b: &mut Block) {
+    b.inst(Sta(Absolute), Addr(register::BREAK));
+}
+
+// Saves the emulator's state, with a tag to tell snapshots apart in the log. Clobbers A.
+pub fn snapshot(b: &mut Block, tag: u8) {
+    b.inst(Lda(Immediate), tag);
+    b.inst(Sta(Absolute), Addr(register::SNAPSHOT));
+}
@@ -1,17 +1,24 @@
+pub mod debug_port;
+
 pub mod single_block {
-    pub use addressing_mode::*;
+    // listed rather than globbed, as `addressing_mode::Trait` would be ambiguous with
+    // `assembler_instruction::Trait`
+    pub use addressing_mode::{
+        Absolute, AbsoluteXIndexed, AbsoluteYIndexed, Accumulator, Immediate, Implied, Indirect,
+        IndirectYIndexed, Relative, XIndexedIndirect, ZeroPage, ZeroPageXIndexed, ZeroPageYIndexed,
+    };
     pub use assembler_instruction::*;
     pub use mos6502_assembler::*;
     pub use mos6502_model::*;
     pub use samples::*;
     use std::io::{self, Write};
 
-    use ines::*;
+    use ines::{Header, Ines, Mapper, Mirroring};
 
     pub const PRG_START: Address = 0xC000;
     pub const INTERRUPT_VECTOR_START_PC_OFFSET: Address = interrupt_vector::START_LO - PRG_START;
 
-    pub fn assemble_ines_file_to_stdout(block: &Block) {
+    pub fn assemble_ines_file(block: &Block) -> Vec<u8> {
         let mut prg_rom = Vec::new();
         block
             .assemble(PRG_START, ines::PRG_ROM_BLOCK_BYTES, &mut prg_rom)
@@ -29,17 +36,27 @@ pub mod single_block {
         };
         let mut output = Vec::new();
         ines.encode(&mut output);
+        output
+    }
+
+    pub fn assemble_ines_file_to_stdout(block: &Block) {
+        let output = assemble_ines_file(block);
         let stdout = io::stdout();
         let mut handle = stdout.lock();
-        handle.write(&output).expect("Failed to write output");
+        handle.write_all(&output).expect("Failed to write output");
     }
 
-    pub fn with_block<F: FnOnce(&mut Block)>(f: F) {
+    // Builds a program which starts at the beginning of the block
+    pub fn single_block<F: FnOnce(&mut Block)>(f: F) -> Block {
         let mut b = Block::new();
         f(&mut b);
         b.set_offset(INTERRUPT_VECTOR_START_PC_OFFSET);
         b.literal_offset_le(0);
-        assemble_ines_file_to_stdout(&b);
+        b
+    }
+
+    pub fn with_block<F: FnOnce(&mut Block)>(f: F) {
+        assemble_ines_file_to_stdout(&single_block(f));
     }
 
     pub fn with_sample<S: Sample>(_: S) {
@@ -0,0 +1,56 @@
+// Runs programs which report through the debug port the way `nes_emulator --debug-port` does,
+// checking what they print and the status they exit with
+#[allow(dead_code)]
+#[path = "../examples/debug_port.rs"]
+mod example;
+
+use ines::Ines;
+use nes_emulator_core::{debug_port::DebugPortEvent, dynamic_nes::DynamicNes};
+use nes_render_output::NoRenderOutput;
+use nes_samples::debug_port;
+use nes_samples::single_block::*;
+
+const MAX_FRAMES: usize = 10;
+
+fn run<F: FnOnce(&mut Block)>(program: F) -> (String, u8) {
+    let ines_file = assemble_ines_file(&single_block(program));
+    let mut nes = DynamicNes::from_ines(&Ines::parse(&ines_file).unwrap()).unwrap();
+    nes.enable_debug_port();
+    let mut output = Vec::new();
+    for _ in 0..MAX_FRAMES {
+        nes.run_for_frame(&mut NoRenderOutput);
+        let debug_port = nes.debug_port_mut().unwrap();
+        output.extend(debug_port.take_output());
+        let exit_status = debug_port
+            .take_events()
+            .into_iter()
+            .find_map(|event| match event {
+                DebugPortEvent::Exit(status) => Some(status),
+                _ => None,
+            });
+        if let Some(exit_status) = exit_status {
+            return (String::from_utf8(output).unwrap(), exit_status);
+        }
+    }
+    panic!("no exit within {} frames", MAX_FRAMES);
+}
+
+#[test]
+fn example_prints_the_sum_and_passes() {
+    assert_eq!(
+        run(example::program),
+        ("sum of 1..=10\nresult: $37\npass\n".to_string(), 0)
+    );
+}
+
+#[test]
+fn failed_assertion_exits_with_its_status() {
+    let (output, exit_status) = run(|b| {
+        b.inst(Lda(Immediate), 54);
+        b.inst(Sta(ZeroPage), 0);
+        debug_port::assert_eq(b, 0, 55, 3);
+        debug_port::pass(b);
+    });
+    assert_eq!(output, "fail\n");
+    assert_eq!(exit_status, 3);
+}
@@ -1,3 +1,20 @@
 pub const SCREEN_WIDTH_PX: u16 = 256;
 pub const SCREEN_HEIGHT_PX: u16 = 240;
 pub const SCREEN_TOTAL_PX: u16 = SCREEN_WIDTH_PX * SCREEN_HEIGHT_PX;
+
+// Registers of an emulator-only device in the unused range 0x4018..=0x401F, which lets test
+// programs report what they're doing. Each register is written to; reads return 0.
+pub mod debug_port {
+    pub const ADDRESS_RANGE: std::ops::RangeInclusive<u16> = 0x4018..=0x401F;
+
+    pub mod register {
+        // a character to print to the host's log
+        pub const OUTPUT: u16 = 0x4018;
+        // ends the run with the written value as its exit status
+        pub const EXIT: u16 = 0x4019;
+        // stops in the debugger, if there is one
+        pub const BREAK: u16 = 0x401A;
+        // saves a snapshot of the emulator's state, tagged with the written value
+        pub const SNAPSHOT: u16 = 0x401B;
+    }
+}
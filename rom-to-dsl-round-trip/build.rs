@@ -0,0 +1,54 @@
+use ines::Ines;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Roms in the test-assets directory, and the names of the modules generated from them
+const ROMS: &[(&str, &str)] = &[
+    ("conway", "conway.nes"),
+    ("butterfly", "butterfly.nes"),
+    ("all_instrs", "instr_test-v5/all_instrs.nes"),
+    ("official_only", "instr_test-v5/official_only.nes"),
+    ("basics", "instr_test-v5/rom_singles/01-basics.nes"),
+    ("implied", "instr_test-v5/rom_singles/02-implied.nes"),
+    ("immediate", "instr_test-v5/rom_singles/03-immediate.nes"),
+    ("zero_page", "instr_test-v5/rom_singles/04-zero_page.nes"),
+    ("zp_xy", "instr_test-v5/rom_singles/05-zp_xy.nes"),
+    ("absolute", "instr_test-v5/rom_singles/06-absolute.nes"),
+    ("abs_xy", "instr_test-v5/rom_singles/07-abs_xy.nes"),
+    ("ind_x", "instr_test-v5/rom_singles/08-ind_x.nes"),
+    ("ind_y", "instr_test-v5/rom_singles/09-ind_y.nes"),
+    ("branches", "instr_test-v5/rom_singles/10-branches.nes"),
+    ("stack", "instr_test-v5/rom_singles/11-stack.nes"),
+    ("jmp_jsr", "instr_test-v5/rom_singles/12-jmp_jsr.nes"),
+    ("rts", "instr_test-v5/rom_singles/13-rts.nes"),
+    ("rti", "instr_test-v5/rom_singles/14-rti.nes"),
+    ("brk", "instr_test-v5/rom_singles/15-brk.nes"),
+    ("special", "instr_test-v5/rom_singles/16-special.nes"),
+];
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let test_assets = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test-assets");
+    println!("cargo:rerun-if-changed=build.rs");
+    for (name, path) in ROMS {
+        let path = test_assets.join(path);
+        println!("cargo:rerun-if-changed={}", path.display());
+        let bytes = fs::read(&path).expect("Failed to read rom file");
+        let ines = Ines::parse(&bytes).expect("Failed to parse rom file");
+        let banked_analysis = rom_to_dsl::analyse(&ines, 0).expect("Failed to analyse rom");
+        let source = rom_to_dsl::generate(&ines, &banked_analysis, &Default::default());
+        fs::write(Path::new(&out_dir).join(format!("{}.rs", name)), source)
+            .expect("Failed to write generated code");
+    }
+    // a test for each rom, so the list of roms only lives here
+    let tests = ROMS
+        .iter()
+        .map(|(name, path)| format!("    {}: \"{}\",\n", name, path))
+        .collect::<String>();
+    fs::write(
+        Path::new(&out_dir).join("round_trip_tests.rs"),
+        format!("round_trip_tests! {{\n{}}}\n", tests),
+    )
+    .expect("Failed to write generated code");
+}
@@ -0,0 +1,36 @@
+use ines::Ines;
+use std::path::Path;
+
+fn assert_round_trip(ines: Ines, path: &str) {
+    let mut encoded = Vec::new();
+    ines.encode(&mut encoded);
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("../test-assets")
+        .join(path);
+    let original = std::fs::read(path).unwrap();
+    assert_eq!(encoded.len(), original.len());
+    if let Some(offset) = (0..original.len()).find(|&i| encoded[i] != original[i]) {
+        panic!(
+            "reassembled rom differs from the original at offset 0x{:X}: 0x{:02X} != 0x{:02X}",
+            offset, encoded[offset], original[offset]
+        );
+    }
+}
+
+// Each rom listed in the build script is disassembled into a module of the same name
+macro_rules! round_trip_tests {
+    ($($name:ident: $path:expr,)*) => {
+        $(
+            mod $name {
+                include!(concat!(env!("OUT_DIR"), "/", stringify!($name), ".rs"));
+            }
+
+            #[test]
+            fn $name() {
+                assert_round_trip($name::ines(), $path);
+            }
+        )*
+    };
+}
+
+include!(concat!(env!("OUT_DIR"), "/round_trip_tests.rs"));
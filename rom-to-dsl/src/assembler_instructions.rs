@@ -0,0 +1,224 @@
+use mos6502_model::AssemblerInstruction;
+use std::collections::HashMap;
+
+macro_rules! assembler_instructions {
+    ($($instruction:ident $(($addressing_mode:ident))?,)*) => {
+        [$((
+            <$instruction $(<$addressing_mode>)? as AssemblerInstruction>::opcode(),
+            concat!(stringify!($instruction) $(, "(", stringify!($addressing_mode), ")")?),
+        ),)*]
+    };
+}
+
+// Maps each opcode which can be emitted with `Block::inst` to the source code naming the
+// instruction, e.g. "Lda(Absolute)". Where several instructions share an opcode, the first is
+// used.
+pub fn by_opcode() -> HashMap<u8, &'static str> {
+    use mos6502_model::addressing_mode::*;
+    use mos6502_model::assembler_instruction::*;
+    let mut by_opcode = HashMap::new();
+    for (opcode, name) in assembler_instructions! {
+        Adc(Absolute),
+        Adc(AbsoluteXIndexed),
+        Adc(AbsoluteYIndexed),
+        Adc(Immediate),
+        Adc(IndirectYIndexed),
+        Adc(XIndexedIndirect),
+        Adc(ZeroPage),
+        Adc(ZeroPageXIndexed),
+        Ahx(IndirectYIndexed),
+        Ahx(AbsoluteYIndexed),
+        Alr,
+        Anc,
+        And(Absolute),
+        And(AbsoluteXIndexed),
+        And(AbsoluteYIndexed),
+        And(Immediate),
+        And(IndirectYIndexed),
+        And(XIndexedIndirect),
+        And(ZeroPage),
+        And(ZeroPageXIndexed),
+        Arr,
+        Asl(Absolute),
+        Asl(AbsoluteXIndexed),
+        Asl(Accumulator),
+        Asl(ZeroPage),
+        Asl(ZeroPageXIndexed),
+        Bcc,
+        Bcs,
+        Beq,
+        Bit(Absolute),
+        Bit(ZeroPage),
+        Bmi,
+        Bne,
+        Bpl,
+        Brk,
+        Bvc,
+        Bvs,
+        Clc,
+        Cld,
+        Cli,
+        Clv,
+        Cmp(Absolute),
+        Cmp(AbsoluteXIndexed),
+        Cmp(AbsoluteYIndexed),
+        Cmp(Immediate),
+        Cmp(IndirectYIndexed),
+        Cmp(XIndexedIndirect),
+        Cmp(ZeroPage),
+        Cmp(ZeroPageXIndexed),
+        Cpx(Absolute),
+        Cpx(Immediate),
+        Cpx(ZeroPage),
+        Cpy(Absolute),
+        Cpy(Immediate),
+        Cpy(ZeroPage),
+        Dcp(XIndexedIndirect),
+        Dcp(ZeroPage),
+        Dcp(IndirectYIndexed),
+        Dcp(ZeroPageXIndexed),
+        Dcp(AbsoluteXIndexed),
+        Dcp(AbsoluteYIndexed),
+        Dec(Absolute),
+        Dec(AbsoluteXIndexed),
+        Dec(ZeroPage),
+        Dec(ZeroPageXIndexed),
+        Dex,
+        Dey,
+        Eor(Absolute),
+        Eor(AbsoluteXIndexed),
+        Eor(AbsoluteYIndexed),
+        Eor(Immediate),
+        Eor(IndirectYIndexed),
+        Eor(XIndexedIndirect),
+        Eor(ZeroPage),
+        Eor(ZeroPageXIndexed),
+        Inc(Absolute),
+        Inc(AbsoluteXIndexed),
+        Inc(ZeroPage),
+        Inc(ZeroPageXIndexed),
+        Inx,
+        Iny,
+        Isc(XIndexedIndirect),
+        Isc(ZeroPage),
+        Isc(IndirectYIndexed),
+        Isc(ZeroPageXIndexed),
+        Isc(AbsoluteXIndexed),
+        Isc(AbsoluteYIndexed),
+        Jmp(Absolute),
+        Jmp(Indirect),
+        Jsr(Absolute),
+        Lda(Absolute),
+        Lda(AbsoluteXIndexed),
+        Lda(AbsoluteYIndexed),
+        Lda(Immediate),
+        Lda(IndirectYIndexed),
+        Lda(XIndexedIndirect),
+        Lda(ZeroPage),
+        Lda(ZeroPageXIndexed),
+        Ldx(Absolute),
+        Ldx(AbsoluteYIndexed),
+        Ldx(Immediate),
+        Ldx(ZeroPage),
+        Ldx(ZeroPageYIndexed),
+        Ldy(Absolute),
+        Ldy(AbsoluteXIndexed),
+        Ldy(Immediate),
+        Ldy(ZeroPage),
+        Ldy(ZeroPageXIndexed),
+        Lsr(Absolute),
+        Lsr(AbsoluteXIndexed),
+        Lsr(Accumulator),
+        Lsr(ZeroPage),
+        Lsr(ZeroPageXIndexed),
+        Nop,
+        Ora(Absolute),
+        Ora(AbsoluteXIndexed),
+        Ora(AbsoluteYIndexed),
+        Ora(Immediate),
+        Ora(IndirectYIndexed),
+        Ora(XIndexedIndirect),
+        Ora(ZeroPage),
+        Ora(ZeroPageXIndexed),
+        Pha,
+        Php,
+        Pla,
+        Plp,
+        Rla(XIndexedIndirect),
+        Rla(ZeroPage),
+        Rla(IndirectYIndexed),
+        Rla(ZeroPageXIndexed),
+        Rla(AbsoluteXIndexed),
+        Rla(AbsoluteYIndexed),
+        Rol(Absolute),
+        Rol(AbsoluteXIndexed),
+        Rol(Accumulator),
+        Rol(ZeroPage),
+        Rol(ZeroPageXIndexed),
+        Ror(Absolute),
+        Ror(AbsoluteXIndexed),
+        Ror(Accumulator),
+        Ror(ZeroPage),
+        Ror(ZeroPageXIndexed),
+        Rra(XIndexedIndirect),
+        Rra(ZeroPage),
+        Rra(IndirectYIndexed),
+        Rra(ZeroPageXIndexed),
+        Rra(AbsoluteXIndexed),
+        Rra(AbsoluteYIndexed),
+        Rti,
+        Rts,
+        Sax(XIndexedIndirect),
+        Sax(ZeroPage),
+        Sax(Absolute),
+        Sax(ZeroPageYIndexed),
+        Sbc(Absolute),
+        Sbc(AbsoluteXIndexed),
+        Sbc(AbsoluteYIndexed),
+        Sbc(Immediate),
+        Sbc(IndirectYIndexed),
+        Sbc(XIndexedIndirect),
+        Sbc(ZeroPage),
+        Sbc(ZeroPageXIndexed),
+        Sec,
+        Sed,
+        Sei,
+        Skb,
+        Slo(XIndexedIndirect),
+        Slo(ZeroPage),
+        Slo(IndirectYIndexed),
+        Slo(ZeroPageXIndexed),
+        Slo(AbsoluteXIndexed),
+        Slo(AbsoluteYIndexed),
+        Sre(XIndexedIndirect),
+        Sre(ZeroPage),
+        Sre(IndirectYIndexed),
+        Sre(ZeroPageXIndexed),
+        Sre(AbsoluteXIndexed),
+        Sre(AbsoluteYIndexed),
+        Sta(Absolute),
+        Sta(AbsoluteXIndexed),
+        Sta(AbsoluteYIndexed),
+        Sta(IndirectYIndexed),
+        Sta(XIndexedIndirect),
+        Sta(ZeroPage),
+        Sta(ZeroPageXIndexed),
+        Stx(Absolute),
+        Stx(ZeroPage),
+        Stx(ZeroPageYIndexed),
+        Sty(Absolute),
+        Sty(ZeroPage),
+        Sty(ZeroPageXIndexed),
+        Sxa,
+        Sya,
+        Tax,
+        Tay,
+        Tsx,
+        Txa,
+        Txs,
+        Tya,
+    } {
+        by_opcode.entry(opcode).or_insert(name);
+    }
+    by_opcode
+}
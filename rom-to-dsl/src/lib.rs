@@ -0,0 +1,718 @@
+use analyser::{Bank, BankedAnalysis, Disassembly, Dispatch, Observations};
+use ines::{Ines, Mapper, Mirroring};
+use mos6502_model::debug::{AddressingMode, InstructionWithOperand};
+use mos6502_model::{interrupt_vector, Address};
+use nes_emulator_core::cdl::CodeDataLogger;
+use nes_emulator_core::dynamic_nes::{self, DynamicNes};
+use nes_render_output::NoRenderOutput;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+
+mod assembler_instructions;
+
+const BYTES_PER_LINE: usize = 16;
+// Runs of at least this many copies of the same byte are emitted as a single repeat expression
+const MIN_REPEATED_BYTES: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    // Name of the rom, mentioned in a comment at the top of the generated code
+    pub rom_name: Option<String>,
+    // Generate a main function which writes the rom to stdout
+    pub main: bool,
+}
+
+// Analyses every bank of a rom's PRG ROM, as required by `generate`. If `num_frames` is
+// non-zero, the rom is first run for that many frames, and the code it executes is analysed even
+// if it can't be found statically (e.g. because it's only reached through a jump to an address
+// computed at runtime).
+pub fn analyse(ines: &Ines, num_frames: u64) -> Result<BankedAnalysis, dynamic_nes::Error> {
+    let mut observations = Observations::default();
+    if num_frames > 0 {
+        let mut dynamic_nes = DynamicNes::from_ines(ines)?;
+        let mut code_data_logger = CodeDataLogger::from_ines(ines);
+        for _ in 0..num_frames {
+            dynamic_nes.run_for_frame_general(&mut code_data_logger, &mut NoRenderOutput);
+        }
+        code_data_logger.add_to_observations(&mut observations);
+    }
+    // a fresh mapper is in its power-on bank mode, which `segments` assumes
+    let dynamic_nes = DynamicNes::from_ines(ines)?;
+    Ok(dynamic_nes.analyse_banked(&observations))
+}
+
+// A window of PRG ROM which is assembled as a single Block
+struct Segment {
+    prg_rom_offset: usize,
+    size: usize,
+    base: Address,
+    // The banks which can be selected while this segment is mapped at `base`
+    selected_banks: Vec<Bank>,
+}
+
+impl Segment {
+    fn end(&self) -> u32 {
+        self.base as u32 + self.size as u32
+    }
+    fn contains(&self, address: Address) -> bool {
+        address >= self.base && (address as u32) < self.end()
+    }
+}
+
+fn bank_function_name(index: usize) -> String {
+    format!("prg_rom_bank_{}", index)
+}
+
+fn segments(ines: &Ines) -> Vec<Segment> {
+    let num_banks = ines.prg_rom.len() / ines::PRG_ROM_BLOCK_BYTES;
+    match ines.header.mapper {
+        Mapper::Mmc1 if num_banks > 2 => {
+            // the last bank is fixed at 0xC000, and the others are switched in at 0x8000
+            let last = num_banks - 1;
+            (0..num_banks)
+                .map(|bank| Segment {
+                    prg_rom_offset: bank * ines::PRG_ROM_BLOCK_BYTES,
+                    size: ines::PRG_ROM_BLOCK_BYTES,
+                    base: if bank == last { 0xC000 } else { 0x8000 },
+                    selected_banks: if bank == last {
+                        (0..last).collect()
+                    } else {
+                        vec![bank]
+                    },
+                })
+                .collect()
+        }
+        // the entire rom is mapped at the end of the address space (mirrored if it's only 16K)
+        _ => vec![Segment {
+            prg_rom_offset: 0,
+            size: ines.prg_rom.len(),
+            base: (0x10000 - ines.prg_rom.len()) as Address,
+            selected_banks: vec![0],
+        }],
+    }
+}
+
+enum Item {
+    // An instruction which can be emitted with `Block::inst`
+    Instruction {
+        instruction_with_operand: InstructionWithOperand,
+        name: &'static str,
+    },
+    // An instruction which the assembler has no way to emit, so it's emitted as bytes
+    RawInstruction(InstructionWithOperand),
+    // Little endian address of a label
+    Word(Address),
+    // Low byte of the address of a label
+    Lo(Address),
+    // High byte of the address of a label
+    Hi(Address),
+}
+
+impl Item {
+    fn size(&self) -> Address {
+        match self {
+            Self::Instruction {
+                instruction_with_operand,
+                ..
+            }
+            | Self::RawInstruction(instruction_with_operand) => {
+                instruction_with_operand.instruction().size() as Address
+            }
+            Self::Word(_) => 2,
+            Self::Lo(_) | Self::Hi(_) => 1,
+        }
+    }
+    fn target(&self) -> Option<Address> {
+        match self {
+            &Self::Word(target) | &Self::Lo(target) | &Self::Hi(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+// The operand of an instruction which can refer to a label
+fn operand_target(instruction_with_operand: &InstructionWithOperand) -> Option<Address> {
+    match instruction_with_operand.instruction().addressing_mode() {
+        AddressingMode::Absolute
+        | AddressingMode::AbsoluteXIndexed
+        | AddressingMode::AbsoluteYIndexed
+        | AddressingMode::Indirect => instruction_with_operand.operand_u16_le(),
+        AddressingMode::Relative => {
+            let next = instruction_with_operand
+                .address()
+                .wrapping_add(instruction_with_operand.instruction().size() as Address);
+            let offset = instruction_with_operand.operand_u8()? as i8;
+            Some(next.wrapping_add(offset as i16 as Address))
+        }
+        _ => None,
+    }
+}
+
+// Which imports the generated code needs
+#[derive(Default)]
+struct Imports {
+    addr: bool,
+    label_relative_offset: bool,
+}
+
+struct SegmentGenerator<'a> {
+    segment: &'a Segment,
+    bytes: &'a [u8],
+    disassemblies: Vec<&'a Disassembly>,
+    // Everything other than plain bytes, keyed by its first address
+    items: BTreeMap<Address, Item>,
+    labels: BTreeMap<Address, String>,
+}
+
+impl<'a> SegmentGenerator<'a> {
+    fn new(
+        ines: &'a Ines,
+        banked_analysis: &'a BankedAnalysis,
+        segment: &'a Segment,
+        assembler_instructions: &HashMap<u8, &'static str>,
+    ) -> Self {
+        let disassemblies = segment
+            .selected_banks
+            .iter()
+            .filter_map(|&bank| banked_analysis.analysis_with_bank_selected(bank))
+            .map(|analysis| analysis.disassembly())
+            .collect();
+        let mut generator = Self {
+            segment,
+            bytes: &ines.prg_rom[segment.prg_rom_offset..(segment.prg_rom_offset + segment.size)],
+            disassemblies,
+            items: BTreeMap::new(),
+            labels: BTreeMap::new(),
+        };
+        generator.add_instructions(assembler_instructions);
+        generator.add_tables();
+        generator.remove_unlabellable_items();
+        generator.add_labels();
+        generator
+    }
+
+    fn read_u8(&self, address: Address) -> u8 {
+        self.bytes[(address - self.segment.base) as usize]
+    }
+
+    // Returns true if the given range is in the segment and not yet part of any item
+    fn is_free(&self, address: Address, size: Address) -> bool {
+        let end = address as u32 + size as u32;
+        if !self.segment.contains(address) || end > self.segment.end() {
+            return false;
+        }
+        let overlaps_previous = self
+            .items
+            .range(..=address)
+            .next_back()
+            .map(|(&start, item)| start as u32 + item.size() as u32 > address as u32)
+            .unwrap_or(false);
+        let overlaps_next = self
+            .items
+            .range(address..)
+            .next()
+            .map(|(&start, _)| (start as u32) < end)
+            .unwrap_or(false);
+        !overlaps_previous && !overlaps_next
+    }
+
+    // Returns true if a label can be placed at `address`, i.e. it's not in the middle of an item
+    fn is_boundary(&self, address: Address) -> bool {
+        self.segment.contains(address)
+            && !self
+                .items
+                .range(..address)
+                .next_back()
+                .map(|(&start, item)| start as u32 + item.size() as u32 > address as u32)
+                .unwrap_or(false)
+    }
+
+    fn add_instructions(&mut self, assembler_instructions: &HashMap<u8, &'static str>) {
+        let mut instructions = BTreeMap::new();
+        for disassembly in self.disassemblies.iter() {
+            for instruction_with_operand in disassembly.instructions() {
+                instructions
+                    .entry(instruction_with_operand.address())
+                    .or_insert_with(|| instruction_with_operand.clone());
+            }
+        }
+        // where disassemblies disagree, the instruction at the lower address wins
+        for (address, instruction_with_operand) in instructions {
+            let size = instruction_with_operand.instruction().size() as Address;
+            if !self.is_free(address, size) {
+                continue;
+            }
+            let opcode = self.read_u8(address);
+            let item = match assembler_instructions.get(&opcode) {
+                Some(&name) => Item::Instruction {
+                    instruction_with_operand,
+                    name,
+                },
+                None => Item::RawInstruction(instruction_with_operand),
+            };
+            self.items.insert(address, item);
+        }
+    }
+
+    fn add_tables(&mut self) {
+        let mut tables = Vec::new();
+        for disassembly in self.disassemblies.iter() {
+            for jump_table in disassembly.jump_tables() {
+                // tables used by RTS hold addresses one less than their targets, which can't be
+                // expressed with labels
+                if jump_table.dispatch == Dispatch::ReturnAddress {
+                    continue;
+                }
+                for (i, &target) in jump_table.targets.iter().enumerate() {
+                    let offset = (i as Address).wrapping_mul(jump_table.stride);
+                    let lo = jump_table.lo.wrapping_add(offset);
+                    let hi = jump_table.hi.wrapping_add(offset);
+                    if jump_table.stride == 2 {
+                        tables.push((lo, Item::Word(target)));
+                    } else {
+                        tables.push((lo, Item::Lo(target)));
+                        tables.push((hi, Item::Hi(target)));
+                    }
+                }
+            }
+        }
+        for vector in [
+            interrupt_vector::NMI_LO,
+            interrupt_vector::START_LO,
+            interrupt_vector::IRQ_LO,
+        ] {
+            if self.is_free(vector, 2) {
+                let target = u16::from_le_bytes([self.read_u8(vector), self.read_u8(vector + 1)]);
+                tables.push((vector, Item::Word(target)));
+            }
+        }
+        for (address, item) in tables {
+            // Block can't assemble the address of a label into the last byte of a block
+            let fits = match item {
+                Item::Lo(_) | Item::Hi(_) => (address as u32) + 1 < self.segment.end(),
+                _ => true,
+            };
+            if fits && self.is_free(address, item.size()) {
+                self.items.insert(address, item);
+            }
+        }
+    }
+
+    // Table entries pointing somewhere a label can't go are emitted as bytes instead. Removing
+    // items never stops an address from being a boundary, so one pass is enough.
+    fn remove_unlabellable_items(&mut self) {
+        let unlabellable = self
+            .items
+            .iter()
+            .filter_map(|(&address, item)| {
+                let target = item.target()?;
+                if self.is_boundary(target) {
+                    None
+                } else {
+                    Some(address)
+                }
+            })
+            .collect::<Vec<_>>();
+        for address in unlabellable {
+            self.items.remove(&address);
+        }
+    }
+
+    fn label_name(&self, address: Address) -> String {
+        if let Some(label) = self
+            .disassemblies
+            .iter()
+            .find_map(|disassembly| disassembly.label(address))
+        {
+            return label;
+        }
+        if let Some(Item::Instruction { .. } | Item::RawInstruction(_)) = self.items.get(&address) {
+            format!("loc_{:04X}", address)
+        } else {
+            format!("dat_{:04X}", address)
+        }
+    }
+
+    fn add_labels(&mut self) {
+        let targets = self
+            .items
+            .values()
+            .filter_map(|item| match item {
+                Item::Instruction {
+                    instruction_with_operand,
+                    ..
+                } => operand_target(instruction_with_operand),
+                other => other.target(),
+            })
+            .filter(|&target| self.is_boundary(target))
+            .collect::<BTreeSet<_>>();
+        for target in targets {
+            let name = self.label_name(target);
+            self.labels.insert(target, name);
+        }
+    }
+
+    fn is_function(&self, address: Address) -> bool {
+        self.disassemblies.iter().any(|disassembly| {
+            disassembly.call_sites(address).next().is_some()
+                || disassembly
+                    .entry_points()
+                    .any(|(entry_point, _)| entry_point == address)
+                || disassembly
+                    .indirectly_jumped_addresses()
+                    .any(|target| target == address)
+        })
+    }
+
+    fn label_arg(&self, target: Address) -> Option<&str> {
+        self.labels.get(&target).map(|label| label.as_str())
+    }
+
+    fn instruction_arg(
+        &self,
+        instruction_with_operand: &InstructionWithOperand,
+        imports: &mut Imports,
+    ) -> String {
+        use AddressingMode::*;
+        let target = operand_target(instruction_with_operand);
+        let label = target.and_then(|target| self.label_arg(target));
+        match instruction_with_operand.instruction().addressing_mode() {
+            Implied | Accumulator => "()".to_string(),
+            Relative => match label {
+                Some(label) => {
+                    imports.label_relative_offset = true;
+                    format!("LabelRelativeOffset({:?})", label)
+                }
+                None => format!("0x{:02X}", instruction_with_operand.operand_u8().unwrap()),
+            },
+            Absolute | AbsoluteXIndexed | AbsoluteYIndexed | Indirect => match label {
+                Some(label) => format!("{:?}", label),
+                None => {
+                    imports.addr = true;
+                    format!("Addr(0x{:04X})", target.unwrap())
+                }
+            },
+            Immediate | ZeroPage | ZeroPageXIndexed | ZeroPageYIndexed | IndirectYIndexed
+            | XIndexedIndirect => {
+                format!("0x{:02X}", instruction_with_operand.operand_u8().unwrap())
+            }
+        }
+    }
+
+    // Writes the function which adds this segment to a Block
+    fn write_function(&self, index: usize, s: &mut String, imports: &mut Imports) {
+        let mut body = String::new();
+        let mut uses_instructions = false;
+        let mut uses_addressing_modes = false;
+        let end = self.segment.end();
+        let mut address = self.segment.base as u32;
+        while address < end {
+            let current = address as Address;
+            if let Some(label) = self.labels.get(&current) {
+                if self.is_function(current) && !body.is_empty() {
+                    writeln!(&mut body).unwrap();
+                }
+                writeln!(&mut body, "    b.label({:?});", label).unwrap();
+            }
+            match self.items.get(&current) {
+                Some(Item::Instruction {
+                    instruction_with_operand,
+                    name,
+                }) => {
+                    uses_instructions = true;
+                    uses_addressing_modes |= name.contains('(');
+                    let arg = self.instruction_arg(instruction_with_operand, imports);
+                    writeln!(&mut body, "    b.inst({}, {});", name, arg).unwrap();
+                }
+                Some(Item::RawInstruction(instruction_with_operand)) => {
+                    let instruction = instruction_with_operand.instruction();
+                    let bytes = (0..instruction.size() as Address)
+                        .map(|i| self.read_u8(current + i))
+                        .collect::<Vec<_>>();
+                    writeln!(
+                        &mut body,
+                        "    b.literal_bytes({}); // {:?}({:?})",
+                        byte_array(&bytes),
+                        instruction.instruction_type(),
+                        instruction.addressing_mode(),
+                    )
+                    .unwrap();
+                }
+                Some(item @ (Item::Word(_) | Item::Lo(_) | Item::Hi(_))) => {
+                    let run = self.table_run(current, item);
+                    let labels = run
+                        .iter()
+                        .map(|&target| format!("{:?}", self.labels[&target]))
+                        .collect::<Vec<_>>();
+                    let (single, table) = match item {
+                        Item::Word(_) => ("label_offset_le", "word_table"),
+                        Item::Lo(_) => ("label_offset_lo", "label_table_lo"),
+                        _ => ("label_offset_hi", "label_table_hi"),
+                    };
+                    if let [label] = labels.as_slice() {
+                        writeln!(&mut body, "    b.{}({});", single, label).unwrap();
+                    } else {
+                        writeln!(&mut body, "    b.{}([{}]);", table, labels.join(", ")).unwrap();
+                    }
+                    address += run.len() as u32 * item.size() as u32;
+                    continue;
+                }
+                None => {
+                    let bytes = self.byte_run(current);
+                    write_literal_bytes(&mut body, &bytes);
+                    address += bytes.len() as u32;
+                    continue;
+                }
+            }
+            address += self.items[&current].size() as u32;
+        }
+        writeln!(s, "fn {}(b: &mut Block) {{", bank_function_name(index)).unwrap();
+        if uses_addressing_modes {
+            writeln!(s, "    use mos6502_model::addressing_mode::*;").unwrap();
+        }
+        if uses_instructions {
+            writeln!(s, "    use mos6502_model::assembler_instruction::*;").unwrap();
+            writeln!(s).unwrap();
+        }
+        s.push_str(&body);
+        writeln!(s, "}}").unwrap();
+    }
+
+    // Returns the targets of consecutive table entries of the same kind as `first`, which is at
+    // `address`, stopping at the next label
+    fn table_run(&self, address: Address, first: &Item) -> Vec<Address> {
+        let mut run = Vec::new();
+        let mut current = address as u32;
+        while current < self.segment.end() {
+            if current != address as u32 && self.labels.contains_key(&(current as Address)) {
+                break;
+            }
+            match (first, self.items.get(&(current as Address))) {
+                (Item::Word(_), Some(&Item::Word(target)))
+                | (Item::Lo(_), Some(&Item::Lo(target)))
+                | (Item::Hi(_), Some(&Item::Hi(target))) => run.push(target),
+                _ => break,
+            }
+            current += first.size() as u32;
+        }
+        run
+    }
+
+    // Returns the bytes from `address` up to the next item or label
+    fn byte_run(&self, address: Address) -> Vec<u8> {
+        let mut end = self
+            .items
+            .range(address..)
+            .next()
+            .map(|(&start, _)| start as u32)
+            .unwrap_or(self.segment.end());
+        if let Some((&label, _)) = self.labels.range(address.saturating_add(1)..).next() {
+            if label > address {
+                end = end.min(label as u32);
+            }
+        }
+        (address as u32..end)
+            .map(|address| self.read_u8(address as Address))
+            .collect()
+    }
+}
+
+fn byte_array(bytes: &[u8]) -> String {
+    let bytes = bytes
+        .iter()
+        .map(|byte| format!("0x{:02X}", byte))
+        .collect::<Vec<_>>();
+    format!("[{}]", bytes.join(", "))
+}
+
+// Writes calls to `literal_bytes`, with runs of the same byte written as repeat expressions
+fn write_literal_bytes(s: &mut String, bytes: &[u8]) {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let repeats = bytes[i..]
+            .iter()
+            .take_while(|&&byte| byte == bytes[i])
+            .count();
+        if repeats >= MIN_REPEATED_BYTES {
+            for line in pending.chunks(BYTES_PER_LINE) {
+                writeln!(s, "    b.literal_bytes({});", byte_array(line)).unwrap();
+            }
+            pending.clear();
+            writeln!(s, "    b.literal_bytes([0x{:02X}; {}]);", bytes[i], repeats).unwrap();
+            i += repeats;
+        } else {
+            pending.extend_from_slice(&bytes[i..(i + repeats)]);
+            i += repeats;
+        }
+    }
+    for line in pending.chunks(BYTES_PER_LINE) {
+        writeln!(s, "    b.literal_bytes({});", byte_array(line)).unwrap();
+    }
+}
+
+fn mapper_name(mapper: Mapper) -> &'static str {
+    match mapper {
+        Mapper::Nrom => "Nrom",
+        Mapper::Mmc1 => "Mmc1",
+    }
+}
+
+fn mirroring_name(mirroring: Mirroring) -> &'static str {
+    match mirroring {
+        Mirroring::Horizontal => "Horizontal",
+        Mirroring::Vertical => "Vertical",
+    }
+}
+
+// Generates rust source code which uses mos6502_assembler to reassemble the given rom. Code found
+// by the analysis is emitted as instructions, with labels for the targets of branches, jumps,
+// jump tables and absolute operands which point into the same bank. Everything else is emitted
+// as literal bytes. The generated code defines `prg_rom`, `chr_rom` and `ines` functions, which
+// produce a rom byte-identical to the original.
+pub fn generate(ines: &Ines, banked_analysis: &BankedAnalysis, options: &Options) -> String {
+    let assembler_instructions = assembler_instructions::by_opcode();
+    let segments = segments(ines);
+    let mut imports = Imports::default();
+    let mut functions = String::new();
+    for (index, segment) in segments.iter().enumerate() {
+        let generator =
+            SegmentGenerator::new(ines, banked_analysis, segment, &assembler_instructions);
+        writeln!(&mut functions).unwrap();
+        generator.write_function(index, &mut functions, &mut imports);
+    }
+    if !ines.chr_rom.is_empty() {
+        writeln!(&mut functions).unwrap();
+        writeln!(&mut functions, "fn chr_rom_data(b: &mut Block) {{").unwrap();
+        write_literal_bytes(&mut functions, &ines.chr_rom);
+        writeln!(&mut functions, "}}").unwrap();
+    }
+    let mut s = String::new();
+    if let Some(rom_name) = options.rom_name.as_ref() {
+        writeln!(&mut s, "// Generated by rom-to-dsl from {}", rom_name).unwrap();
+        writeln!(&mut s).unwrap();
+    }
+    writeln!(&mut s, "use ines::Ines;").unwrap();
+    let mut assembler_imports = Vec::new();
+    if imports.addr {
+        assembler_imports.push("Addr");
+    }
+    assembler_imports.push("Block");
+    if imports.label_relative_offset {
+        assembler_imports.push("LabelRelativeOffset");
+    }
+    if let [single] = assembler_imports.as_slice() {
+        writeln!(&mut s, "use mos6502_assembler::{};", single).unwrap();
+    } else {
+        writeln!(
+            &mut s,
+            "use mos6502_assembler::{{{}}};",
+            assembler_imports.join(", ")
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "use mos6502_model::Address;").unwrap();
+    s.push_str(&functions);
+    writeln!(&mut s).unwrap();
+    s.push_str(
+        r#"
+// A bank of PRG ROM, with the address it's assembled at and its size in bytes
+struct PrgRomBank {
+    program: fn(&mut Block),
+    base: Address,
+    size: usize,
+}
+
+const PRG_ROM_BANKS: &[PrgRomBank] = &[
+"#,
+    );
+    for (index, segment) in segments.iter().enumerate() {
+        writeln!(
+            &mut s,
+            "    PrgRomBank {{ program: {}, base: 0x{:04X}, size: 0x{:X} }},",
+            bank_function_name(index),
+            segment.base,
+            segment.size
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "];").unwrap();
+    s.push_str(
+        r#"
+pub fn prg_rom() -> Vec<u8> {
+    let mut prg_rom = Vec::new();
+    for prg_rom_bank in PRG_ROM_BANKS {
+        let mut block = Block::new();
+        (prg_rom_bank.program)(&mut block);
+        let mut bank = Vec::new();
+        block
+            .assemble(prg_rom_bank.base, prg_rom_bank.size, &mut bank)
+            .expect("Failed to assemble");
+        prg_rom.extend(bank);
+    }
+    prg_rom
+}
+"#,
+    );
+    writeln!(&mut s).unwrap();
+    writeln!(&mut s, "pub fn chr_rom() -> Vec<u8> {{").unwrap();
+    if ines.chr_rom.is_empty() {
+        writeln!(&mut s, "    Vec::new()").unwrap();
+    } else {
+        write!(
+            &mut s,
+            r#"    let mut block = Block::new();
+    chr_rom_data(&mut block);
+    let mut chr_rom = Vec::new();
+    block
+        .assemble(0, 0x{:X}, &mut chr_rom)
+        .expect("Failed to assemble");
+    chr_rom
+"#,
+            ines.chr_rom.len()
+        )
+        .unwrap();
+    }
+    writeln!(&mut s, "}}").unwrap();
+    let header = &ines.header;
+    write!(
+        &mut s,
+        r#"
+pub fn ines() -> Ines {{
+    Ines {{
+        header: ines::Header {{
+            num_prg_rom_blocks: {},
+            num_chr_rom_blocks: {},
+            mapper: ines::Mapper::{},
+            mirroring: ines::Mirroring::{},
+            four_screen_vram: {},
+        }},
+        prg_rom: prg_rom(),
+        chr_rom: chr_rom(),
+    }}
+}}
+"#,
+        header.num_prg_rom_blocks,
+        header.num_chr_rom_blocks,
+        mapper_name(header.mapper),
+        mirroring_name(header.mirroring),
+        header.four_screen_vram,
+    )
+    .unwrap();
+    if options.main {
+        s.push_str(
+            r#"
+fn main() {
+    use std::io::Write;
+    let mut encoded = Vec::new();
+    ines().encode(&mut encoded);
+    std::io::stdout()
+        .lock()
+        .write_all(&encoded)
+        .expect("Failed to write encoded rom to stdout");
+}
+"#,
+        );
+    }
+    s
+}
@@ -0,0 +1,66 @@
+use ines::Ines;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+struct Args {
+    rom_path: String,
+    output_path: Option<String>,
+    num_frames: u64,
+    main: bool,
+}
+
+impl Args {
+    fn parser() -> impl meap::Parser<Item = Self> {
+        meap::let_map! {
+            let {
+                rom_path = opt_req::<String, _>("PATH", 'r').name("rom-path").desc("path to rom file (ines format)");
+                output_path = opt_opt::<String, _>("PATH", 'o').name("output-path").desc("path to rust file to create (defaults to stdout)");
+                num_frames = opt_opt::<u64, _>("INT", 'f').name("run-frames").desc("run the rom for this many frames to find code which can't be found statically").with_default(0);
+                main = flag('m').name("main").desc("generate a main function which writes the rom to stdout");
+            } in {
+                Self {
+                    rom_path,
+                    output_path,
+                    num_frames,
+                    main,
+                }
+            }
+        }
+    }
+}
+
+fn main() {
+    use meap::Parser;
+    let Args {
+        rom_path,
+        output_path,
+        num_frames,
+        main,
+    } = Args::parser().with_help_default().parse_env_or_exit();
+    let mut buffer = Vec::new();
+    let mut rom_file = File::open(&rom_path).expect("Failed to open rom file");
+    rom_file
+        .read_to_end(&mut buffer)
+        .expect("Failed to read rom file");
+    let ines = Ines::parse(&buffer).expect("Failed to parse rom file");
+    let banked_analysis = rom_to_dsl::analyse(&ines, num_frames).expect("Failed to analyse rom");
+    let options = rom_to_dsl::Options {
+        rom_name: Path::new(&rom_path)
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string()),
+        main,
+    };
+    let source = rom_to_dsl::generate(&ines, &banked_analysis, &options);
+    match output_path {
+        Some(output_path) => {
+            let mut file = File::create(output_path).expect("Failed to create output file");
+            file.write_all(source.as_bytes())
+                .expect("Failed to write output file");
+        }
+        None => io::stdout()
+            .lock()
+            .write_all(source.as_bytes())
+            .expect("Failed to write to stdout"),
+    }
+}
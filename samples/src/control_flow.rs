@@ -0,0 +1,69 @@
+/// Exercises the structured control flow helpers on `Block`. Stores 0xEE at even addresses and
+/// 0x0D at odd addresses in the range 0..8, stores 0x42 at 0x10 from a subroutine, and
+/// increments 0x20 70 times in each of 3 iterations of a loop which is too long for a relative
+/// branch.
+///
+/// Instructions:
+/// Ldx
+/// Ldy
+/// Txa
+/// And
+/// Lda
+/// Sta
+/// Inc
+/// Inx
+/// Dey
+/// Cpx
+/// Beq
+/// Bne
+/// Jmp
+/// Jsr
+/// Rts
+use crate::prelude::*;
+
+pub struct ControlFlow;
+impl Sample for ControlFlow {
+    fn program(b: &mut Block) {
+        b.for_x(0, 8, |b| {
+            b.inst(Txa, ());
+            b.inst(And(Immediate), 1);
+            b.if_(Beq, |b| {
+                b.inst(Lda(Immediate), 0xEE);
+                b.inst(Sta(ZeroPageXIndexed), 0);
+            })
+            .else_(|b| {
+                b.inst(Lda(Immediate), 0x0D);
+                b.inst(Sta(ZeroPageXIndexed), 0);
+            });
+        });
+
+        b.call("store-0x42");
+
+        b.inst(Ldy(Immediate), 3);
+        b.loop_while(Bne, |b| {
+            for _ in 0..70 {
+                b.inst(Inc(ZeroPage), 0x20);
+            }
+            b.inst(Dey, ());
+        });
+
+        b.label("loop");
+        b.inst(Jmp(Absolute), "loop");
+
+        b.label("store-0x42");
+        b.inst(Lda(Immediate), 0x42);
+        b.inst(Sta(ZeroPage), 0x10);
+        b.ret();
+    }
+    fn num_steps() -> usize {
+        1000
+    }
+    fn check_result<M: MemoryReadOnly>(_cpu: &Cpu, m: &M) {
+        for i in 0..8 {
+            let expected = if i % 2 == 0 { 0xEE } else { 0x0D };
+            assert_eq!(m.read_u8_read_only(i), expected);
+        }
+        assert_eq!(m.read_u8_read_only(0x10), 0x42);
+        assert_eq!(m.read_u8_read_only(0x20), 210);
+    }
+}
@@ -1,12 +1,14 @@
 use mos6502_assembler::Block;
 pub use mos6502_model::machine::{Address, Cpu, MemoryReadOnly};
 
+pub mod subroutine_test;
 #[cfg(test)]
 pub mod test;
 #[cfg(test)]
 pub mod test_framework;
 
 mod arithmetic;
+mod control_flow;
 mod counter;
 mod factorial;
 mod infinite_loop;
@@ -20,6 +22,7 @@ mod stack_status_register;
 mod store_accumulator;
 mod wide_factorial;
 pub use arithmetic::*;
+pub use control_flow::*;
 pub use counter::*;
 pub use factorial::*;
 pub use infinite_loop::*;
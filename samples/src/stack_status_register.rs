@@ -42,7 +42,7 @@ impl Sample for StackStatusRegister {
     fn check_result<M: MemoryReadOnly>(_cpu: &Cpu, m: &M) {
         use status::flag::*;
         let mask = EXPANSION | BRK;
-        assert_eq!(m.read_u8_read_only(0x01FF), 0x00 | mask);
+        assert_eq!(m.read_u8_read_only(0x01FF), mask);
         assert_eq!(m.read_u8_read_only(0x01FE), 0x04 | mask);
         assert_eq!(m.read_u8_read_only(0x01FD), 0x05 | mask);
         assert_eq!(m.read_u8_read_only(0x01FC), 0x0D | mask);
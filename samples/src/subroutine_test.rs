@@ -0,0 +1,136 @@
+use mos6502_assembler::{Block, SymbolFile};
+use mos6502_model::flat_memory::FlatMemory;
+use mos6502_model::machine::{Cpu, MemoryReadOnly};
+use mos6502_model::{address, opcode, Address};
+use std::fmt;
+
+// Where a routine under test returns to. It's in the interrupt vector table, so it isn't the
+// address of any code.
+pub const RETURN_ADDRESS: Address = 0xFFFF;
+pub const DEFAULT_MAX_CYCLES: u64 = 1_000_000;
+const INITIAL_STACK_POINTER: u8 = 0xFD;
+
+#[derive(Debug, Clone)]
+pub enum CallError {
+    UndeclaredLabel(String),
+    UnknownOpcode { pc: Address, opcode: u8 },
+    // the routine reached a BRK, which would jump to the IRQ handler rather than return
+    Brk { pc: Address },
+    // the routine didn't return within the cycle limit
+    CycleLimitExceeded { pc: Address, max_cycles: u64 },
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UndeclaredLabel(label) => write!(f, "no label named \"{}\"", label),
+            Self::UnknownOpcode { pc, opcode } => {
+                write!(f, "unknown opcode {:02X} at ${:04X}", opcode, pc)
+            }
+            Self::Brk { pc } => write!(f, "BRK at ${:04X}", pc),
+            Self::CycleLimitExceeded { pc, max_cycles } => write!(
+                f,
+                "routine didn't return within {} cycles (pc is ${:04X})",
+                max_cycles, pc
+            ),
+        }
+    }
+}
+
+// Runs individual labelled routines of a program on a bare CPU with 64KB of RAM, for unit
+// testing. Set up registers and memory with `cpu_mut` and `memory_mut`, call a routine, then
+// check the registers, flags and memory it leaves behind. Each test should start from a fresh
+// (or cloned) `SubroutineTest`, as memory isn't reset between calls.
+#[derive(Clone)]
+pub struct SubroutineTest {
+    cpu: Cpu,
+    memory: FlatMemory,
+    symbol_file: SymbolFile,
+    max_cycles: u64,
+}
+
+impl SubroutineTest {
+    // Assembles `block` into memory starting at `base`
+    pub fn new(
+        block: &Block,
+        base: Address,
+        size: usize,
+    ) -> Result<Self, mos6502_assembler::Error> {
+        let mut program = Vec::new();
+        let assembled_block = block.assemble(base, size, &mut program)?;
+        let mut memory = FlatMemory::new();
+        memory.load(base, &program);
+        let mut cpu = Cpu::new();
+        cpu.sp = INITIAL_STACK_POINTER;
+        Ok(Self {
+            cpu,
+            memory,
+            symbol_file: assembled_block.symbol_file(),
+            max_cycles: DEFAULT_MAX_CYCLES,
+        })
+    }
+
+    pub fn set_max_cycles(&mut self, max_cycles: u64) {
+        self.max_cycles = max_cycles;
+    }
+
+    pub fn address_of_label(&self, label: &str) -> Option<Address> {
+        self.symbol_file.address_of_label(label)
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &FlatMemory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut FlatMemory {
+        &mut self.memory
+    }
+
+    // Calls the routine at `label` as if with a JSR, and runs until it returns with the matching
+    // RTS. Returns the number of cycles taken by the routine, including the RTS but not the JSR.
+    pub fn call(&mut self, label: &str) -> Result<u64, CallError> {
+        let address = self
+            .address_of_label(label)
+            .ok_or_else(|| CallError::UndeclaredLabel(label.to_string()))?;
+        self.call_address(address)
+    }
+
+    pub fn call_address(&mut self, address: Address) -> Result<u64, CallError> {
+        // JSR pushes the address of its last byte, which RTS increments
+        let return_address = RETURN_ADDRESS.wrapping_sub(1);
+        self.cpu
+            .push_stack_u8(&mut self.memory, address::hi(return_address));
+        self.cpu
+            .push_stack_u8(&mut self.memory, address::lo(return_address));
+        let sp = self.cpu.sp.wrapping_add(2);
+        self.cpu.pc = address;
+        let mut cycles = 0;
+        while self.cpu.pc != RETURN_ADDRESS || self.cpu.sp != sp {
+            if cycles >= self.max_cycles {
+                return Err(CallError::CycleLimitExceeded {
+                    pc: self.cpu.pc,
+                    max_cycles: self.max_cycles,
+                });
+            }
+            let pc = self.cpu.pc;
+            if self.memory.read_u8_read_only(pc) == opcode::brk::IMPLIED {
+                return Err(CallError::Brk { pc });
+            }
+            cycles += self.cpu.step(&mut self.memory).map_err(|unknown_opcode| {
+                CallError::UnknownOpcode {
+                    pc,
+                    opcode: unknown_opcode.0,
+                }
+            })? as u64;
+        }
+        Ok(cycles)
+    }
+}
@@ -1,11 +1,24 @@
 use crate::*;
-use test_framework::test_sample;
+use mos6502_assembler::Block;
+use mos6502_model::hook::HookAction;
+use mos6502_model::machine::Memory;
+use mos6502_model::{addressing_mode::*, assembler_instruction::*};
+use proptest::prelude::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use subroutine_test::{CallError, SubroutineTest};
+use test_framework::{run_sample_with_hooks, test_sample};
 
 #[test]
 fn arithmetic() {
     test_sample(Arithmetic);
 }
 
+#[test]
+fn control_flow() {
+    test_sample(ControlFlow);
+}
+
 #[test]
 fn counter() {
     test_sample(Counter);
@@ -65,3 +78,115 @@ fn store_accumulator() {
 fn wide_factorial() {
     test_sample(WideFactorial);
 }
+
+#[test]
+fn factorial_with_native_multiply() {
+    let num_calls = Rc::new(Cell::new(0));
+    let (_, devices) = run_sample_with_hooks(Factorial, |assembled_block, hooks| {
+        let num_calls = num_calls.clone();
+        let multiply = assembled_block.address_of_label("multiply").unwrap();
+        hooks.insert(multiply, move |cpu, memory| {
+            // the arguments are the 2 bytes before X, and the result replaces them
+            let lhs = memory.read_u8_read_only(cpu.x.wrapping_sub(2) as Address);
+            let rhs = memory.read_u8_read_only(cpu.x.wrapping_sub(1) as Address);
+            memory.write_u8(cpu.x.wrapping_sub(2) as Address, lhs.wrapping_mul(rhs));
+            cpu.x = cpu.x.wrapping_sub(1);
+            num_calls.set(num_calls.get() + 1);
+            HookAction::Return
+        });
+    });
+    assert_eq!(devices.read_u8_read_only(0), 120);
+    assert_eq!(num_calls.get(), 4);
+}
+
+#[test]
+fn factorial_with_fault_injected() {
+    let (_, devices) = run_sample_with_hooks(Factorial, |assembled_block, hooks| {
+        // make the base case return 2 rather than 1
+        let base_case = assembled_block.address_of_label("factorial_a").unwrap();
+        hooks.insert(base_case, |cpu, _| {
+            cpu.acc = 2;
+            HookAction::Continue
+        });
+    });
+    assert_eq!(devices.read_u8_read_only(0), 240);
+}
+
+#[test]
+fn factorial_with_recursion_skipped() {
+    let (_, devices) = run_sample_with_hooks(Factorial, |assembled_block, hooks| {
+        let base_case = assembled_block.address_of_label("factorial_a").unwrap();
+        let factorial = assembled_block.address_of_label("factorial").unwrap();
+        hooks.insert(factorial, move |cpu, _| {
+            cpu.x = cpu.x.wrapping_sub(1);
+            cpu.acc = 1;
+            HookAction::Jump(base_case)
+        });
+    });
+    assert_eq!(devices.read_u8_read_only(0), 1);
+}
+
+fn factorial_subroutine_test() -> SubroutineTest {
+    let mut block = Block::new();
+    Factorial::program(&mut block);
+    SubroutineTest::new(&block, PRG_START, 0x4000).unwrap()
+}
+
+#[test]
+fn factorial_multiply_subroutine() {
+    let mut test = factorial_subroutine_test();
+    test.memory_mut().write_u8(0x10, 6);
+    test.memory_mut().write_u8(0x11, 7);
+    test.cpu_mut().x = 0x12;
+    let cycles = test.call("multiply").unwrap();
+    assert_eq!(test.memory().read_u8_read_only(0x10), 42);
+    assert_eq!(test.cpu().x, 0x11);
+    assert!(!test.cpu().status.is_zero());
+    assert!(!test.cpu().status.is_negative());
+    assert_eq!(cycles, 175);
+}
+
+#[test]
+fn factorial_multiply_subroutine_cycle_limit() {
+    let mut test = factorial_subroutine_test();
+    test.set_max_cycles(100);
+    test.memory_mut().write_u8(0x10, 200);
+    test.cpu_mut().x = 0x12;
+    assert!(test.call("multiply").is_err());
+}
+
+#[test]
+fn subroutine_reaching_brk() {
+    let mut block = Block::new();
+    block.label("routine");
+    block.inst(Lda(Immediate), 1);
+    block.label("brk");
+    block.inst(Brk, ());
+    block.inst(Rts, ());
+    let mut test = SubroutineTest::new(&block, PRG_START, 0x4000).unwrap();
+    let brk = test.address_of_label("brk").unwrap();
+    match test.call("routine") {
+        Err(CallError::Brk { pc }) => assert_eq!(pc, brk),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    // stopped before the BRK
+    assert_eq!(test.cpu().pc, brk);
+    assert_eq!(test.cpu().acc, 1);
+}
+
+proptest! {
+    #[test]
+    fn factorial_multiply_subroutine_matches_model(lhs: u8, rhs: u8, x in 2u8..=0xFF) {
+        let mut test = factorial_subroutine_test();
+        test.memory_mut().write_u8(x.wrapping_sub(2) as Address, lhs);
+        test.memory_mut().write_u8(x.wrapping_sub(1) as Address, rhs);
+        test.cpu_mut().x = x;
+        let cycles = test.call("multiply").unwrap();
+        prop_assert_eq!(
+            test.memory().read_u8_read_only(x.wrapping_sub(2) as Address),
+            lhs.wrapping_mul(rhs)
+        );
+        prop_assert_eq!(test.cpu().x, x - 1);
+        prop_assert_eq!(cycles, 25 * (lhs as u64 + 1));
+    }
+}
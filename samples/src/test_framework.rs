@@ -1,4 +1,6 @@
 use crate::*;
+use mos6502_assembler::AssembledBlock;
+use mos6502_model::hook::Hooks;
 use mos6502_model::interrupt_vector;
 use mos6502_model::machine::*;
 
@@ -34,15 +36,27 @@ impl Memory for Devices {
 
 const INTERRUPT_VECTOR_START_PC_OFFSET: Address = interrupt_vector::START_LO - PRG_START;
 
-pub fn test_sample<S: Sample>(_: S) {
+pub fn test_sample<S: Sample>(sample: S) {
+    let (cpu, devices) = run_sample_with_hooks(sample, |_, _| ());
+    S::check_result(&cpu, &devices);
+}
+
+// Runs a sample for its usual number of steps, with hooks added by `add_hooks`, which is passed
+// the assembled sample so hooks can be placed at its labels
+pub fn run_sample_with_hooks<S: Sample, F: FnOnce(&AssembledBlock, &mut Hooks)>(
+    _: S,
+    add_hooks: F,
+) -> (Cpu, impl MemoryReadOnly) {
     let mut block = Block::new();
     S::program(&mut block);
     block.set_offset(INTERRUPT_VECTOR_START_PC_OFFSET);
     block.literal_offset_le(0);
     let mut rom = Vec::new();
-    block
+    let assembled_block = block
         .assemble(PRG_START, ROM_BYTES, &mut rom)
         .expect("Failed to assemble");
+    let mut hooks = Hooks::new();
+    add_hooks(&assembled_block, &mut hooks);
     let mut devices = Devices {
         ram: [0; RAM_BYTES],
         rom,
@@ -50,7 +64,7 @@ pub fn test_sample<S: Sample>(_: S) {
     let mut cpu = Cpu::new();
     cpu.start(&mut devices);
     for _ in 0..S::num_steps() {
-        cpu.step(&mut devices).unwrap();
+        cpu.step_with_hooks(&mut devices, &mut hooks).unwrap();
     }
-    S::check_result(&cpu, &devices);
+    (cpu, devices)
 }
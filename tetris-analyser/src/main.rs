@@ -1,8 +1,12 @@
+use analyser::{BankedAddress, Observations};
 use gif_renderer::{Frame as GifFrame, Renderer as GifRenderer};
 use ines::Ines;
-use mos6502_model::debug::{InstructionType, InstructionWithOperand};
+use mos6502_model::debug::{AddressingMode, InstructionType, InstructionWithOperand};
 use mos6502_model::machine::{Address, Cpu, Memory, MemoryReadOnly};
-use nes_emulator_core::{dynamic_nes::DynamicNes, nes::RunForCycles};
+use mos6502_model::opcode;
+use nes_emulator_core::cdl::CodeDataLogger;
+use nes_emulator_core::dynamic_nes::DynamicNes;
+use nes_emulator_core::nes::{RomMapping, RunForCycles};
 use nes_render_output::NoRenderOutput;
 use std::collections::BTreeMap;
 use std::fmt;
@@ -93,25 +97,23 @@ impl<T: Ord> Histogram<T> {
 struct TraceRun {
     nmi_address_histogram: Histogram<Address>,
     function_call_histogram: Histogram<Address>,
+    observations: Observations,
+    code_data_logger: CodeDataLogger,
 }
 
 impl TraceRun {
-    fn new() -> Self {
+    fn new(code_data_logger: CodeDataLogger) -> Self {
         Self {
             nmi_address_histogram: Histogram::new(),
             function_call_histogram: Histogram::new(),
+            observations: Observations::default(),
+            code_data_logger,
         }
     }
 }
 
-impl Default for TraceRun {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl RunForCycles for TraceRun {
-    fn run_for_cycles<M: Memory + MemoryReadOnly>(
+    fn run_for_cycles<M: Memory + MemoryReadOnly + RomMapping>(
         &mut self,
         cpu: &mut Cpu,
         memory: &mut M,
@@ -122,19 +124,47 @@ impl RunForCycles for TraceRun {
         }
         let mut count = 0;
         while count < num_cycles {
-            if let Ok(instruction_with_operand) = InstructionWithOperand::next(cpu, memory) {
-                match instruction_with_operand.instruction().instruction_type() {
-                    InstructionType::Jsr => {
-                        let function_address = instruction_with_operand.operand_u16_le().unwrap();
-                        match function_address {
-                            _ => (),
-                        }
-                        self.function_call_histogram.insert(function_address);
+            let instruction_with_operand = InstructionWithOperand::next(cpu, memory).ok();
+            if let Some(instruction_with_operand) = instruction_with_operand.as_ref() {
+                if let InstructionType::Jsr =
+                    instruction_with_operand.instruction().instruction_type()
+                {
+                    let function_address = instruction_with_operand.operand_u16_le().unwrap();
+                    self.function_call_histogram.insert(function_address);
+                    if let (Some(site_bank), Some(function_bank)) = (
+                        memory.prg_rom_bank(instruction_with_operand.address()),
+                        memory.prg_rom_bank(function_address),
+                    ) {
+                        let call_site = BankedAddress {
+                            bank: site_bank,
+                            address: instruction_with_operand.address(),
+                        };
+                        self.observations
+                            .call_site_banks
+                            .insert((call_site, function_bank));
+                    }
+                }
+            }
+            count += self.code_data_logger.step(cpu, memory).unwrap() as u32;
+            if let Some(instruction_with_operand) = instruction_with_operand {
+                let instruction = instruction_with_operand.instruction();
+                let is_indirect_jump = match instruction.instruction_type() {
+                    InstructionType::Jmp => {
+                        instruction.addressing_mode() == AddressingMode::Indirect
+                    }
+                    // an RTS which doesn't return to just after a JSR is being used to jump to
+                    // an address which was pushed onto the stack
+                    InstructionType::Rts => {
+                        memory.read_u8_read_only(cpu.pc.wrapping_sub(3)) != opcode::jsr::ABSOLUTE
                     }
-                    _ => (),
+                    _ => false,
+                };
+                if is_indirect_jump {
+                    self.observations
+                        .indirect_jumps
+                        .insert((instruction_with_operand.address(), cpu.pc));
                 }
             }
-            count += cpu.step(memory).unwrap() as u32;
         }
     }
 }
@@ -165,7 +195,7 @@ fn main() {
     let args = Args::parser().with_help_default().parse_env_or_exit();
     let ines = ines_from_file(args.rom_path.as_str());
     let mut nes = DynamicNes::from_ines(&ines).unwrap();
-    let mut trace_run = TraceRun::new();
+    let mut trace_run = TraceRun::new(CodeDataLogger::from_ines(&ines));
     start_game(&mut nes, 0, &mut trace_run);
     let mut renderer = GifRenderer::new(File::create(args.gif_path.as_str()).unwrap());
     let mut frame = GifFrame::new();
@@ -175,7 +205,17 @@ fn main() {
         renderer.add(&frame);
     }
     println!("{}", trace_run);
-    let analysis = nes.analyse();
+    {
+        let file = File::create("/tmp/tetris.cdl").unwrap();
+        trace_run.code_data_logger.write_fceux_cdl(file).unwrap();
+        println!(
+            "Wrote /tmp/tetris.cdl (CHR ROM usage is sampled once per frame, so is approximate)"
+        );
+    }
+    trace_run
+        .code_data_logger
+        .add_to_observations(&mut trace_run.observations);
+    let analysis = nes.analyse_with_observations(&trace_run.observations);
     {
         /*
         let (&likely_idle_address, _) = trace_run
@@ -216,12 +256,49 @@ fn main() {
         )
         .unwrap();
     }
+    {
+        let mut file = File::create("/tmp/banked-call-graph.dot").unwrap();
+        write!(
+            &mut file,
+            "{}",
+            nes.analyse_banked(&trace_run.observations)
+                .call_graph_dot_string()
+        )
+        .unwrap();
+    }
+    {
+        let mut file = File::create("/tmp/physics-cfg.dot").unwrap();
+        write!(
+            &mut file,
+            "{}",
+            analysis.control_flow_graph(0x9D17).unwrap().dot_string()
+        )
+        .unwrap();
+    }
     {
         let mut file = File::create("/tmp/functions.txt").unwrap();
         for (address, trace) in analysis.function_traces() {
             write!(&mut file, "0x{:X}:\n{}\n", address, trace).unwrap();
         }
     }
+    {
+        let mut file = File::create("/tmp/xrefs.txt").unwrap();
+        write!(
+            &mut file,
+            "{}",
+            analysis.xref_index(&nes).report(&analysis, &nes)
+        )
+        .unwrap();
+    }
+    {
+        let mut file = File::create("/tmp/disassembly.txt").unwrap();
+        write!(
+            &mut file,
+            "{}",
+            analysis.disassembly().listing(&nes, 0x8000..=0xFFFF)
+        )
+        .unwrap();
+    }
     {
         for i in 0..256 {
             let byte = nes.read_u8_read_only(0x8A9C + i);
@@ -328,7 +328,7 @@ fn modify_rom(ines: &mut Ines, args: &Args) {
         let addresses_of_calls_to_replace = vec![0x8192, 0x817A];
         for &address in &addresses_of_calls_to_replace {
             let base = address as usize - 0x8000;
-            assert!(&ines.prg_rom[base..(base + code_to_replace.len())] == &code_to_replace);
+            assert!(ines.prg_rom[base..(base + code_to_replace.len())] == code_to_replace);
             ines.prg_rom[base..(base + code_to_replace.len())]
                 .copy_from_slice(&code_to_replace_with);
             log::info!(
@@ -363,7 +363,7 @@ fn modify_rom(ines: &mut Ines, args: &Args) {
         let addresses_of_calls_to_replace = vec![0x81CF];
         for &address in &addresses_of_calls_to_replace {
             let base = address as usize - 0x8000;
-            assert!(&ines.prg_rom[base..(base + code_to_replace.len())] == &code_to_replace);
+            assert!(ines.prg_rom[base..(base + code_to_replace.len())] == code_to_replace);
             ines.prg_rom[base..(base + code_to_replace.len())]
                 .copy_from_slice(&code_to_replace_with);
             log::info!(